@@ -0,0 +1,257 @@
+//! Operator endpoints under `/admin`
+//!
+//! Admin routes are disabled unless an `admin_token` or `admin_keys` are
+//! configured, and every request must present the token or a key granted
+//! access to its tenant (see [`crate::scopes`]) as a bearer token.
+
+use spin_sdk::http::{Request, Response};
+
+use std::collections::HashMap;
+
+use crate::{
+    app::App,
+    config::{Config, Overrides, TENANT_TUNABLE, TUNABLE},
+    encoding::Encoding,
+    error::Error,
+    scalable::ScalableFilter,
+    scopes::Access,
+    Exists, ProbeSet,
+};
+
+impl App {
+    /// Check that the request carries the configured admin token, or an
+    /// admin key granted what the request does to its tenant
+    pub fn authorize(&self, req: &Request) -> Result<(), Error> {
+        let config = &self.config;
+        if config.admin_token.is_none() && config.admin_keys.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let Some(token) = bearer(req) else {
+            return Err(Error::Unauthorized);
+        };
+        if let Some(expected) = &config.admin_token {
+            if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+                return Ok(());
+            }
+        }
+        let access = Access::of(req.method());
+        match config
+            .admin_keys
+            .check(token, access, config.tenant.as_deref())
+        {
+            Some(Ok(())) => Ok(()),
+            Some(Err(denial)) => Err(Error::Forbidden(denial.to_string())),
+            None => Err(Error::Unauthorized),
+        }
+    }
+
+    /// Who makes `req`: `admin` with the admin token, the key's ID with an
+    /// admin key
+    pub fn actor(&self, req: &Request) -> Option<String> {
+        let token = bearer(req)?;
+        match &self.config.admin_token {
+            Some(expected) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                Some("admin".into())
+            }
+            _ => self.config.admin_keys.id_of(token).map(str::to_owned),
+        }
+    }
+
+    /// Report the density of set bits across the newest slice in coarse buckets
+    pub fn heatmap(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: HeatmapQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+
+        let filter = self.load_filter()?;
+        let heatmap = bucket_density(filter.current().array.as_bitslice(), query.buckets);
+        Ok(match query.format {
+            HeatmapFormat::Json => crate::json_response(&heatmap)?,
+            HeatmapFormat::Svg => http::Response::builder()
+                .status(200)
+                .header(http::header::CONTENT_TYPE, "image/svg+xml")
+                .body(Some(render_svg(&heatmap).into_bytes().into()))
+                .map_err(anyhow::Error::from)?,
+        })
+    }
+
+    /// Verify the live filter still answers correctly for the canary emails
+    ///
+    /// Responds 503 when an inserted canary is reported as absent, which can
+    /// only happen when the state was lost or corrupted.
+    pub fn canary(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let filter = self.load_filter()?;
+
+        let missing = unexpected(&filter, &self.config.canary_members, Exists::Maybe);
+        let report = CanaryReport {
+            healthy: missing.is_empty(),
+            missing,
+            false_positives: unexpected(&filter, &self.config.canary_absent, Exists::No),
+        };
+        let mut response = crate::json_response(&report)?;
+        if !report.healthy {
+            *response.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+        }
+        Ok(response)
+    }
+
+    /// Insert the configured member canaries into the filter
+    pub fn seed_canaries(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        self.update_filter(|filter| {
+            for email in &self.config.canary_members {
+                filter.insert_probes(&ProbeSet::new(email));
+            }
+            Ok(())
+        })?;
+        Ok(crate::status_response(200))
+    }
+
+    /// Show the runtime configuration overrides
+    pub fn config_overrides(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        Ok(Encoding::accepted(&req).response(&Overrides::load(&self.store)?)?)
+    }
+
+    /// Replace the runtime configuration overrides
+    ///
+    /// The body maps tunable settings to their new values, which for a
+    /// tenant include the [`TENANT_TUNABLE`] ones. They are checked
+    /// to make a valid configuration before being stored as the next
+    /// generation, which instances pick up from their next request on.
+    pub fn override_config(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let Some(body) = req.body().as_ref() else {
+            return Err(Error::bad_request("no body"));
+        };
+        let settings: HashMap<String, String> = Encoding::of_body(&req)
+            .decode(body)
+            .map_err(Error::bad_request)?;
+        let tenanted = self.config.tenant.is_some();
+        for name in settings.keys().map(String::as_str) {
+            if TUNABLE.contains(&name) || tenanted && TENANT_TUNABLE.contains(&name) {
+                continue;
+            }
+            return Err(Error::bad_request(if TENANT_TUNABLE.contains(&name) {
+                format!("`{name}` can only be changed at runtime for a tenant")
+            } else {
+                format!("`{name}` can't be changed at runtime")
+            }));
+        }
+        let overrides = Overrides {
+            generation: Overrides::load(&self.store)?.generation + 1,
+            settings,
+        };
+        Config::with_overrides(overrides.clone(), tenanted)
+            .map_err(|e| Error::bad_request(format!("{e:#}")))?;
+        overrides.save(&self.store)?;
+        Ok(Encoding::accepted(&req).response(&overrides)?)
+    }
+}
+
+/// The bearer token `req` presents, if any
+pub(crate) fn bearer(req: &Request) -> Option<&str> {
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(serde::Deserialize)]
+struct HeatmapQuery {
+    #[serde(default = "default_buckets")]
+    buckets: usize,
+    #[serde(default)]
+    format: HeatmapFormat,
+}
+
+fn default_buckets() -> usize {
+    16
+}
+
+#[derive(serde::Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum HeatmapFormat {
+    #[default]
+    Json,
+    Svg,
+}
+
+#[derive(serde::Serialize)]
+struct Heatmap {
+    num_bits: usize,
+    bucket_bits: usize,
+    /// Fraction of set bits in each bucket
+    density: Vec<f32>,
+}
+
+fn bucket_density(bits: &bitvec::slice::BitSlice<u32>, buckets: usize) -> Heatmap {
+    let bucket_bits = bits.len().div_ceil(buckets.clamp(1, bits.len()));
+    Heatmap {
+        num_bits: bits.len(),
+        bucket_bits,
+        density: bits
+            .chunks(bucket_bits)
+            .map(|chunk| chunk.count_ones() as f32 / chunk.len() as f32)
+            .collect(),
+    }
+}
+
+/// Render the heatmap as a strip of cells, darker meaning more bits set
+fn render_svg(heatmap: &Heatmap) -> String {
+    const CELL: usize = 16;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{CELL}">"#,
+        heatmap.density.len() * CELL
+    );
+    for (i, density) in heatmap.density.iter().enumerate() {
+        svg.push_str(&format!(
+            r#"<rect x="{}" width="{CELL}" height="{CELL}" fill="black" fill-opacity="{density:.3}"/>"#,
+            i * CELL
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[derive(serde::Serialize)]
+struct CanaryReport<'a> {
+    healthy: bool,
+    /// Inserted canaries the filter wrongly rules out
+    missing: Vec<&'a str>,
+    /// Absent canaries the filter can't rule out; expected at the false
+    /// positive rate and so not a failure on their own
+    false_positives: Vec<&'a str>,
+}
+
+/// The emails the filter doesn't give the `expected` answer for
+fn unexpected<'a>(filter: &ScalableFilter, emails: &'a [String], expected: Exists) -> Vec<&'a str> {
+    emails
+        .iter()
+        .filter(|e| filter.exists_probes(&ProbeSet::new(e)) != expected)
+        .map(String::as_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::prelude::*;
+
+    #[test]
+    fn density_per_bucket() {
+        let mut bits = bitarr![u32, Lsb0; 0; 128];
+        bits[..32].fill(true);
+        bits.set(127, true);
+        let heatmap = bucket_density(&bits, 4);
+        assert_eq!(heatmap.bucket_bits, 32);
+        assert_eq!(heatmap.density, vec![1.0, 0.0, 0.0, 1.0 / 32.0]);
+        assert_eq!(bucket_density(&bits, 1000).density.len(), 128);
+        assert!(render_svg(&heatmap).contains(r#"fill-opacity="1.000""#));
+    }
+}
@@ -0,0 +1,52 @@
+//! Roughly how long an email has been registered, for fraud investigations
+//!
+//! Investigators of account takeovers want to know when an email was first
+//! registered without querying the database. A rotating filter knows that
+//! to within a window: an email is inserted into the window it registered
+//! in and stays there until that window expires. So `GET /email` requests
+//! presenting `Authorization: Bearer <membership_age_token>` get a taken
+//! email answered with the start of the oldest active window that may hold
+//! it, as a Unix time in a `member-since` header. Emails registered before
+//! the oldest active window, and filters that don't rotate, get no header.
+//! Like any answer of a Bloom filter, an older window may hold the email by
+//! chance and make it look older than it is.
+
+use anyhow::Result;
+use spin_sdk::http::Request;
+
+use crate::{
+    admin::{bearer, constant_time_eq},
+    app::App,
+    Exists, ProbeSet,
+};
+
+pub(crate) const HEADER: &str = "member-since";
+
+impl App {
+    /// Whether `req` may learn how long emails have been registered
+    pub fn reveals_age(&self, req: &Request) -> bool {
+        let Some(expected) = &self.config.membership_age_token else {
+            return false;
+        };
+        bearer(req).is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+    }
+
+    /// When the oldest active window that may hold any of `emails` started,
+    /// `None` if none may or the filter doesn't rotate
+    pub fn member_since(&self, emails: &[String]) -> Result<Option<u64>> {
+        let Some(rotation) = self.config.rotation else {
+            return Ok(None);
+        };
+        for i in 0..rotation.windows {
+            let Some(slice) = self.load_slice(&self.slice_key(i))? else {
+                continue;
+            };
+            let holds =
+                |email: &String| slice.exists_probes(&ProbeSet::new(email)) == Exists::Maybe;
+            if emails.iter().any(holds) {
+                return Ok(Some(rotation.started(i)));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,70 @@
+//! Plus-address alias expansion
+//!
+//! Many providers deliver `user+tag@domain` to `user@domain`, so for those
+//! domains both addresses identify the same mailbox.
+
+/// Domains whose plus tags should be ignored
+#[derive(Default, serde::Serialize)]
+pub(crate) struct AliasDomains(Vec<String>);
+
+impl AliasDomains {
+    fn matches(&self, domain: &str) -> bool {
+        self.0
+            .iter()
+            .any(|d| d == "*" || d.eq_ignore_ascii_case(domain))
+    }
+
+    /// The canonical, tag-less form of `email` if it is a plus alias on one
+    /// of the configured domains
+    pub fn canonical(&self, email: &str) -> Option<String> {
+        let (local, domain) = email.rsplit_once('@')?;
+        let (user, _tag) = local.split_once('+')?;
+        (!user.is_empty() && self.matches(domain)).then(|| format!("{user}@{domain}"))
+    }
+
+    /// The addresses to consult for `email`: itself followed by its
+    /// canonical form, if any
+    pub fn expand(&self, email: &str) -> Vec<String> {
+        std::iter::once(email.to_owned())
+            .chain(self.canonical(email))
+            .collect()
+    }
+}
+
+impl std::str::FromStr for AliasDomains {
+    type Err = std::convert::Infallible;
+
+    /// Parse a comma separated list of domains, `*` matching every domain
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_strips_tags_on_listed_domains() {
+        let domains: AliasDomains = "gmail.com, fastmail.com".parse().unwrap();
+        assert_eq!(
+            domains.canonical("me+news@Gmail.com").as_deref(),
+            Some("me@Gmail.com")
+        );
+        assert_eq!(domains.canonical("me@gmail.com"), None);
+        assert_eq!(domains.canonical("me+news@example.com"), None);
+        assert_eq!(domains.canonical("+news@gmail.com"), None);
+
+        let all: AliasDomains = "*".parse().unwrap();
+        assert_eq!(
+            all.expand("me+a+b@example.com"),
+            vec!["me+a+b@example.com", "me@example.com"]
+        );
+    }
+}
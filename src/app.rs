@@ -0,0 +1,1037 @@
+//! The request handlers and the services they share
+
+use anyhow::{Context, Result};
+use spin_sdk::http::{Request, Response};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "redis-backend")]
+use crate::filter_store::{Header, Redis};
+use crate::{
+    age,
+    coldstart::Startup,
+    config::{Config, DegradedPolicy, Mode, Overrides},
+    db::{self, Database},
+    domain_quota, domains,
+    error::Error,
+    features::{Feature, Features},
+    filter_store::{self, FilterStore, KeyValue},
+    kv::Kv,
+    metrics::{Counter, Histogram, Metrics},
+    nonce, padding, quota, reference, rotation,
+    scalable::{self, Membership, ScalableFilter},
+    shadow::Shadowed,
+    shards::{self, FilterView, Manifest, SliceView},
+    skeleton, sources, status_response,
+    tenant::Tenants,
+    trace::Trace,
+    traffic, validation, verdict_cache, verification, BloomFilter, Exists, ProbeSet, Removal,
+};
+
+/// The most emails `POST /check` takes at once
+const MAX_BATCH: usize = 100;
+/// How many times a filter update is tried before giving up
+const UPDATE_ATTEMPTS: u32 = 5;
+/// How long the first retry of a filter update waits, doubling after that
+const UPDATE_BACKOFF: Duration = Duration::from_millis(10);
+/// The routes of the admin API, which builds without the `admin-api`
+/// feature leave out
+#[cfg(feature = "admin-api")]
+const ADMIN_ROUTES: &[&str] = &[
+    "/bulk",
+    "/admin/heatmap",
+    "/admin/canary",
+    "/admin/config",
+    "/admin/capacity",
+    "/admin/keys",
+    "/admin/layout",
+    "/admin/audit",
+    "/admin/budget",
+    "/admin/reconcile",
+    "/admin/quarantine",
+    "/admin/rebalance",
+    "/rebuild",
+    "/admin/export",
+    "/merge",
+    "/admin/merge/estimate",
+    "/admin/subtract",
+    "/admin/import",
+    "/admin/import/commit",
+    "/admin/compactions",
+];
+
+/// Everything a request's handlers need, set up once per request
+pub(crate) struct App {
+    pub config: Config,
+    pub store: Kv,
+    pub database: Box<dyn Database>,
+    pub metrics: Box<dyn Metrics>,
+    pub startup: Startup,
+    /// The address of the client behind any trusted proxies, `unknown` when
+    /// it isn't known
+    pub client: String,
+    /// The generation of the newest slice of the filter last loaded, which
+    /// problems report the request was answered against
+    pub filter_generation: Cell<Option<u64>>,
+}
+
+/// The configured database, with its schema brought up to date
+fn open_database(config: &Config, store: &Kv) -> Result<Box<dyn Database>> {
+    Ok(match config.database {
+        db::Backend::Simulated => Box::new(db::Simulated),
+        db::Backend::Mysql => {
+            let mysql = db::MySql {
+                address: config
+                    .mysql_address
+                    .clone()
+                    .context("the mysql database requires `mysql_address`")?,
+                users_table: config.users_table.clone(),
+                invites_table: config.invites_table.clone(),
+            };
+            if config.migrate_schema {
+                db::migrate(store, &mysql)?;
+            }
+            Box::new(mysql)
+        }
+        #[cfg(feature = "sqlite-db")]
+        db::Backend::Sqlite => {
+            let sqlite = db::Sqlite::new(
+                config.sqlite_database.clone(),
+                config.users_table.clone(),
+                config.invites_table.clone(),
+            );
+            if config.migrate_schema {
+                db::migrate(store, &sqlite)?;
+            }
+            Box::new(sqlite)
+        }
+        db::Backend::Service => Box::new(db::Service {
+            url: config
+                .user_service_url
+                .clone()
+                .context("the service database requires `user_service_url`")?,
+            timeout: config.user_service_timeout,
+            tenant: config.tenant.clone(),
+        }),
+    })
+}
+
+impl App {
+    /// Set up for `req`, with the state of the tenant it names
+    pub fn new(req: &Request) -> Result<Self, Error> {
+        let mut startup = Startup::new();
+        let mut store = startup.time("store", Kv::configured);
+        let tenants = startup.time("tenants", Tenants::configured)?;
+        let tenant = tenants.of_request(req)?;
+        if let Some(tenant) = tenant {
+            store.within(&format!("t/{}", tenant.name));
+        }
+        let overrides = startup.time("overrides", || Overrides::load(&store))?;
+        let mut config = startup.time("config", || {
+            Config::with_overrides(overrides.clone(), tenant.is_some())
+        })?;
+        if let Some(tenant) = tenant {
+            tenant.apply(&mut config, &overrides);
+        }
+        let database = startup.time("database", || open_database(&config, &store))?;
+        let metrics = startup.time("metrics", || config.metrics.metrics());
+        let client = config
+            .trusted_proxies
+            .client(req)
+            .map_or_else(|| "unknown".to_owned(), |client| client.to_string());
+        Ok(Self {
+            metrics,
+            config,
+            store,
+            database,
+            startup,
+            client,
+            filter_generation: Cell::new(None),
+        })
+    }
+
+    /// Wrap up after answering a request to `route` with `status`
+    pub fn finish(&self, method: &http::Method, route: &str, status: http::StatusCode) {
+        self.metrics.count(Counter::response(method, status));
+        for _ in 0..self.store.failovers() {
+            self.metrics.count(Counter::StoreFailover);
+        }
+        let (opened, reopened) = self.store.opens();
+        for _ in 0..opened {
+            self.metrics.count(Counter::StoreOpened);
+        }
+        for _ in 0..reopened {
+            self.metrics.count(Counter::StoreReopened);
+        }
+        let (reads, writes) = self.store.take_traffic();
+        for &size in &reads {
+            self.metrics.observe(Histogram::StoreReadBytes, size as f64);
+        }
+        for &size in &writes {
+            self.metrics
+                .observe(Histogram::StoreWriteBytes, size as f64);
+        }
+        if let Err(e) = self.metrics.flush(&self.store) {
+            eprintln!("failed to save metrics: {e:#}");
+        }
+        if self.config.storage_traffic {
+            if let Err(e) = traffic::record(&self.store, route, &reads, &writes) {
+                eprintln!("failed to save storage traffic: {e:#}");
+            }
+        }
+        self.store.catch_up();
+    }
+
+    /// Answer `req`, recording it in the audit log if it is an admin
+    /// mutation that succeeded
+    pub fn route(&self, req: Request) -> Result<Response, Error> {
+        validation::check(&req, self.config.strict_requests)?;
+        let audited = self.audited(&req);
+        let response = self.dispatch(req)?;
+        if let Some(pending) = audited.filter(|_| response.status().is_success()) {
+            if let Err(e) = self.record_audit(pending) {
+                eprintln!("failed to record admin mutation: {e:#}");
+            }
+        }
+        Ok(response)
+    }
+
+    fn dispatch(&self, req: Request) -> Result<Response, Error> {
+        match (req.method(), req.uri().path()) {
+            (&http::Method::GET, "/email") => self.padded(|| self.available(req)),
+            (&http::Method::POST, "/email") => self.add(req),
+            #[cfg(feature = "counting-filter")]
+            (&http::Method::DELETE, "/email") => self.remove(req),
+            (&http::Method::POST, "/check") => self.padded(|| self.check_batch(req)),
+            (&http::Method::GET, "/version") => Ok(self.version()?),
+            #[cfg(feature = "metrics")]
+            (&http::Method::GET, "/metrics") => self.metrics_endpoint(req),
+            (&http::Method::GET, "/stats") => self.health(req),
+            (&http::Method::GET, "/stats/sources") => self.source_stats(req),
+            (&http::Method::GET, "/stats/domains") => self.domain_stats(req),
+            (&http::Method::GET, "/stats/storage-traffic") => self.storage_traffic(req),
+            (&http::Method::GET, "/debug/coldstart") => self.coldstart(req),
+            (&http::Method::GET, "/widget/available") => self.widget(req),
+            (&http::Method::POST, "/support/reference") => self.reference(req),
+            (&http::Method::POST, "/support/reference/verify") => self.verify_reference(req),
+            (&http::Method::GET, path) if path == "/schemas" || path.starts_with("/schemas/") => {
+                self.schemas(req)
+            }
+            #[cfg(feature = "admin-api")]
+            (_, path) if ADMIN_ROUTES.contains(&path) => self.dispatch_admin(req),
+            #[cfg(feature = "metrics")]
+            (_, "/metrics") => Err(Error::MethodNotAllowed),
+            #[cfg(not(feature = "counting-filter"))]
+            (&http::Method::DELETE, "/email") => Err(Error::NotFound),
+            (
+                _,
+                "/email"
+                | "/check"
+                | "/version"
+                | "/stats"
+                | "/stats/sources"
+                | "/stats/domains"
+                | "/stats/storage-traffic"
+                | "/debug/coldstart"
+                | "/widget/available"
+                | "/support/reference"
+                | "/support/reference/verify"
+                | "/schemas",
+            ) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Route a request to one of the `ADMIN_ROUTES`
+    #[cfg(feature = "admin-api")]
+    fn dispatch_admin(&self, req: Request) -> Result<Response, Error> {
+        match (req.method(), req.uri().path()) {
+            (&http::Method::POST, "/bulk") => self.bulk_insert(req),
+            (&http::Method::GET, "/admin/heatmap") => self.heatmap(req),
+            (&http::Method::GET, "/admin/canary") => self.canary(req),
+            (&http::Method::POST, "/admin/canary") => self.seed_canaries(req),
+            (&http::Method::GET, "/admin/config") => self.config_overrides(req),
+            (&http::Method::PUT, "/admin/config") => self.override_config(req),
+            (&http::Method::GET, "/admin/capacity") => self.capacity(req),
+            (&http::Method::GET, "/admin/keys") => self.key_usage(req),
+            (&http::Method::GET, "/admin/layout") => self.layout(req),
+            (&http::Method::GET, "/admin/audit") => self.audit_log(req),
+            (&http::Method::GET, "/admin/budget") => self.budget(req),
+            (&http::Method::POST, "/admin/reconcile") => self.reconcile(req),
+            (&http::Method::POST, "/admin/quarantine") => self.quarantine(req),
+            (&http::Method::POST, "/admin/rebalance") => self.rebalance(req),
+            (&http::Method::POST, "/rebuild") => self.rebuild(req),
+            (&http::Method::GET, "/admin/compactions") => self.compactions(req),
+            (&http::Method::GET, "/admin/export") => self.export(req),
+            (&http::Method::POST, "/merge") => self.merge(req),
+            (&http::Method::POST, "/admin/merge/estimate") => self.estimate_merge(req),
+            (&http::Method::POST, "/admin/import") => self.open_import(req),
+            (&http::Method::GET, "/admin/import") => self.import_state(req),
+            (&http::Method::PUT, "/admin/import") => self.upload_import_chunk(req),
+            (&http::Method::POST, "/admin/import/commit") => self.commit_import(req),
+            #[cfg(feature = "counting-filter")]
+            (&http::Method::POST, "/admin/subtract") => self.subtract(req),
+            #[cfg(not(feature = "counting-filter"))]
+            (_, "/admin/subtract") => Err(Error::NotFound),
+            _ => Err(Error::MethodNotAllowed),
+        }
+    }
+
+    /// The key slice `index` of the filter for the configured mode is
+    /// stored under
+    pub fn slice_key(&self, index: usize) -> String {
+        let key = self.config.mode.state_key();
+        match self.config.rotation {
+            Some(rotation) => rotation.key(key, index),
+            None => scalable::slice_key(key, index),
+        }
+    }
+
+    /// Load the filter for the configured mode, with all of its slices
+    ///
+    /// A rotating filter has a slice for every active window, stored or not.
+    pub fn load_filter(&self) -> Result<ScalableFilter> {
+        let filter = match self.config.rotation {
+            Some(rotation) => {
+                let slices = (0..rotation.windows)
+                    .map(|i| self.load_filter_at(&self.slice_key(i)))
+                    .collect::<Result<_>>()?;
+                ScalableFilter::new(slices, false)
+            }
+            None => {
+                let key = self.config.mode.state_key();
+                let mut slices = vec![self.load_filter_at(key)?];
+                while let Some(slice) = self.load_slice(&scalable::slice_key(key, slices.len()))? {
+                    slices.push(slice);
+                }
+                ScalableFilter::new(slices, self.config.scalable)
+            }
+        };
+        self.filter_generation
+            .set(Some(filter.current().generation));
+        Ok(filter)
+    }
+
+    /// Apply `change` to the filter for the configured mode and store it
+    ///
+    /// The store has no compare-and-swap, so every write bumps the stored
+    /// slice's generation and is read back. A slice whose generation moved on
+    /// since it was loaded, or that reads back as someone else's write, was
+    /// written concurrently: the filter is then reloaded and `change` applied
+    /// again after a backoff. This leaves writers only the short time between
+    /// a check and its write to lose each other's emails in.
+    pub fn update_filter<T>(
+        &self,
+        mut change: impl FnMut(&mut ScalableFilter) -> Result<T>,
+    ) -> Result<T> {
+        for attempt in 0..UPDATE_ATTEMPTS {
+            if attempt > 0 {
+                self.metrics.count(Counter::WriteConflict);
+                let backoff = UPDATE_BACKOFF * 2u32.pow(attempt - 1);
+                sleep(backoff + padding::jitter(UPDATE_BACKOFF));
+            }
+            let mut filter = self.load_filter()?;
+            let result = change(&mut filter)?;
+            if self.try_save_filter(&mut filter)? {
+                self.expire_windows()?;
+                return Ok(result);
+            }
+        }
+        anyhow::bail!(
+            "the filter kept being written concurrently, gave up after {UPDATE_ATTEMPTS} attempts"
+        )
+    }
+
+    /// Store the slices that changed, unless another writer got there first
+    fn try_save_filter(&self, filter: &mut ScalableFilter) -> Result<bool> {
+        let filters = self.filters();
+        for (i, slice) in filter.dirty() {
+            let key = self.slice_key(i);
+            if !filters.merge(&key, slice)? {
+                return Ok(false);
+            }
+            self.shadow_save(&key, slice);
+        }
+        Ok(true)
+    }
+
+    /// The stored slices of the filter for the configured mode, oldest first,
+    /// with their keys
+    pub fn stored_slices(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let filters = self.filters();
+        let mut slices = Vec::new();
+        match self.config.rotation {
+            // Windows nothing was inserted into yet aren't stored
+            Some(rotation) => {
+                for i in 0..rotation.windows {
+                    let key = self.slice_key(i);
+                    if let Some(state) = filters.state(&key)? {
+                        slices.push((key, state));
+                    }
+                }
+            }
+            None => loop {
+                let key = self.slice_key(slices.len());
+                let Some(state) = filters.state(&key)? else {
+                    break;
+                };
+                slices.push((key, state));
+            },
+        }
+        Ok(slices)
+    }
+
+    /// Delete the slices of the windows a rotating filter has passed
+    fn expire_windows(&self) -> Result<()> {
+        let Some(rotation) = self.config.rotation else {
+            return Ok(());
+        };
+        let filters = self.filters();
+        let state_key = self.config.mode.state_key();
+        for key in rotation.expired(state_key) {
+            if !filters.delete(&key)? {
+                break;
+            }
+            self.shadow_delete(&key);
+            eprintln!("dropped the expired filter window at {key}");
+        }
+        rotation::record(&self.store, state_key, &rotation)
+    }
+
+    /// Load the filter for answering availability checks
+    ///
+    /// Slices stored in shards or as Redis bitmaps only have the bits checks
+    /// probe read. A filter that can't be loaded, because its state is
+    /// corrupt or the store is down, is replaced by a saturated one so that
+    /// every check is answered from the database rather than failing.
+    /// Writers keep using `load_filter` so they never save over state that
+    /// failed to load. With a shadow filter store, checks are also compared
+    /// with it, see [`crate::shadow`].
+    fn read_filter(&self) -> Box<dyn Membership + '_> {
+        let mut keys = Vec::new();
+        let mut view = || {
+            let mut slices = Vec::new();
+            for (slice_key, state) in self.stored_slices()? {
+                self.filter_generation
+                    .set(Some(BloomFilter::stored_generation(&state)));
+                keys.push(slice_key.clone());
+                slices.push(if shards::is_manifest(&state) {
+                    SliceView::sharded(&slice_key, Manifest::decode(&state)?)
+                } else if filter_store::is_header(&state) {
+                    self.bitmap_view(&self.config.filter_store, slice_key, &state)?
+                } else {
+                    SliceView::Whole(self.key_value().decode(&slice_key, state)?)
+                });
+            }
+            if slices.is_empty() {
+                slices.push(SliceView::Whole(BloomFilter::new(self.config.filter)));
+            }
+            anyhow::Ok(slices)
+        };
+        let (slices, loaded) = match view() {
+            Ok(slices) => (slices, true),
+            Err(e) => {
+                let key = self.config.mode.state_key();
+                eprintln!(
+                    "can't load the filter at {key}, answering from the database only: {e:#}"
+                );
+                self.metrics.count(Counter::DegradedRead);
+                let saturated = BloomFilter::saturated(self.config.filter);
+                (vec![SliceView::Whole(saturated)], false)
+            }
+        };
+        let primary = FilterView::new(&self.store, &*self.metrics, slices);
+        if self.config.shadow.is_none() {
+            return Box::new(primary);
+        }
+        // A saturated stand-in would only disagree with the shadow
+        let shadow = match loaded {
+            true => self.shadow_view(&keys),
+            false => None,
+        };
+        Box::new(Shadowed {
+            primary,
+            shadow,
+            metrics: &*self.metrics,
+        })
+    }
+
+    /// The slice stored at `slice_key` in `backend` as a Redis bitmap with
+    /// the header `state`
+    #[cfg(feature = "redis-backend")]
+    pub fn bitmap_view(
+        &self,
+        backend: &filter_store::Backend,
+        slice_key: String,
+        state: &[u8],
+    ) -> Result<SliceView> {
+        let filter_store::Backend::Redis(address) = backend else {
+            anyhow::bail!("a Redis bitmap header is stored at {slice_key}");
+        };
+        Ok(SliceView::Bitmap {
+            params: Header::decode(state)?.params,
+            key: slice_key,
+            redis: Redis::new(address, &self.store),
+        })
+    }
+
+    #[cfg(not(feature = "redis-backend"))]
+    pub fn bitmap_view(
+        &self,
+        _backend: &filter_store::Backend,
+        slice_key: String,
+        _state: &[u8],
+    ) -> Result<SliceView> {
+        anyhow::bail!("a Redis bitmap header is stored at {slice_key}")
+    }
+
+    /// The key-value stores as a filter store
+    fn key_value(&self) -> KeyValue<'_> {
+        self.key_value_in(self.config.shard_bits)
+    }
+
+    /// The key-value stores as a filter store with shards of `shard_bits`
+    pub fn key_value_in(&self, shard_bits: Option<usize>) -> KeyValue<'_> {
+        KeyValue {
+            store: &self.store,
+            shard_bits,
+            max_value: self.config.max_value_size,
+            metrics: &*self.metrics,
+        }
+    }
+
+    /// Where the configured filter store keeps slices
+    pub fn filters(&self) -> Box<dyn FilterStore + '_> {
+        match &self.config.filter_store {
+            filter_store::Backend::KeyValue => Box::new(self.key_value()),
+            #[cfg(feature = "redis-backend")]
+            filter_store::Backend::Redis(address) => Box::new(Redis::new(address, &self.store)),
+        }
+    }
+
+    pub fn load_filter_at(&self, key: &str) -> Result<BloomFilter> {
+        match self.load_slice(key)? {
+            Some(filter) => Ok(filter),
+            None => Ok(BloomFilter::new(
+                self.config.seed.fresh(self.config.filter)?,
+            )),
+        }
+    }
+
+    /// Load the filter stored at `key`, if there is one
+    pub fn load_slice(&self, key: &str) -> Result<Option<BloomFilter>> {
+        self.filters().load(key)
+    }
+
+    pub fn save_filter_at(&self, key: &str, filter: &BloomFilter) -> Result<()> {
+        self.filters().save(key, filter)?;
+        self.shadow_save(key, filter);
+        Ok(())
+    }
+
+    /// Whether lookalikes are flagged in answer to a request with `features`
+    fn detects_similar(&self, features: &Features) -> bool {
+        self.config.mode == Mode::Denylist
+            && (self.config.similar_names || features.contains(Feature::SimilarNames))
+    }
+
+    /// Whether registrations are recorded for lookalike detection, which is
+    /// needed as soon as any request may ask for it
+    pub fn records_similar(&self) -> bool {
+        self.detects_similar(&self.config.feature_flags)
+    }
+
+    /// Start tracing a request about `email`, naming the email and the
+    /// client it is from when there is a reference key to name them with
+    fn trace(&self, route: &'static str, email: &str, req: &Request) -> Trace {
+        let keys = &self.config.reference_keys;
+        let canonical = self.config.normalization.apply(email);
+        let mut trace = Trace::start(route, &canonical, &self.config.trace_sampling);
+        trace.step(|| match reference::pseudonym(keys, "email", &canonical) {
+            Some(name) => format!("email={name}"),
+            None => "email=hidden".to_owned(),
+        });
+        trace.step(|| {
+            let Some(client) = self.config.trusted_proxies.client(req) else {
+                return "client=unknown".to_owned();
+            };
+            match reference::pseudonym(keys, "client", &client) {
+                Some(name) => format!("client={name}"),
+                None => "client=hidden".to_owned(),
+            }
+        });
+        trace
+    }
+
+    /// Check whether the supplied email is taken or not.
+    ///
+    /// This is a best effort and might return a 200 when the email is indeed already taken.
+    ///
+    /// Returns 409 if the email is not available, otherwise 200. When the filter
+    /// can't rule the email out and the lookup budget is spent, the configured
+    /// degraded policy decides the status instead.
+    ///
+    /// In allowlist mode this instead checks whether the email was invited,
+    /// returning 200 if it may sign up and 403 otherwise.
+    ///
+    /// Plus aliases on the configured domains are also checked against their
+    /// canonical address.
+    ///
+    /// With `similar_names` enabled, or the `similar-names` feature flag on,
+    /// an available email that looks like a registered one is flagged with a
+    /// `similar-name: taken` header.
+    ///
+    /// With `add_nonce_secret` set, an available answer in denylist mode
+    /// carries the `add-nonce` registering the email needs.
+    ///
+    /// A taken answer to a request presenting the `membership_age_token`
+    /// carries a `member-since` header, see [`crate::age`].
+    fn available(&self, req: Request) -> Result<Response, Error> {
+        let query = req.uri().query();
+        let Some(query) = query else {
+            return Err(Error::bad_request("no query argument"));
+        };
+        let query: Query = serde_qs::from_str(query).map_err(Error::bad_request)?;
+        self.config.email_length.check(&query.email)?;
+
+        let features = Features::of_request(&req, &self.config.feature_flags)?;
+        let mut trace = self.trace("available", &query.email, &req);
+        let filter = self.read_filter();
+
+        let emails = self.config.addresses(&query.email);
+        let member = match self.is_member(&*filter, &emails, true, &mut trace) {
+            Ok(member) => member,
+            Err(e) => {
+                trace.finish(e.status());
+                return Err(e);
+            }
+        };
+        let status = match (self.config.mode, member) {
+            (Mode::Denylist, true) => 409,
+            (Mode::Denylist, false) => 200,
+            (Mode::Allowlist, true) => 200,
+            (Mode::Allowlist, false) => 403,
+        };
+
+        let mut response = status_response(status);
+        if let (Mode::Denylist, 409) = (self.config.mode, status) {
+            if self.reveals_age(&req) {
+                if let Some(since) = self.member_since(&emails)? {
+                    trace.step(|| format!("member_since={since}"));
+                    response
+                        .headers_mut()
+                        .insert(age::HEADER, http::HeaderValue::from(since));
+                }
+            }
+        }
+        if let (Mode::Denylist, 200) = (self.config.mode, status) {
+            let ttl = self.config.add_nonce_ttl;
+            if let Some(nonce) = nonce::issue(&self.config.add_nonce_keys, &emails[0], ttl)? {
+                let nonce = http::HeaderValue::from_str(&nonce).map_err(anyhow::Error::from)?;
+                response.headers_mut().insert(nonce::HEADER, nonce);
+            }
+        }
+        if status == 200 && self.detects_similar(&features) {
+            let skeletons = self.load_filter_at(skeleton::SKELETON_KEY)?;
+            let similar =
+                skeletons.exists_probes(&ProbeSet::new(&skeleton::skeleton(&query.email)));
+            trace.step(|| format!("similar={similar:?}"));
+            if similar == Exists::Maybe {
+                response.headers_mut().insert(
+                    skeleton::SIMILAR_HEADER,
+                    http::HeaderValue::from_static("taken"),
+                );
+            }
+        }
+        trace.finish(status);
+        Ok(response)
+    }
+
+    /// Check a batch of emails at once, answering which are available
+    ///
+    /// The body is a JSON array of at most `MAX_BATCH` emails, and the answer
+    /// maps each to `available` or `taken`, or in allowlist mode to
+    /// `available` or `not_invited`. The filter is loaded once, only emails it
+    /// can't rule out are looked up, and repeated emails are checked once.
+    fn check_batch(&self, req: Request) -> Result<Response, Error> {
+        let Some(body) = req.body().as_ref() else {
+            return Err(Error::bad_request("no body"));
+        };
+        let emails: BTreeSet<String> = serde_json::from_slice(body).map_err(Error::bad_request)?;
+        if emails.len() > MAX_BATCH {
+            return Err(Error::bad_request(format!(
+                "at most {MAX_BATCH} emails can be checked at once"
+            )));
+        }
+        for email in &emails {
+            self.config.email_length.check(email)?;
+        }
+
+        let filter = self.read_filter();
+        let mut verdicts = BTreeMap::new();
+        for email in &emails {
+            let mut trace = self.trace("check", email, &req);
+            let aliases = self.config.addresses(email);
+            let member = match self.is_member(&*filter, &aliases, true, &mut trace) {
+                Ok(member) => member,
+                Err(e) => {
+                    trace.finish(e.status());
+                    return Err(e);
+                }
+            };
+            let verdict = match (self.config.mode, member) {
+                (Mode::Denylist, true) => Verdict::Taken,
+                (Mode::Allowlist, false) => Verdict::NotInvited,
+                _ => Verdict::Available,
+            };
+            trace.finish(200);
+            verdicts.insert(email.as_str(), verdict);
+        }
+        Ok(crate::json_response(&verdicts)?)
+    }
+
+    /// Whether any of `emails` is in the set, consulting the database for the
+    /// ones the filter can't rule out
+    ///
+    /// Database lookups are only limited by the lookup budget if `budgeted`.
+    fn is_member(
+        &self,
+        filter: &dyn Membership,
+        emails: &[String],
+        budgeted: bool,
+        trace: &mut Trace,
+    ) -> Result<bool, Error> {
+        let config = &self.config;
+        for (i, email) in emails.iter().enumerate() {
+            let exists = filter.exists_probes(&ProbeSet::new(email));
+            trace.step(|| format!("candidate={i} filter={exists:?}"));
+            if exists == Exists::No {
+                self.metrics.count(Counter::FilterNo);
+                continue;
+            }
+            self.metrics.count(Counter::FilterMaybe);
+            let state_key = config.mode.state_key();
+            let cached = match config.verdict_cache_ttl {
+                Some(_) => verdict_cache::get(&self.store, state_key, email)?,
+                None => None,
+            };
+            if let Some(found) = cached {
+                trace.step(|| format!("cached_found={found}"));
+                self.metrics.count(Counter::VerdictCacheHit);
+                if found {
+                    return Ok(true);
+                }
+                continue;
+            }
+            if let Some(exhausted) = self.lookup_refused(budgeted)? {
+                trace.step(|| {
+                    format!(
+                        "budget=exhausted tier={exhausted:?} policy={:?}",
+                        config.degraded_policy
+                    )
+                });
+                self.metrics.count(match exhausted {
+                    quota::Exhausted::Total => Counter::BudgetExhausted,
+                    quota::Exhausted::Client => Counter::ClientBudgetExhausted,
+                });
+                // Assuming membership of an allowlist would let anyone in
+                match (config.mode, config.degraded_policy) {
+                    (Mode::Denylist, DegradedPolicy::Taken) => return Ok(true),
+                    _ => {
+                        return Err(Error::Unavailable {
+                            detail: match exhausted {
+                                quota::Exhausted::Total => "the lookup budget is exhausted",
+                                quota::Exhausted::Client => {
+                                    "this client spent its share of the lookup budget"
+                                }
+                            }
+                            .into(),
+                            retry_after: quota::seconds_until_reset(config.lookup_budget_window),
+                        })
+                    }
+                }
+            }
+            let started = Instant::now();
+            let found = match config.mode {
+                Mode::Denylist => self.database.user_exists(email),
+                Mode::Allowlist => self.database.invite_exists(email),
+            };
+            // Failing open would let anyone sign up, so invites always fail
+            // closed; the fallback isn't cached, unlike real answers
+            let found = match found {
+                Err(e)
+                    if e.is::<db::Unavailable>()
+                        && config.mode == Mode::Denylist
+                        && config.user_service_failure_policy == DegradedPolicy::Taken =>
+                {
+                    eprintln!("{e}, reporting the email as taken");
+                    trace.step(|| "lookup=unavailable policy=Taken".to_owned());
+                    return Ok(true);
+                }
+                found => found?,
+            };
+            self.metrics.count(Counter::DatabaseLookup);
+            self.metrics.observe(
+                Histogram::DatabaseLookupSeconds,
+                started.elapsed().as_secs_f64(),
+            );
+            trace.step(|| format!("lookup_found={found}"));
+            if let Some(ttl) = config.verdict_cache_ttl {
+                verdict_cache::put(&self.store, state_key, email, found, ttl)?;
+            }
+            if found {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The lookup budget's limits
+    pub fn lookup_limits(&self) -> quota::Limits {
+        quota::Limits {
+            total: self.config.lookup_budget,
+            per_client: self.config.client_lookup_budget,
+            window: self.config.lookup_budget_window,
+        }
+    }
+
+    /// Why the lookup budget leaves no room for another expensive lookup,
+    /// `None` if it does or the lookup isn't `budgeted`
+    fn lookup_refused(&self, budgeted: bool) -> Result<Option<quota::Exhausted>> {
+        let limits = self.lookup_limits();
+        if !budgeted || limits.total.is_none() && limits.per_client.is_none() {
+            return Ok(None);
+        }
+        quota::try_acquire(&self.store, limits, &self.client)
+    }
+
+    /// Register an email, or in allowlist mode invite it
+    ///
+    /// Inviting is an admin operation and requires the admin token.
+    ///
+    /// Returns 409 without touching the database or the filter if the email
+    /// is already registered, or 200 if `allow_existing` is set so that
+    /// retried adds are idempotent.
+    ///
+    /// With a verification service configured, emails whose ownership isn't
+    /// confirmed yet are recorded as pending and answered with 202.
+    fn add(&self, req: Request) -> Result<Response, Error> {
+        let config = &self.config;
+        if config.mode == Mode::Allowlist {
+            self.authorize(&req)?;
+        }
+        let Some(body) = req.body().as_ref() else {
+            return Err(Error::bad_request("no body"));
+        };
+        let body: Body = serde_json::from_slice(body).map_err(Error::bad_request)?;
+        config.email_length.check(&body.email)?;
+        let mut trace = self.trace("add", &body.email, &req);
+        let emails = config.addresses(&body.email);
+        let refused = |refusal: nonce::Refusal| {
+            Error::Forbidden(format!(
+                "{refusal}: registrations need the add-nonce of an available answer \
+                 to GET /email"
+            ))
+        };
+        // Verified now, but only spent once the registration is let through
+        let mut nonce = None;
+        if config.mode == Mode::Denylist && !config.add_nonce_keys.is_empty() {
+            let presented = req
+                .headers()
+                .get(nonce::HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            match nonce::verify(&config.add_nonce_keys, &emails[0], presented)? {
+                Ok(verified) => nonce = Some(verified),
+                Err(refusal) => {
+                    trace.finish(403);
+                    return Err(refused(refusal));
+                }
+            }
+        }
+
+        // Admins register quarantined emails once their owners are verified
+        if self.authorize(&req).is_err() && self.is_quarantined(&emails[0])? {
+            trace.finish(422);
+            return Err(Error::Quarantined);
+        }
+        if let Err(e) = self.ensure_storage() {
+            trace.finish(e.status());
+            return Err(e);
+        }
+
+        let state = self.load_filter()?;
+        // The lookup budget protects availability checks, registrations
+        // always get an accurate answer
+        if self.is_member(&state, &emails, false, &mut trace)? {
+            if body.allow_existing {
+                trace.finish(200);
+                return Ok(status_response(200));
+            }
+            trace.finish(409);
+            return Err(Error::Conflict(format!(
+                "{} is already registered",
+                body.email
+            )));
+        }
+        if let Some(verified) = &nonce {
+            if let Err(refusal) = nonce::spend(&self.store, verified)? {
+                trace.finish(403);
+                return Err(refused(refusal));
+            }
+        }
+
+        if let (Mode::Denylist, Some(limit)) = (config.mode, config.domain_signup_limit) {
+            let verdict =
+                domain_quota::check(&self.store, &body.email, limit, config.domain_signup_window)?;
+            if let domain_quota::Verdict::Exceeded { retry_after } = verdict {
+                self.metrics.count(Counter::DomainQuotaExceeded);
+                trace.finish(429);
+                return Err(Error::TooManyRequests {
+                    detail: format!(
+                        "too many signups from {}, at most {limit} are allowed per {}s",
+                        domain_quota::domain(&body.email).unwrap_or_default(),
+                        config.domain_signup_window.as_secs()
+                    ),
+                    retry_after,
+                });
+            }
+        }
+        if let Some(url) = &config.verification_url {
+            let verified =
+                verification::verify(&self.store, url, &config.verification_keys, &body.email)?;
+            trace.step(|| format!("verified={verified}"));
+            if !verified {
+                verification::record_pending(&self.store, &body.email)?;
+                self.metrics.count(Counter::RegistrationPending);
+                trace.finish(202);
+                return Ok(status_response(202));
+            }
+            verification::clear_pending(&self.store, &body.email)?;
+        }
+        // The canonical form, which later lookups of any form of it consult
+        let canonical = &emails[0];
+        match config.mode {
+            Mode::Denylist => self.database.add_user(canonical)?,
+            Mode::Allowlist => self.database.add_invite(canonical)?,
+        }
+        if let (Mode::Denylist, Some(_)) = (config.mode, config.domain_signup_limit) {
+            domain_quota::record(&self.store, &body.email, config.domain_signup_window)?;
+        }
+
+        // Also remember the canonical address so that checking it, or any
+        // other alias of it, finds this registration. The filter is loaded
+        // afresh so that writes since the membership check aren't lost.
+        self.update_filter(|state| {
+            for email in &emails {
+                state.insert_probes(&ProbeSet::new(email));
+            }
+            Ok(())
+        })?;
+        trace.step(|| format!("inserted={}", emails.len()));
+        if self.records_similar() {
+            let mut skeletons = self.load_filter_at(skeleton::SKELETON_KEY)?;
+            skeletons.insert(skeleton::skeleton(&body.email));
+            self.save_filter_at(skeleton::SKELETON_KEY, &skeletons)?;
+        }
+        // Earlier checks may have cached these emails as free
+        if let Some(ttl) = config.verdict_cache_ttl {
+            for email in &emails {
+                verdict_cache::put(&self.store, config.mode.state_key(), email, true, ttl)?;
+            }
+        }
+        sources::record(&self.store, &body.source)?;
+        domains::record(&self.store, &emails[..1])?;
+        self.metrics.count(Counter::Registered);
+        trace.finish(200);
+        Ok(status_response(200))
+    }
+
+    /// Remove a deleted account's email from a counting filter
+    ///
+    /// Only the filter is changed; the account itself is deleted elsewhere.
+    /// The email and its aliases are removed as they were inserted. Counters
+    /// that had lost count keep their bits set, so the email may still be a
+    /// `Maybe`, which is reported as `saturated`.
+    fn remove(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let Some(query) = req.uri().query() else {
+            return Err(Error::bad_request("no query argument"));
+        };
+        let query: Query = serde_qs::from_str(query).map_err(Error::bad_request)?;
+        self.config.email_length.check(&query.email)?;
+        let emails = self.config.addresses(&query.email);
+        let report = self.update_filter(|filter| {
+            if !filter.current().params().counting {
+                return Ok(None);
+            }
+            let mut report = RemovalReport::default();
+            for email in &emails {
+                match filter.remove_probes(&ProbeSet::new(email))? {
+                    Removal::Absent => {}
+                    Removal::Removed { saturated } => {
+                        report.removed = true;
+                        report.saturated |= saturated > 0;
+                    }
+                }
+            }
+            Ok(Some(report))
+        })?;
+        let Some(report) = report else {
+            return Err(Error::bad_request(
+                "only filters created with `bloom_counting` set can remove emails",
+            ));
+        };
+        if report.removed {
+            self.metrics.count(if report.saturated {
+                Counter::RemovedSaturated
+            } else {
+                Counter::Removed
+            });
+        }
+        // Checks may have cached these emails as taken
+        if let Some(ttl) = self.config.verdict_cache_ttl {
+            for email in &emails {
+                verdict_cache::put(&self.store, self.config.mode.state_key(), email, false, ttl)?;
+            }
+        }
+        Ok(crate::json_response(&report)?)
+    }
+}
+
+/// The answer for one email of a batch check
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Available,
+    Taken,
+    NotInvited,
+}
+
+#[derive(serde::Serialize, Default)]
+struct RemovalReport {
+    /// Whether the filter held the email
+    removed: bool,
+    /// Whether some of its bits stay set because their counters lost count
+    saturated: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct Query {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Body {
+    email: String,
+    /// Answer 200 rather than 409 when the email is already registered
+    #[serde(default)]
+    allow_existing: bool,
+    /// Where the registration came from, for `/stats/sources`
+    #[serde(default)]
+    source: sources::Source,
+}
@@ -0,0 +1,177 @@
+//! A log of the admin mutations
+//!
+//! Every admin request that changes state and succeeds is recorded with who
+//! made it, when, a digest of its parameters and the configuration and
+//! filter generations it left behind. Entries are numbered from 0 and each
+//! is stored under its own `__audit:<number>` key, never rewritten, with
+//! `__audit` holding how many there are. The store has no compare-and-swap,
+//! so an entry's number is claimed by checking its key is free before
+//! writing it and reading it back; two mutations finishing at the same
+//! instant can still leave one entry.
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use spin_sdk::http::{Request, Response};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{app::App, config::Overrides, encoding::Encoding, error::Error, kv::Kv};
+
+const COUNT_KEY: &str = "__audit";
+/// The most entries a page lists
+const MAX_PAGE: usize = 500;
+
+/// The routes recorded, those of the admin mutations
+const AUDITED: &[(http::Method, &str)] = &[
+    (http::Method::DELETE, "/email"),
+    (http::Method::POST, "/bulk"),
+    (http::Method::POST, "/admin/canary"),
+    (http::Method::PUT, "/admin/config"),
+    (http::Method::POST, "/admin/reconcile"),
+    (http::Method::POST, "/admin/quarantine"),
+    (http::Method::POST, "/admin/rebalance"),
+    (http::Method::POST, "/rebuild"),
+    (http::Method::POST, "/merge"),
+    (http::Method::POST, "/admin/subtract"),
+    (http::Method::POST, "/admin/import/commit"),
+];
+
+/// An admin mutation, as recorded
+#[derive(PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Entry {
+    pub number: u64,
+    /// When it finished, in Unix seconds
+    pub at: u64,
+    /// `admin` for the admin token, the key's ID for an admin key
+    pub actor: String,
+    /// The method and path
+    pub operation: String,
+    /// The SHA-256 of the query and body, in hex
+    pub digest: String,
+    /// The generation of the runtime overrides afterwards
+    pub config_generation: u64,
+    /// The generation of the filter's newest slice afterwards
+    pub filter_generation: u64,
+}
+
+/// A mutation that is recorded once it succeeds
+pub(crate) struct Pending {
+    actor: String,
+    operation: String,
+    digest: String,
+}
+
+/// Its key, for the entry numbered `number`
+fn entry_key(number: u64) -> String {
+    format!("{COUNT_KEY}:{number}")
+}
+
+fn count(store: &Kv) -> Result<u64> {
+    Ok(match store.get(COUNT_KEY)? {
+        Some(bytes) => u64::from_be_bytes(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid audit log count"))?,
+        ),
+        None => 0,
+    })
+}
+
+/// Append an entry made by `make` with the number it is stored under
+fn append(store: &Kv, make: impl Fn(u64) -> Entry) -> Result<u64> {
+    let mut number = count(store)?;
+    loop {
+        let key = entry_key(number);
+        if store.get(&key)?.is_none() {
+            let entry = serde_json::to_vec(&make(number))?;
+            store.set(&key, &entry)?;
+            if store.get(&key)?.as_deref() == Some(&entry[..]) {
+                store.set(COUNT_KEY, &(number + 1).to_be_bytes())?;
+                return Ok(number);
+            }
+        }
+        number += 1;
+    }
+}
+
+/// The entries numbered from `after + 1` on, at most `limit` of them
+fn page(store: &Kv, after: Option<u64>, limit: usize) -> Result<Vec<Entry>> {
+    let first = after.map_or(0, |after| after + 1);
+    let end = count(store)?.min(first.saturating_add(limit as u64));
+    (first..end)
+        .filter_map(|number| store.get(&entry_key(number)).transpose())
+        .map(|entry| Ok(serde_json::from_slice(&entry?)?))
+        .collect()
+}
+
+impl App {
+    /// The mutation `req` makes if it is an audited one
+    pub fn audited(&self, req: &Request) -> Option<Pending> {
+        let path = req.uri().path();
+        AUDITED
+            .iter()
+            .find(|(method, audited)| method == req.method() && *audited == path)?;
+        let mut digest = Sha256::new();
+        digest.update(req.uri().query().unwrap_or_default());
+        digest.update([0]);
+        digest.update(req.body().as_deref().unwrap_or_default());
+        Some(Pending {
+            actor: self.actor(req)?,
+            operation: format!("{} {path}", req.method()),
+            digest: digest
+                .finalize()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        })
+    }
+
+    /// Record `pending`, which succeeded
+    pub fn record_audit(&self, pending: Pending) -> Result<()> {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let config_generation = Overrides::load(&self.store)?.generation;
+        let filter_generation = self.load_filter()?.current().generation;
+        append(&self.store, |number| Entry {
+            number,
+            at,
+            actor: pending.actor.clone(),
+            operation: pending.operation.clone(),
+            digest: pending.digest.clone(),
+            config_generation,
+            filter_generation,
+        })?;
+        Ok(())
+    }
+
+    /// List the audit log, oldest first
+    pub fn audit_log(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: Query = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let limit = query.limit.clamp(1, MAX_PAGE);
+        let entries = page(&self.store, query.after, limit)?;
+        let next = match entries.last() {
+            Some(last) if entries.len() == limit => Some(last.number),
+            _ => None,
+        };
+        Ok(Encoding::accepted(&req).response(&Page { entries, next })?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Query {
+    /// The number of the last entry already listed
+    after: Option<u64>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    50
+}
+
+#[derive(serde::Serialize)]
+struct Page {
+    entries: Vec<Entry>,
+    /// What to pass as `after` for the next page, `None` on the last one
+    next: Option<u64>,
+}
@@ -0,0 +1,364 @@
+//! Seeding the filter with many emails at once
+//!
+//! Migrating an existing user table one `POST /email` at a time reads and
+//! writes the whole filter for every user. `POST /bulk` takes a JSON array of
+//! emails, or plain text with one email per line, and writes the filter back
+//! once.
+//!
+//! Bodies listing emails are read one email at a time rather than parsed
+//! into a list of them all, and handed on `bulk_max_in_flight` at a time, so
+//! that the parsed emails held in memory don't grow with the upload. The
+//! body itself is not streamed: Spin 1 hands the component the whole upload
+//! already buffered, so bodies larger than `bulk_max_body_size` are refused,
+//! and each step that needs the emails parses the buffered body again, up
+//! to four times for `POST /bulk`. Reading the body as it arrives needs an
+//! SDK with streaming bodies. JSON bodies are held to their schema as they
+//! are read instead of before reaching the handler.
+
+use anyhow::Result;
+use bitvec::prelude::*;
+use serde::de::{Deserializer, Error as _, SeqAccess, Visitor};
+use serde_json::Value;
+use spin_sdk::http::{Request, Response};
+
+use crate::{
+    app::App,
+    config::Config,
+    domains,
+    encoding::{self, JSON},
+    error::Error,
+    schema, skeleton, Exists, ProbeSet,
+};
+
+/// The media type of bodies listing one email per line
+pub(crate) const LINES: &str = "text/plain";
+/// The most emails one request may insert
+const MAX_EMAILS: usize = 100_000;
+
+#[derive(serde::Serialize)]
+pub(crate) struct Report {
+    received: usize,
+    inserted: usize,
+    /// Emails the filter may already have held, including repeats within
+    /// the request and the odd false positive
+    already_present: usize,
+}
+
+impl App {
+    /// Insert many emails into the filter, storing it once
+    ///
+    /// The emails are only added to the filter, not to the database, as they
+    /// are expected to come from it. Verdicts cached for them expire with the
+    /// verdict cache's TTL.
+    pub fn bulk_insert(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        self.ensure_storage()?;
+        let listing = Listing::of(&req, "bulk.request", MAX_EMAILS, &self.config)?;
+        Ok(crate::json_response(&self.insert_listing(&listing)?)?)
+    }
+
+    /// Insert the emails `listing` lists into the filter, storing it once
+    pub fn insert_listing(&self, listing: &Listing) -> Result<Report, Error> {
+        let received = listing.each_chunk(|emails| {
+            emails
+                .iter()
+                .try_for_each(|email| self.config.email_length.check(email))
+        })?;
+
+        let (report, inserted) = self.update_filter(|filter| {
+            let mut report = Report {
+                received,
+                inserted: 0,
+                already_present: 0,
+            };
+            // Which of the emails were inserted, by position in the body
+            let mut inserted = BitVec::<usize, Lsb0>::with_capacity(received);
+            listing.reread(|emails| {
+                for email in emails {
+                    let aliases = self.config.addresses(email);
+                    let present = aliases
+                        .iter()
+                        .all(|alias| filter.exists_probes(&ProbeSet::new(alias)) == Exists::Maybe);
+                    inserted.push(!present);
+                    if present {
+                        report.already_present += 1;
+                        continue;
+                    }
+                    for alias in &aliases {
+                        filter.insert_probes(&ProbeSet::new(alias));
+                    }
+                    report.inserted += 1;
+                }
+            })?;
+            Ok((report, inserted))
+        })?;
+        if inserted.any() {
+            let mut tally = domains::Tally::load(&self.store)?;
+            let mut positions = inserted.iter().by_vals();
+            listing.reread(|emails| {
+                for email in emails {
+                    if positions.next() == Some(true) {
+                        tally.count(&self.config.addresses(email)[0]);
+                    }
+                }
+            })?;
+            tally.save(&self.store)?;
+        }
+        if self.records_similar() {
+            let mut skeletons = self.load_filter_at(skeleton::SKELETON_KEY)?;
+            listing.reread(|emails| {
+                for email in emails {
+                    skeletons.insert(skeleton::skeleton(email));
+                }
+            })?;
+            self.save_filter_at(skeleton::SKELETON_KEY, &skeletons)?;
+        }
+        eprintln!(
+            "bulk insert of {} emails added {}",
+            report.received, report.inserted
+        );
+        Ok(report)
+    }
+}
+
+/// A request body listing emails, as a JSON array or one per line
+pub(crate) struct Listing<'a> {
+    json: bool,
+    body: &'a [u8],
+    /// The schema JSON bodies must match
+    schema: &'static str,
+    /// The most emails the body may list
+    max: usize,
+    /// The most emails handed on at once
+    in_flight: usize,
+}
+
+impl<'a> Listing<'a> {
+    /// The body of `req`, refused if it is larger than `bulk_max_body_size`
+    pub fn of(
+        req: &'a Request,
+        schema: &'static str,
+        max: usize,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let body = req.body().as_deref().unwrap_or_default();
+        if body.len() > config.bulk_max_body {
+            return Err(Error::PayloadTooLarge(format!(
+                "bodies may be at most {} bytes",
+                config.bulk_max_body
+            )));
+        }
+        let content_type = req
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let json = encoding::essence(content_type).eq_ignore_ascii_case(JSON);
+        Ok(Self::new(body, json, schema, max, config))
+    }
+
+    /// `body`, a JSON array if `json` and one email per line otherwise
+    pub fn new(
+        body: &'a [u8],
+        json: bool,
+        schema: &'static str,
+        max: usize,
+        config: &Config,
+    ) -> Self {
+        Self {
+            json,
+            body,
+            schema,
+            max,
+            in_flight: config.bulk_in_flight.max(1),
+        }
+    }
+
+    /// Hand the emails to `f` in order, at most `in_flight` at a time,
+    /// answering how many there were
+    ///
+    /// Reading stops at the first error, whether the body's or `f`'s.
+    pub fn each_chunk(
+        &self,
+        mut f: impl FnMut(&[String]) -> Result<(), Error>,
+    ) -> Result<usize, Error> {
+        let mut chunks = Chunks {
+            listing: self,
+            chunk: Vec::with_capacity(self.in_flight.min(self.max)),
+            count: 0,
+            f: &mut f,
+            failure: None,
+        };
+        if self.json {
+            let mut deserializer = serde_json::Deserializer::from_slice(self.body);
+            if let Err(e) = deserializer.deserialize_seq(&mut chunks) {
+                return Err(match chunks.failure {
+                    Some(failure) => failure,
+                    None if e.is_data() => self.mismatch("", "must be of type array"),
+                    None => Error::bad_request(e),
+                });
+            }
+            deserializer.end().map_err(Error::bad_request)?;
+        } else {
+            for line in self.body.split(|&b| b == b'\n') {
+                let line = std::str::from_utf8(line).map_err(Error::bad_request)?;
+                let line = line.trim();
+                if !line.is_empty() {
+                    chunks.push(line.to_owned())?;
+                }
+            }
+        }
+        chunks.flush()?;
+        Ok(chunks.count)
+    }
+
+    /// Hand the emails of a body `each_chunk` already read to `f` again
+    pub fn reread(&self, mut f: impl FnMut(&[String])) -> Result<()> {
+        self.each_chunk(|emails| {
+            f(emails);
+            Ok(())
+        })
+        .map_err(|e| anyhow::anyhow!("a body that was read once no longer reads: {e:?}"))?;
+        Ok(())
+    }
+
+    fn mismatch(&self, pointer: &str, detail: &str) -> Error {
+        schema::mismatch(self.schema, vec![schema::violation(pointer, detail)])
+    }
+}
+
+/// The emails read so far that weren't handed on yet
+struct Chunks<'l, 'f> {
+    listing: &'l Listing<'l>,
+    chunk: Vec<String>,
+    /// How many emails were read
+    count: usize,
+    f: &'f mut dyn FnMut(&[String]) -> Result<(), Error>,
+    /// Why reading a JSON body stopped, unless it was the JSON itself
+    failure: Option<Error>,
+}
+
+impl Chunks<'_, '_> {
+    fn push(&mut self, email: String) -> Result<(), Error> {
+        let listing = self.listing;
+        if self.count == listing.max {
+            return Err(if listing.json {
+                listing.mismatch("", &format!("must have at most {} items", listing.max))
+            } else {
+                Error::bad_request(format!(
+                    "at most {} emails can be sent at once",
+                    listing.max
+                ))
+            });
+        }
+        self.count += 1;
+        self.chunk.push(email);
+        if self.chunk.len() == listing.in_flight {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.chunk.is_empty() {
+            (self.f)(&self.chunk)?;
+            self.chunk.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Visitor<'de> for &mut Chunks<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an array of emails")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(item) = seq.next_element::<Value>()? {
+            let pushed = match item {
+                Value::String(email) => self.push(email),
+                _ => Err(self
+                    .listing
+                    .mismatch(&format!("/{}", self.count), "must be of type string")),
+            };
+            if let Err(e) = pushed {
+                self.failure = Some(e);
+                return Err(A::Error::custom("stopped reading"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(json: bool, body: &[u8], in_flight: usize) -> Listing {
+        Listing {
+            json,
+            body,
+            schema: "bulk.request",
+            max: 3,
+            in_flight,
+        }
+    }
+
+    /// The chunks `listing` hands on, or the error reading it fails with
+    fn chunks(listing: &Listing) -> Result<Vec<Vec<String>>, Error> {
+        let mut chunks = Vec::new();
+        listing.each_chunk(|emails| {
+            chunks.push(emails.to_vec());
+            Ok(())
+        })?;
+        Ok(chunks)
+    }
+
+    #[test]
+    fn reads_both_formats_a_chunk_at_a_time() {
+        let (a, b, c) = ("a@example.com", "b@example.com", "c@example.com");
+        let json = br#"["a@example.com", "b@example.com", "c@example.com"]"#;
+        assert_eq!(
+            chunks(&listing(true, json, 2)).unwrap(),
+            [vec![a, b], vec![c]]
+        );
+        let lines = b"a@example.com\r\n\n  b@example.com\nc@example.com";
+        assert_eq!(chunks(&listing(false, lines, 1)).unwrap(), [[a], [b], [c]]);
+        assert_eq!(listing(false, b"", 1).each_chunk(|_| Ok(())).unwrap(), 0);
+
+        let invalid = |body: &[u8]| match chunks(&listing(true, body, 2)) {
+            Err(Error::InvalidBody { errors, .. }) => errors,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(
+            invalid(br#"["a@example.com", 7]"#),
+            [schema::violation("/1", "must be of type string")]
+        );
+        assert_eq!(
+            invalid(br#"{"email": "a@example.com"}"#),
+            [schema::violation("", "must be of type array")]
+        );
+        assert_eq!(
+            invalid(br#"["a", "b", "c", "d"]"#),
+            [schema::violation("", "must have at most 3 items")]
+        );
+        assert!(matches!(
+            chunks(&listing(true, b"a@example.com", 2)),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            chunks(&listing(false, b"a\nb\nc\nd", 2)),
+            Err(Error::BadRequest(_))
+        ));
+        // An error from the receiver stops reading
+        let mut received = 0;
+        let stopped = listing(true, json, 1).each_chunk(|_| {
+            received += 1;
+            Err(Error::bad_request("enough"))
+        });
+        assert!(stopped.is_err());
+        assert_eq!(received, 1);
+    }
+}
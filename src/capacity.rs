@@ -0,0 +1,125 @@
+//! Capacity planning
+//!
+//! Projects how the filter fills up under an expected signup rate, starting
+//! from the number of emails the filter's current fill suggests it holds.
+
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, encoding::Encoding, error::Error, filter::estimated_items, Params};
+
+/// How far ahead projections may look
+const MAX_HORIZON_DAYS: u32 = 3650;
+
+#[derive(serde::Deserialize)]
+struct CapacityQuery {
+    signups_per_day: f64,
+    #[serde(default = "default_horizon_days")]
+    horizon_days: u32,
+    /// The false positive rate that calls for a bigger filter
+    #[serde(default = "default_target_fpr")]
+    target_fpr: f64,
+}
+
+fn default_horizon_days() -> u32 {
+    30
+}
+
+fn default_target_fpr() -> f64 {
+    0.01
+}
+
+#[derive(serde::Serialize)]
+struct Projection {
+    estimated_items: f64,
+    current_fpr: f64,
+    /// Days until the false positive rate passes the target, `None` if not
+    /// within the horizon; 0 if it already has
+    resize_in_days: Option<u32>,
+    trajectory: Vec<Day>,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct Day {
+    day: u32,
+    fill: f64,
+    fpr: f64,
+}
+
+impl App {
+    /// Project when the filter will need to be resized
+    pub fn capacity(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: CapacityQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let valid = query.signups_per_day >= 0.0
+            && query.target_fpr > 0.0
+            && query.target_fpr < 1.0
+            && query.horizon_days <= MAX_HORIZON_DAYS;
+        if !valid {
+            return Err(Error::bad_request(format!(
+                "signups_per_day must not be negative, target_fpr must be between 0 and 1 \
+                 and horizon_days at most {MAX_HORIZON_DAYS}"
+            )));
+        }
+        let filter = self.load_filter()?;
+        let current = filter.current();
+        let params = current.params();
+        let items = estimated_items(current.array.count_ones(), params);
+        Ok(Encoding::accepted(&req).response(&project(items, params, &query))?)
+    }
+}
+
+/// The expected fraction of set bits after inserting `items`
+fn fill(items: f64, params: Params) -> f64 {
+    1.0 - (-(params.num_hashes as f64) * items / params.num_bits as f64).exp()
+}
+
+fn project(items: f64, params: Params, query: &CapacityQuery) -> Projection {
+    let trajectory: Vec<Day> = (0..=query.horizon_days)
+        .map(|day| {
+            let fill = fill(items + query.signups_per_day * f64::from(day), params);
+            Day {
+                day,
+                fill,
+                fpr: fill.powi(params.num_hashes as i32),
+            }
+        })
+        .collect();
+    Projection {
+        estimated_items: items,
+        current_fpr: trajectory[0].fpr,
+        resize_in_days: trajectory
+            .iter()
+            .find(|d| d.fpr > query.target_fpr)
+            .map(|d| d.day),
+        trajectory,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projects_saturation() {
+        let params = Params::LEGACY;
+        assert_eq!(estimated_items(0, params), 0.0);
+        let items = estimated_items(40, params);
+        assert!((fill(items, params) * 128.0 - 40.0).abs() < 1e-9);
+
+        let query = CapacityQuery {
+            signups_per_day: 2.0,
+            horizon_days: 10,
+            target_fpr: 0.01,
+        };
+        let projection = project(0.0, params, &query);
+        assert_eq!(projection.trajectory.len(), 11);
+        assert_eq!(projection.current_fpr, 0.0);
+        // Two hashes in 128 bits pass 1% with the seventh item
+        assert_eq!(projection.resize_in_days, Some(4));
+        assert!(projection
+            .trajectory
+            .windows(2)
+            .all(|w| w[0].fpr < w[1].fpr));
+    }
+}
@@ -0,0 +1,249 @@
+//! Fault injection for chaos testing, only built with the `chaos` feature
+//!
+//! Faults are listed in the `CHAOS_FAULTS` environment variable and in a
+//! request's `x-chaos-faults` header, as comma separated
+//! `operation=fault[@probability]` rules such as
+//! `kv.get=fail@0.5,db.*=delay:200ms,kv.get=corrupt@0.1`. Operations are
+//! `kv.get`, `kv.set`, `kv.delete` and `kv.keys`, and `db.` followed by the
+//! name of a `Database` method; a trailing `*` matches any operation
+//! starting with what precedes it.
+//!
+//! `fail` makes a store operation fail with a transient error, which is
+//! retried like a real one, and a database operation fail outright. `delay`
+//! sleeps before the operation, and `corrupt` truncates a value read from the
+//! store or inverts a database membership answer. Operations made before the
+//! request is routed, such as loading the configuration, see no faults.
+
+use anyhow::{Context, Result};
+use spin_sdk::http::Request;
+use std::{
+    cell::Cell,
+    rc::Rc,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    app::App,
+    config::HumanDuration,
+    db::{Created, Database},
+    error::Error,
+};
+
+const ENV_VAR: &str = "CHAOS_FAULTS";
+const HEADER: &str = "x-chaos-faults";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Fault {
+    Fail,
+    Delay(Duration),
+    Corrupt,
+}
+
+#[derive(PartialEq, Debug)]
+struct Rule {
+    operation: String,
+    fault: Fault,
+    probability: f64,
+}
+
+impl Rule {
+    fn matches(&self, operation: &str) -> bool {
+        match self.operation.strip_suffix('*') {
+            Some(prefix) => operation.starts_with(prefix),
+            None => self.operation == operation,
+        }
+    }
+}
+
+/// The faults to inject into a request's operations
+#[derive(Default, Debug)]
+pub(crate) struct Faults {
+    rules: Vec<Rule>,
+    /// Mixed into each roll so that rolls in quick succession differ
+    rolls: Cell<u32>,
+}
+
+impl std::str::FromStr for Faults {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (operation, fault) = entry
+                .split_once('=')
+                .context("expected `operation=fault[@probability]`")?;
+            let (fault, probability) = match fault.split_once('@') {
+                Some((fault, probability)) => (fault, probability.parse()?),
+                None => (fault, 1.0),
+            };
+            if !(0.0..=1.0).contains(&probability) {
+                anyhow::bail!("probabilities must be between 0 and 1");
+            }
+            let fault = match fault.split_once(':') {
+                None if fault == "fail" => Fault::Fail,
+                None if fault == "corrupt" => Fault::Corrupt,
+                Some(("delay", duration)) => Fault::Delay(duration.parse::<HumanDuration>()?.0),
+                _ => anyhow::bail!("unknown fault `{fault}`"),
+            };
+            rules.push(Rule {
+                operation: operation.trim().to_owned(),
+                fault,
+                probability,
+            });
+        }
+        Ok(Self {
+            rules,
+            rolls: Cell::new(0),
+        })
+    }
+}
+
+impl Faults {
+    /// The faults configured for the component and for `req`
+    fn of_request(req: &Request) -> Result<Self> {
+        let env = std::env::var(ENV_VAR).unwrap_or_default();
+        let header = req
+            .headers()
+            .get(HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        format!("{env},{header}").parse()
+    }
+
+    /// Whether `rule` strikes `operation` this time
+    fn strikes(&self, rule: &Rule, operation: &str) -> bool {
+        rule.matches(operation) && self.roll() < rule.probability
+    }
+
+    /// Delay `operation` or fail it, before it runs
+    pub fn before(&self, operation: &str) -> Result<()> {
+        for rule in self.rules.iter().filter(|r| r.fault != Fault::Corrupt) {
+            if !self.strikes(rule, operation) {
+                continue;
+            }
+            match rule.fault {
+                Fault::Fail => anyhow::bail!("injected failure in {operation}"),
+                Fault::Delay(delay) => sleep(delay),
+                Fault::Corrupt => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the result of `operation` is corrupted this time
+    pub fn corrupts(&self, operation: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|r| r.fault == Fault::Corrupt)
+            .any(|rule| self.strikes(rule, operation))
+    }
+
+    /// A number between 0 and 1
+    fn roll(&self) -> f64 {
+        let rolls = self.rolls.get();
+        self.rolls.set(rolls.wrapping_add(1));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        crate::murmur3(&(nanos, rolls)) as f64 / u32::MAX as f64
+    }
+}
+
+impl App {
+    /// Inject the faults configured for `req` into the store and database
+    pub fn with_faults(self, req: &Request) -> Result<Self, Error> {
+        let faults = Rc::new(Faults::of_request(req).map_err(Error::bad_request)?);
+        if faults.rules.is_empty() {
+            return Ok(self);
+        }
+        let App {
+            config,
+            mut store,
+            database,
+            metrics,
+            startup,
+        } = self;
+        store.faults = faults.clone();
+        Ok(App {
+            config,
+            store,
+            database: Box::new(Faulty {
+                inner: database,
+                faults,
+            }),
+            metrics,
+            startup,
+        })
+    }
+}
+
+/// A database whose operations run into the injected faults
+struct Faulty {
+    inner: Box<dyn Database>,
+    faults: Rc<Faults>,
+}
+
+impl Faulty {
+    fn exists(&self, operation: &str, exists: impl FnOnce() -> Result<bool>) -> Result<bool> {
+        self.faults.before(operation)?;
+        let exists = exists()?;
+        Ok(exists != self.faults.corrupts(operation))
+    }
+}
+
+impl Database for Faulty {
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        self.exists("db.user_exists", || self.inner.user_exists(email))
+    }
+
+    fn add_user(&self, email: &str) -> Result<()> {
+        self.faults.before("db.add_user")?;
+        self.inner.add_user(email)
+    }
+
+    fn invite_exists(&self, email: &str) -> Result<bool> {
+        self.exists("db.invite_exists", || self.inner.invite_exists(email))
+    }
+
+    fn add_invite(&self, email: &str) -> Result<()> {
+        self.faults.before("db.add_invite")?;
+        self.inner.add_invite(email)
+    }
+
+    fn users_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.faults.before("db.users_created_after")?;
+        self.inner.users_created_after(after, limit)
+    }
+
+    fn invites_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.faults.before("db.invites_created_after")?;
+        self.inner.invites_created_after(after, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_strikes() {
+        let faults: Faults = "kv.get=fail, db.*=delay:1ms@0.5, kv.get=corrupt@0"
+            .parse()
+            .unwrap();
+        assert_eq!(faults.rules.len(), 3);
+        assert_eq!(
+            faults.rules[1].fault,
+            Fault::Delay(Duration::from_millis(1))
+        );
+        assert!(faults.rules[1].matches("db.user_exists"));
+        assert!(!faults.rules[1].matches("kv.get"));
+
+        assert!(faults.before("kv.get").is_err());
+        assert!(faults.before("kv.set").is_ok());
+        assert!(!faults.corrupts("kv.get"));
+        assert!("kv.get=explode".parse::<Faults>().is_err());
+        assert!("kv.get=fail@2".parse::<Faults>().is_err());
+        assert!("".parse::<Faults>().unwrap().rules.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Identifying the client behind any trusted proxies
+
+use std::net::IpAddr;
+
+use spin_sdk::http::Request;
+
+/// The header Spin sets to the address of the connecting peer
+const CLIENT_ADDR_HEADER: &str = "spin-client-addr";
+
+/// Proxies whose forwarding headers are believed
+#[derive(Default)]
+pub(crate) struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl TrustedProxies {
+    fn contains(&self, addr: IpAddr) -> bool {
+        self.0
+            .iter()
+            .any(|&(network, prefix)| in_network(addr, network, prefix))
+    }
+
+    /// The address of the client that made the request
+    ///
+    /// Forwarding headers are walked from the nearest hop outwards for as
+    /// long as the hops are trusted proxies; the first untrusted hop is the
+    /// client. `None` if not even the peer address is known.
+    pub fn client(&self, req: &Request) -> Option<IpAddr> {
+        let peer = header(req, CLIENT_ADDR_HEADER).and_then(parse_addr)?;
+        let forwarded = match header(req, "forwarded") {
+            Some(value) => forwarded_for(value),
+            None => header(req, "x-forwarded-for")
+                .map(|v| v.split(',').filter_map(parse_addr).collect())
+                .unwrap_or_default(),
+        };
+        Some(self.walk(peer, &forwarded))
+    }
+
+    fn walk(&self, peer: IpAddr, forwarded: &[IpAddr]) -> IpAddr {
+        let mut client = peer;
+        for &hop in forwarded.iter().rev() {
+            if !self.contains(client) {
+                break;
+            }
+            client = hop;
+        }
+        client
+    }
+}
+
+impl std::str::FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated addresses or CIDR networks
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut networks = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+                None => (entry.parse::<IpAddr>()?, None),
+            };
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix.unwrap_or(max);
+            if prefix > max {
+                anyhow::bail!("prefix length {prefix} is too long for {addr}");
+            }
+            networks.push((addr, prefix));
+        }
+        Ok(Self(networks))
+    }
+}
+
+fn header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// The `for=` addresses of an RFC 7239 `Forwarded` header
+fn forwarded_for(value: &str) -> Vec<IpAddr> {
+    value
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("for")
+                .then(|| parse_addr(value.trim().trim_matches('"')))?
+        })
+        .collect()
+}
+
+/// Parse an address that may carry a port, with IPv6 optionally bracketed
+fn parse_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(addr) = value.parse() {
+        return Some(addr);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    value.rsplit_once(':')?.0.parse().ok()
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(a) & mask == u32::from(n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(a) & mask == u128::from(n) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn walks_past_trusted_proxies_only() {
+        let proxies: TrustedProxies = "10.0.0.0/8, 192.0.2.1".parse().unwrap();
+        let chain = [ip("203.0.113.9"), ip("198.51.100.7"), ip("10.1.2.3")];
+        // The proxy at 10.1.2.3 is trusted but 198.51.100.7 isn't, so it
+        // could have made up the address before it
+        assert_eq!(proxies.walk(ip("192.0.2.1"), &chain), ip("198.51.100.7"));
+        assert_eq!(proxies.walk(ip("198.51.100.1"), &chain), ip("198.51.100.1"));
+        assert_eq!(
+            TrustedProxies::default().walk(ip("10.0.0.1"), &chain),
+            ip("10.0.0.1")
+        );
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn parses_forwarding_headers() {
+        assert_eq!(
+            forwarded_for(r#"for=192.0.2.60;proto=http, For="[2001:db8::1]:4711""#),
+            vec![ip("192.0.2.60"), ip("2001:db8::1")]
+        );
+        assert_eq!(parse_addr("127.0.0.1:3000"), Some(ip("127.0.0.1")));
+        assert_eq!(parse_addr("unknown"), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Where the time setting up an instance goes
+//!
+//! Spin instantiates the component afresh for every request, so every request
+//! pays for reading the variables and configuration overrides and setting up
+//! the database before its handler runs. `GET /debug/coldstart` reports how
+//! long each of these phases took for the instance answering it. Stores and
+//! SQLite connections are only opened once something needs them, and schemas
+//! are only parsed for the route being validated, so the phases stay cheap
+//! for requests the filter answers on its own.
+
+use spin_sdk::http::{Request, Response};
+use std::time::{Duration, Instant};
+
+use crate::{app::App, encoding::Encoding, error::Error};
+
+/// The phases of setting up an instance, in the order they ran
+pub(crate) struct Startup {
+    started: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Startup {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Run `phase`, recording how long it took
+    pub fn time<T>(&mut self, name: &'static str, phase: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = phase();
+        self.phases.push((name, started.elapsed()));
+        result
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Report {
+    phases: Vec<Phase>,
+    /// How long setting up took in total
+    setup_seconds: f64,
+    /// How long the request has taken so far, validation included
+    elapsed_seconds: f64,
+}
+
+#[derive(serde::Serialize)]
+struct Phase {
+    name: &'static str,
+    seconds: f64,
+}
+
+impl App {
+    /// Report how long setting up the instance answering took
+    ///
+    /// Like the admin endpoints this requires the admin token.
+    pub fn coldstart(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let phases: Vec<_> = self
+            .startup
+            .phases
+            .iter()
+            .map(|&(name, took)| Phase {
+                name,
+                seconds: took.as_secs_f64(),
+            })
+            .collect();
+        let report = Report {
+            setup_seconds: phases.iter().map(|p| p.seconds).sum(),
+            phases,
+            elapsed_seconds: self.startup.started.elapsed().as_secs_f64(),
+        };
+        Ok(Encoding::accepted(&req).response(&report)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_phases_in_order() {
+        let mut startup = Startup::new();
+        assert_eq!(startup.time("store", || 1), 1);
+        startup.time("config", || std::thread::sleep(Duration::from_millis(2)));
+        let names: Vec<_> = startup.phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["store", "config"]);
+        assert!(startup.phases[1].1 >= Duration::from_millis(2));
+        assert!(startup.started.elapsed() >= startup.phases[1].1);
+    }
+}
@@ -0,0 +1,766 @@
+//! Runtime configuration read from Spin application variables
+
+use anyhow::{Context, Result};
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    alias::AliasDomains,
+    client::TrustedProxies,
+    db::{self, TableName},
+    features::Features,
+    filter_store,
+    hashing::Seed,
+    keys::Keyring,
+    kv::Kv,
+    length, metrics,
+    normalize::Normalization,
+    rotation::{Period, Rotation, Schedule},
+    scopes::ScopedKeys,
+    shadow,
+    trace::Sampling,
+    Params,
+};
+
+/// The key runtime overrides of tunable settings are stored under
+const OVERRIDE_KEY: &str = "__config_override";
+
+/// The settings that may be overridden at runtime
+pub(crate) const TUNABLE: &[&str] = &[
+    "lookup_budget",
+    "lookup_budget_window",
+    "client_lookup_budget",
+    "lookup_degraded_policy",
+    "domain_signup_limit",
+    "domain_signup_window",
+    "verdict_cache_ttl",
+    "plus_alias_domains",
+    "trace_sampling",
+    "similar_names",
+];
+
+/// The settings a tenant's runtime overrides may also change, as they only
+/// shape that tenant's state
+///
+/// The filter's size and store only apply to filters created from then on,
+/// so a tenant's existing filter keeps its own until it is rebuilt.
+pub(crate) const TENANT_TUNABLE: &[&str] = &[
+    "expected_items",
+    "target_fp_rate",
+    "bloom_num_bits",
+    "bloom_num_hashes",
+    "filter_store",
+    "user_service_failure_policy",
+];
+
+/// The tenant tunable settings that size new filters
+const FILTER_SIZE: &[&str] = &[
+    "expected_items",
+    "target_fp_rate",
+    "bloom_num_bits",
+    "bloom_num_hashes",
+];
+
+/// Component settings
+pub(crate) struct Config {
+    /// The generation of the runtime overrides in effect, 0 if there are none
+    pub generation: u64,
+    /// What the filter's set means
+    pub mode: Mode,
+    /// The shape of newly created filters, existing ones keep theirs
+    pub filter: Params,
+    /// How new filters are seeded, already in `filter` unless each draws
+    /// its own
+    pub seed: Seed,
+    /// Whether a filter starts a bigger slice once its newest one fills up
+    pub scalable: bool,
+    /// The time windows the filter rotates through, `None` to keep emails
+    /// until they are removed
+    pub rotation: Option<Rotation>,
+    /// The shape of a newly created quarantine filter
+    pub quarantine: Params,
+    /// How many bits each shard of a filter stored in shards holds, `None` to
+    /// store filters whole
+    pub shard_bits: Option<usize>,
+    /// The largest value the key-value stores take
+    pub max_value_size: usize,
+    /// The most bytes the component's keys may take up, `None` for no limit
+    pub storage_limit: Option<usize>,
+    /// Where the filter's slices are stored
+    pub filter_store: filter_store::Backend,
+    /// The filter store checks are verified against ahead of migrating to
+    /// it, see [`crate::shadow`]
+    pub shadow: Option<shadow::Target>,
+    /// The authoritative database behind the filter
+    pub database: db::Backend,
+    /// The MySQL connection string, required for the `mysql` database
+    pub mysql_address: Option<String>,
+    /// The SQLite database the `sqlite` database opens
+    pub sqlite_database: String,
+    /// The user service the `service` database asks
+    pub user_service_url: Option<String>,
+    /// The tenant the request named, which the user service is told of
+    pub tenant: Option<String>,
+    /// How long the user service may take to answer
+    pub user_service_timeout: Duration,
+    /// What a lookup the user service fails to answer reports
+    pub user_service_failure_policy: DegradedPolicy,
+    /// The table holding registered users' emails
+    pub users_table: String,
+    /// The table holding invited emails
+    pub invites_table: String,
+    /// Whether to create the database's tables on first use
+    pub migrate_schema: bool,
+    /// How many expensive user lookups may run per window, `None` for unlimited
+    pub lookup_budget: Option<u64>,
+    /// The window the lookup budget applies to
+    pub lookup_budget_window: Duration,
+    /// How many expensive user lookups each client may run per window,
+    /// `None` for unlimited
+    pub client_lookup_budget: Option<u32>,
+    /// What to answer once the lookup budget is exhausted
+    pub degraded_policy: DegradedPolicy,
+    /// How many signups a single email domain may make per window, `None`
+    /// for unlimited
+    pub domain_signup_limit: Option<u32>,
+    /// The window the domain signup limit applies to
+    pub domain_signup_window: Duration,
+    /// How long database verdicts are cached for, `None` to not cache them
+    pub verdict_cache_ttl: Option<Duration>,
+    /// Domains on which `user+tag@domain` is treated as `user@domain`
+    pub alias_domains: AliasDomains,
+    /// How emails are canonicalized before anything else
+    pub normalization: Normalization,
+    /// The longest emails taken as they are, and what happens to longer ones
+    pub email_length: length::Limit,
+    /// Proxies whose `Forwarded`/`X-Forwarded-For` headers identify the client
+    pub trusted_proxies: TrustedProxies,
+    /// Which share of requests get their decisions logged, per route
+    pub trace_sampling: Sampling,
+    /// The least time an availability check takes, `None` to not pad them
+    pub response_floor: Option<Duration>,
+    /// Up to how much random time is added to the floor
+    pub response_jitter: Duration,
+    /// Where metrics are recorded
+    pub metrics: metrics::Sink,
+    /// The service confirming email ownership before registration, if any
+    pub verification_url: Option<String>,
+    /// The keys the verification service may sign its answers with
+    pub verification_keys: Keyring,
+    /// The keys support reference codes are made with, the first making new
+    /// ones
+    pub reference_keys: Keyring,
+    /// The keys add nonces are signed with, the first signing new ones;
+    /// registrations need no nonce when empty
+    pub add_nonce_keys: Keyring,
+    /// How long an add nonce may be used for
+    pub add_nonce_ttl: Duration,
+    /// Whether to flag available emails that look like registered ones
+    pub similar_names: bool,
+    /// The feature flags requests may switch on
+    pub feature_flags: Features,
+    /// Whether query parameters a route doesn't know are refused
+    pub strict_requests: bool,
+    /// Whether store traffic is summed per route and hour
+    pub storage_traffic: bool,
+    /// The bearer token admin requests must present, admin routes are
+    /// disabled when unset and there are no `admin_keys`
+    pub admin_token: Option<String>,
+    /// Admin keys that only reach the tenants they are granted
+    pub admin_keys: ScopedKeys,
+    /// The bearer token that reveals roughly when taken emails registered,
+    /// see [`crate::age`]
+    pub membership_age_token: Option<String>,
+    /// The most emails of a bulk body held in memory at once
+    pub bulk_in_flight: usize,
+    /// The largest bulk body taken, in bytes
+    pub bulk_max_body: usize,
+    /// The most bytes an import uploaded in chunks may take
+    pub import_max_size: usize,
+    /// Emails inserted into the filter to verify it against
+    pub canary_members: Vec<String>,
+    /// Emails never inserted into the filter
+    pub canary_absent: Vec<String>,
+}
+
+impl Config {
+    /// Load the configuration with `overrides` taking precedence over the
+    /// Spin variables, including the [`TENANT_TUNABLE`] ones if they are a
+    /// tenant's
+    pub fn with_overrides(overrides: Overrides, tenanted: bool) -> Result<Self> {
+        let vars = Variables {
+            overrides,
+            tenanted,
+        };
+        let lookup_budget_window = vars.window("lookup_budget_window", Duration::from_secs(60))?;
+        let domain_signup_window =
+            vars.window("domain_signup_window", Duration::from_secs(3600))?;
+        let verification_url = vars.variable("verification_url");
+        // Not parsed with `setting`, whose errors would quote the secret
+        let verification_keys = match vars.variable("verification_secret") {
+            Some(keys) => keys
+                .parse::<Keyring>()
+                .context("invalid value for variable `verification_secret`")?,
+            None => Keyring::default(),
+        };
+        let reference_keys = match vars.variable("reference_secret") {
+            Some(keys) => keys
+                .parse::<Keyring>()
+                .context("invalid value for variable `reference_secret`")?,
+            None => Keyring::default(),
+        };
+        let admin_keys = match vars.variable("admin_keys") {
+            Some(keys) => keys
+                .parse::<ScopedKeys>()
+                .context("invalid value for variable `admin_keys`")?,
+            None => ScopedKeys::default(),
+        };
+        let add_nonce_keys = match vars.variable("add_nonce_secret") {
+            Some(keys) => keys
+                .parse::<Keyring>()
+                .context("invalid value for variable `add_nonce_secret`")?,
+            None => Keyring::default(),
+        };
+        // Not a quota window, so it may be shorter than a second
+        let user_service_timeout = vars
+            .setting::<HumanDuration>("user_service_timeout")?
+            .map_or(Duration::from_secs(2), |d| d.0);
+        if user_service_timeout.is_zero() {
+            anyhow::bail!("variable `user_service_timeout` must be longer than zero");
+        }
+        let scalable = vars.setting("bloom_scalable")?.unwrap_or(false);
+        let period = match (
+            vars.setting::<HumanDuration>("bloom_rotation_window")?,
+            vars.setting::<Schedule>("bloom_rotation_schedule")?,
+        ) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "`bloom_rotation_window` and `bloom_rotation_schedule` can't be combined"
+            ),
+            (Some(window), None) => {
+                if window.0.as_secs() == 0 {
+                    anyhow::bail!("`bloom_rotation_window` must be at least `1s`");
+                }
+                Some(Period::Every(window.0))
+            }
+            (None, schedule) => schedule.map(Period::Schedule),
+        };
+        let rotation = match period {
+            Some(period) => {
+                let windows = vars.setting("bloom_rotation_windows")?.unwrap_or(4);
+                if windows < 2 {
+                    anyhow::bail!("`bloom_rotation_windows` must be at least 2");
+                }
+                if scalable {
+                    anyhow::bail!("`bloom_scalable` and rotation can't be combined");
+                }
+                Some(Rotation::now(period, windows))
+            }
+            None => None,
+        };
+        let seed = vars.hash_seed()?;
+        let filter = vars.filter_params(seed)?;
+        let shard_bits = vars.shard_bits("bloom_shard_bits")?;
+        let filter_store = match vars.filter_backend("filter_store", &filter, shard_bits)? {
+            Some(backend) => backend,
+            None => filter_store::Backend::KeyValue,
+        };
+        let shadow = match vars.filter_backend("shadow_filter_store", &filter, None)? {
+            Some(backend) => {
+                let target = shadow::Target {
+                    shard_bits: vars.shard_bits("shadow_shard_bits")?,
+                    backend,
+                };
+                if target.shard_bits.is_some() && target.backend != filter_store::Backend::KeyValue
+                {
+                    anyhow::bail!("`shadow_shard_bits` only applies to the `kv` shadow store");
+                }
+                if (&target.backend, target.shard_bits) == (&filter_store, shard_bits) {
+                    anyhow::bail!(
+                        "`shadow_filter_store` and `shadow_shard_bits` must differ from \
+                         `filter_store` and `bloom_shard_bits`"
+                    );
+                }
+                Some(target)
+            }
+            None => None,
+        };
+        let email_length = length::Limit {
+            max: vars
+                .setting::<HumanSize>("max_email_length")?
+                .map_or(length::Limit::default().max, |size| size.0),
+            policy: vars
+                .setting("long_email_policy")?
+                .unwrap_or(length::Policy::Reject),
+        };
+        if email_length.max == 0 {
+            anyhow::bail!("`max_email_length` must be at least 1");
+        }
+        let bulk_in_flight = vars.setting("bulk_max_in_flight")?.unwrap_or(1000);
+        if bulk_in_flight == 0 {
+            anyhow::bail!("`bulk_max_in_flight` must be at least 1");
+        }
+        if verification_url.is_some() && verification_keys.is_empty() {
+            anyhow::bail!("`verification_url` requires `verification_secret`");
+        }
+        Ok(Self {
+            generation: vars.overrides.generation,
+            mode: vars.setting("filter_mode")?.unwrap_or(Mode::Denylist),
+            filter,
+            seed,
+            scalable,
+            rotation,
+            quarantine: Params::optimal(
+                vars.setting("quarantine_capacity")?.unwrap_or(100_000),
+                0.001,
+                false,
+            )
+            .context("invalid `quarantine_capacity`")?
+            .hashed_like(filter),
+            shard_bits,
+            max_value_size: vars
+                .setting::<HumanSize>("max_value_size")?
+                .map_or(1 << 20, |size| size.0),
+            storage_limit: vars
+                .setting::<HumanSize>("storage_limit")?
+                .map(|size| size.0),
+            filter_store,
+            shadow,
+            database: vars
+                .setting("user_database")?
+                .unwrap_or(db::Backend::Simulated),
+            mysql_address: vars.variable("mysql_address"),
+            sqlite_database: vars
+                .variable("sqlite_database")
+                .unwrap_or_else(|| "default".into()),
+            user_service_url: vars.variable("user_service_url"),
+            tenant: None,
+            user_service_timeout,
+            user_service_failure_policy: vars
+                .setting("user_service_failure_policy")?
+                .unwrap_or(DegradedPolicy::Unavailable),
+            users_table: vars
+                .setting::<TableName>("users_table")?
+                .map_or("users".into(), |t| t.0),
+            invites_table: vars
+                .setting::<TableName>("invites_table")?
+                .map_or("invites".into(), |t| t.0),
+            migrate_schema: vars.setting("migrate_schema")?.unwrap_or(true),
+            lookup_budget: vars.setting("lookup_budget")?,
+            lookup_budget_window,
+            client_lookup_budget: vars.setting("client_lookup_budget")?,
+            degraded_policy: vars
+                .setting("lookup_degraded_policy")?
+                .unwrap_or(DegradedPolicy::Taken),
+            domain_signup_limit: vars.setting("domain_signup_limit")?,
+            domain_signup_window,
+            verdict_cache_ttl: vars
+                .setting::<HumanDuration>("verdict_cache_ttl")?
+                .map(|d| d.0),
+            alias_domains: vars.setting("plus_alias_domains")?.unwrap_or_default(),
+            normalization: Normalization {
+                fold_local_case: vars.setting("email_case_folding")?.unwrap_or(false),
+                strip_gmail_dots: vars.setting("gmail_dot_stripping")?.unwrap_or(false),
+            },
+            email_length,
+            trusted_proxies: vars.setting("trusted_proxies")?.unwrap_or_default(),
+            trace_sampling: vars.setting("trace_sampling")?.unwrap_or_default(),
+            response_floor: vars
+                .setting::<HumanDuration>("response_time_floor")?
+                .map(|d| d.0),
+            response_jitter: vars
+                .setting::<HumanDuration>("response_time_jitter")?
+                .map_or(Duration::ZERO, |d| d.0),
+            metrics: vars.setting("metrics")?.unwrap_or(metrics::Sink::None),
+            verification_url,
+            verification_keys,
+            reference_keys,
+            add_nonce_keys,
+            add_nonce_ttl: vars.window("add_nonce_ttl", Duration::from_secs(600))?,
+            similar_names: vars.setting("similar_names")?.unwrap_or(false),
+            feature_flags: vars.setting("feature_flags")?.unwrap_or_default(),
+            strict_requests: vars.setting("strict_requests")?.unwrap_or(false),
+            storage_traffic: vars.setting("storage_traffic")?.unwrap_or(false),
+            admin_token: vars.variable("admin_token"),
+            admin_keys,
+            membership_age_token: vars.variable("membership_age_token"),
+            bulk_in_flight,
+            bulk_max_body: vars
+                .setting::<HumanSize>("bulk_max_body_size")?
+                .map_or(8 << 20, |size| size.0),
+            import_max_size: vars
+                .setting::<HumanSize>("import_max_size")?
+                .map_or(512 << 20, |size| size.0),
+            canary_members: vars.list("canary_members"),
+            canary_absent: vars.list("canary_absent"),
+        })
+    }
+}
+
+impl Config {
+    /// The addresses to consult for `email`: its canonical form followed by
+    /// that form's plus alias, if any
+    ///
+    /// An email longer than `max_email_length` is only consulted as the
+    /// digest of its canonical form.
+    pub fn addresses(&self, email: &str) -> Vec<String> {
+        let canonical = self.normalization.apply(email);
+        if self.email_length.exceeded_by(email) {
+            return vec![length::digest(&canonical)];
+        }
+        self.alias_domains.expand(&canonical)
+    }
+}
+
+/// What membership in the filter means
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mode {
+    /// The filter holds registered emails, which are not available
+    Denylist,
+    /// The filter holds invited emails, which are the only ones that may sign up
+    Allowlist,
+}
+
+impl Mode {
+    /// The key each mode's filter is stored under
+    pub fn state_key(self) -> &'static str {
+        match self {
+            Mode::Denylist => "__state",
+            Mode::Allowlist => "__invites",
+        }
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "denylist" => Ok(Mode::Denylist),
+            "allowlist" => Ok(Mode::Allowlist),
+            _ => anyhow::bail!("expected `denylist` or `allowlist`"),
+        }
+    }
+}
+
+/// The response given for a `Maybe` when no lookup can be afforded
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DegradedPolicy {
+    /// Optimistically report the email as taken (409)
+    Taken,
+    /// Report the service as temporarily unavailable (503)
+    Unavailable,
+}
+
+impl std::str::FromStr for DegradedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "taken" => Ok(DegradedPolicy::Taken),
+            "unavailable" => Ok(DegradedPolicy::Unavailable),
+            _ => anyhow::bail!("expected `taken` or `unavailable`"),
+        }
+    }
+}
+
+/// A duration written as a number and a unit (`ms`, `s`, `m`, `h` or `d`),
+/// e.g. `10m` or `2h`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct HumanDuration(pub Duration);
+
+impl std::str::FromStr for HumanDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let split = s
+            .find(|c: char| !c.is_ascii_digit())
+            .context("expected a unit such as `s`, `m` or `h`")?;
+        let (amount, unit) = s.split_at(split);
+        let amount: u64 = amount.parse().context("expected a whole number")?;
+        let unit_millis = match unit.trim() {
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            other => anyhow::bail!("unknown duration unit `{other}`"),
+        };
+        let millis = amount
+            .checked_mul(unit_millis)
+            .context("duration is too long")?;
+        Ok(Self(Duration::from_millis(millis)))
+    }
+}
+
+/// A size in bytes written as a number and an optional unit (`B`, `KB`,
+/// `MB`, `GB`, `KiB`, `MiB` or `GiB`), e.g. `254` or `512KiB`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct HumanSize(pub usize);
+
+impl std::str::FromStr for HumanSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (amount, unit) = s.split_at(split);
+        let amount: usize = amount.parse().context("expected a whole number")?;
+        let unit_bytes = match unit.trim() {
+            "" | "B" => 1,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            other => anyhow::bail!("unknown size unit `{other}`"),
+        };
+        let bytes = amount
+            .checked_mul(unit_bytes)
+            .context("size is too large")?;
+        Ok(Self(bytes))
+    }
+}
+
+/// Runtime overrides of tunable settings, stored as JSON
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Overrides {
+    /// Bumped on every change so operators can tell when it is in effect
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub settings: HashMap<String, String>,
+}
+
+impl Overrides {
+    /// The overrides currently stored
+    pub fn load(store: &Kv) -> Result<Self> {
+        Ok(match store.get(OVERRIDE_KEY)? {
+            Some(json) => {
+                serde_json::from_slice(&json).context("invalid configuration override")?
+            }
+            None => Self::default(),
+        })
+    }
+
+    pub fn save(&self, store: &Kv) -> Result<()> {
+        store.set(OVERRIDE_KEY, &serde_json::to_vec(self)?)
+    }
+
+    /// Whether a tenant's overrides size its new filters, rather than the
+    /// size `tenants` lists for it
+    pub fn sizes_filters(&self) -> bool {
+        self.settings
+            .iter()
+            .any(|(name, value)| FILTER_SIZE.contains(&name.as_str()) && !value.trim().is_empty())
+    }
+}
+
+/// The Spin variables, with overrides taking precedence
+struct Variables {
+    overrides: Overrides,
+    /// Whether the overrides are a tenant's
+    tenanted: bool,
+}
+
+impl Variables {
+    /// Get a duration of whole seconds to use as a quota window
+    fn window(&self, name: &str, default: Duration) -> Result<Duration> {
+        let window = self
+            .setting::<HumanDuration>(name)?
+            .map_or(default, |d| d.0);
+        if window.as_secs() == 0 {
+            anyhow::bail!("variable `{name}` must be at least one second");
+        }
+        Ok(window)
+    }
+
+    /// Get a number of bits per shard
+    fn shard_bits(&self, name: &str) -> Result<Option<usize>> {
+        self.setting::<usize>(name)?
+            .map(|bits| {
+                if bits == 0 || bits % 32 != 0 || bits > u32::MAX as usize {
+                    anyhow::bail!("`{name}` must be a positive multiple of 32");
+                }
+                Ok(bits)
+            })
+            .transpose()
+    }
+
+    /// Get a filter store for filters of `filter` in shards of `shard_bits`
+    #[cfg_attr(not(feature = "redis-backend"), allow(unused_variables))]
+    fn filter_backend(
+        &self,
+        name: &str,
+        filter: &Params,
+        shard_bits: Option<usize>,
+    ) -> Result<Option<filter_store::Backend>> {
+        Ok(match self.variable(name).as_deref().map(str::trim) {
+            None => None,
+            Some("kv") => Some(filter_store::Backend::KeyValue),
+            #[cfg(feature = "redis-backend")]
+            Some("redis") => {
+                if filter.counting || shard_bits.is_some() {
+                    anyhow::bail!(
+                        "`{name}` `redis` can't be combined with `bloom_counting` or \
+                         `bloom_shard_bits`"
+                    );
+                }
+                // Not parsed with `setting`, whose errors would quote the secret
+                let address = self
+                    .variable("redis_address")
+                    .with_context(|| format!("`{name}` `redis` requires `redis_address`"))?;
+                Some(filter_store::Backend::Redis(address))
+            }
+            #[cfg(not(feature = "redis-backend"))]
+            Some("redis") => {
+                anyhow::bail!("`{name}` `redis` requires the `redis-backend` feature")
+            }
+            Some(other) => anyhow::bail!(
+                "invalid value {other:?} for variable `{name}`: expected `kv` or `redis`"
+            ),
+        })
+    }
+
+    /// Get and parse a variable, `None` when it is unset or empty
+    fn setting<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        self.variable(name).map(|v| parse(name, &v)).transpose()
+    }
+
+    /// How new filters are seeded
+    fn hash_seed(&self) -> Result<Seed> {
+        // Not parsed with `setting`, whose errors would quote the secret
+        Ok(match self.variable("bloom_hash_seed") {
+            Some(seed) => seed
+                .parse()
+                .context("invalid value for variable `bloom_hash_seed`")?,
+            None => Seed::None,
+        })
+    }
+
+    /// The shape of new filters, sized for `expected_items` if it is set
+    fn filter_params(&self, seed: Seed) -> Result<Params> {
+        let counting = self.setting("bloom_counting")?.unwrap_or(false);
+        if counting && !cfg!(feature = "counting-filter") {
+            anyhow::bail!("`bloom_counting` requires the `counting-filter` feature");
+        }
+        let hashing = self.setting("bloom_hashing")?.unwrap_or_default();
+        let seed = seed.shared();
+        if let Some(items) = self.setting("expected_items")? {
+            let fp_rate = self.setting("target_fp_rate")?.unwrap_or(0.01);
+            return Ok(Params::optimal(items, fp_rate, counting)
+                .context("invalid `expected_items` or `target_fp_rate`")?
+                .hashed_with(hashing)
+                .seeded(seed));
+        }
+        Ok(Params::new(
+            self.setting("bloom_num_bits")?
+                .unwrap_or(Params::LEGACY.num_bits),
+            self.setting("bloom_num_hashes")?
+                .unwrap_or(Params::LEGACY.num_hashes),
+            counting,
+        )
+        .context("invalid `bloom_num_bits` or `bloom_num_hashes`")?
+        .hashed_with(hashing)
+        .seeded(seed))
+    }
+
+    /// Get a comma separated list variable
+    fn list(&self, name: &str) -> Vec<String> {
+        self.variable(name)
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|e| !e.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get a variable, treating unset and empty values alike
+    ///
+    /// Overrides only apply to tunable settings, and one set to an empty
+    /// value unsets the variable.
+    fn variable(&self, name: &str) -> Option<String> {
+        let tunable = TUNABLE.contains(&name) || self.tenanted && TENANT_TUNABLE.contains(&name);
+        match self.overrides.settings.get(name) {
+            Some(value) if tunable => Some(value.clone()),
+            _ => spin_sdk::config::get(name).ok(),
+        }
+        .filter(|v| !v.trim().is_empty())
+    }
+}
+
+fn parse<T>(name: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(Into::<anyhow::Error>::into)
+        .with_context(|| format!("invalid value {value:?} for variable `{name}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reports_variable() {
+        assert_eq!(
+            parse::<DegradedPolicy>("policy", " unavailable ").unwrap(),
+            DegradedPolicy::Unavailable
+        );
+        let err = parse::<u64>("lookup_budget", "lots").unwrap_err();
+        assert!(err.to_string().contains("lookup_budget"));
+    }
+
+    #[test]
+    fn overrides_default_to_generation_zero() {
+        let overrides: Overrides =
+            serde_json::from_str(r#"{"settings": {"lookup_budget": "10"}}"#).unwrap();
+        assert_eq!(overrides.generation, 0);
+        assert_eq!(overrides.settings["lookup_budget"], "10");
+        assert!(!overrides.sizes_filters());
+    }
+
+    #[test]
+    fn tenant_overrides_size_filters() {
+        let overrides: Overrides =
+            serde_json::from_str(r#"{"settings": {"expected_items": "1000"}}"#).unwrap();
+        assert!(overrides.sizes_filters());
+        let unset: Overrides =
+            serde_json::from_str(r#"{"settings": {"bloom_num_bits": " "}}"#).unwrap();
+        assert!(!unset.sizes_filters());
+    }
+
+    #[test]
+    fn human_durations() {
+        let parse = |s: &str| s.parse::<HumanDuration>().map(|d| d.0);
+        assert_eq!(parse("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse("10").is_err());
+        assert!(parse("m").is_err());
+        assert!(parse("3 weeks").is_err());
+    }
+
+    #[test]
+    fn human_sizes() {
+        let parse = |s: &str| s.parse::<HumanSize>().map(|size| size.0);
+        assert_eq!(parse("254").unwrap(), 254);
+        assert_eq!(parse("512KiB").unwrap(), 512 * 1024);
+        assert_eq!(parse("8 MiB").unwrap(), 8 << 20);
+        assert_eq!(parse("2MB").unwrap(), 2_000_000);
+        assert!(parse("KiB").is_err());
+        assert!(parse("5 pages").is_err());
+        assert!(parse(&format!("{}GiB", usize::MAX)).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! The authoritative user database the filter sits in front of
+
+use anyhow::Result;
+use std::{thread::sleep, time::Duration};
+
+pub(crate) use migrations::{migrate, Schema};
+pub(crate) use mysql::MySql;
+pub(crate) use service::Service;
+#[cfg(feature = "sqlite-db")]
+pub(crate) use sqlite::Sqlite;
+
+mod migrations;
+mod mysql;
+mod service;
+#[cfg(feature = "sqlite-db")]
+mod sqlite;
+
+/// The tables the filter caches membership of
+pub(crate) trait Database {
+    /// Whether a user with this email is registered
+    fn user_exists(&self, email: &str) -> Result<bool>;
+    /// Register a user
+    fn add_user(&self, email: &str) -> Result<()>;
+    /// Whether this email was invited to sign up
+    fn invite_exists(&self, email: &str) -> Result<bool>;
+    /// Invite an email to sign up
+    fn add_invite(&self, email: &str) -> Result<()>;
+    /// Up to `limit` users registered after `after`, in creation order
+    fn users_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>>;
+    /// Up to `limit` invites made after `after`, in creation order
+    fn invites_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>>;
+}
+
+/// A row's place in creation order, which also serves as a scan cursor
+///
+/// Rows created in the same second are ordered by email.
+#[derive(Clone, Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Created {
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub email: String,
+}
+
+/// Which database implementation to use
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    Simulated,
+    Mysql,
+    /// Only available in builds with the `sqlite-db` feature
+    #[cfg(feature = "sqlite-db")]
+    Sqlite,
+    /// A user service reached over HTTP
+    Service,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "simulated" => Ok(Backend::Simulated),
+            "mysql" => Ok(Backend::Mysql),
+            #[cfg(feature = "sqlite-db")]
+            "sqlite" => Ok(Backend::Sqlite),
+            #[cfg(not(feature = "sqlite-db"))]
+            "sqlite" => anyhow::bail!("the `sqlite` database requires the `sqlite-db` feature"),
+            "service" => Ok(Backend::Service),
+            _ => anyhow::bail!("expected `simulated`, `mysql`, `sqlite` or `service`"),
+        }
+    }
+}
+
+/// The database can't be reached for now, reported as a 503
+#[derive(Debug)]
+pub(crate) struct Unavailable(pub String);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// The email being added is already there, reported as a 409
+#[derive(Debug)]
+pub(crate) struct Taken;
+
+impl std::fmt::Display for Taken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the email is already registered")
+    }
+}
+
+impl std::error::Error for Taken {}
+
+/// A table name, restricted so it can be spliced into SQL statements
+pub(crate) struct TableName(pub String);
+
+impl std::str::FromStr for TableName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("table names may only contain ASCII letters, digits and `_`");
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// A stand-in database that pretends every lookup is an expensive hit
+///
+/// The `mysql`, `sqlite` and `service` databases are real ones
+pub(crate) struct Simulated;
+
+impl Database for Simulated {
+    fn user_exists(&self, _email: &str) -> Result<bool> {
+        sleep(Duration::from_millis(10));
+        Ok(true)
+    }
+
+    fn add_user(&self, _email: &str) -> Result<()> {
+        // This is where the user would be added to the database
+        Ok(())
+    }
+
+    fn invite_exists(&self, _email: &str) -> Result<bool> {
+        sleep(Duration::from_millis(10));
+        Ok(true)
+    }
+
+    fn add_invite(&self, _email: &str) -> Result<()> {
+        // This is where the invite would be added to the database
+        Ok(())
+    }
+
+    fn users_created_after(&self, _after: &Created, _limit: usize) -> Result<Vec<Created>> {
+        Ok(Vec::new())
+    }
+
+    fn invites_created_after(&self, _after: &Created, _limit: usize) -> Result<Vec<Created>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_names_are_plain_identifiers() {
+        assert!("users".parse::<TableName>().is_ok());
+        assert!("app_users2".parse::<TableName>().is_ok());
+        assert!("users; DROP TABLE users".parse::<TableName>().is_err());
+        assert!("".parse::<TableName>().is_err());
+    }
+}
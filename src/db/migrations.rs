@@ -0,0 +1,131 @@
+//! Schema bootstrap for the SQL databases
+//!
+//! Migrations are applied in order on first use, and the number applied so
+//! far is kept in the store, separately for each kind of database and set of
+//! tables, so that configuring other tables creates them. The
+//! store has no compare-and-swap, so instances take a lease on a lock key and
+//! read it back to check they won; an instance that finds the lock held
+//! carries on without migrating.
+
+use anyhow::Result;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::kv::Kv;
+
+const LOCK_KEY: &str = "__schema_lock";
+/// How long a migration lock is honored for, in seconds
+const LOCK_LEASE: u64 = 30;
+
+/// A database whose schema is created by migrations
+pub(crate) trait Schema {
+    /// Where the number of migrations applied is kept, followed by the
+    /// tables they create
+    const VERSION_KEY: &'static str;
+
+    /// The tables the migrations create
+    fn tables(&self) -> Vec<&str>;
+
+    /// The statements creating the schema, in order
+    ///
+    /// Statements may only be appended, as databases that applied the
+    /// earlier ones skip them.
+    fn migrations(&self) -> Vec<String>;
+
+    /// Run a statement that takes no parameters
+    fn execute(&self, statement: &str) -> Result<()>;
+}
+
+/// Bring the database's schema up to date
+pub(crate) fn migrate<D: Schema>(store: &Kv, database: &D) -> Result<()> {
+    let migrations = database.migrations();
+    let version_key = version_key(database);
+    if applied(store, &version_key)? >= migrations.len() {
+        return Ok(());
+    }
+    if !lock(store)? {
+        eprintln!("schema migration already in progress elsewhere");
+        return Ok(());
+    }
+    // Another instance may have finished while we were taking the lock
+    let applied = applied(store, &version_key)?;
+    let result = migrations
+        .iter()
+        .enumerate()
+        .skip(applied)
+        .try_for_each(|(i, statement)| {
+            database.execute(statement)?;
+            eprintln!("applied schema migration {}", i + 1);
+            store.set(&version_key, &(i as u64 + 1).to_be_bytes())
+        });
+    // Expire the lease whether or not the migrations went through
+    store.set(LOCK_KEY, &[0; 16])?;
+    result
+}
+
+/// The key the number of migrations applied to `database`'s tables is kept
+/// under
+///
+/// Databases that kept it before tables could be configured apply their
+/// migrations again, all of which are idempotent.
+fn version_key<D: Schema>(database: &D) -> String {
+    format!("{}:{}", D::VERSION_KEY, database.tables().join(","))
+}
+
+/// How many migrations have been applied
+fn applied(store: &Kv, version_key: &str) -> Result<usize> {
+    Ok(store
+        .get(version_key)?
+        .and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?) as usize))
+        .unwrap_or(0))
+}
+
+/// Try to take the migration lock
+fn lock(store: &Kv) -> Result<bool> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    if let Some(lease) = store.get(LOCK_KEY)? {
+        if held(&lease, now.as_secs()) {
+            return Ok(false);
+        }
+    }
+    let mut lease = [0; 16];
+    lease[..8].copy_from_slice(&(now.as_secs() + LOCK_LEASE).to_be_bytes());
+    lease[8..].copy_from_slice(&(now.as_nanos() as u64).to_be_bytes());
+    store.set(LOCK_KEY, &lease)?;
+    Ok(store.get(LOCK_KEY)?.as_deref() == Some(&lease[..]))
+}
+
+/// Whether a stored lease is still in force at `now`
+fn held(lease: &[u8], now: u64) -> bool {
+    lease
+        .get(..8)
+        .is_some_and(|expiry| u64::from_be_bytes(expiry.try_into().unwrap()) > now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MySql;
+
+    #[test]
+    fn leases_expire() {
+        let mut lease = [0; 16];
+        lease[..8].copy_from_slice(&100u64.to_be_bytes());
+        assert!(held(&lease, 99));
+        assert!(!held(&lease, 100));
+        assert!(!held(&[0; 16], 0));
+        assert!(!held(&[], 0));
+        let mysql = MySql {
+            address: String::new(),
+            users_table: "users".into(),
+            invites_table: "invites".into(),
+        };
+        assert!(mysql.migrations()[1].contains("EXISTS invites ("));
+        let key = version_key(&mysql);
+        assert_eq!(key, "__schema_version:users,invites");
+        let renamed = MySql {
+            users_table: "members".into(),
+            ..mysql
+        };
+        assert_ne!(version_key(&renamed), key);
+    }
+}
@@ -0,0 +1,121 @@
+//! Users and invites kept in MySQL, reached through Spin's outbound MySQL
+
+use anyhow::Result;
+use spin_sdk::mysql::{self, DbValue, ParameterValue};
+
+use super::{Created, Database, Schema, Taken};
+
+pub(crate) struct MySql {
+    pub address: String,
+    pub users_table: String,
+    pub invites_table: String,
+}
+
+impl MySql {
+    fn contains(&self, table: &str, email: &str) -> Result<bool> {
+        let statement = format!("SELECT 1 FROM {table} WHERE email = ? LIMIT 1");
+        let rows = mysql::query(&self.address, &statement, &[ParameterValue::Str(email)])
+            .map_err(error)?;
+        Ok(!rows.rows.is_empty())
+    }
+
+    /// Insert a row, failing with [`Taken`] when the email is already there,
+    /// such as when a concurrent registration got in first
+    fn insert(&self, table: &str, email: &str) -> Result<()> {
+        let statement = format!("INSERT INTO {table} (email) VALUES (?)");
+        match mysql::execute(&self.address, &statement, &[ParameterValue::Str(email)]) {
+            Ok(()) => Ok(()),
+            // Error 1062, ER_DUP_ENTRY
+            Err(mysql::MysqlError::QueryFailed(message)) if message.contains("Duplicate entry") => {
+                Err(Taken.into())
+            }
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    fn created_after(&self, table: &str, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        let statement = format!(
+            "SELECT UNIX_TIMESTAMP(created_at), email FROM {table} \
+             WHERE created_at > FROM_UNIXTIME(?) \
+             OR (created_at = FROM_UNIXTIME(?) AND email > ?) \
+             ORDER BY created_at, email LIMIT {limit}"
+        );
+        let created_at = after.created_at as i64;
+        let params = [
+            ParameterValue::Int64(created_at),
+            ParameterValue::Int64(created_at),
+            ParameterValue::Str(&after.email),
+        ];
+        let rows = mysql::query(&self.address, &statement, &params).map_err(error)?;
+        rows.rows
+            .into_iter()
+            .map(|row| match &row[..] {
+                [created_at, DbValue::Str(email)] => Ok(Created {
+                    created_at: match created_at {
+                        DbValue::Int64(secs) => *secs as u64,
+                        DbValue::Uint64(secs) => *secs,
+                        other => anyhow::bail!("unexpected creation time {other:?}"),
+                    },
+                    email: email.clone(),
+                }),
+                _ => anyhow::bail!("unexpected row {row:?}"),
+            })
+            .collect()
+    }
+}
+
+impl Database for MySql {
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        self.contains(&self.users_table, email)
+    }
+
+    fn add_user(&self, email: &str) -> Result<()> {
+        self.insert(&self.users_table, email)
+    }
+
+    fn invite_exists(&self, email: &str) -> Result<bool> {
+        self.contains(&self.invites_table, email)
+    }
+
+    fn add_invite(&self, email: &str) -> Result<()> {
+        self.insert(&self.invites_table, email)
+    }
+
+    fn users_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after(&self.users_table, after, limit)
+    }
+
+    fn invites_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after(&self.invites_table, after, limit)
+    }
+}
+
+impl Schema for MySql {
+    const VERSION_KEY: &'static str = "__schema_version";
+
+    fn tables(&self) -> Vec<&str> {
+        vec![&self.users_table, &self.invites_table]
+    }
+
+    fn migrations(&self) -> Vec<String> {
+        [&self.users_table, &self.invites_table]
+            .iter()
+            .map(|table| {
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {table} (\
+                     email VARCHAR(320) NOT NULL PRIMARY KEY, \
+                     created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP, \
+                     INDEX (created_at))"
+                )
+            })
+            .collect()
+    }
+
+    fn execute(&self, statement: &str) -> Result<()> {
+        mysql::execute(&self.address, statement, &[]).map_err(error)
+    }
+}
+
+fn error(e: mysql::MysqlError) -> anyhow::Error {
+    anyhow::anyhow!("mysql error: {e:?}")
+}
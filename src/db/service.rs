@@ -0,0 +1,155 @@
+//! Users and invites kept by a separate user service, reached over HTTP
+//!
+//! The service at `user_service_url` answers
+//!
+//! * `GET /users?email=...` and `GET /invites?email=...` with 200 if the
+//!   email is there and 404 if it isn't
+//! * `POST /users` and `POST /invites` with `{"email": ...}` with a 2xx once
+//!   it is stored, or 409 if it already is
+//! * `GET /users?after=<seconds>&after_email=...&limit=...` (or `/invites`)
+//!   with a JSON array of `{"created_at": <seconds>, "email": ...}` in
+//!   creation order
+//!
+//! Requests for a tenant carry its name in the `tenant` header, and the
+//! service must answer them from that tenant's users and invites only.
+//!
+//! Outbound requests can't be cancelled, so an answer that arrives after
+//! `user_service_timeout` is discarded rather than cut short, and counts as
+//! a failure. Failed lookups are answered according to
+//! `user_service_failure_policy`, and failed registrations with a 503.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::time::{Duration, Instant};
+
+use super::{Created, Database, Taken, Unavailable};
+use crate::tenant;
+
+pub(crate) struct Service {
+    pub url: String,
+    pub timeout: Duration,
+    /// The tenant the users and invites asked about belong to
+    pub tenant: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Lookup<'a> {
+    email: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct Scan<'a> {
+    after: u64,
+    after_email: &'a str,
+    limit: usize,
+}
+
+impl Service {
+    fn send(&self, mut request: Request) -> Result<Response> {
+        if let Some(tenant) = &self.tenant {
+            request
+                .headers_mut()
+                .insert(tenant::HEADER, http::HeaderValue::from_str(tenant)?);
+        }
+        let started = Instant::now();
+        let response = spin_sdk::outbound_http::send_request(request)
+            .map_err(|e| Unavailable(format!("the user service can't be reached: {e:?}")))?;
+        if started.elapsed() > self.timeout {
+            return Err(Unavailable(format!(
+                "the user service took longer than {:?} to answer",
+                self.timeout
+            ))
+            .into());
+        }
+        if response.status().is_server_error() {
+            return Err(
+                Unavailable(format!("the user service answered {}", response.status())).into(),
+            );
+        }
+        Ok(response)
+    }
+
+    fn get(&self, collection: &str, query: &impl serde::Serialize) -> Result<Response> {
+        let query = serde_qs::to_string(query)?;
+        let request = http::Request::builder()
+            .method("GET")
+            .uri(format!(
+                "{}/{collection}?{query}",
+                self.url.trim_end_matches('/')
+            ))
+            .body(None)?;
+        self.send(request)
+    }
+
+    fn contains(&self, collection: &str, email: &str) -> Result<bool> {
+        let response = self.get(collection, &Lookup { email })?;
+        match response.status().as_u16() {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => anyhow::bail!("the user service answered a lookup with {status}"),
+        }
+    }
+
+    fn insert(&self, collection: &str, email: &str) -> Result<()> {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri(format!("{}/{collection}", self.url.trim_end_matches('/')))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Some(serde_json::to_vec(&Lookup { email })?.into()))?;
+        let response = self.send(request)?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            409 => Err(Taken.into()),
+            status => anyhow::bail!("the user service answered a registration with {status}"),
+        }
+    }
+
+    fn created_after(
+        &self,
+        collection: &str,
+        after: &Created,
+        limit: usize,
+    ) -> Result<Vec<Created>> {
+        let scan = Scan {
+            after: after.created_at,
+            after_email: &after.email,
+            limit,
+        };
+        let response = self.get(collection, &scan)?;
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "the user service answered a scan with {}",
+                response.status()
+            );
+        }
+        Ok(serde_json::from_slice(
+            response.body().as_deref().unwrap_or_default(),
+        )?)
+    }
+}
+
+impl Database for Service {
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        self.contains("users", email)
+    }
+
+    fn add_user(&self, email: &str) -> Result<()> {
+        self.insert("users", email)
+    }
+
+    fn invite_exists(&self, email: &str) -> Result<bool> {
+        self.contains("invites", email)
+    }
+
+    fn add_invite(&self, email: &str) -> Result<()> {
+        self.insert("invites", email)
+    }
+
+    fn users_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after("users", after, limit)
+    }
+
+    fn invites_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after("invites", after, limit)
+    }
+}
@@ -0,0 +1,148 @@
+//! Users and invites kept in SQLite, reached through Spin's SQLite databases
+
+use anyhow::Result;
+use spin_sdk::sqlite::{self, Connection, ValueParam, ValueResult};
+use std::cell::OnceCell;
+
+use super::{Created, Database, Schema, Taken};
+
+/// The database is only opened once a statement needs it, which requests the
+/// filter answers on its own never do
+pub(crate) struct Sqlite {
+    name: String,
+    connection: OnceCell<Connection>,
+    pub users_table: String,
+    pub invites_table: String,
+}
+
+impl Sqlite {
+    /// The database the component was granted under `name`
+    pub fn new(name: String, users_table: String, invites_table: String) -> Self {
+        Self {
+            name,
+            connection: OnceCell::new(),
+            users_table,
+            invites_table,
+        }
+    }
+
+    fn connection(&self) -> Result<&Connection> {
+        if let Some(connection) = self.connection.get() {
+            return Ok(connection);
+        }
+        let connection = Connection::open(&self.name).map_err(error)?;
+        Ok(self.connection.get_or_init(|| connection))
+    }
+
+    fn query(&self, statement: &str, params: &[ValueParam]) -> Result<sqlite::QueryResult> {
+        self.connection()?.execute(statement, params).map_err(error)
+    }
+
+    fn contains(&self, table: &str, email: &str) -> Result<bool> {
+        let statement = format!("SELECT 1 FROM {table} WHERE email = ? LIMIT 1");
+        let rows = self.query(&statement, &[ValueParam::Text(email)])?;
+        Ok(!rows.rows.is_empty())
+    }
+
+    /// Insert a row, failing with [`Taken`] when the email is already there,
+    /// such as when a concurrent registration got in first
+    fn insert(&self, table: &str, email: &str) -> Result<()> {
+        let statement = format!("INSERT INTO {table} (email) VALUES (?)");
+        match self
+            .connection()?
+            .execute(&statement, &[ValueParam::Text(email)])
+        {
+            Ok(_) => Ok(()),
+            Err(sqlite::Error::Io(message)) if message.contains("UNIQUE constraint failed") => {
+                Err(Taken.into())
+            }
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    fn created_after(&self, table: &str, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        let statement = format!(
+            "SELECT created_at, email FROM {table} \
+             WHERE created_at > ? OR (created_at = ? AND email > ?) \
+             ORDER BY created_at, email LIMIT {limit}"
+        );
+        let created_at = after.created_at as i64;
+        let params = [
+            ValueParam::Integer(created_at),
+            ValueParam::Integer(created_at),
+            ValueParam::Text(&after.email),
+        ];
+        self.query(&statement, &params)?
+            .rows
+            .into_iter()
+            .map(|row| match &row.values[..] {
+                [ValueResult::Integer(created_at), ValueResult::Text(email)] => Ok(Created {
+                    created_at: *created_at as u64,
+                    email: email.clone(),
+                }),
+                values => anyhow::bail!("unexpected row {values:?}"),
+            })
+            .collect()
+    }
+}
+
+impl Database for Sqlite {
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        self.contains(&self.users_table, email)
+    }
+
+    fn add_user(&self, email: &str) -> Result<()> {
+        self.insert(&self.users_table, email)
+    }
+
+    fn invite_exists(&self, email: &str) -> Result<bool> {
+        self.contains(&self.invites_table, email)
+    }
+
+    fn add_invite(&self, email: &str) -> Result<()> {
+        self.insert(&self.invites_table, email)
+    }
+
+    fn users_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after(&self.users_table, after, limit)
+    }
+
+    fn invites_created_after(&self, after: &Created, limit: usize) -> Result<Vec<Created>> {
+        self.created_after(&self.invites_table, after, limit)
+    }
+}
+
+impl Schema for Sqlite {
+    const VERSION_KEY: &'static str = "__schema_version:sqlite";
+
+    fn tables(&self) -> Vec<&str> {
+        vec![&self.users_table, &self.invites_table]
+    }
+
+    fn migrations(&self) -> Vec<String> {
+        [&self.users_table, &self.invites_table]
+            .iter()
+            .flat_map(|table| {
+                [
+                    format!(
+                        "CREATE TABLE IF NOT EXISTS {table} (\
+                         email TEXT NOT NULL PRIMARY KEY, \
+                         created_at INTEGER NOT NULL \
+                         DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)))"
+                    ),
+                    format!(
+                        "CREATE INDEX IF NOT EXISTS {table}_created_at ON {table} (created_at)"
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    fn execute(&self, statement: &str) -> Result<()> {
+        self.query(statement, &[]).map(|_| ())
+    }
+}
+
+fn error(e: sqlite::Error) -> anyhow::Error {
+    anyhow::anyhow!("sqlite error: {e:?}")
+}
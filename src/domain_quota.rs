@@ -0,0 +1,149 @@
+//! Per-domain signup quotas
+//!
+//! Signups per email domain are counted in a count-min sketch that is reset
+//! at the start of every quota window. The sketch never undercounts, so a
+//! domain is never let through past its quota, but busy windows may reject a
+//! quiet domain slightly early. Signups are checked against the quota
+//! before they are made and only counted once they succeed, so failed ones
+//! don't use up their domain's quota.
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::kv::Kv;
+
+const SKETCH_KEY: &str = "__domain_sketch";
+const DEPTH: usize = 4;
+const WIDTH: usize = 256;
+
+/// A count-min sketch of signups per domain within one window, also used
+/// for lookups per client (see [`crate::quota`])
+pub(crate) struct Sketch {
+    window: u64,
+    counters: Vec<u32>,
+}
+
+impl Sketch {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            counters: vec![0; DEPTH * WIDTH],
+        }
+    }
+
+    /// Decode a stored sketch, starting over if it is from another window
+    pub fn from_bytes(bytes: &[u8], window: u64) -> Self {
+        if bytes.len() != 8 + DEPTH * WIDTH * 4 || bytes[..8] != window.to_be_bytes() {
+            return Self::new(window);
+        }
+        Self {
+            window,
+            counters: bytes[8..]
+                .chunks_exact(4)
+                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.counters.len() * 4);
+        bytes.extend(self.window.to_be_bytes());
+        for counter in &self.counters {
+            bytes.extend(counter.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn slots(domain: &str) -> impl Iterator<Item = usize> + '_ {
+        (0..DEPTH).map(move |row| row * WIDTH + crate::fnv(&(row, domain)) % WIDTH)
+    }
+
+    pub fn estimate(&self, domain: &str) -> u32 {
+        Self::slots(domain)
+            .map(|slot| self.counters[slot])
+            .min()
+            .unwrap_or_default()
+    }
+
+    pub fn increment(&mut self, domain: &str) {
+        for slot in Self::slots(domain) {
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+        }
+    }
+}
+
+/// The outcome of checking a signup against its domain's quota
+pub(crate) enum Verdict {
+    Allowed,
+    /// The domain is over quota until the window resets, in seconds
+    Exceeded {
+        retry_after: u64,
+    },
+}
+
+/// The current window's sketch, with the current time in Unix seconds
+fn load(store: &Kv, window: Duration) -> Result<(Sketch, u64)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let window = now / window.as_secs();
+    let sketch = match store.get(SKETCH_KEY)? {
+        Some(bytes) => Sketch::from_bytes(&bytes, window),
+        None => Sketch::new(window),
+    };
+    Ok((sketch, now))
+}
+
+/// Check a signup of the email against its domain's `limit`
+pub(crate) fn check(store: &Kv, email: &str, limit: u32, window: Duration) -> Result<Verdict> {
+    let Some(domain) = domain(email) else {
+        return Ok(Verdict::Allowed);
+    };
+    let (sketch, now) = load(store, window)?;
+    if sketch.estimate(&domain) >= limit {
+        let window_secs = window.as_secs();
+        return Ok(Verdict::Exceeded {
+            retry_after: window_secs - now % window_secs,
+        });
+    }
+    Ok(Verdict::Allowed)
+}
+
+/// Count a signup of the email that succeeded against its domain's quota
+pub(crate) fn record(store: &Kv, email: &str, window: Duration) -> Result<()> {
+    let Some(domain) = domain(email) else {
+        return Ok(());
+    };
+    let (mut sketch, _) = load(store, window)?;
+    sketch.increment(&domain);
+    store.set(SKETCH_KEY, &sketch.to_bytes())
+}
+
+/// The lowercased domain of an email
+pub(crate) fn domain(email: &str) -> Option<String> {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sketch_counts_per_domain_and_window() {
+        let mut sketch = Sketch::new(3);
+        for _ in 0..5 {
+            sketch.increment("spam.example");
+        }
+        sketch.increment("example.com");
+        assert!(sketch.estimate("spam.example") >= 5);
+        assert!(sketch.estimate("example.com") >= 1);
+        assert_eq!(sketch.estimate("other.org"), 0);
+
+        let bytes = sketch.to_bytes();
+        assert_eq!(Sketch::from_bytes(&bytes, 3).estimate("spam.example"), 5);
+        assert_eq!(Sketch::from_bytes(&bytes, 4).estimate("spam.example"), 0);
+        assert_eq!(domain("Me@Example.COM").as_deref(), Some("example.com"));
+        assert_eq!(domain("nobody"), None);
+    }
+}
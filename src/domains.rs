@@ -0,0 +1,159 @@
+//! The most common registration domains
+//!
+//! Registrations are counted per email domain in a Space-Saving sketch of
+//! `TRACKED` domains: a domain not tracked yet replaces the least counted
+//! one, taking over its count plus one. Every domain registered more often
+//! than one in `TRACKED` times is therefore tracked, and a tracked domain's
+//! count overestimates its registrations by at most its `error`. Only
+//! domains are kept, never which emails registered.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, domain_quota, encoding::Encoding, error::Error, kv::Kv};
+
+const DOMAINS_KEY: &str = "__domain_counts";
+/// How many domains are tracked
+const TRACKED: usize = 100;
+
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    domain: String,
+    /// Registrations counted for the domain, at most `error` too many
+    count: u64,
+    /// How many of the count were inherited from the domain it replaced
+    error: u64,
+}
+
+/// The sketch, most counted domains first
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct TopDomains {
+    domains: Vec<Entry>,
+}
+
+impl TopDomains {
+    fn load(store: &Kv) -> Result<Self> {
+        Ok(match store.get(DOMAINS_KEY)? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => Self::default(),
+        })
+    }
+
+    fn record(&mut self, domain: &str) {
+        let domains = &mut self.domains;
+        let at = match domains.iter().position(|entry| entry.domain == domain) {
+            Some(at) => {
+                domains[at].count += 1;
+                at
+            }
+            None if domains.len() < TRACKED => {
+                domains.push(Entry {
+                    domain: domain.to_owned(),
+                    count: 1,
+                    error: 0,
+                });
+                domains.len() - 1
+            }
+            None => {
+                // Kept in order, so the least counted domain is the last one
+                let last = domains.len() - 1;
+                let least = domains[last].count;
+                domains[last] = Entry {
+                    domain: domain.to_owned(),
+                    count: least + 1,
+                    error: least,
+                };
+                last
+            }
+        };
+        // Move the entry up past the domains it overtook
+        let mut at = at;
+        while at > 0 && domains[at - 1].count < domains[at].count {
+            domains.swap(at - 1, at);
+            at -= 1;
+        }
+    }
+}
+
+/// Registrations counted into the stored sketch, which is written back once
+/// they all are
+pub(crate) struct Tally {
+    top: TopDomains,
+    counted: bool,
+}
+
+impl Tally {
+    pub fn load(store: &Kv) -> Result<Self> {
+        Ok(Self {
+            top: TopDomains::load(store)?,
+            counted: false,
+        })
+    }
+
+    /// Count a registration of the canonical `email`
+    pub fn count(&mut self, email: &str) {
+        if let Some(domain) = domain_quota::domain(email) {
+            self.top.record(&domain);
+            self.counted = true;
+        }
+    }
+
+    pub fn save(self, store: &Kv) -> Result<()> {
+        if !self.counted {
+            return Ok(());
+        }
+        // Without compare and swap, concurrent registrations may lose a count
+        store.set(DOMAINS_KEY, &serde_json::to_vec(&self.top)?)
+    }
+}
+
+/// Count registrations of the canonical `emails`
+pub(crate) fn record<'a>(store: &Kv, emails: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    let mut emails = emails.into_iter().peekable();
+    if emails.peek().is_none() {
+        return Ok(());
+    }
+    let mut tally = Tally::load(store)?;
+    for email in emails {
+        tally.count(email);
+    }
+    tally.save(store)
+}
+
+impl App {
+    /// Report the most common registration domains with their approximate
+    /// counts
+    ///
+    /// Like the admin endpoints this requires the admin token.
+    pub fn domain_stats(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        Ok(Encoding::accepted(&req).response(&TopDomains::load(&self.store)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequent_domains_are_kept() {
+        let mut top = TopDomains::default();
+        for i in 0..TRACKED * 3 {
+            top.record("example.com");
+            top.record(&format!("d{i}.example"));
+        }
+        assert_eq!(top.domains.len(), TRACKED);
+        assert_eq!(
+            top.domains[0],
+            Entry {
+                domain: "example.com".into(),
+                count: TRACKED as u64 * 3,
+                error: 0,
+            }
+        );
+        assert!(top.domains.windows(2).all(|w| w[0].count >= w[1].count));
+        let newest = format!("d{}.example", TRACKED * 3 - 1);
+        let newest = top.domains.iter().find(|e| e.domain == newest).unwrap();
+        assert_eq!(newest.count - newest.error, 1);
+    }
+}
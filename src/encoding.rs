@@ -0,0 +1,123 @@
+//! Negotiating JSON or MessagePack bodies
+//!
+//! Endpoints with large payloads also speak MessagePack, picked by the
+//! `Accept` header for responses and `Content-Type` for request bodies, with
+//! JSON as the default.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+
+pub(crate) const JSON: &str = "application/json";
+pub(crate) const MSGPACK: &str = "application/msgpack";
+/// Still widely used for MessagePack despite not being registered
+pub(crate) const MSGPACK_LEGACY: &str = "application/x-msgpack";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// The encoding the client would like its response in
+    ///
+    /// The first of the supported media types listed in `Accept` wins.
+    pub fn accepted(req: &Request) -> Self {
+        let accept = header(req, http::header::ACCEPT);
+        accept
+            .split(',')
+            .find_map(Self::from_media_type)
+            .unwrap_or(Encoding::Json)
+    }
+
+    /// The encoding of the request's body
+    pub fn of_body(req: &Request) -> Self {
+        Self::from_media_type(header(req, http::header::CONTENT_TYPE)).unwrap_or(Encoding::Json)
+    }
+
+    fn from_media_type(value: &str) -> Option<Self> {
+        let essence = essence(value);
+        if essence.eq_ignore_ascii_case(JSON) {
+            Some(Encoding::Json)
+        } else if essence.eq_ignore_ascii_case(MSGPACK)
+            || essence.eq_ignore_ascii_case(MSGPACK_LEGACY)
+        {
+            Some(Encoding::MessagePack)
+        } else {
+            None
+        }
+    }
+
+    /// The media type bodies in this encoding are sent as
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Encoding::Json => JSON,
+            Encoding::MessagePack => MSGPACK,
+        }
+    }
+
+    pub fn decode<T: serde::de::DeserializeOwned>(self, body: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(body)?,
+            Encoding::MessagePack => rmp_serde::from_slice(body)?,
+        })
+    }
+
+    /// A 200 response carrying `value`
+    pub fn response<T: serde::Serialize>(self, value: &T) -> Result<Response> {
+        let body = match self {
+            Encoding::Json => serde_json::to_vec(value)?,
+            // Named fields keep the two encodings' models interchangeable
+            Encoding::MessagePack => rmp_serde::to_vec_named(value)?,
+        };
+        Ok(http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, self.media_type())
+            .header(http::header::VARY, "accept")
+            .body(Some(body.into()))?)
+    }
+}
+
+/// A media type without its parameters
+pub(crate) fn essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or_default().trim()
+}
+
+fn header(req: &Request, name: http::header::HeaderName) -> &str {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_media_types() {
+        let accepted = |accept: &str| {
+            let req = http::Request::builder()
+                .header(http::header::ACCEPT, accept)
+                .body(None)
+                .unwrap();
+            Encoding::accepted(&req)
+        };
+        assert_eq!(accepted("application/msgpack"), Encoding::MessagePack);
+        assert_eq!(
+            accepted("text/html, application/x-msgpack;q=0.9"),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            accepted("application/json, application/msgpack"),
+            Encoding::Json
+        );
+        assert_eq!(accepted("*/*"), Encoding::Json);
+
+        let value = std::collections::BTreeMap::from([("a", 1u64)]);
+        let bytes = rmp_serde::to_vec_named(&value).unwrap();
+        let decoded: std::collections::BTreeMap<String, u64> =
+            Encoding::MessagePack.decode(&bytes).unwrap();
+        assert_eq!(decoded["a"], 1);
+    }
+}
@@ -0,0 +1,276 @@
+//! Mapping of handler errors to RFC 7807 `application/problem+json` responses
+
+use spin_sdk::{http::Response, key_value};
+
+use crate::{db, filter::CorruptState, schema::Violation};
+
+/// When to retry once the database couldn't be reached, in seconds
+const DATABASE_RETRY_AFTER: u64 = 5;
+
+/// An error that is reported to the client as a problem document
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The request was malformed
+    BadRequest(String),
+    /// The request body doesn't match its route's schema
+    InvalidBody {
+        detail: String,
+        errors: Vec<Violation>,
+    },
+    /// The request lacks valid credentials
+    Unauthorized,
+    /// The request isn't allowed, whoever makes it
+    Forbidden(String),
+    /// The email is quarantined as possibly compromised
+    Quarantined,
+    /// No route matches the request path
+    NotFound,
+    /// The route exists but doesn't support the request method
+    MethodNotAllowed,
+    /// The request conflicts with the current state, such as registering a
+    /// taken email
+    Conflict(String),
+    /// The request body is larger than the route takes
+    PayloadTooLarge(String),
+    /// The request body isn't in a format the route takes
+    UnsupportedMediaType(String),
+    /// The store is over its `storage_limit`
+    InsufficientStorage(String),
+    /// The client exceeded a quota
+    TooManyRequests {
+        detail: String,
+        /// Seconds after which the quota resets
+        retry_after: u64,
+    },
+    /// The request can't be served right now but may be retried later
+    Unavailable {
+        detail: String,
+        /// Seconds after which the request may succeed
+        retry_after: u64,
+    },
+    /// Stored state can't be decoded; details are logged but not sent to
+    /// the client
+    CorruptState(anyhow::Error),
+    /// Any other failure; details are logged but not sent to the client
+    Internal(anyhow::Error),
+}
+
+/// The problem document body
+#[derive(serde::Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    title: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [Violation]>,
+    /// The generation of the filter's newest slice, when the request got as
+    /// far as loading it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter_generation: Option<u64>,
+}
+
+impl Error {
+    pub fn bad_request(detail: impl std::fmt::Display) -> Self {
+        Error::BadRequest(detail.to_string())
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::BadRequest(_) | Error::InvalidBody { .. } => 400,
+            Error::Unauthorized => 401,
+            Error::Forbidden(_) => 403,
+            Error::NotFound => 404,
+            Error::MethodNotAllowed => 405,
+            Error::Conflict(_) => 409,
+            Error::Quarantined => 422,
+            Error::PayloadTooLarge(_) => 413,
+            Error::UnsupportedMediaType(_) => 415,
+            Error::TooManyRequests { .. } => 429,
+            Error::Unavailable { .. } => 503,
+            Error::InsufficientStorage(_) => 507,
+            Error::CorruptState(_) | Error::Internal(_) => 500,
+        }
+    }
+
+    /// The problem type URI and its title
+    fn kind(&self) -> (&'static str, &'static str) {
+        match self {
+            Error::BadRequest(_) => ("/problems/bad-request", "Bad request"),
+            Error::InvalidBody { .. } => ("/problems/invalid-body", "Invalid body"),
+            Error::Unauthorized => ("/problems/unauthorized", "Unauthorized"),
+            Error::Forbidden(_) => ("/problems/forbidden", "Forbidden"),
+            Error::NotFound => ("/problems/not-found", "Not found"),
+            Error::MethodNotAllowed => ("/problems/method-not-allowed", "Method not allowed"),
+            Error::Conflict(_) => ("/problems/conflict", "Conflict"),
+            Error::Quarantined => ("/problems/quarantined", "Quarantined"),
+            Error::PayloadTooLarge(_) => ("/problems/payload-too-large", "Payload too large"),
+            Error::UnsupportedMediaType(_) => {
+                ("/problems/unsupported-media-type", "Unsupported media type")
+            }
+            Error::TooManyRequests { .. } => ("/problems/too-many-requests", "Too many requests"),
+            Error::Unavailable { .. } => ("/problems/unavailable", "Temporarily unavailable"),
+            Error::InsufficientStorage(_) => {
+                ("/problems/insufficient-storage", "Insufficient storage")
+            }
+            Error::CorruptState(_) => ("/problems/corrupt-state", "Corrupted state"),
+            Error::Internal(_) => ("/problems/internal", "Internal error"),
+        }
+    }
+
+    fn problem(&self) -> Problem<'_> {
+        let (kind, title) = self.kind();
+        let (detail, retry_after) = match self {
+            Error::BadRequest(detail)
+            | Error::InvalidBody { detail, .. }
+            | Error::Forbidden(detail)
+            | Error::Conflict(detail)
+            | Error::PayloadTooLarge(detail)
+            | Error::UnsupportedMediaType(detail)
+            | Error::InsufficientStorage(detail) => (Some(detail.as_str()), None),
+            Error::TooManyRequests {
+                detail,
+                retry_after,
+            }
+            | Error::Unavailable {
+                detail,
+                retry_after,
+            } => (Some(detail.as_str()), Some(*retry_after)),
+            Error::Quarantined => (
+                Some("the email may have been compromised, its owner must be verified first"),
+                None,
+            ),
+            Error::Unauthorized
+            | Error::NotFound
+            | Error::MethodNotAllowed
+            | Error::CorruptState(_)
+            | Error::Internal(_) => (None, None),
+        };
+        let errors = match self {
+            Error::InvalidBody { errors, .. } => Some(errors.as_slice()),
+            _ => None,
+        };
+        Problem {
+            kind,
+            title,
+            status: self.status(),
+            detail,
+            retry_after,
+            errors,
+            filter_generation: None,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        self.into_response_at(None)
+    }
+
+    /// The response for a request answered against the filter generation
+    /// `filter_generation`
+    pub fn into_response_at(self, filter_generation: Option<u64>) -> Response {
+        match &self {
+            Error::CorruptState(e) => eprintln!("corrupted state: {e:#}"),
+            Error::Internal(e) => eprintln!("internal error: {e:#}"),
+            _ => {}
+        }
+        let mut response = http::Response::builder()
+            .status(self.status())
+            .header(http::header::CONTENT_TYPE, "application/problem+json");
+        match &self {
+            Error::TooManyRequests { retry_after, .. } | Error::Unavailable { retry_after, .. } => {
+                response = response.header(http::header::RETRY_AFTER, *retry_after);
+            }
+            Error::Unauthorized => {
+                response = response.header(http::header::WWW_AUTHENTICATE, "Bearer");
+            }
+            _ => {}
+        }
+        let problem = Problem {
+            filter_generation,
+            ..self.problem()
+        };
+        let body = serde_json::to_vec(&problem).expect("problem serializes");
+        response.body(Some(body.into())).unwrap()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        if e.is::<CorruptState>() {
+            Error::CorruptState(e)
+        } else if let Some(db::Unavailable(detail)) = e.downcast_ref::<db::Unavailable>() {
+            eprintln!("database unavailable: {detail}");
+            Error::Unavailable {
+                detail: "the user database is temporarily unavailable".into(),
+                retry_after: DATABASE_RETRY_AFTER,
+            }
+        } else if e.is::<db::Taken>() {
+            Error::Conflict(e.to_string())
+        } else {
+            Error::Internal(e)
+        }
+    }
+}
+
+impl From<key_value::Error> for Error {
+    fn from(e: key_value::Error) -> Self {
+        Error::Internal(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problem_members() {
+        let error = Error::Unavailable {
+            detail: "busy".into(),
+            retry_after: 12,
+        };
+        let problem = serde_json::to_value(error.problem()).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "/problems/unavailable",
+                "title": "Temporarily unavailable",
+                "status": 503,
+                "detail": "busy",
+                "retry_after": 12,
+            })
+        );
+
+        let conflict = Error::Conflict("taken".into());
+        let problem = Problem {
+            filter_generation: Some(7),
+            ..conflict.problem()
+        };
+        let problem = serde_json::to_value(problem).unwrap();
+        assert_eq!(problem["filter_generation"], 7);
+        assert!(problem.get("retry_after").is_none());
+
+        let internal = Error::from(anyhow::anyhow!("secret"));
+        let problem = serde_json::to_value(internal.problem()).unwrap();
+        assert!(problem.get("detail").is_none());
+
+        let corrupt = crate::BloomFilter::deserialize(vec![1, 2, 3]).unwrap_err();
+        let corrupt = Error::from(corrupt.context("loading the filter"));
+        assert_eq!(corrupt.status(), 500);
+        assert_eq!(corrupt.kind().0, "/problems/corrupt-state");
+
+        let down = anyhow::Error::new(db::Unavailable("10.0.0.7 refused".into()));
+        let down = Error::from(down.context("looking up the email"));
+        assert_eq!(down.status(), 503);
+        assert!(!format!("{:?}", down.problem().detail).contains("10.0.0.7"));
+        assert_eq!(Error::from(anyhow::Error::new(db::Taken)).status(), 409);
+        assert_eq!(
+            Error::Quarantined.kind(),
+            ("/problems/quarantined", "Quarantined")
+        );
+        assert_eq!(Error::Quarantined.status(), 422);
+    }
+}
@@ -0,0 +1,106 @@
+//! Per-request feature flags
+//!
+//! The calling backend can switch experimental behavior on for single
+//! requests with an `X-Feature-Flags` header, so new code paths can be tried
+//! on a share of traffic before becoming defaults. Only the flags listed in
+//! the `feature_flags` variable may be used.
+
+use crate::error::Error;
+use spin_sdk::http::Request;
+
+const HEADER: &str = "x-feature-flags";
+
+/// An experimental behavior that can be switched on per request
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Feature {
+    /// Flag lookalikes of registered emails, see `similar_names`
+    SimilarNames,
+}
+
+impl Feature {
+    fn name(self) -> &'static str {
+        match self {
+            Feature::SimilarNames => "similar-names",
+        }
+    }
+}
+
+impl std::str::FromStr for Feature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "similar-names" => Ok(Feature::SimilarNames),
+            _ => anyhow::bail!("unknown feature flag `{s}`"),
+        }
+    }
+}
+
+/// A set of feature flags
+#[derive(Default, Debug, PartialEq, Eq)]
+pub(crate) struct Features(Vec<Feature>);
+
+impl Features {
+    pub fn contains(&self, feature: Feature) -> bool {
+        self.0.contains(&feature)
+    }
+
+    /// The flags the request switches on, which must all be `allowed`
+    pub fn of_request(req: &Request, allowed: &Features) -> Result<Self, Error> {
+        let Some(header) = req.headers().get(HEADER) else {
+            return Ok(Self::default());
+        };
+        let requested: Self = header
+            .to_str()
+            .map_err(Error::bad_request)?
+            .parse()
+            .map_err(|e| Error::bad_request(format!("invalid {HEADER} header: {e}")))?;
+        if let Some(feature) = requested.0.iter().find(|f| !allowed.contains(**f)) {
+            return Err(Error::bad_request(format!(
+                "feature flag `{}` is not enabled",
+                feature.name()
+            )));
+        }
+        Ok(requested)
+    }
+}
+
+impl std::str::FromStr for Features {
+    type Err = anyhow::Error;
+
+    /// Parse a comma separated list of flags
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut features = Vec::new();
+        for feature in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let feature = feature.parse()?;
+            if !features.contains(&feature) {
+                features.push(feature);
+            }
+        }
+        Ok(Self(features))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_use_allowed_flags_only() {
+        let request = |flags: &str| {
+            http::Request::builder()
+                .header(HEADER, flags)
+                .body(None)
+                .unwrap()
+        };
+        let allowed: Features = "similar-names".parse().unwrap();
+        let features = Features::of_request(&request("similar-names"), &allowed).unwrap();
+        assert!(features.contains(Feature::SimilarNames));
+        assert!(Features::of_request(&request("similar-names"), &Features::default()).is_err());
+        assert!(Features::of_request(&request("telepathy"), &allowed).is_err());
+        assert_eq!(
+            Features::of_request(&request(""), &Features::default()).unwrap(),
+            Features::default()
+        );
+    }
+}
@@ -0,0 +1,955 @@
+//! The Bloom filter itself, independent of the HTTP component
+//!
+//! A [`BloomFilter`] is made with [`BloomFilter::with_params`], takes elements
+//! with [`BloomFilter::insert`] and answers [`BloomFilter::contains`] with
+//! [`Exists::No`] only for elements that were never inserted. Filters of the
+//! same shape can be merged, and [`BloomFilter::serialize`] encodes a filter
+//! in the versioned format [`BloomFilter::deserialize`] reads back.
+
+use anyhow::{Context, Result};
+use bitvec::prelude::*;
+use core::hash::Hash;
+use hash32::Hasher;
+use std::cell::OnceCell;
+
+use crate::hashing::{Element, FilterHasher, Hashing};
+
+/// A Bloom filter over elements of any hashable type
+pub struct BloomFilter {
+    pub(crate) array: BitVec<u32, Lsb0>,
+    pub(crate) num_hashes: usize,
+    pub(crate) hashing: Hashing,
+    /// Hashed before every element, 0 for none
+    pub(crate) seed: u64,
+    /// How many inserted elements set each bit, in counting filters
+    pub(crate) counters: Option<Vec<u8>>,
+    /// How many times the stored filter has been written
+    pub(crate) generation: u64,
+    /// How many elements were inserted, less those removed
+    pub(crate) num: usize,
+}
+/// The most hash functions a filter may use
+pub(crate) const MAX_HASHES: usize = 32;
+/// The largest value of a counting filter's 4-bit counters. A counter that
+/// reaches it has lost count, so it is never decremented again.
+const COUNTER_MAX: u8 = 15;
+
+/// Marks stored state as being in a versioned format
+pub(crate) const MAGIC: &[u8; 3] = b"BLM";
+/// The version of the stored state format written by `serialize`
+const FORMAT_VERSION: u8 = 6;
+/// The version of the manifests of filters stored in shards, which
+/// `deserialize` doesn't read
+pub(crate) const SHARDED_VERSION: u8 = 7;
+/// The version `serialize` writes for filters hashing other than the
+/// default way or seeded, which older deployments can't read
+const HASHED_VERSION: u8 = 8;
+/// The version of the manifests of filters hashing other than the default
+/// way or seeded
+pub(crate) const HASHED_SHARDED_VERSION: u8 = 9;
+/// The version of the headers of slices stored as Redis bitmaps
+pub(crate) const BITMAP_VERSION: u8 = 10;
+/// The version of the manifests of filters whose shards were rebalanced
+/// onto another mapping, or are being
+pub(crate) const REMAPPED_SHARDED_VERSION: u8 = 11;
+
+/// The shape of a filter
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+pub(crate) struct Params {
+    pub num_bits: usize,
+    pub num_hashes: usize,
+    /// Whether the filter counts insertions so elements can be removed
+    pub counting: bool,
+    /// How elements are hashed onto the bits
+    pub hashing: Hashing,
+    /// The seed hashed before every element, 0 for none. It is kept out of
+    /// reports, since knowing it is what lets emails be crafted against the
+    /// filter.
+    #[serde(skip)]
+    pub seed: u64,
+}
+
+impl Params {
+    /// The shape filters had before it could be configured, which all state
+    /// stored in the older formats has
+    pub const LEGACY: Params = Params {
+        num_bits: 128,
+        num_hashes: 2,
+        counting: false,
+        hashing: Hashing::Murmur3Fnv,
+        seed: 0,
+    };
+
+    pub fn new(num_bits: usize, num_hashes: usize, counting: bool) -> Result<Self> {
+        if num_bits == 0 || num_bits > u32::MAX as usize {
+            anyhow::bail!("a filter needs between 1 and {} bits", u32::MAX);
+        }
+        if !(1..=MAX_HASHES).contains(&num_hashes) {
+            anyhow::bail!("a filter needs between 1 and {MAX_HASHES} hash functions");
+        }
+        Ok(Self {
+            num_bits,
+            num_hashes,
+            counting,
+            hashing: Hashing::Murmur3Fnv,
+            seed: 0,
+        })
+    }
+
+    /// The same shape, hashing elements with `hashing`
+    pub fn hashed_with(self, hashing: Hashing) -> Self {
+        Self { hashing, ..self }
+    }
+
+    /// The same shape, hashing `seed` before every element
+    pub fn seeded(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// The same shape, hashing elements like filters of shape `other`
+    pub fn hashed_like(self, other: Params) -> Self {
+        self.hashed_with(other.hashing).seeded(other.seed)
+    }
+
+    /// Whether elements are hashed other than as they were before hashing
+    /// could be configured, which the older formats can't record
+    pub fn hashed_specially(self) -> bool {
+        self.hashing != Hashing::default() || self.seed != 0
+    }
+
+    /// The smallest shape holding `items` elements at a false positive rate
+    /// of at most `fp_rate`
+    pub fn optimal(items: u64, fp_rate: f64, counting: bool) -> Result<Self> {
+        let valid = items > 0 && fp_rate > 0.0 && fp_rate < 1.0;
+        if !valid {
+            anyhow::bail!(
+                "a filter needs at least one item and a false positive rate between 0 and 1"
+            );
+        }
+        let ln2 = std::f64::consts::LN_2;
+        // m = -n ln(p) / ln(2)^2 and k = m/n ln(2)
+        let num_bits = (-(items as f64) * fp_rate.ln() / (ln2 * ln2)).ceil();
+        if num_bits > u32::MAX as f64 {
+            anyhow::bail!(
+                "a filter of that size would need more than {} bits",
+                u32::MAX
+            );
+        }
+        let num_hashes = (num_bits / items as f64 * ln2).round() as usize;
+        Self::new(num_bits as usize, num_hashes.clamp(1, MAX_HASHES), counting)
+    }
+
+    /// How many `u32` words hold the filter's bits
+    fn words(self) -> usize {
+        self.num_bits.div_ceil(32)
+    }
+
+    /// How many bytes hold the filter's counters, two to a byte
+    fn counter_bytes(self) -> usize {
+        if self.counting {
+            self.num_bits.div_ceil(2)
+        } else {
+            0
+        }
+    }
+}
+
+impl BloomFilter {
+    /// An empty filter of `num_bits` bits probed by `num_hashes` hash
+    /// functions
+    pub fn with_params(num_bits: usize, num_hashes: usize) -> Result<Self> {
+        Ok(Self::new(Params::new(num_bits, num_hashes, false)?))
+    }
+
+    pub(crate) fn new(params: Params) -> Self {
+        Self {
+            array: BitVec::repeat(false, params.num_bits),
+            num_hashes: params.num_hashes,
+            hashing: params.hashing,
+            seed: params.seed,
+            counters: params.counting.then(|| vec![0; params.num_bits]),
+            generation: 0,
+            num: 0,
+        }
+    }
+
+    /// A filter with every bit set, which holds everything
+    pub(crate) fn saturated(params: Params) -> Self {
+        Self {
+            array: BitVec::repeat(true, params.num_bits),
+            num_hashes: params.num_hashes,
+            hashing: params.hashing,
+            seed: params.seed,
+            counters: None,
+            generation: 0,
+            num: 0,
+        }
+    }
+
+    pub(crate) fn params(&self) -> Params {
+        Params {
+            num_bits: self.array.len(),
+            num_hashes: self.num_hashes,
+            counting: self.counters.is_some(),
+            hashing: self.hashing,
+            seed: self.seed,
+        }
+    }
+
+    /// Decode a stored filter, in the current or any older format
+    ///
+    /// The legacy format is the bare words of a `Params::LEGACY` filter, and
+    /// version 1 puts `MAGIC` and the version in front of them. Version 2
+    /// follows those with the number of bits as a big-endian `u32` and the
+    /// number of hash functions as a byte before the words. Version 3 adds a
+    /// byte that is 1 for counting filters, whose counters follow the words,
+    /// two to a byte with the first in the high nibble. Version 4 puts the
+    /// filter's generation as a big-endian `u64` before all that, and version
+    /// 5 follows the generation with the number of inserted elements, also a
+    /// big-endian `u64`. Filters in the older formats didn't keep count, so
+    /// their count is estimated from how many bits are set. Version 6, the
+    /// current one, ends with the Murmur3 checksum of everything before it, as a
+    /// big-endian `u32`, so that damaged state is told apart from a filter.
+    /// Version 7 is the manifest of a filter stored in shards, described to
+    /// other readers by `GET /admin/layout` rather than decoded here. Version
+    /// 8 is version 6 with a byte naming the filter's [`Hashing`] and its seed
+    /// as a big-endian `u64` after the counting byte, written only for
+    /// filters not hashing the default way or seeded, and version 9 is
+    /// likewise the manifest of such a filter. Version 10 is the header of a
+    /// slice stored as a Redis bitmap, see [`crate::filter_store`].
+    ///
+    /// In all of them each `u32` word is stored big-endian and bit `i` of the
+    /// filter is bit `i % 32` of word `i / 32`, counting from the least
+    /// significant bit, whatever the byte order of the host that wrote or
+    /// reads it. Filters were first kept in bitvec's `LocalBits` order, which
+    /// is only this order on little-endian hosts, but the component has only
+    /// ever run as 32-bit WebAssembly, which is little-endian, so all state
+    /// stored then is in this order too and needs no conversion.
+    ///
+    /// Errors are [`CorruptState`]s.
+    pub fn deserialize(e: Vec<u8>) -> Result<Self> {
+        Self::decode(e).map_err(|e| CorruptState(e.to_string()).into())
+    }
+
+    fn decode(e: Vec<u8>) -> Result<Self> {
+        let num_bits = |rest: &[u8]| u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let shape = |rest: &[u8]| {
+            let counting = match rest[5] {
+                0 => false,
+                1 => true,
+                _ => anyhow::bail!("corrupted state"),
+            };
+            Params::new(num_bits(rest), rest[4].into(), counting)
+        };
+        let u64_at =
+            |rest: &[u8], at: usize| u64::from_be_bytes(rest[at..at + 8].try_into().unwrap());
+        let hashed = |rest: &[u8]| {
+            let hashing = Hashing::from_id(rest[6]).context("corrupted state: unknown hashing")?;
+            Ok::<_, anyhow::Error>(shape(rest)?.hashed_with(hashing).seeded(u64_at(rest, 7)))
+        };
+        let (params, body, generation, num) = if e.len() == Params::LEGACY.num_bits / 8 {
+            (Params::LEGACY, &e[..], 0, None)
+        } else {
+            match e.strip_prefix(MAGIC) {
+                Some([1, words @ ..]) => (Params::LEGACY, words, 0, None),
+                Some([2, rest @ ..]) if rest.len() >= 5 => (
+                    Params::new(num_bits(rest), rest[4].into(), false)?,
+                    &rest[5..],
+                    0,
+                    None,
+                ),
+                Some([3, rest @ ..]) if rest.len() >= 6 => (shape(rest)?, &rest[6..], 0, None),
+                Some([4, rest @ ..]) if rest.len() >= 14 => {
+                    (shape(&rest[8..])?, &rest[14..], u64_at(rest, 0), None)
+                }
+                Some([5, rest @ ..]) if rest.len() >= 22 => (
+                    shape(&rest[16..])?,
+                    &rest[22..],
+                    u64_at(rest, 0),
+                    Some(u64_at(rest, 8)),
+                ),
+                Some([FORMAT_VERSION, rest @ ..]) if rest.len() >= 26 => {
+                    let (checked, stored) = e.split_at(e.len() - 4);
+                    if stored != checksum(checked).to_be_bytes() {
+                        anyhow::bail!("corrupted state: checksum mismatch");
+                    }
+                    let rest = &rest[..rest.len() - 4];
+                    (
+                        shape(&rest[16..])?,
+                        &rest[22..],
+                        u64_at(rest, 0),
+                        Some(u64_at(rest, 8)),
+                    )
+                }
+                Some([HASHED_VERSION, rest @ ..]) if rest.len() >= 35 => {
+                    let (checked, stored) = e.split_at(e.len() - 4);
+                    if stored != checksum(checked).to_be_bytes() {
+                        anyhow::bail!("corrupted state: checksum mismatch");
+                    }
+                    let rest = &rest[..rest.len() - 4];
+                    (
+                        hashed(&rest[16..])?,
+                        &rest[31..],
+                        u64_at(rest, 0),
+                        Some(u64_at(rest, 8)),
+                    )
+                }
+                Some([SHARDED_VERSION | HASHED_SHARDED_VERSION | REMAPPED_SHARDED_VERSION, ..]) => {
+                    anyhow::bail!("the state is the manifest of a filter stored in shards")
+                }
+                Some([BITMAP_VERSION, ..]) => {
+                    anyhow::bail!("the state is the header of a slice stored as a Redis bitmap")
+                }
+                Some([version, ..]) if *version > REMAPPED_SHARDED_VERSION => {
+                    anyhow::bail!("unsupported state format version {version}")
+                }
+                _ => anyhow::bail!("corrupted state"),
+            }
+        };
+        if body.len() != params.words() * 4 + params.counter_bytes() {
+            anyhow::bail!("corrupted state");
+        }
+        let (words, counters) = body.split_at(params.words() * 4);
+        let mut array = BitVec::from_vec(decode_words(words));
+        array.truncate(params.num_bits);
+        let counters = params.counting.then(|| {
+            counters
+                .iter()
+                .flat_map(|pair| [pair >> 4, pair & 0xf])
+                .take(params.num_bits)
+                .collect()
+        });
+        let num = match num {
+            Some(num) => usize::try_from(num).unwrap_or(usize::MAX),
+            None => estimated_items(array.count_ones(), params).round() as usize,
+        };
+        Ok(Self {
+            array,
+            num_hashes: params.num_hashes,
+            hashing: params.hashing,
+            seed: params.seed,
+            counters,
+            generation,
+            num,
+        })
+    }
+
+    /// The generation of a stored filter, without decoding the rest of it
+    pub(crate) fn stored_generation(bytes: &[u8]) -> u64 {
+        match bytes.strip_prefix(MAGIC) {
+            Some([4..=REMAPPED_SHARDED_VERSION, rest @ ..]) if rest.len() >= 8 => {
+                u64::from_be_bytes(rest[..8].try_into().unwrap())
+            }
+            _ => 0,
+        }
+    }
+
+    /// Whether stored state is in an older format than `serialize` writes
+    pub(crate) fn is_outdated(bytes: &[u8]) -> bool {
+        bytes.len() == Params::LEGACY.num_bits / 8
+            || matches!(bytes.strip_prefix(MAGIC), Some([version, ..]) if *version < FORMAT_VERSION)
+    }
+
+    /// Encode the filter in the current format
+    pub fn serialize(&self) -> Vec<u8> {
+        let params = self.params();
+        let words = self.array.as_raw_slice();
+        let mut bytes =
+            Vec::with_capacity(MAGIC.len() + 36 + words.len() * 4 + params.counter_bytes());
+        bytes.extend(MAGIC);
+        let hashed = params.hashed_specially();
+        bytes.push(if hashed {
+            HASHED_VERSION
+        } else {
+            FORMAT_VERSION
+        });
+        bytes.extend(self.generation.to_be_bytes());
+        bytes.extend((self.num as u64).to_be_bytes());
+        bytes.extend((self.array.len() as u32).to_be_bytes());
+        bytes.push(self.num_hashes as u8);
+        bytes.push(params.counting.into());
+        if hashed {
+            bytes.push(params.hashing.id());
+            bytes.extend(params.seed.to_be_bytes());
+        }
+        for word in words {
+            bytes.extend(word.to_be_bytes());
+        }
+        if let Some(counters) = &self.counters {
+            for pair in counters.chunks(2) {
+                bytes.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+            }
+        }
+        bytes.extend(checksum(&bytes).to_be_bytes());
+        bytes
+    }
+
+    /// Insert element into filter
+    pub fn insert<E>(&mut self, element: E)
+    where
+        E: Hash,
+    {
+        self.insert_probes(&ProbeSet::new(&element))
+    }
+
+    /// Insert an already hashed element into filter
+    pub(crate) fn insert_probes(&mut self, probes: &ProbeSet) {
+        self.num = self.num.saturating_add(1);
+        for index in probes.indices(self.params()) {
+            self.array.set(index, true);
+            if let Some(counters) = &mut self.counters {
+                counters[index] = (counters[index] + 1).min(COUNTER_MAX);
+            }
+        }
+    }
+
+    /// Remove an already hashed element from a counting filter
+    ///
+    /// Counters are only decremented if all of the element's counters show it
+    /// could be in the filter, so they never underflow. Counters that have
+    /// lost count are left as they are and the element stays a `Maybe` on
+    /// those bits.
+    pub(crate) fn remove_probes(&mut self, probes: &ProbeSet) -> Result<Removal> {
+        let params = self.params();
+        let Some(counters) = &mut self.counters else {
+            anyhow::bail!("only counting filters can remove elements");
+        };
+        // The same bit may be probed more than once
+        let mut probed: Vec<(usize, u8)> = Vec::with_capacity(params.num_hashes);
+        for index in probes.indices(params) {
+            match probed.iter_mut().find(|(i, _)| *i == index) {
+                Some((_, times)) => *times += 1,
+                None => probed.push((index, 1)),
+            }
+        }
+        let absent = probed
+            .iter()
+            .any(|&(index, times)| counters[index] < times && counters[index] != COUNTER_MAX);
+        if absent {
+            return Ok(Removal::Absent);
+        }
+        let mut saturated = 0;
+        for (index, times) in probed {
+            if counters[index] == COUNTER_MAX {
+                saturated += 1;
+                continue;
+            }
+            counters[index] -= times;
+            if counters[index] == 0 {
+                self.array.set(index, false);
+            }
+        }
+        self.num = self.num.saturating_sub(1);
+        Ok(Removal::Removed { saturated })
+    }
+
+    /// Check whether element does not exist in the filter
+    ///
+    /// `Exists::No` means the element is definitely not in the set
+    /// `Exists::Maybe` means the element *might* be in the set
+    pub fn contains<E>(&self, element: E) -> Exists
+    where
+        E: Hash,
+    {
+        self.exists_probes(&ProbeSet::new(&element))
+    }
+
+    /// Check whether an already hashed element does not exist in the filter
+    pub(crate) fn exists_probes(&self, probes: &ProbeSet) -> Exists {
+        probes
+            .indices(self.params())
+            .any(|index| !self.array[index])
+            .then(|| Exists::No)
+            .unwrap_or(Exists::Maybe)
+    }
+
+    /// Add the elements of `other`, a filter of the same shape
+    ///
+    /// The result holds every element either filter held.
+    pub fn merge(&mut self, other: &BloomFilter) -> Result<()> {
+        if self.params() != other.params() {
+            anyhow::bail!("only filters of the same shape can be merged");
+        }
+        let words = self.array.as_raw_mut_slice().iter_mut();
+        for (word, other) in words.zip(other.array.as_raw_slice()) {
+            *word |= other;
+        }
+        if let (Some(counters), Some(others)) = (&mut self.counters, &other.counters) {
+            for (counter, other) in counters.iter_mut().zip(others) {
+                *counter = counter.saturating_add(*other).min(COUNTER_MAX);
+            }
+        }
+        self.num = self.num.saturating_add(other.num);
+        Ok(())
+    }
+
+    /// Estimate how many elements only one of this filter and `other`, a
+    /// filter of the same shape, holds
+    ///
+    /// See `difference_estimates`, of which this is the sum.
+    pub fn symmetric_difference_estimate(&self, other: &BloomFilter) -> Result<f64> {
+        let (added, dropped) = self.difference_estimates(other)?;
+        Ok(added + dropped)
+    }
+
+    /// Estimate how many elements only `other`, a filter of the same shape,
+    /// holds and how many only this filter holds
+    ///
+    /// The counts are estimated from how many bits each filter and their
+    /// union have set, so they are only as good as the filters are sparse,
+    /// and infinite once the union has every bit set.
+    pub(crate) fn difference_estimates(&self, other: &BloomFilter) -> Result<(f64, f64)> {
+        let params = self.params();
+        let uncounted = |params: Params| Params {
+            counting: false,
+            ..params
+        };
+        if uncounted(params) != uncounted(other.params()) {
+            anyhow::bail!("only filters of the same shape can be compared");
+        }
+        let mut union = self.array.clone();
+        let words = union.as_raw_mut_slice().iter_mut();
+        for (word, other) in words.zip(other.array.as_raw_slice()) {
+            *word |= other;
+        }
+        let union = estimated_items(union.count_ones(), params);
+        if union.is_infinite() {
+            return Ok((f64::INFINITY, f64::INFINITY));
+        }
+        let ours = estimated_items(self.array.count_ones(), params);
+        let theirs = estimated_items(other.array.count_ones(), params);
+        Ok(((union - ours).max(0.0), (union - theirs).max(0.0)))
+    }
+
+    /// Remove the elements of `other`, a counting filter of the same shape,
+    /// from this counting filter
+    ///
+    /// Like `remove_probes`, nothing is removed unless every counter shows
+    /// this filter could hold everything `other` does, and counters that
+    /// have lost count are left as they are.
+    pub(crate) fn subtract(&mut self, other: &BloomFilter) -> Result<Removal> {
+        if self.params() != other.params() {
+            anyhow::bail!("only filters of the same shape can be subtracted");
+        }
+        let (Some(counters), Some(others)) = (&mut self.counters, &other.counters) else {
+            anyhow::bail!("only counting filters can be subtracted");
+        };
+        let absent = counters
+            .iter()
+            .zip(others)
+            .any(|(counter, other)| counter < other && *counter != COUNTER_MAX);
+        if absent {
+            return Ok(Removal::Absent);
+        }
+        let mut saturated = 0;
+        for (index, (counter, other)) in counters.iter_mut().zip(others).enumerate() {
+            if *other == 0 {
+                continue;
+            }
+            if *counter == COUNTER_MAX {
+                saturated += 1;
+                continue;
+            }
+            *counter -= other;
+            if *counter == 0 {
+                self.array.set(index, false);
+            }
+        }
+        self.num = self.num.saturating_sub(other.num);
+        Ok(Removal::Removed { saturated })
+    }
+
+    #[cfg(test)]
+    /// The percent likelihood of a false positive
+    fn false_positive_percent(&self) -> f32 {
+        let k = self.num_hashes as f32;
+        let m = self.array.len() as f32;
+        100.0 * (1.0 - (1.0 - 1.0 / m).powf(k * self.num as f32)).powf(k)
+    }
+}
+
+/// Stored state that can't be decoded into a filter
+#[derive(Debug)]
+pub struct CorruptState(pub(crate) String);
+
+impl std::fmt::Display for CorruptState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CorruptState {}
+
+/// What removing an element from a counting filter did
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Removal {
+    /// The filter can't hold the element
+    Absent,
+    /// The element was removed, except from `saturated` counters that had
+    /// lost count
+    Removed { saturated: usize },
+}
+
+/// What the filter says about the existence of a key
+#[derive(PartialEq, Eq, Debug)]
+pub enum Exists {
+    /// The filter can only answer no with 100% certainty
+    No,
+    /// Otherwise the filter can't be sure
+    Maybe,
+}
+
+/// The hashes of an element, computed once per hashing and seed and reused
+/// by every filter the element is checked against or inserted into
+pub(crate) struct ProbeSet<'a> {
+    element: &'a dyn Element,
+    /// The hashes under each hashing and seed filters needed them for, in
+    /// the order they were first needed. Beyond that many are recomputed
+    /// every time.
+    hashes: [OnceCell<(Hashing, u64, [u64; 2])>; 4],
+}
+
+impl<'a> ProbeSet<'a> {
+    pub fn new<E>(element: &'a E) -> Self
+    where
+        E: Hash,
+    {
+        Self {
+            element,
+            hashes: Default::default(),
+        }
+    }
+
+    /// The two hashes of the element under `hashing` with `seed`
+    fn hashes(&self, hashing: Hashing, seed: u64) -> [u64; 2] {
+        let compute = || (hashing, seed, hashing.hashes(seed, self.element));
+        for cached in &self.hashes {
+            let (h, s, hashes) = *cached.get_or_init(compute);
+            if (h, s) == (hashing, seed) {
+                return hashes;
+            }
+        }
+        compute().2
+    }
+
+    /// The bit positions probed in a filter of the given shape
+    ///
+    /// Following Kirsch and Mitzenmacher, the `i`th position is `a + i * b`
+    /// modulo the number of bits. `a` is the first hash and `b` the
+    /// difference of the two, so the first two positions are the hashes
+    /// themselves, just as when filters always had two hash functions.
+    pub fn indices(&self, params: Params) -> impl Iterator<Item = usize> {
+        let m = params.num_bits as u64;
+        let [h1, h2] = self.hashes(params.hashing, params.seed);
+        let a = h1 % m;
+        let b = (h2 % m + m - a) % m;
+        (0..params.num_hashes as u64).map(move |i| ((a + i * b) % m) as usize)
+    }
+}
+
+pub(crate) fn murmur3<E>(element: &E) -> usize
+where
+    E: Hash,
+{
+    let mut hasher = hash32::Murmur3Hasher::default();
+    element.hash(&mut hasher);
+    hasher.finish32() as usize
+}
+
+/// The number of distinct items a filter with `ones` set bits likely holds
+pub(crate) fn estimated_items(ones: usize, params: Params) -> f64 {
+    let (m, k) = (params.num_bits as f64, params.num_hashes as f64);
+    if ones >= params.num_bits {
+        return f64::INFINITY;
+    }
+    -(m / k) * (1.0 - ones as f64 / m).ln()
+}
+
+/// The big-endian `u32` words in `bytes`, whose length is a multiple of 4
+pub(crate) fn decode_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+/// The checksum ending stored state
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = hash32::Murmur3Hasher::default();
+    core::hash::Hasher::write(&mut hasher, bytes);
+    hasher.finish32()
+}
+
+pub(crate) fn fnv<E>(element: &E) -> usize
+where
+    E: Hash,
+{
+    let mut hasher = hash32::FnvHasher::default();
+    element.hash(&mut hasher);
+    hasher.finish32() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_count;
+
+    #[test]
+    fn api_check() {
+        let mut filter = BloomFilter::new(Params::LEGACY);
+        filter.insert("hello");
+        assert_eq!(filter.contains("hello"), Exists::Maybe);
+        assert_eq!(filter.contains("hallo"), Exists::No);
+        assert_eq!(filter.false_positive_percent(), 0.0242237);
+
+        let mut other = BloomFilter::with_params(128, 2).unwrap();
+        other.insert("hallo");
+        other.merge(&filter).unwrap();
+        assert_eq!(other.contains("hello"), Exists::Maybe);
+        assert_eq!(other.contains("hallo"), Exists::Maybe);
+        assert_eq!(other.num, 2);
+        assert!(other
+            .merge(&BloomFilter::with_params(256, 2).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn deserialize_decodes_big_endian_words() {
+        let mut bytes = vec![0u8; 16];
+        bytes[3] = 1;
+        bytes[4] = 0x80;
+        let filter = BloomFilter::deserialize(bytes).unwrap();
+        assert!(filter.array[0]);
+        assert!(filter.array[63]);
+        assert_eq!(filter.array.count_ones(), 2);
+        assert_eq!(filter.params(), Params::LEGACY);
+        assert!(BloomFilter::deserialize(vec![0u8; 15]).is_err());
+        assert_eq!(decode_words(&[0, 0, 0, 1, 0x80, 0, 0, 2]), [1, 0x8000_0002]);
+
+        // Written back the same way, bit 0 and bit 63 land in the same bytes
+        let bytes = filter.serialize();
+        let mut words = [0u8; 16];
+        words[3] = 1;
+        words[4] = 0x80;
+        assert_eq!(&bytes[26..42], words);
+        // Read in the order of a big-endian `LocalBits`, the same words would
+        // have set other bits
+        let msb0 = BitVec::<u32, Msb0>::from_vec(vec![1, 0x8000_0000, 0, 0]);
+        assert_eq!(msb0.iter_ones().collect::<Vec<_>>(), [31, 32]);
+        assert_eq!(filter.array.iter_ones().collect::<Vec<_>>(), [0, 63]);
+    }
+
+    #[test]
+    fn older_formats_decode_alike() {
+        let mut filter = BloomFilter::new(Params::LEGACY);
+        filter.insert("hello");
+        filter.generation = 7;
+        let bytes = filter.serialize();
+        assert_eq!(&bytes[..12], b"BLM\x06\x00\x00\x00\x00\x00\x00\x00\x07");
+        assert_eq!(&bytes[12..20], 1u64.to_be_bytes());
+        assert_eq!(&bytes[20..26], b"\x00\x00\x00\x80\x02\x00");
+        assert!(!BloomFilter::is_outdated(&bytes));
+        assert_eq!(BloomFilter::stored_generation(&bytes), 7);
+        let decoded = BloomFilter::deserialize(bytes.clone()).unwrap();
+        assert_eq!((decoded.generation, decoded.num), (7, 1));
+        let unchecked = &bytes[..bytes.len() - 4];
+        let legacy = unchecked[26..].to_vec();
+        let v1 = [&b"BLM\x01"[..], &legacy].concat();
+        let v2 = [&b"BLM\x02\x00\x00\x00\x80\x02"[..], &legacy].concat();
+        let v3 = [&b"BLM\x03"[..], &unchecked[20..]].concat();
+        let v4 = [&b"BLM\x04"[..], &unchecked[4..12], &unchecked[20..]].concat();
+        let v5 = [&b"BLM\x05"[..], &unchecked[4..]].concat();
+        assert_eq!(BloomFilter::stored_generation(&v4), 7);
+        for old in [legacy, v1, v2, v3, v4, v5] {
+            assert!(BloomFilter::is_outdated(&old));
+            let decoded = BloomFilter::deserialize(old).unwrap();
+            assert_eq!(
+                (decoded.array, decoded.num_hashes, decoded.num),
+                (filter.array.clone(), 2, 1)
+            );
+        }
+
+        let mut damaged = bytes.clone();
+        damaged[30] ^= 1;
+        let err = BloomFilter::deserialize(damaged).err().unwrap();
+        assert!(err.to_string().contains("checksum"));
+        assert!(err.is::<CorruptState>());
+        let mut future = bytes;
+        future[3] = 12;
+        let err = BloomFilter::deserialize(future).err().unwrap();
+        assert!(err.to_string().contains("version 12"));
+    }
+
+    #[test]
+    fn filters_remember_their_hashing() {
+        let params = Params::new(1000, 4, true).unwrap();
+        let mut default = BloomFilter::new(params);
+        default.insert("hello");
+        assert_eq!(default.serialize()[3], FORMAT_VERSION);
+        for hashing in [Hashing::XxHash64, Hashing::SipHash13] {
+            let mut filter = BloomFilter::new(params.hashed_with(hashing));
+            filter.insert("hello");
+            let bytes = filter.serialize();
+            assert_eq!(bytes[3], HASHED_VERSION);
+            assert!(!BloomFilter::is_outdated(&bytes));
+            let decoded = BloomFilter::deserialize(bytes).unwrap();
+            assert_eq!(decoded.params(), params.hashed_with(hashing));
+            assert_eq!(decoded.counters, filter.counters);
+            assert_eq!(decoded.contains("hello"), Exists::Maybe);
+            assert!(default.merge(&decoded).is_err());
+        }
+    }
+
+    #[test]
+    fn seeded_filters_probe_elsewhere() {
+        let params = Params::new(1 << 20, 4, false).unwrap();
+        let seeded = params.seeded(0x5eed);
+        let mut filter = BloomFilter::new(seeded);
+        filter.insert("hello");
+        let bytes = filter.serialize();
+        assert_eq!(bytes[3], HASHED_VERSION);
+        let decoded = BloomFilter::deserialize(bytes).unwrap();
+        assert_eq!(decoded.params(), seeded);
+        assert_eq!(decoded.contains("hello"), Exists::Maybe);
+        let hello = ProbeSet::new(&"hello");
+        let indices = |params| hello.indices(params).collect::<Vec<_>>();
+        assert_ne!(indices(seeded), indices(params));
+        assert_eq!(indices(params.seeded(0x5eed)), indices(seeded));
+        // Past the cached hashings the hashes are recomputed alike
+        for seed in 1..8 {
+            assert_eq!(indices(params.seeded(seed)), indices(params.seeded(seed)));
+        }
+        assert!(BloomFilter::new(params).merge(&decoded).is_err());
+    }
+
+    #[test]
+    fn filters_of_any_shape() {
+        let params = Params::new(1000, 7, false).unwrap();
+        let mut filter = BloomFilter::new(params);
+        filter.insert("hello");
+        assert!((1..=7).contains(&filter.array.count_ones()));
+        let decoded = BloomFilter::deserialize(filter.serialize()).unwrap();
+        assert_eq!(decoded.params(), params);
+        assert_eq!(
+            decoded.exists_probes(&ProbeSet::new(&"hello")),
+            Exists::Maybe
+        );
+        assert!(Params::new(0, 2, false).is_err());
+        assert!(Params::new(128, 0, false).is_err());
+        assert!(Params::new(128, MAX_HASHES + 1, false).is_err());
+        let params = Params::optimal(1000, 0.01, false).unwrap();
+        assert_eq!((params.num_bits, params.num_hashes), (9586, 7));
+        assert_eq!(Params::optimal(1, 0.5, false).unwrap().num_hashes, 1);
+        assert!(Params::optimal(0, 0.01, false).is_err());
+        assert!(Params::optimal(1000, 1.0, false).is_err());
+        assert!(Params::optimal(u64::MAX, 0.01, false).is_err());
+    }
+
+    #[test]
+    fn counting_filters_remove_elements() {
+        let params = Params::new(1001, 3, true).unwrap();
+        let mut filter = BloomFilter::new(params);
+        let (hello, world) = (ProbeSet::new(&"hello"), ProbeSet::new(&"world"));
+        filter.insert_probes(&hello);
+        filter.insert_probes(&hello);
+        filter.insert_probes(&world);
+        let mut filter = BloomFilter::deserialize(filter.serialize()).unwrap();
+        assert_eq!(filter.params(), params);
+
+        for _ in 0..2 {
+            assert_eq!(
+                filter.remove_probes(&hello).unwrap(),
+                Removal::Removed { saturated: 0 }
+            );
+        }
+        assert_eq!(filter.exists_probes(&hello), Exists::No);
+        assert_eq!(filter.exists_probes(&world), Exists::Maybe);
+        assert_eq!(filter.remove_probes(&hello).unwrap(), Removal::Absent);
+
+        for _ in 0..20 {
+            filter.insert_probes(&hello);
+        }
+        for _ in 0..30 {
+            assert!(matches!(
+                filter.remove_probes(&hello).unwrap(),
+                Removal::Removed { saturated } if saturated > 0
+            ));
+        }
+        assert_eq!(filter.exists_probes(&hello), Exists::Maybe);
+        assert!(BloomFilter::new(Params::LEGACY)
+            .remove_probes(&hello)
+            .is_err());
+    }
+
+    #[test]
+    fn counting_filters_subtract_and_compare() {
+        let params = Params::new(4096, 3, true).unwrap();
+        let (mut ours, mut theirs) = (BloomFilter::new(params), BloomFilter::new(params));
+        for i in 0..100 {
+            ours.insert(format!("user{i}@example.com"));
+        }
+        for i in 80..130 {
+            theirs.insert(format!("user{i}@example.com"));
+        }
+        let (added, dropped) = ours.difference_estimates(&theirs).unwrap();
+        assert!((25.0..35.0).contains(&added), "{added}");
+        assert!((75.0..85.0).contains(&dropped), "{dropped}");
+        let estimate = ours.symmetric_difference_estimate(&theirs).unwrap();
+        assert_eq!(estimate, added + dropped);
+        assert_eq!(ours.symmetric_difference_estimate(&ours).unwrap(), 0.0);
+
+        // Not everything subtracted is held
+        assert_eq!(ours.subtract(&theirs).unwrap(), Removal::Absent);
+        let mut some = BloomFilter::new(params);
+        for i in 0..20 {
+            some.insert(format!("user{i}@example.com"));
+        }
+        assert_eq!(
+            ours.subtract(&some).unwrap(),
+            Removal::Removed { saturated: 0 }
+        );
+        assert_eq!(ours.num, 80);
+        assert_eq!(ours.contains("user3@example.com"), Exists::No);
+        assert_eq!(ours.contains("user50@example.com"), Exists::Maybe);
+
+        let mut plain = BloomFilter::new(Params::new(4096, 3, false).unwrap());
+        let other = BloomFilter::new(plain.params());
+        assert!(plain.subtract(&other).is_err());
+        assert!(ours.subtract(&plain).is_err());
+        assert!(ours.symmetric_difference_estimate(&plain).is_ok());
+        assert!(ours
+            .symmetric_difference_estimate(&BloomFilter::new(Params::LEGACY))
+            .is_err());
+    }
+
+    #[test]
+    fn first_two_probes_are_the_hashes() {
+        let probes = ProbeSet::new(&"hello");
+        let indices: Vec<_> = probes.indices(Params::LEGACY).collect();
+        let [h1, h2] = probes.hashes(Hashing::Murmur3Fnv, 0);
+        assert_eq!(indices, [h1 as usize % 128, h2 as usize % 128]);
+    }
+
+    #[test]
+    fn saturated_filter_holds_everything() {
+        let filter = BloomFilter::saturated(Params::LEGACY);
+        assert_eq!(
+            filter.exists_probes(&ProbeSet::new(&"anyone")),
+            Exists::Maybe
+        );
+    }
+
+    #[test]
+    fn hot_path_allocations() {
+        let mut filter = BloomFilter::new(Params::LEGACY);
+        filter.insert("hello");
+        let (bytes, n) = alloc_count::allocations(|| filter.serialize());
+        assert_eq!(n, 1);
+        // Only the bit vector itself
+        let (decoded, n) = alloc_count::allocations(|| BloomFilter::deserialize(bytes).unwrap());
+        assert_eq!(n, 1);
+        let (exists, n) =
+            alloc_count::allocations(|| decoded.exists_probes(&ProbeSet::new(&"hello")));
+        assert_eq!((exists, n), (Exists::Maybe, 0));
+    }
+}
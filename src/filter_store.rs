@@ -0,0 +1,500 @@
+//! Where the slices of filters are stored
+//!
+//! The `filter_store` variable picks the backend. With `kv`, the default,
+//! slices go through the key-value stores like all other state, whole or in
+//! shards. With `redis`, each slice is kept on the Redis server at
+//! `redis_address` as a Redis bitmap under `<slice key>/bits`, with a header
+//! holding the filter's shape, generation and count under the slice key.
+//!
+//! Availability checks then read only the bits they probe, in one
+//! `BITFIELD` of `GET u1`s per slice. Writers fold their bits into the
+//! bitmap with `BITOP OR` rather than overwrite it, so two writers racing
+//! past the generation check still keep each other's emails. Counting
+//! filters and shards don't fit a bitmap and aren't supported with Redis.
+//! Filters aren't carried over when the backend changes, so a new one
+//! starts empty until it is rebuilt with `POST /rebuild`. The `redis`
+//! backend is only available in builds with the `redis-backend` feature.
+
+use anyhow::{Context, Result};
+use bitvec::prelude::*;
+#[cfg(feature = "redis-backend")]
+use spin_sdk::redis::{self, RedisParameter, RedisResult};
+use std::time::Duration;
+
+use crate::{
+    filter::{checksum, CorruptState, BITMAP_VERSION, MAGIC},
+    hashing::Hashing,
+    kv::Kv,
+    metrics::{Counter, Metrics},
+    shards::{self, Manifest},
+    transaction::Interleaved,
+    BloomFilter, Params,
+};
+
+/// The smallest filters a failed write is retried in shards for, smaller
+/// ones can't have failed for their size
+const MIN_OUTGROWN: usize = 4 << 10;
+/// The length of a bitmap's header
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8 + 8 + 4 + 1 + 1 + 8 + 4;
+/// How long the bits a writer is folding in are kept should it fail before
+/// deleting them
+const INCOMING_TTL: Duration = Duration::from_secs(60);
+
+/// Which backend filters are stored in
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Backend {
+    KeyValue,
+    /// The Redis server at this address
+    #[cfg(feature = "redis-backend")]
+    Redis(String),
+}
+
+impl Backend {
+    /// The name the backend is configured by
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::KeyValue => "kv",
+            #[cfg(feature = "redis-backend")]
+            Backend::Redis(_) => "redis",
+        }
+    }
+}
+
+/// Loads and stores the slices of filters
+pub(crate) trait FilterStore {
+    /// The state stored at `key`: a whole filter, a manifest or a header
+    fn state(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Load the slice stored at `key`, if there is one
+    fn load(&self, key: &str) -> Result<Option<BloomFilter>>;
+
+    /// Store `filter` at `key`, replacing whatever is there
+    fn save(&self, key: &str, filter: &BloomFilter) -> Result<()>;
+
+    /// Store the changes made to `filter` since it was loaded from `key`,
+    /// bumping its generation
+    ///
+    /// Returns `false`, storing nothing or only bits another writer is about
+    /// to store too, if the slice was written since or is being written.
+    fn merge(&self, key: &str, filter: &mut BloomFilter) -> Result<bool>;
+
+    /// Delete the slice stored at `key`, returning whether there was one
+    fn delete(&self, key: &str) -> Result<bool>;
+}
+
+/// Slices kept in the key-value stores, whole or in shards
+pub(crate) struct KeyValue<'a> {
+    pub store: &'a Kv,
+    /// How many bits each shard holds, `None` to store slices whole
+    pub shard_bits: Option<usize>,
+    /// The largest value the stores take, slices stored whole are split
+    /// into shards as they approach it
+    pub max_value: usize,
+    pub metrics: &'a dyn Metrics,
+}
+
+impl KeyValue<'_> {
+    /// Decode a filter stored whole at `key`, migrating it to the current
+    /// format if it is in an older one
+    pub fn decode(&self, key: &str, state: Vec<u8>) -> Result<BloomFilter> {
+        let outdated = BloomFilter::is_outdated(&state);
+        let filter = BloomFilter::deserialize(state)?;
+        if outdated {
+            self.save(key, &filter)?;
+            eprintln!("migrated the filter at {key} to the current state format");
+            self.metrics.count(Counter::StateMigrated);
+        }
+        Ok(filter)
+    }
+
+    /// Store a filter at `key`, in shards if they are configured or it
+    /// outgrew a single value, over the state `stored` there. Returns what
+    /// was written at `key` itself.
+    ///
+    /// Stores refuse values too large for them with the same I/O error as
+    /// anything else, so a large filter failing to be written whole is taken
+    /// to have outgrown the store and is tried again in shards.
+    fn write(&self, key: &str, filter: &BloomFilter, stored: Option<&[u8]>) -> Result<Vec<u8>> {
+        if let Some(shard_bits) = self.shard_bits {
+            return shards::save(self.store, key, filter, shard_bits, stored);
+        }
+        let bytes = filter.serialize();
+        if bytes.len() > self.max_value / 4 * 3 {
+            return self.outgrown(key, filter, stored, self.max_value);
+        }
+        match self.store.set(key, &bytes) {
+            Ok(()) => {}
+            Err(e) if bytes.len() >= MIN_OUTGROWN => {
+                eprintln!("can't store the filter at {key} whole, trying shards: {e:#}");
+                return self.outgrown(key, filter, stored, bytes.len() / 2);
+            }
+            Err(e) => return Err(e),
+        }
+        shards::forget(self.store, key, stored)?;
+        Ok(bytes)
+    }
+
+    /// Store a filter that outgrew a single value of `limit` bytes in shards
+    /// of at most half that
+    fn outgrown(
+        &self,
+        key: &str,
+        filter: &BloomFilter,
+        stored: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<u8>> {
+        let shard_bits = shards::bits_within(filter.params(), limit / 2);
+        let bytes = shards::save(self.store, key, filter, shard_bits, stored)?;
+        if !stored.is_some_and(shards::is_manifest) {
+            eprintln!("the filter at {key} outgrew a single value, stored it in shards");
+            self.metrics.count(Counter::Resharded);
+        }
+        Ok(bytes)
+    }
+}
+
+impl FilterStore for KeyValue<'_> {
+    fn state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get(key)
+    }
+
+    fn load(&self, key: &str) -> Result<Option<BloomFilter>> {
+        let Some(state) = self.store.get(key)? else {
+            return Ok(None);
+        };
+        if shards::is_manifest(&state) {
+            return Ok(Some(shards::load(
+                self.store,
+                key,
+                &Manifest::decode(&state)?,
+            )?));
+        }
+        Ok(Some(self.decode(key, state)?))
+    }
+
+    fn save(&self, key: &str, filter: &BloomFilter) -> Result<()> {
+        let stored = self.store.get(key)?;
+        self.write(key, filter, stored.as_deref())?;
+        Ok(())
+    }
+
+    /// The store has no compare-and-swap, so the slice is read back to tell
+    /// whether another writer's came in after it
+    fn merge(&self, key: &str, filter: &mut BloomFilter) -> Result<bool> {
+        let stored = self.store.get(key)?;
+        if stored.as_deref().map_or(0, BloomFilter::stored_generation) != filter.generation {
+            return Ok(false);
+        }
+        filter.generation += 1;
+        let bytes = match self.write(key, filter, stored.as_deref()) {
+            Ok(bytes) => bytes,
+            // Another writer's shards came in between ours
+            Err(e) if e.is::<Interleaved>() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        Ok(self.store.get(key)?.as_deref() == Some(&bytes[..]))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        let Some(stored) = self.store.get(key)? else {
+            return Ok(false);
+        };
+        self.store.delete(key)?;
+        shards::forget(self.store, key, Some(&stored))?;
+        Ok(true)
+    }
+}
+
+/// Whether stored state is the header of a slice stored as a Redis bitmap
+pub(crate) fn is_header(bytes: &[u8]) -> bool {
+    matches!(bytes.strip_prefix(MAGIC), Some([BITMAP_VERSION, ..]))
+}
+
+/// The header of a slice stored as a Redis bitmap
+///
+/// It is stored as `MAGIC`, `BITMAP_VERSION`, the generation and the number
+/// of inserted elements as big-endian `u64`s, the number of bits as a
+/// big-endian `u32`, the number of hash functions and the id of the slice's
+/// hashing as bytes, its seed as a big-endian `u64` and the checksum of
+/// everything before it as a big-endian `u32`.
+#[derive(PartialEq, Debug)]
+pub(crate) struct Header {
+    pub generation: u64,
+    pub num: u64,
+    pub params: Params,
+}
+
+impl Header {
+    fn of(filter: &BloomFilter) -> Self {
+        Self {
+            generation: filter.generation,
+            num: filter.num as u64,
+            params: filter.params(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::parse(bytes).map_err(|e| CorruptState(format!("{e:#}")).into())
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if !is_header(bytes) || bytes.len() != HEADER_LEN {
+            anyhow::bail!("corrupted state: not a bitmap header");
+        }
+        let (checked, stored) = bytes.split_at(bytes.len() - 4);
+        if stored != checksum(checked).to_be_bytes() {
+            anyhow::bail!("corrupted state: checksum mismatch");
+        }
+        let rest = &checked[MAGIC.len() + 1..];
+        let u64_at = |at: usize| u64::from_be_bytes(rest[at..at + 8].try_into().unwrap());
+        let num_bits = u32::from_be_bytes(rest[16..20].try_into().unwrap());
+        let hashing = Hashing::from_id(rest[21]).context("corrupted state: unknown hashing")?;
+        Ok(Self {
+            generation: u64_at(0),
+            num: u64_at(8),
+            params: Params::new(num_bits as usize, rest[20].into(), false)?
+                .hashed_with(hashing)
+                .seeded(u64_at(22)),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend(MAGIC);
+        bytes.push(BITMAP_VERSION);
+        bytes.extend(self.generation.to_be_bytes());
+        bytes.extend(self.num.to_be_bytes());
+        bytes.extend((self.params.num_bits as u32).to_be_bytes());
+        bytes.push(self.params.num_hashes as u8);
+        bytes.push(self.params.hashing.id());
+        bytes.extend(self.params.seed.to_be_bytes());
+        bytes.extend(checksum(&bytes).to_be_bytes());
+        bytes
+    }
+}
+
+/// A filter's bits as a Redis bitmap, in which bit `i` is the `i % 8`-th
+/// most significant bit of byte `i / 8`
+fn bitmap(filter: &BloomFilter) -> Vec<u8> {
+    let words = filter.array.as_raw_slice();
+    (0..filter.array.len().div_ceil(8))
+        .map(|i| ((words[i / 4] >> (8 * (i % 4))) as u8).reverse_bits())
+        .collect()
+}
+
+/// Put a slice back together from its header and bitmap
+///
+/// Redis leaves out the zero bytes at the end of a bitmap that was never
+/// written that far, so a short bitmap is padded with them.
+fn assemble(header: &Header, bitmap: &[u8]) -> Result<BloomFilter> {
+    let params = header.params;
+    if bitmap.len() > params.num_bits.div_ceil(8) {
+        anyhow::bail!(CorruptState("the bitmap is longer than its slice".into()));
+    }
+    let mut words = vec![0u32; params.num_bits.div_ceil(32)];
+    for (i, byte) in bitmap.iter().enumerate() {
+        words[i / 4] |= u32::from(byte.reverse_bits()) << (8 * (i % 4));
+    }
+    let mut array = BitVec::from_vec(words);
+    array.truncate(params.num_bits);
+    Ok(BloomFilter {
+        array,
+        num_hashes: params.num_hashes,
+        hashing: params.hashing,
+        seed: params.seed,
+        counters: None,
+        generation: header.generation,
+        num: usize::try_from(header.num).unwrap_or(usize::MAX),
+    })
+}
+
+/// The key the bitmap of the slice at `key` is stored under, without the
+/// prefix
+pub(crate) fn bits_key(key: &str) -> String {
+    format!("{key}/bits")
+}
+
+/// Redis errors end the request rather than fail over, no other store holds
+/// the bitmaps
+#[cfg(feature = "redis-backend")]
+fn redis_error(e: redis::Error) -> anyhow::Error {
+    anyhow::anyhow!("redis: {e:?}")
+}
+
+/// Slices kept as bitmaps on a Redis server
+#[cfg(feature = "redis-backend")]
+#[derive(Clone)]
+pub(crate) struct Redis {
+    address: String,
+    /// What keys are prefixed with, as in the key-value stores
+    prefix: String,
+}
+
+#[cfg(feature = "redis-backend")]
+impl Redis {
+    /// The server at `address`, with keys confined to the namespace `store`
+    /// is
+    pub fn new(address: &str, store: &Kv) -> Self {
+        Self {
+            address: address.to_owned(),
+            prefix: store.full_key(""),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value = redis::get(&self.address, &self.key(key)).map_err(redis_error)?;
+        Ok(Some(value).filter(|value| !value.is_empty()))
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        redis::set(&self.address, &self.key(key), value).map_err(redis_error)
+    }
+
+    fn execute(&self, command: &str, arguments: &[RedisParameter]) -> Result<Vec<RedisResult>> {
+        redis::execute(&self.address, command, arguments).map_err(redis_error)
+    }
+
+    /// Whether every bit at `indices` is set in the bitmap of the slice at
+    /// `key`
+    pub fn all_set(&self, key: &str, indices: impl Iterator<Item = usize>) -> Result<bool> {
+        let bits = self.key(&bits_key(key));
+        let mut arguments = vec![RedisParameter::Binary(bits.as_bytes())];
+        for index in indices {
+            arguments.push(RedisParameter::Binary(b"GET"));
+            arguments.push(RedisParameter::Binary(b"u1"));
+            arguments.push(RedisParameter::Int64(index as i64));
+        }
+        let mut all = true;
+        for value in self.execute("BITFIELD", &arguments)? {
+            match value {
+                RedisResult::Int64(bit) => all &= bit == 1,
+                _ => anyhow::bail!("redis: BITFIELD answered with something other than bits"),
+            }
+        }
+        Ok(all)
+    }
+
+    /// Fold `filter`'s bits into the bitmap at `key`
+    ///
+    /// They are written to a key of their own first, which is then ORed into
+    /// the bitmap, as Redis has no command setting many bits at once.
+    fn fold_in(&self, key: &str, filter: &BloomFilter) -> Result<()> {
+        let mut nonce = [0u8; 8];
+        getrandom::getrandom(&mut nonce).context("no randomness for the bits being folded in")?;
+        let bits = self.key(&bits_key(key));
+        let incoming = format!("{bits}/incoming/{:016x}", u64::from_be_bytes(nonce));
+        self.execute(
+            "SET",
+            &[
+                RedisParameter::Binary(incoming.as_bytes()),
+                RedisParameter::Binary(&bitmap(filter)),
+                RedisParameter::Binary(b"PX"),
+                RedisParameter::Int64(INCOMING_TTL.as_millis() as i64),
+            ],
+        )?;
+        let merged = self.execute(
+            "BITOP",
+            &[
+                RedisParameter::Binary(b"OR"),
+                RedisParameter::Binary(bits.as_bytes()),
+                RedisParameter::Binary(bits.as_bytes()),
+                RedisParameter::Binary(incoming.as_bytes()),
+            ],
+        );
+        redis::del(&self.address, &[incoming.as_str()]).map_err(redis_error)?;
+        merged.map(|_| ())
+    }
+}
+
+#[cfg(feature = "redis-backend")]
+impl FilterStore for Redis {
+    fn state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key)
+    }
+
+    fn load(&self, key: &str) -> Result<Option<BloomFilter>> {
+        let Some(state) = self.get(key)? else {
+            return Ok(None);
+        };
+        let header = Header::decode(&state)?;
+        let bitmap = self.get(&bits_key(key))?.unwrap_or_default();
+        Ok(Some(assemble(&header, &bitmap)?))
+    }
+
+    /// The bitmap is written before the header, so that checks never find a
+    /// header with the bits of an older slice
+    fn save(&self, key: &str, filter: &BloomFilter) -> Result<()> {
+        if filter.counters.is_some() {
+            anyhow::bail!("counting filters can't be stored in Redis");
+        }
+        self.set(&bits_key(key), &bitmap(filter))?;
+        self.set(key, &Header::of(filter).encode())
+    }
+
+    /// Bits are ORed in, so they make it into the bitmap even when the
+    /// header then turns out to have been written by someone else
+    fn merge(&self, key: &str, filter: &mut BloomFilter) -> Result<bool> {
+        if filter.counters.is_some() {
+            anyhow::bail!("counting filters can't be stored in Redis");
+        }
+        let stored = self.get(key)?;
+        if stored.as_deref().map_or(0, BloomFilter::stored_generation) != filter.generation {
+            return Ok(false);
+        }
+        filter.generation += 1;
+        self.fold_in(key, filter)?;
+        let header = Header::of(filter).encode();
+        self.set(key, &header)?;
+        Ok(self.get(key)?.as_deref() == Some(&header[..]))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        let (key, bits) = (self.key(key), self.key(&bits_key(key)));
+        let deleted = redis::del(&self.address, &[key.as_str(), bits.as_str()]);
+        Ok(deleted.map_err(redis_error)? > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmaps_roundtrip() {
+        let params = Params::new(1000, 3, false).unwrap();
+        let mut filter = BloomFilter::new(params.hashed_with(Hashing::XxHash64).seeded(7));
+        for i in 0..50 {
+            filter.insert(format!("user{i}@example.com"));
+        }
+        filter.generation = 3;
+        let header = Header::of(&filter);
+        assert!(is_header(&header.encode()));
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+        assert_eq!(BloomFilter::stored_generation(&header.encode()), 3);
+        assert!(!is_header(&filter.serialize()));
+
+        let bitmap = bitmap(&filter);
+        assert_eq!(bitmap.len(), 125);
+        let decoded = assemble(&header, &bitmap).unwrap();
+        assert_eq!(decoded.array, filter.array);
+        assert_eq!((decoded.generation, decoded.num), (3, 50));
+        for i in 0..1000 {
+            assert_eq!(bitmap[i / 8] >> (7 - i % 8) & 1 == 1, filter.array[i]);
+        }
+
+        // Redis drops trailing zero bytes of bitmaps never written that far
+        let mut one = BloomFilter::new(Params::new(64, 1, false).unwrap());
+        one.array.set(3, true);
+        let short = assemble(&Header::of(&one), &[0b0001_0000]).unwrap();
+        assert_eq!(short.array, one.array);
+        assert!(assemble(&Header::of(&one), &[0; 9]).is_err());
+
+        let mut damaged = header.encode();
+        damaged[12] ^= 1;
+        assert!(Header::decode(&damaged).err().unwrap().is::<CorruptState>());
+    }
+}
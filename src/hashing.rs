@@ -0,0 +1,232 @@
+//! The hash functions an element's probe positions are derived from
+//!
+//! Every probe position comes from two hashes of the element (see
+//! `ProbeSet::indices`). Filters were first stored with Murmur3 and FNV-1a,
+//! two 32-bit hashes computed one after the other, which every filter not
+//! told otherwise still uses. xxHash64 and SipHash-1-3 hash the element once
+//! into 64 bits instead, taking the hash and the hash with its halves swapped
+//! as the two, which is cheaper and spreads better over filters of billions
+//! of bits. Each filter records the hashing it was created with, so changing
+//! `bloom_hashing` only affects filters created afterwards, such as rebuilt
+//! ones or new slices.
+//!
+//! Anyone knowing the hash functions can craft emails that saturate a filter
+//! or collide with a victim's. Filters created with a seed, from
+//! `bloom_hash_seed`, hash it as a big-endian `u64` before every element, so
+//! that positions differ between deployments while staying the same for the
+//! filter's lifetime. Seed 0 stands for no seed. A `random` seed is drawn from
+//! the host's secure randomness for each new filter, so a rebuilt filter gets
+//! a new one and filters of different instances can't be merged; only a
+//! secret seed carries over to rebuilds and is shared by the instances that
+//! merge each other's filters.
+
+use core::hash::Hasher;
+use hash32::Hasher as _;
+use sha2::{Digest, Sha256};
+
+use crate::filter::Params;
+
+/// Hashes elements for probing
+pub(crate) trait FilterHasher {
+    /// The two hashes of an element in a filter with `seed`
+    fn hashes(&self, seed: u64, element: &dyn Element) -> [u64; 2];
+}
+
+/// Something hashable without knowing its type
+pub(crate) trait Element {
+    fn feed(&self, hasher: &mut dyn Hasher);
+}
+
+impl<T: core::hash::Hash + ?Sized> Element for T {
+    fn feed(&self, mut hasher: &mut dyn Hasher) {
+        self.hash(&mut hasher)
+    }
+}
+
+/// The hash functions a filter probes with
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, serde::Serialize)]
+pub(crate) enum Hashing {
+    /// Murmur3 32-bit with seed 0 and FNV-1a 32-bit
+    #[default]
+    #[serde(rename = "murmur3-fnv")]
+    Murmur3Fnv,
+    /// xxHash64 with seed 0
+    #[serde(rename = "xxhash64")]
+    XxHash64,
+    /// SipHash-1-3 with both keys 0
+    #[serde(rename = "siphash13")]
+    SipHash13,
+}
+
+impl Hashing {
+    /// Every hashing, indexed by their `id`
+    pub const ALL: [Hashing; 3] = [Hashing::Murmur3Fnv, Hashing::XxHash64, Hashing::SipHash13];
+
+    /// The byte identifying the hashing in stored state
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(id)).copied()
+    }
+}
+
+impl FilterHasher for Hashing {
+    fn hashes(&self, seed: u64, element: &dyn Element) -> [u64; 2] {
+        let feed = |hasher: &mut dyn Hasher| {
+            if seed != 0 {
+                hasher.write(&seed.to_be_bytes());
+            }
+            element.feed(hasher);
+        };
+        match self {
+            Hashing::Murmur3Fnv => {
+                let mut murmur = hash32::Murmur3Hasher::default();
+                feed(&mut murmur);
+                let mut fnv = hash32::FnvHasher::default();
+                feed(&mut fnv);
+                [murmur.finish32().into(), fnv.finish32().into()]
+            }
+            Hashing::XxHash64 => {
+                let mut hasher = twox_hash::XxHash64::with_seed(0);
+                feed(&mut hasher);
+                split(hasher.finish())
+            }
+            Hashing::SipHash13 => {
+                let mut hasher = siphasher::sip::SipHasher13::new();
+                feed(&mut hasher);
+                split(hasher.finish())
+            }
+        }
+    }
+}
+
+/// The seed new filters are created with
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum Seed {
+    /// No seed, as filters were before seeds
+    #[default]
+    None,
+    /// A fresh seed for every filter
+    Random,
+    /// The seed made from a configured secret
+    Fixed(u64),
+}
+
+impl Seed {
+    /// The seed all new filters share, 0 when there is none or when each
+    /// draws its own
+    pub fn shared(self) -> u64 {
+        match self {
+            Seed::Fixed(seed) => seed,
+            Seed::None | Seed::Random => 0,
+        }
+    }
+
+    /// The shape `params` of a filter created now, with a seed drawn for it
+    /// if seeds are random
+    ///
+    /// Only called when a filter is created, as the filter keeps its seed in
+    /// its header from then on.
+    pub fn fresh(self, params: Params) -> anyhow::Result<Params> {
+        if self != Seed::Random {
+            return Ok(params);
+        }
+        // Not from the clock, which an attacker guessing when the filter was
+        // created could search through
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|e| anyhow::anyhow!("no randomness for a seed: {e}"))?;
+        Ok(params.seeded(nonzero(&bytes)))
+    }
+}
+
+/// The first eight bytes of `bytes` as a seed, which is never 0
+fn nonzero(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap()).max(1)
+}
+
+impl std::str::FromStr for Seed {
+    type Err = anyhow::Error;
+
+    /// Empty for no seed, `random`, or a secret of at least 16 characters
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim() {
+            "" => Ok(Seed::None),
+            "random" => Ok(Seed::Random),
+            secret if secret.len() >= 16 => {
+                Ok(Seed::Fixed(nonzero(&Sha256::digest(secret.as_bytes()))))
+            }
+            _ => anyhow::bail!("expected `random` or a secret of at least 16 characters"),
+        }
+    }
+}
+
+/// The two hashes double hashing derives positions from, out of one 64-bit
+/// hash
+fn split(hash: u64) -> [u64; 2] {
+    [hash, hash.rotate_left(32)]
+}
+
+impl std::str::FromStr for Hashing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "murmur3-fnv" => Ok(Hashing::Murmur3Fnv),
+            "xxhash64" => Ok(Hashing::XxHash64),
+            "siphash13" => Ok(Hashing::SipHash13),
+            _ => anyhow::bail!("expected `murmur3-fnv`, `xxhash64` or `siphash13`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_the_functions_named() {
+        let email = "me@example.com".to_owned();
+        assert_eq!(
+            Hashing::Murmur3Fnv.hashes(0, &email),
+            [crate::murmur3(&email) as u64, crate::fnv(&email) as u64]
+        );
+        let mut xx = twox_hash::XxHash64::with_seed(0);
+        xx.write(email.as_bytes());
+        xx.write_u8(0xff);
+        assert_eq!(Hashing::XxHash64.hashes(0, &email), split(xx.finish()));
+        assert_ne!(
+            Hashing::SipHash13.hashes(0, &email),
+            Hashing::XxHash64.hashes(0, &email)
+        );
+        let mut seeded = twox_hash::XxHash64::with_seed(0);
+        seeded.write(&7u64.to_be_bytes());
+        seeded.write(email.as_bytes());
+        seeded.write_u8(0xff);
+        assert_eq!(Hashing::XxHash64.hashes(7, &email), split(seeded.finish()));
+        for hashing in Hashing::ALL {
+            assert_ne!(hashing.hashes(7, &email), hashing.hashes(0, &email));
+        }
+        for hashing in Hashing::ALL {
+            assert_eq!(Hashing::from_id(hashing.id()), Some(hashing));
+        }
+        assert_eq!(Hashing::from_id(3), None);
+        assert_eq!("xxhash64".parse::<Hashing>().unwrap(), Hashing::XxHash64);
+    }
+
+    #[test]
+    fn seeds_are_picked_as_configured() {
+        let seed = |seed: Seed| seed.fresh(Params::LEGACY).unwrap().seed;
+        assert_eq!(seed("".parse().unwrap()), 0);
+        let fixed: Seed = "correct horse battery staple".parse().unwrap();
+        assert_ne!(fixed.shared(), 0);
+        assert_eq!(seed(fixed), 0, "a shared seed is already in the shape");
+        let random: Seed = "random".parse().unwrap();
+        assert_eq!(random.shared(), 0);
+        assert_ne!(seed(random), 0);
+        assert_ne!(seed(random), seed(random));
+        assert!("short".parse::<Seed>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Filter health, for alerting before the filter stops ruling emails out
+//!
+//! As a filter fills up its false positive rate climbs, until nearly every
+//! check falls through to the database and the filter saves nothing.
+//! `GET /stats` reports how full each slice is, how many emails it holds and
+//! the false positive rate that gives, along with the store traffic the
+//! `store` metrics sink has counted and how the quarantine is doing. It also
+//! says how each slice is stored, as a slice stored whole is split into
+//! shards once it outgrows a single value, and when a rotating filter
+//! rotates next.
+//!
+//! Sharded slices are reported from their manifest and the popcounts
+//! written with it, so that even very large filters are cheap to report on.
+//! Only slices stored otherwise, or whose popcounts are missing or stale, are
+//! loaded in full.
+//!
+//! It also reports how much of the store is used, see [`crate::storage`].
+
+use spin_sdk::http::{Request, Response};
+
+use crate::{
+    app::App,
+    encoding::Encoding,
+    error::Error,
+    filter::estimated_items,
+    filter_store,
+    metrics::{self, StoreTotals},
+    quarantine,
+    rotation::Timing,
+    shards::{self, Manifest},
+    storage::Usage,
+    BloomFilter, Params,
+};
+
+#[derive(serde::Serialize)]
+struct Health {
+    /// The chance that a check of an email never inserted answers `Maybe`,
+    /// across all slices
+    false_positive_rate: f64,
+    /// The fraction of bits set, across all slices
+    saturation: f64,
+    slices: Vec<Stored>,
+    /// What the store was asked to read and write, `None` unless the metrics
+    /// are kept in the store
+    store: Option<StoreTotals>,
+    /// `None` until emails are quarantined
+    quarantine: Option<Quarantine>,
+    /// When the current window of a rotating filter started and when the
+    /// next one starts
+    rotation: Option<Timing>,
+    /// How much of the store the component's keys take up
+    storage: Usage,
+}
+
+#[derive(serde::Serialize)]
+struct Stored {
+    #[serde(flatten)]
+    filter: Slice,
+    layout: Layout,
+    /// How many bits each shard holds, `None` unless stored in shards
+    shard_bits: Option<usize>,
+}
+
+/// How a slice is stored
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Layout {
+    /// Not yet, as nothing was inserted into it
+    Unstored,
+    Whole,
+    Sharded,
+    /// As a Redis bitmap
+    Bitmap,
+}
+
+impl Layout {
+    /// The layout of `state` and the bits per shard of a sharded one
+    fn of(state: Option<&[u8]>) -> (Self, Option<usize>) {
+        match state {
+            None => (Layout::Unstored, None),
+            Some(state) if shards::is_manifest(state) => (
+                Layout::Sharded,
+                Manifest::decode(state).ok().map(|m| m.shard_bits()),
+            ),
+            Some(state) if filter_store::is_header(state) => (Layout::Bitmap, None),
+            Some(_) => (Layout::Whole, None),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Quarantine {
+    #[serde(flatten)]
+    filter: Slice,
+    /// How many registrations were refused as quarantined
+    hits: u64,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct Slice {
+    num_bits: usize,
+    /// How many bits are set
+    #[serde(skip)]
+    set_bits: usize,
+    num_hashes: usize,
+    counting: bool,
+    /// The fraction of bits set
+    saturation: f64,
+    /// How many emails were inserted
+    inserted: usize,
+    /// How many distinct emails the set bits suggest
+    estimated_items: f64,
+    false_positive_rate: f64,
+}
+
+impl Slice {
+    fn of(filter: &BloomFilter) -> Self {
+        Self::counted(filter.params(), filter.array.count_ones(), filter.num)
+    }
+
+    /// The slice of shape `params` with `set_bits` set after `inserted`
+    /// insertions
+    fn counted(params: Params, set_bits: usize, inserted: usize) -> Self {
+        let saturation = set_bits as f64 / params.num_bits as f64;
+        Self {
+            num_bits: params.num_bits,
+            set_bits,
+            num_hashes: params.num_hashes,
+            counting: params.counting,
+            saturation,
+            inserted,
+            estimated_items: estimated_items(set_bits, params),
+            false_positive_rate: saturation.powi(params.num_hashes as i32),
+        }
+    }
+}
+
+impl App {
+    /// Report how full the filter is and how often it is wrong
+    pub fn health(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let stored = self.stored_slices()?;
+        let count = match self.config.rotation {
+            Some(rotation) => rotation.windows,
+            // A filter nothing was inserted into yet has an unstored slice
+            None => stored.len().max(1),
+        };
+        let slices = (0..count)
+            .map(|i| {
+                let key = self.slice_key(i);
+                let state = stored.iter().find(|(k, _)| *k == key).map(|(_, s)| &s[..]);
+                Ok((self.slice_health(&key, state)?, Layout::of(state)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let (filters, layouts): (Vec<_>, Vec<_>) = slices.into_iter().unzip();
+        let false_positive_rate = combined(&filters);
+        let saturation = overall_saturation(&filters);
+        let slices = filters
+            .into_iter()
+            .zip(layouts)
+            .map(|(filter, (layout, shard_bits))| Stored {
+                filter,
+                layout,
+                shard_bits,
+            })
+            .collect();
+        let store = match self.config.metrics {
+            #[cfg(feature = "metrics")]
+            metrics::Sink::Store => Some(metrics::store_totals(&self.store)?),
+            metrics::Sink::None => None,
+        };
+        let quarantine = match self.load_slice(quarantine::QUARANTINE_KEY)? {
+            Some(filter) => Some(Quarantine {
+                filter: Slice::of(&filter),
+                hits: quarantine::hits(&self.store)?,
+            }),
+            None => None,
+        };
+        Ok(Encoding::accepted(&req).response(&Health {
+            false_positive_rate,
+            saturation,
+            slices,
+            store,
+            quarantine,
+            rotation: self.config.rotation.map(|rotation| rotation.timing()),
+            storage: self.storage_usage()?,
+        })?)
+    }
+}
+
+impl App {
+    /// How full the slice stored at `key` as `state` is, from its popcounts
+    /// if it is sharded and they are up to date
+    fn slice_health(&self, key: &str, state: Option<&[u8]>) -> Result<Slice, Error> {
+        if let Some(state) = state.filter(|state| shards::is_manifest(state)) {
+            let manifest = Manifest::decode(state)?;
+            if let Some(set_bits) = shards::set_bits(&self.store, key, &manifest, state)? {
+                let inserted = usize::try_from(manifest.inserted()).unwrap_or(usize::MAX);
+                return Ok(Slice::counted(manifest.params(), set_bits, inserted));
+            }
+        }
+        Ok(Slice::of(&self.load_filter_at(key)?))
+    }
+}
+
+/// The fraction of bits set across all slices
+fn overall_saturation(slices: &[Slice]) -> f64 {
+    let bits: usize = slices.iter().map(|s| s.num_bits).sum();
+    let set: usize = slices.iter().map(|s| s.set_bits).sum();
+    if bits == 0 {
+        return 0.0;
+    }
+    set as f64 / bits as f64
+}
+
+/// The chance that at least one slice is wrong
+fn combined(slices: &[Slice]) -> f64 {
+    1.0 - slices
+        .iter()
+        .map(|s| 1.0 - s.false_positive_rate)
+        .product::<f64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProbeSet;
+
+    #[test]
+    fn reports_fill() {
+        let mut filter = BloomFilter::new(Params::LEGACY);
+        let empty = Slice::of(&filter);
+        assert_eq!((empty.saturation, empty.false_positive_rate), (0.0, 0.0));
+
+        for i in 0..10 {
+            filter.insert_probes(&ProbeSet::new(&format!("user{i}@example.com")));
+        }
+        let slice = Slice::of(&filter);
+        assert_eq!(slice.inserted, 10);
+        assert!(slice.saturation > 0.0 && slice.saturation <= 20.0 / 128.0);
+        assert!((5.0..15.0).contains(&slice.estimated_items));
+        assert_eq!(slice.false_positive_rate, slice.saturation.powi(2));
+
+        let full = Slice::of(&BloomFilter::saturated(Params::LEGACY));
+        assert_eq!(overall_saturation(&[]), 0.0);
+        assert_eq!(
+            overall_saturation(&[empty, Slice::counted(Params::LEGACY, 64, 40)]),
+            0.25
+        );
+        assert_eq!(combined(&[slice, full]), 1.0);
+        assert_eq!(combined(&[]), 0.0);
+
+        assert_eq!(Layout::of(None), (Layout::Unstored, None));
+        assert_eq!(Layout::of(Some(&filter.serialize())), (Layout::Whole, None));
+    }
+}
@@ -0,0 +1,306 @@
+//! Resumable imports, uploaded in chunks
+//!
+//! `POST /bulk` takes its emails in a single body, and an upload cut short
+//! has to start over. An import instead opens a session with
+//! `POST /admin/import`, uploads the listing in numbered chunks with
+//! `PUT /admin/import?session=<id>&sequence=<n>&sha256=<hex>` and inserts it
+//! with `POST /admin/import/commit?session=<id>`. Each chunk is checked
+//! against its SHA-256 and stored under `__import:<id>/<n>` before it is
+//! acknowledged, so an interrupted upload asks `GET /admin/import` for the
+//! next sequence number and carries on from there. A chunk uploaded again
+//! with the checksum it was acknowledged with is acknowledged again, so
+//! retrying a request whose answer was lost does no harm.
+//!
+//! Committing joins the chunks back into a listing like those `POST /bulk`
+//! takes, JSON or one email per line as the session was opened with, and
+//! inserts its emails the same way. Sessions expire a day after they were
+//! opened; expired ones are deleted when the next session is opened.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use spin_sdk::http::{Request, Response};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{app::App, bulk::Listing, error::Error, filter::CorruptState, kv::Kv};
+
+/// The media type of chunks
+pub(crate) const CHUNK: &str = "application/octet-stream";
+const PREFIX: &str = "__import:";
+/// How long a session may be uploaded to
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The most emails one import may insert
+const MAX_EMAILS: usize = 10_000_000;
+
+/// An import being uploaded
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Session {
+    format: Format,
+    /// When it was opened, in Unix seconds
+    opened_at: u64,
+    /// The SHA-256 of each acknowledged chunk, in hex
+    chunks: Vec<String>,
+    bytes: u64,
+}
+
+/// How an import lists its emails
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    /// A JSON array
+    #[default]
+    Json,
+    /// One email per line
+    Lines,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn session_key(id: &str) -> String {
+    format!("{PREFIX}{id}")
+}
+
+fn chunk_key(id: &str, sequence: usize) -> String {
+    format!("{PREFIX}{id}/{sequence}")
+}
+
+impl Session {
+    fn load(store: &Kv, id: &str) -> Result<Option<Self>> {
+        // IDs are hex, anything else never named a session
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        match store.get(&session_key(id))? {
+            Some(json) => Ok(Some(serde_json::from_slice(&json).map_err(|e| {
+                CorruptState(format!("the import session {id} can't be decoded: {e}"))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, store: &Kv, id: &str) -> Result<()> {
+        store.set(&session_key(id), &serde_json::to_vec(self)?)
+    }
+
+    /// Delete the session with its chunks
+    fn delete(&self, store: &Kv, id: &str) -> Result<()> {
+        for sequence in 0..self.chunks.len() {
+            store.delete(&chunk_key(id, sequence))?;
+        }
+        store.delete(&session_key(id))
+    }
+
+    fn expires_at(&self) -> u64 {
+        self.opened_at + SESSION_TTL.as_secs()
+    }
+
+    fn state<'a>(&self, id: &'a str) -> Result<State<'a>> {
+        Ok(State {
+            session: id,
+            format: self.format,
+            next_sequence: self.chunks.len(),
+            bytes: self.bytes,
+            expires_in_secs: self.expires_at().saturating_sub(now()?),
+        })
+    }
+}
+
+/// Delete the sessions that expired
+fn expire(store: &Kv) -> Result<()> {
+    let now = now()?;
+    for key in store.keys()? {
+        let Some(id) = key.strip_prefix(PREFIX).filter(|id| !id.contains('/')) else {
+            continue;
+        };
+        match Session::load(store, id) {
+            Ok(Some(session)) if session.expires_at() <= now => {
+                session.delete(store, id)?;
+                eprintln!("deleted the expired import session {id}");
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("can't read the import session {id}: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+impl App {
+    /// The session the `session` query parameter names, unless it expired
+    fn import_session(&self, id: &str) -> Result<Session, Error> {
+        match Session::load(&self.store, id)? {
+            Some(session) if session.expires_at() > now()? => Ok(session),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Open an import session for a listing in the `format` query parameter
+    pub fn open_import(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: OpenQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        expire(&self.store)?;
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id).context("no randomness for the session ID")?;
+        let id = hex(&id);
+        let session = Session {
+            format: query.format,
+            opened_at: now()?,
+            chunks: Vec::new(),
+            bytes: 0,
+        };
+        session.save(&self.store, &id)?;
+        Ok(crate::json_response(&session.state(&id)?)?)
+    }
+
+    /// Report how far the upload of an import got
+    pub fn import_state(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: SessionQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let session = self.import_session(&query.session)?;
+        Ok(crate::json_response(&session.state(&query.session)?)?)
+    }
+
+    /// Store the chunk numbered `sequence` of an import
+    ///
+    /// Chunks are taken in order. One already acknowledged may be sent
+    /// again, and is acknowledged again without being stored again.
+    pub fn upload_import_chunk(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: ChunkQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let id = &query.session;
+        let mut session = self.import_session(id)?;
+        let chunk = req.body().as_deref().unwrap_or_default();
+        let digest = hex(&Sha256::digest(chunk));
+        if !digest.eq_ignore_ascii_case(query.sha256.trim()) {
+            return Err(Error::bad_request(
+                "the chunk doesn't match its `sha256`, it was damaged on the way",
+            ));
+        }
+        let next = session.chunks.len();
+        if query.sequence < next {
+            if session.chunks[query.sequence] != digest {
+                return Err(Error::Conflict(format!(
+                    "chunk {} was acknowledged with another checksum",
+                    query.sequence
+                )));
+            }
+            return Ok(crate::json_response(&session.state(id)?)?);
+        }
+        if query.sequence > next {
+            return Err(Error::Conflict(format!("chunk {next} is expected next")));
+        }
+        if chunk.is_empty() {
+            return Err(Error::bad_request("chunks must not be empty"));
+        }
+        let max_chunk = self.config.max_value_size.min(self.config.bulk_max_body);
+        if chunk.len() > max_chunk {
+            return Err(Error::PayloadTooLarge(format!(
+                "chunks may be at most {max_chunk} bytes"
+            )));
+        }
+        let bytes = session.bytes + chunk.len() as u64;
+        if bytes > self.config.import_max_size as u64 {
+            return Err(Error::PayloadTooLarge(format!(
+                "imports may be at most {} bytes",
+                self.config.import_max_size
+            )));
+        }
+        self.ensure_storage()?;
+        self.store.set(&chunk_key(id, next), chunk)?;
+        session.chunks.push(digest);
+        session.bytes = bytes;
+        session.save(&self.store, id)?;
+        Ok(crate::json_response(&session.state(id)?)?)
+    }
+
+    /// Insert the emails an import lists, then delete it
+    pub fn commit_import(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        self.ensure_storage()?;
+        let query: SessionQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let id = &query.session;
+        let session = self.import_session(id)?;
+        let mut body = Vec::with_capacity(session.bytes as usize);
+        for (sequence, digest) in session.chunks.iter().enumerate() {
+            let chunk = self.store.get(&chunk_key(id, sequence))?;
+            let chunk = chunk
+                .filter(|chunk| hex(&Sha256::digest(chunk)) == *digest)
+                .ok_or_else(|| {
+                    CorruptState(format!(
+                        "chunk {sequence} of import {id} is missing or damaged"
+                    ))
+                })
+                .map_err(anyhow::Error::from)?;
+            body.extend_from_slice(&chunk);
+        }
+        let json = session.format == Format::Json;
+        let listing = Listing::new(&body, json, "bulk.request", MAX_EMAILS, &self.config);
+        let report = self.insert_listing(&listing)?;
+        session.delete(&self.store, id)?;
+        Ok(crate::json_response(&report)?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct OpenQuery {
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(serde::Deserialize)]
+struct SessionQuery {
+    session: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ChunkQuery {
+    session: String,
+    sequence: usize,
+    /// The SHA-256 of the chunk, in hex
+    sha256: String,
+}
+
+#[derive(serde::Serialize)]
+struct State<'a> {
+    session: &'a str,
+    format: Format,
+    /// The number of the chunk to upload next
+    next_sequence: usize,
+    /// How many bytes were acknowledged
+    bytes: u64,
+    expires_in_secs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_name_their_chunks() {
+        let query: ChunkQuery = serde_qs::from_str("session=00ff&sequence=3&sha256=AB").unwrap();
+        assert_eq!((query.session.as_str(), query.sequence), ("00ff", 3));
+        assert_eq!(chunk_key(&query.session, query.sequence), "__import:00ff/3");
+        let open: OpenQuery = serde_qs::from_str("").unwrap();
+        assert!(open.format == Format::Json);
+        let open: OpenQuery = serde_qs::from_str("format=lines").unwrap();
+        assert!(open.format == Format::Lines);
+        assert!(serde_qs::from_str::<OpenQuery>("format=csv").is_err());
+        assert_eq!(hex(&Sha256::digest(b"")[..4]), "e3b0c442");
+        let session = Session {
+            format: Format::Lines,
+            opened_at: 0,
+            chunks: vec!["e3b0".into()],
+            bytes: 7,
+        };
+        assert_eq!(session.expires_at(), 86_400);
+        assert_eq!(session.state("00ff").unwrap().next_sequence, 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Named secrets that can be rotated without downtime
+//!
+//! A keyring lists `id:secret` pairs. Signatures name the key they were made
+//! with, so a new key can be added next to the old one, the signer switched
+//! over, and the old key dropped once the usage report shows it is no longer
+//! presented.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{app::App, error::Error, kv::Kv};
+
+const USAGE_KEY: &str = "__key_usage";
+
+/// Accepted keys by ID, in the order they were configured
+#[derive(Default)]
+pub(crate) struct Keyring(Vec<(String, String)>);
+
+impl Keyring {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The first key, which new signatures are made with
+    pub fn signer(&self) -> Option<(&str, &str)> {
+        self.0
+            .first()
+            .map(|(id, secret)| (id.as_str(), secret.as_str()))
+    }
+
+    /// The secret of the key with `id`
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, secret)| secret.as_str())
+    }
+}
+
+impl std::str::FromStr for Keyring {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated `id:secret` pairs
+    fn from_str(s: &str) -> Result<Self> {
+        let mut keys = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((id, secret)) = entry.split_once(':') else {
+                anyhow::bail!("expected `id:secret` pairs");
+            };
+            if id.is_empty() || secret.is_empty() {
+                anyhow::bail!("key IDs and secrets must not be empty");
+            }
+            if keys.iter().any(|(known, _)| known == id) {
+                anyhow::bail!("key ID `{id}` is listed twice");
+            }
+            keys.push((id.to_owned(), secret.to_owned()));
+        }
+        Ok(Self(keys))
+    }
+}
+
+/// When each key ID was last presented, per keyring
+type Usage = BTreeMap<String, BTreeMap<String, u64>>;
+
+fn load_usage(store: &Kv) -> Result<Usage> {
+    Ok(match store.get(USAGE_KEY)? {
+        Some(json) => serde_json::from_slice(&json)?,
+        None => Usage::default(),
+    })
+}
+
+/// Note that the key `id` of the keyring named `ring` was just used
+pub(crate) fn record_use(store: &Kv, ring: &str, id: &str) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut usage = load_usage(store)?;
+    usage
+        .entry(ring.to_owned())
+        .or_default()
+        .insert(id.to_owned(), now);
+    store.set(USAGE_KEY, &serde_json::to_vec(&usage)?)
+}
+
+impl App {
+    /// Report when each key was last used, in Unix seconds
+    pub fn key_usage(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        Ok(crate::json_response(&load_usage(&self.store)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keyrings() {
+        let keys: Keyring = "k2:new-secret, k1:old:secret".parse().unwrap();
+        assert_eq!(keys.get("k1"), Some("old:secret"));
+        assert_eq!(keys.get("k2"), Some("new-secret"));
+        assert_eq!(keys.get("k3"), None);
+        assert_eq!(keys.signer(), Some(("k2", "new-secret")));
+        assert!("secret".parse::<Keyring>().is_err());
+        assert!("k1:a,k1:b".parse::<Keyring>().is_err());
+        assert!("".parse::<Keyring>().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,463 @@
+//! Access to the key-value stores
+//!
+//! Stores are only opened once something actually needs them, and calls that
+//! fail with a transient I/O error are retried with jittered backoff. A store
+//! that still fails is closed, and opened again by the next call to it.
+//!
+//! The `key_value_stores` variable lists the stores to use in priority order.
+//! Reads fail over to the next store when one keeps failing, and writes go to
+//! the first store, the primary. Once the request is handled its writes are
+//! replayed on the other stores, where failures are only logged, so that
+//! those can stand in for the primary during an outage.
+//!
+//! Keys can be confined to a namespace, which then prefixes every key read
+//! or written.
+//!
+//! The store named `redis` is the Redis server at `redis_address` rather
+//! than a Spin key-value store. Redis reads an empty value as a missing key,
+//! which no value stored here is. It holds the same values as the other
+//! stores, filters whole or in shards included; `filter_store` keeps filters
+//! on Redis as bitmaps instead, see [`crate::filter_store`]. It is only
+//! available in builds with the `redis-backend` feature.
+
+use anyhow::Result;
+use spin_sdk::key_value::{self, Store};
+#[cfg(feature = "redis-backend")]
+use spin_sdk::redis;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How often a failing store call is attempted in total
+const ATTEMPTS: u32 = 3;
+/// The delay before the first retry, doubled for each further one
+const BASE_DELAY: Duration = Duration::from_millis(5);
+/// The variable listing the stores, read before any overrides as those are
+/// kept in the stores themselves
+const STORES_VARIABLE: &str = "key_value_stores";
+const DEFAULT_STORE: &str = "default";
+/// The store name standing for the Redis server at `REDIS_VARIABLE`
+const REDIS_STORE: &str = "redis";
+#[cfg(feature = "redis-backend")]
+const REDIS_VARIABLE: &str = "redis_address";
+
+/// One of the stores values are kept in
+enum Backend {
+    KeyValue(Store),
+    /// A Redis server, by address
+    #[cfg(feature = "redis-backend")]
+    Redis(String),
+}
+
+impl Backend {
+    fn open(name: &str) -> Result<Self, key_value::Error> {
+        Ok(match name {
+            DEFAULT_STORE => Backend::KeyValue(retry(Store::open_default)?),
+            #[cfg(feature = "redis-backend")]
+            REDIS_STORE => match spin_sdk::config::get(REDIS_VARIABLE) {
+                Ok(address) if !address.trim().is_empty() => Backend::Redis(address),
+                _ => return Err(key_value::Error::NoSuchStore),
+            },
+            #[cfg(not(feature = "redis-backend"))]
+            REDIS_STORE => return Err(key_value::Error::NoSuchStore),
+            name => Backend::KeyValue(retry(|| Store::open(name))?),
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, key_value::Error> {
+        match self {
+            Backend::KeyValue(store) => store.get(key),
+            #[cfg(feature = "redis-backend")]
+            Backend::Redis(address) => match redis::get(address, key) {
+                Ok(value) if value.is_empty() => Err(key_value::Error::NoSuchKey),
+                result => result.map_err(redis_error),
+            },
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), key_value::Error> {
+        match self {
+            Backend::KeyValue(store) => store.set(key, value),
+            #[cfg(feature = "redis-backend")]
+            Backend::Redis(address) => redis::set(address, key, value).map_err(redis_error),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), key_value::Error> {
+        match self {
+            Backend::KeyValue(store) => store.delete(key),
+            #[cfg(feature = "redis-backend")]
+            Backend::Redis(address) => redis::del(address, &[key]).map(|_| ()).map_err(redis_error),
+        }
+    }
+
+    /// The keys starting with `prefix`
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, key_value::Error> {
+        match self {
+            Backend::KeyValue(store) => Ok(store
+                .get_keys()?
+                .into_iter()
+                .filter(|key| key.starts_with(prefix))
+                .collect()),
+            #[cfg(feature = "redis-backend")]
+            Backend::Redis(address) => {
+                let pattern = format!("{prefix}*");
+                let keys = redis::execute(
+                    address,
+                    "KEYS",
+                    &[redis::RedisParameter::Binary(pattern.as_bytes())],
+                )
+                .map_err(redis_error)?;
+                Ok(keys
+                    .into_iter()
+                    .filter_map(|key| match key {
+                        redis::RedisResult::Binary(key) => String::from_utf8(key).ok(),
+                        _ => None,
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// A store's handle, opened on first use
+#[derive(Default)]
+struct Handle {
+    backend: RefCell<Option<Rc<Backend>>>,
+    /// Whether the store was opened before, so opening it again is a reopen
+    opened: Cell<bool>,
+}
+
+/// Redis errors are taken to be transient, so they are retried and fail over
+#[cfg(feature = "redis-backend")]
+fn redis_error(e: redis::Error) -> key_value::Error {
+    key_value::Error::Io(format!("redis: {e:?}"))
+}
+
+/// Lazily opened handles to the configured stores
+pub(crate) struct Kv {
+    /// The names of the stores, primary first
+    names: Vec<String>,
+    stores: Vec<Handle>,
+    /// Writes the other stores are yet to catch up on, `None` for a delete
+    pending: RefCell<Vec<(String, Option<Vec<u8>>)>>,
+    /// How many times a read moved on to the next store
+    failovers: Cell<u32>,
+    /// How many stores were opened, and how many of them were opened again
+    /// after failing
+    opens: Cell<(u32, u32)>,
+    /// The sizes of the values read and of those written
+    traffic: RefCell<(Vec<usize>, Vec<usize>)>,
+    /// What keys are prefixed with, empty outside of a namespace
+    prefix: String,
+    #[cfg(feature = "chaos")]
+    pub faults: std::rc::Rc<crate::chaos::Faults>,
+}
+
+impl Default for Kv {
+    fn default() -> Self {
+        Self::new(vec![DEFAULT_STORE.to_owned()])
+    }
+}
+
+impl Kv {
+    fn new(names: Vec<String>) -> Self {
+        Self {
+            stores: names.iter().map(|_| Handle::default()).collect(),
+            names,
+            pending: RefCell::default(),
+            failovers: Cell::new(0),
+            opens: Cell::new((0, 0)),
+            traffic: RefCell::default(),
+            prefix: String::new(),
+            #[cfg(feature = "chaos")]
+            faults: Default::default(),
+        }
+    }
+
+    /// Handles to the stores listed in `key_value_stores`
+    pub fn configured() -> Self {
+        let names = spin_sdk::config::get(STORES_VARIABLE).unwrap_or_default();
+        let names: Vec<String> = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if names.is_empty() {
+            return Self::default();
+        }
+        Self::new(names)
+    }
+
+    /// Confine keys to `namespace`
+    pub fn within(&mut self, namespace: &str) {
+        self.prefix = format!("{namespace}/");
+    }
+
+    /// The key `key` is stored under, within the namespace if there is one
+    pub fn full_key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    fn store(&self, index: usize) -> Result<Rc<Backend>, key_value::Error> {
+        let handle = &self.stores[index];
+        if let Some(store) = &*handle.backend.borrow() {
+            return Ok(store.clone());
+        }
+        let store = Rc::new(Backend::open(&self.names[index])?);
+        let (opened, reopened) = self.opens.get();
+        self.opens.set(match handle.opened.replace(true) {
+            false => (opened + 1, reopened),
+            true => (opened, reopened + 1),
+        });
+        *handle.backend.borrow_mut() = Some(store.clone());
+        Ok(store)
+    }
+
+    /// Run `op` on the store at `index`, retrying it and closing the store
+    /// if it keeps failing
+    fn call<T>(
+        &self,
+        index: usize,
+        mut op: impl FnMut(&Backend) -> Result<T, key_value::Error>,
+    ) -> Result<T, key_value::Error> {
+        let store = self.store(index)?;
+        let result = retry(|| op(&store));
+        if let Err(key_value::Error::Io(_)) = result {
+            self.stores[index].backend.take();
+        }
+        result
+    }
+
+    /// Get a value, `None` if the key doesn't exist
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = &self.full_key(key);
+        let mut index = 0;
+        let result = loop {
+            let result = self.call(index, |store| {
+                self.inject("kv.get")?;
+                store.get(key)
+            });
+            match result {
+                Err(key_value::Error::Io(e)) if index + 1 < self.names.len() => {
+                    eprintln!(
+                        "store `{}` failed, failing over to `{}`: {e}",
+                        self.names[index],
+                        self.names[index + 1]
+                    );
+                    self.failovers.set(self.failovers.get() + 1);
+                    index += 1;
+                }
+                result => break result,
+            }
+        };
+        match result {
+            #[cfg(feature = "chaos")]
+            Ok(mut value) if self.faults.corrupts("kv.get") => {
+                value.pop();
+                Ok(Some(value))
+            }
+            Ok(value) => {
+                self.traffic.borrow_mut().0.push(value.len());
+                Ok(Some(value))
+            }
+            Err(key_value::Error::NoSuchKey) => {
+                self.traffic.borrow_mut().0.push(0);
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = &self.full_key(key);
+        self.call(0, |store| {
+            self.inject("kv.set")?;
+            store.set(key, value)
+        })?;
+        self.traffic.borrow_mut().1.push(value.len());
+        self.replay(key, Some(value));
+        Ok(())
+    }
+
+    /// The keys in the primary within the namespace, without its prefix
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let keys = self.call(0, |store| {
+            self.inject("kv.keys")?;
+            store.keys(&self.prefix)
+        })?;
+        Ok(keys
+            .into_iter()
+            .map(|key| key[self.prefix.len()..].to_owned())
+            .collect())
+    }
+
+    /// Delete a key, doing nothing if it doesn't exist
+    pub fn delete(&self, key: &str) -> Result<()> {
+        let key = &self.full_key(key);
+        match self.call(0, |store| {
+            self.inject("kv.delete")?;
+            store.delete(key)
+        }) {
+            Ok(()) | Err(key_value::Error::NoSuchKey) => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.replay(key, None);
+        Ok(())
+    }
+
+    /// Queue a write to the primary for the other stores
+    fn replay(&self, key: &str, value: Option<&[u8]>) {
+        if self.names.len() > 1 {
+            let mut pending = self.pending.borrow_mut();
+            pending.retain(|(pending, _)| pending != key);
+            pending.push((key.to_owned(), value.map(<[u8]>::to_vec)));
+        }
+    }
+
+    /// Bring the other stores up to date with this request's writes
+    pub fn catch_up(&self) {
+        let pending = self.pending.take();
+        for index in 1..self.names.len() {
+            for (key, value) in &pending {
+                let result = self.call(index, |store| match value {
+                    Some(value) => store.set(key, value),
+                    None => match store.delete(key) {
+                        Err(key_value::Error::NoSuchKey) => Ok(()),
+                        result => result,
+                    },
+                });
+                if let Err(e) = result {
+                    eprintln!(
+                        "store `{}` missed a write to `{key}`: {e}",
+                        self.names[index]
+                    );
+                }
+            }
+        }
+    }
+
+    /// How many times reads failed over to another store
+    pub fn failovers(&self) -> u32 {
+        self.failovers.get()
+    }
+
+    /// How many stores were opened, and how many were opened again after
+    /// failing
+    pub fn opens(&self) -> (u32, u32) {
+        self.opens.get()
+    }
+
+    /// How many bytes of values this request wrote so far
+    pub fn bytes_written(&self) -> u64 {
+        self.traffic.borrow().1.iter().map(|&len| len as u64).sum()
+    }
+
+    /// The sizes of the values read and of those written so far, which are
+    /// then forgotten
+    pub fn take_traffic(&self) -> (Vec<usize>, Vec<usize>) {
+        self.traffic.take()
+    }
+}
+
+impl Kv {
+    /// Run into the faults injected into `operation`, as transient errors
+    #[cfg(feature = "chaos")]
+    fn inject(&self, operation: &str) -> Result<(), key_value::Error> {
+        self.faults
+            .before(operation)
+            .map_err(|e| key_value::Error::Io(e.to_string()))
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn inject(&self, _operation: &str) -> Result<(), key_value::Error> {
+        Ok(())
+    }
+}
+
+/// Run a store operation, retrying it on transient errors
+fn retry<T>(mut op: impl FnMut() -> Result<T, key_value::Error>) -> Result<T, key_value::Error> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(key_value::Error::Io(e)) if attempt < ATTEMPTS => {
+                eprintln!("retrying store operation after error: {e}");
+                sleep(backoff(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The delay before retry number `attempt`: exponential with up to 50% jitter
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY * 2u32.pow(attempt - 1);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let mut calls = 0;
+        let result = retry(|| {
+            calls += 1;
+            match calls {
+                1 => Err(key_value::Error::Io("flaky".into())),
+                _ => Ok(calls),
+            }
+        });
+        assert!(matches!(result, Ok(2)));
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry(|| {
+            calls += 1;
+            Err(key_value::Error::NoSuchKey)
+        });
+        assert!(matches!(result, Err(key_value::Error::NoSuchKey)));
+        assert_eq!(calls, 1);
+
+        for attempt in 1..ATTEMPTS {
+            let delay = backoff(attempt);
+            assert!(delay >= BASE_DELAY * 2u32.pow(attempt - 1));
+            assert!(delay <= BASE_DELAY * 2u32.pow(attempt - 1) * 3 / 2);
+        }
+    }
+
+    #[test]
+    fn replays_last_write_per_key() {
+        let single = Kv::default();
+        single.replay("a", Some(b"1"));
+        assert!(single.pending.borrow().is_empty());
+
+        let kv = Kv::new(vec!["primary".into(), "secondary".into()]);
+        kv.replay("a", Some(b"1"));
+        kv.replay("b", Some(b"2"));
+        kv.replay("a", None);
+        assert_eq!(
+            *kv.pending.borrow(),
+            [
+                ("b".to_owned(), Some(b"2".to_vec())),
+                ("a".to_owned(), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn namespaces_prefix_keys() {
+        let mut kv = Kv::default();
+        assert_eq!(kv.full_key("__state"), "__state");
+        kv.within("t/shop");
+        assert_eq!(kv.full_key("__state"), "t/shop/__state");
+    }
+}
@@ -0,0 +1,232 @@
+//! How filters are laid out in the stores, for readers outside the component
+//!
+//! Batch systems can evaluate membership offline by reading a filter's keys
+//! straight from the store. `GET /admin/layout` tells them everything they
+//! need to do so: the key of each slice and of each of its shards or of
+//! its Redis bitmap, the shape of each slice and the checksum each shard had
+//! when it was last written, along with how emails are hashed onto bits. A shard whose
+//! checksum doesn't match the layout was written since it was read, so the
+//! layout should be fetched again. The shards of a rebalanced slice hold its
+//! bits at the positions its `multiplier` maps them to; during a migration
+//! they are the shards migrated from, which stay whole until it completes.
+
+use spin_sdk::http::{Request, Response};
+
+use crate::{
+    app::App,
+    encoding::Encoding,
+    error::Error,
+    filter::MAGIC,
+    filter_store::{self, Header},
+    hashing::Hashing,
+    shards::{self, Manifest},
+    BloomFilter,
+};
+
+/// How emails are hashed onto the bits of a slice, which every reader must
+/// follow exactly
+const HASHING: Description = Description {
+    element: "each address the email normalizes and expands to, hashed as Rust hashes a `str`: \
+              its UTF-8 bytes followed by the byte 0xff, preceded by the slice's seed as a \
+              big-endian u64 unless it is 0",
+    hashes: &[
+        (
+            Hashing::Murmur3Fnv,
+            ["Murmur3 32-bit with seed 0", "FNV-1a 32-bit"],
+        ),
+        (
+            Hashing::XxHash64,
+            [
+                "xxHash64 with seed 0",
+                "xxHash64 with seed 0, its 32-bit halves swapped",
+            ],
+        ),
+        (
+            Hashing::SipHash13,
+            [
+                "SipHash-1-3 with both keys 0",
+                "SipHash-1-3 with both keys 0, its 32-bit halves swapped",
+            ],
+        ),
+    ],
+    positions: "with m bits, a = h1 mod m and b = (h2 mod m - a) mod m, the i-th of the \
+                num_hashes positions is (a + i * b) mod m; an address may be present if all its \
+                positions are set, and an email if any slice may hold any of its addresses",
+    bits: "bit n is bit n mod 32, counting from the least significant, of the n / 32-th \
+           big-endian u32 word, but in a Redis bitmap it is bit n mod 8, counting from the \
+           most significant, of byte n / 8",
+    checksum: "Murmur3 32-bit with seed 0 of everything before it, as a big-endian u32",
+};
+
+#[derive(serde::Serialize)]
+struct Description {
+    element: &'static str,
+    /// The two hashes h1 and h2 of each slice's hashing
+    #[serde(serialize_with = "by_hashing")]
+    hashes: &'static [(Hashing, [&'static str; 2])],
+    positions: &'static str,
+    bits: &'static str,
+    checksum: &'static str,
+}
+
+/// Serialize the hashes of each hashing as an object keyed by its name
+fn by_hashing<S: serde::Serializer>(
+    hashes: &&[(Hashing, [&str; 2])],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(hashes.iter().map(|(hashing, hashes)| (hashing, hashes)))
+}
+
+#[derive(serde::Serialize)]
+struct Layout {
+    hashing: &'static Description,
+    slices: Vec<Slice>,
+}
+
+#[derive(serde::Serialize)]
+struct Slice {
+    /// The key the slice, or its manifest, is stored under
+    key: String,
+    /// The format the slice is stored in, see `BloomFilter::deserialize`, 0
+    /// for the legacy format
+    format: u8,
+    generation: u64,
+    num_bits: usize,
+    num_hashes: usize,
+    counting: bool,
+    hashing: Hashing,
+    /// Hashed before every element, so as secret as the admin token
+    seed: u64,
+    /// `None` for a slice stored whole
+    shard_bits: Option<usize>,
+    /// Bit `i` of a sharded slice is at position `i * multiplier % num_bits`
+    /// of its shards, 1 until it is rebalanced
+    multiplier: u32,
+    shards: Vec<Shard>,
+    /// The Redis key of the bits of a slice stored as a Redis bitmap
+    bitmap: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Shard {
+    key: String,
+    /// The first of the slice's positions the shard holds
+    first_bit: usize,
+    num_bits: usize,
+    checksum: u32,
+}
+
+impl App {
+    /// Describe how the filter is stored
+    ///
+    /// Like the admin endpoints this requires the admin token.
+    pub fn layout(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let mut slices = Vec::new();
+        for (slice_key, state) in self.stored_slices()? {
+            let stored_key = self.store.full_key(&slice_key);
+            let format = match state.strip_prefix(MAGIC) {
+                Some([version, ..]) => *version,
+                _ => 0,
+            };
+            slices.push(if shards::is_manifest(&state) {
+                let manifest = Manifest::decode(&state)?;
+                let params = manifest.params();
+                Slice {
+                    format,
+                    generation: manifest.generation(),
+                    num_bits: params.num_bits,
+                    num_hashes: params.num_hashes,
+                    counting: params.counting,
+                    hashing: params.hashing,
+                    seed: params.seed,
+                    shard_bits: Some(manifest.shard_bits()),
+                    multiplier: manifest.mapping().multiplier,
+                    bitmap: None,
+                    shards: manifest
+                        .shards(&stored_key)
+                        .map(|(key, bits, checksum)| Shard {
+                            key,
+                            first_bit: bits.start,
+                            num_bits: bits.len(),
+                            checksum,
+                        })
+                        .collect(),
+                    key: stored_key,
+                }
+            } else if filter_store::is_header(&state) {
+                let header = Header::decode(&state)?;
+                let params = header.params;
+                Slice {
+                    format,
+                    generation: header.generation,
+                    num_bits: params.num_bits,
+                    num_hashes: params.num_hashes,
+                    counting: false,
+                    hashing: params.hashing,
+                    seed: params.seed,
+                    shard_bits: None,
+                    multiplier: 1,
+                    shards: Vec::new(),
+                    bitmap: Some(self.store.full_key(&filter_store::bits_key(&slice_key))),
+                    key: stored_key,
+                }
+            } else {
+                let filter = BloomFilter::deserialize(state)?;
+                let params = filter.params();
+                Slice {
+                    key: stored_key,
+                    format,
+                    generation: filter.generation,
+                    num_bits: params.num_bits,
+                    num_hashes: params.num_hashes,
+                    counting: params.counting,
+                    hashing: params.hashing,
+                    seed: params.seed,
+                    shard_bits: None,
+                    multiplier: 1,
+                    shards: Vec::new(),
+                    bitmap: None,
+                }
+            });
+        }
+        Ok(Encoding::accepted(&req).response(&Layout {
+            hashing: &HASHING,
+            slices,
+        })?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Params, ProbeSet};
+    use core::hash::Hasher;
+    use hash32::Hasher as _;
+
+    #[test]
+    fn hashing_is_described_faithfully() {
+        let email = "me@example.com";
+        let mut bytes = email.as_bytes().to_vec();
+        bytes.push(0xff);
+        let mut murmur = hash32::Murmur3Hasher::default();
+        murmur.write(&bytes);
+        let mut fnv = hash32::FnvHasher::default();
+        fnv.write(&bytes);
+        let (h1, h2) = (murmur.finish32() as u64, fnv.finish32() as u64);
+
+        let params = Params::new(1000, 5, false).unwrap();
+        let m = params.num_bits as u64;
+        let (a, b) = (h1 % m, (h2 % m + m - h1 % m) % m);
+        let described: Vec<_> = (0..5).map(|i| ((a + i * b) % m) as usize).collect();
+        let probed: Vec<_> = ProbeSet::new(&email).indices(params).collect();
+        assert_eq!(described, probed);
+
+        let mut seeded = 7u64.to_be_bytes().to_vec();
+        seeded.extend(&bytes);
+        let mut murmur = hash32::Murmur3Hasher::default();
+        murmur.write(&seeded);
+        let probed: Vec<_> = ProbeSet::new(&email).indices(params.seeded(7)).collect();
+        assert_eq!(probed[0], (murmur.finish32() as u64 % m) as usize);
+    }
+}
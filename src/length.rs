@@ -0,0 +1,113 @@
+//! How emails longer than any real address are handled
+//!
+//! Addresses are at most 254 bytes long, but nothing stops a client from
+//! sending a 10KB "email". Left alone, such inputs cost normalization,
+//! hashing and lookups in proportion to their length, and a database column
+//! narrower than the input may truncate it, making two different inputs
+//! one identity there but two in the filter. Emails longer than
+//! `max_email_length` are either refused before anything else looks at them,
+//! or stand for the digest of their full canonical form everywhere, which is
+//! short enough for any column and the same for the filter and the database.
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+/// The domain digests of long emails are on
+const DIGEST_DOMAIN: &str = "digest.invalid";
+
+/// What happens to emails longer than the limit
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Policy {
+    /// Refuse them with 400
+    Reject,
+    /// Answer for the digest of the whole email
+    Digest,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "reject" => Ok(Policy::Reject),
+            "digest" => Ok(Policy::Digest),
+            _ => anyhow::bail!("expected `reject` or `digest`"),
+        }
+    }
+}
+
+/// The longest emails taken as they are
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+pub(crate) struct Limit {
+    /// In bytes, surrounding whitespace aside
+    pub max: usize,
+    pub policy: Policy,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Limit {
+            max: 254,
+            policy: Policy::Reject,
+        }
+    }
+}
+
+impl Limit {
+    /// Whether `email` is longer than the limit
+    ///
+    /// This only looks at the length, so that refusing an email costs nothing
+    /// however long it is.
+    pub fn exceeded_by(&self, email: &str) -> bool {
+        email.trim().len() > self.max
+    }
+
+    /// Refuse `email` if it is too long and the policy is to reject it
+    pub fn check(&self, email: &str) -> Result<(), Error> {
+        if self.policy == Policy::Reject && self.exceeded_by(email) {
+            return Err(Error::bad_request(format!(
+                "emails may be at most {} bytes long",
+                self.max
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The address a too long email stands for, given its canonical form
+pub(crate) fn digest(canonical: &str) -> String {
+    let digest: String = Sha256::digest(canonical.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("{digest}@{DIGEST_DOMAIN}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_emails_are_refused_or_digested() {
+        let huge = format!("{}@example.com", "a".repeat(10 * 1024));
+        let limit = Limit::default();
+        assert!(matches!(limit.check(&huge), Err(Error::BadRequest(_))));
+        assert!(limit.check(&format!(" {} ", "a".repeat(254))).is_ok());
+        assert!(limit.check(&"a".repeat(255)).is_err());
+
+        let digesting = Limit {
+            policy: Policy::Digest,
+            ..limit
+        };
+        assert!(digesting.check(&huge).is_ok());
+        let digested = digest(&huge);
+        assert!(digested.len() <= limit.max);
+        assert!(!digesting.exceeded_by(&digested));
+        assert_eq!(digested, digest(&huge));
+        // Inputs a truncating column would store the same stay distinct
+        let other = format!("{}b@example.com", "a".repeat(10 * 1024));
+        assert_ne!(digest(&other), digested);
+    }
+}
@@ -0,0 +1,158 @@
+//! Combining the filters of instances that each built their own
+//!
+//! Deployments in several regions register emails into separate filters,
+//! which drift apart as each only sees its own signups. `GET /admin/export`
+//! serves a slice of the filter in its stored format, and `POST /merge`
+//! takes such a slice from another instance and ORs its bits into the local
+//! slice of the same shape, so that periodic cross-region reconciliation is
+//! an export on one side and a merge on the other. Filters of different
+//! shapes can't be merged. Merged emails answered as available before
+//! remain cached as such until the verdict cache's TTL runs out.
+//!
+//! `POST /admin/merge/estimate` estimates, without changing anything, how
+//! many emails merging an exported slice would add and how many the local
+//! slice holds that it lacks. `POST /admin/subtract` takes a slice of a
+//! counting filter and removes its emails from the local slice, but only if
+//! the local slice could hold all of them.
+
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, error::Error, BloomFilter, Removal};
+
+/// The media type of exported slices
+pub(crate) const FILTER: &str = "application/octet-stream";
+
+#[derive(serde::Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    slice: usize,
+}
+
+#[derive(serde::Serialize)]
+struct Report {
+    /// The local slice the filter was merged into
+    slice: usize,
+    num_bits: usize,
+    num_hashes: usize,
+}
+
+#[derive(serde::Serialize)]
+struct Estimate {
+    /// The local slice the filter was compared with
+    slice: usize,
+    /// About how many emails merging the filter would add
+    added: f64,
+    /// About how many emails only one of the two holds
+    symmetric_difference: f64,
+}
+
+#[derive(serde::Serialize)]
+struct Subtraction {
+    /// The local slice the filter was subtracted from
+    slice: usize,
+    /// How many counters had lost count and were left as they were
+    saturated: usize,
+}
+
+/// The exported slice in the body of `req`
+fn exported(req: &Request) -> Result<BloomFilter, Error> {
+    let Some(body) = req.body().as_ref().filter(|body| !body.is_empty()) else {
+        return Err(Error::bad_request("no body"));
+    };
+    BloomFilter::deserialize(body.to_vec()).map_err(Error::bad_request)
+}
+
+/// The error for a filter no local slice has the shape of
+fn no_slice_like(other: &BloomFilter, action: &str) -> Error {
+    let params = other.params();
+    Error::Conflict(format!(
+        "no slice has {} bits and {} hashes{} hashed the same way, only filters \
+         of the same shape can be {action}",
+        params.num_bits,
+        params.num_hashes,
+        if params.counting { " and counts" } else { "" }
+    ))
+}
+
+impl App {
+    /// Serve a slice of the filter for the configured mode, the first by
+    /// default
+    pub fn export(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: ExportQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let filter = self.load_filter()?;
+        let slice = filter.slices().get(query.slice).ok_or(Error::NotFound)?;
+        Ok(http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, FILTER)
+            .body(Some(slice.serialize().into()))
+            .map_err(anyhow::Error::from)?)
+    }
+
+    /// Merge an exported slice into the local slice of the same shape
+    pub fn merge(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        self.ensure_storage()?;
+        let other = exported(&req)?;
+        let params = other.params();
+        let Some(slice) = self.update_filter(|filter| filter.merge(&other))? else {
+            return Err(no_slice_like(&other, "merged"));
+        };
+        eprintln!(
+            "merged a filter of {} bits into slice {slice}",
+            params.num_bits
+        );
+        Ok(crate::json_response(&Report {
+            slice,
+            num_bits: params.num_bits,
+            num_hashes: params.num_hashes,
+        })?)
+    }
+
+    /// Estimate what merging an exported slice into the local slice of the
+    /// same shape would change
+    ///
+    /// The shapes only have to match in bits, hashes and hashing, so that a
+    /// counting slice can be compared with one that doesn't count.
+    pub fn estimate_merge(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let other = exported(&req)?;
+        let filter = self.load_filter()?;
+        let Some(slice) = filter.same_shape(&other) else {
+            return Err(no_slice_like(&other, "compared"));
+        };
+        let local = &filter.slices()[slice];
+        let (added, dropped) = local.difference_estimates(&other)?;
+        Ok(crate::json_response(&Estimate {
+            slice,
+            added,
+            symmetric_difference: added + dropped,
+        })?)
+    }
+
+    /// Remove the emails of an exported counting slice from the local slice
+    /// of the same shape
+    pub fn subtract(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let other = exported(&req)?;
+        if !other.params().counting {
+            return Err(Error::bad_request(
+                "only counting filters can be subtracted",
+            ));
+        }
+        let Some((slice, removal)) = self.update_filter(|filter| filter.subtract(&other))? else {
+            return Err(no_slice_like(&other, "subtracted"));
+        };
+        let Removal::Removed { saturated } = removal else {
+            return Err(Error::Conflict(format!(
+                "slice {slice} can't hold every email of the filter, nothing was subtracted"
+            )));
+        };
+        eprintln!(
+            "subtracted a filter of {} bits from slice {slice}",
+            other.params().num_bits
+        );
+        Ok(crate::json_response(&Subtraction { slice, saturated })?)
+    }
+}
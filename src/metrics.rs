@@ -0,0 +1,591 @@
+//! Operational metrics
+//!
+//! Handlers record typed counters and histograms through the `Metrics`
+//! facade, which hands them to the configured sink. With the `store` sink a
+//! request's metrics are buffered and merged into aggregates in the store
+//! when it finishes, and `GET /metrics` renders those aggregates in the
+//! Prometheus text format. Without compare and swap, concurrent merges may lose
+//! updates. The `store` sink and `GET /metrics` are only available in builds
+//! with the `metrics` feature.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::{cell::RefCell, collections::BTreeMap, fmt::Write};
+
+use crate::{app::App, error::Error, kv::Kv};
+
+const METRICS_KEY: &str = "__metrics";
+/// Prefixed to every metric's name
+const NAMESPACE: &str = "bloom_filter";
+
+/// Something worth counting
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Counter {
+    /// The filter ruled an email out
+    FilterNo,
+    /// The filter couldn't rule an email out
+    FilterMaybe,
+    /// A database verdict came from the verdict cache
+    VerdictCacheHit,
+    /// The database was asked about an email
+    DatabaseLookup,
+    /// A lookup was refused for lack of budget
+    BudgetExhausted,
+    /// A lookup was refused as its client spent its share of the budget
+    ClientBudgetExhausted,
+    /// An availability check had to do without a loadable filter
+    DegradedRead,
+    /// A registration was added
+    Registered,
+    /// A registration waits for its email to be verified
+    RegistrationPending,
+    /// A registration was refused by its domain's quota
+    DomainQuotaExceeded,
+    /// Stored state was rewritten in the current format
+    StateMigrated,
+    /// An email was removed from a counting filter
+    Removed,
+    /// Removing an email left counters that had lost count
+    RemovedSaturated,
+    /// Reconciliation found a database row the filter was missing
+    Backfilled,
+    /// An availability check took longer than the response time floor
+    PaddingOverrun,
+    /// A filter update found the filter written concurrently and retried
+    WriteConflict,
+    /// A filter stored whole outgrew a single value and was stored in shards
+    Resharded,
+    /// A store read failed and moved on to the next store
+    StoreFailover,
+    /// A store was opened
+    StoreOpened,
+    /// A store that failed was opened again
+    StoreReopened,
+    /// The shadow filter store answered a check as the filter did
+    ShadowMatched,
+    /// The shadow filter store answered a check differently from the filter
+    ShadowMismatched,
+    /// A check wasn't compared, the shadow filter store lacking the filter
+    ShadowSkipped,
+    /// A write to the shadow filter store failed
+    ShadowWriteFailed,
+    /// A request was answered
+    Response { method: &'static str, status: u16 },
+}
+
+impl Counter {
+    /// The metric's name, help text and labels
+    fn describe(self) -> (&'static str, &'static str, &'static str) {
+        const CHECKS: &str = "Emails checked against the filter";
+        const REGISTRATIONS: &str = "Registration attempts that passed the duplicate check";
+        const REMOVALS: &str = "Emails removed from a counting filter";
+        const SHADOW: &str = "Filter checks compared with the shadow filter store";
+        match self {
+            Counter::FilterNo => ("filter_checks_total", CHECKS, r#"result="no""#),
+            Counter::FilterMaybe => ("filter_checks_total", CHECKS, r#"result="maybe""#),
+            Counter::VerdictCacheHit => (
+                "verdict_cache_hits_total",
+                "Database verdicts served from the verdict cache",
+                "",
+            ),
+            Counter::DatabaseLookup => (
+                "database_lookups_total",
+                "Emails looked up in the database",
+                "",
+            ),
+            Counter::BudgetExhausted => (
+                "lookup_budget_exhausted_total",
+                "Lookups refused because the lookup budget was spent",
+                "",
+            ),
+            Counter::ClientBudgetExhausted => (
+                "client_lookup_budget_exhausted_total",
+                "Lookups refused because their client spent its share of the lookup budget",
+                "",
+            ),
+            Counter::DegradedRead => (
+                "degraded_reads_total",
+                "Availability checks answered without a loadable filter",
+                "",
+            ),
+            Counter::Registered => ("registrations_total", REGISTRATIONS, r#"outcome="added""#),
+            Counter::RegistrationPending => {
+                ("registrations_total", REGISTRATIONS, r#"outcome="pending""#)
+            }
+            Counter::DomainQuotaExceeded => (
+                "registrations_total",
+                REGISTRATIONS,
+                r#"outcome="domain_quota""#,
+            ),
+            Counter::StateMigrated => (
+                "state_migrations_total",
+                "Stored filters rewritten in the current format",
+                "",
+            ),
+            Counter::Removed => ("removals_total", REMOVALS, r#"outcome="removed""#),
+            Counter::RemovedSaturated => ("removals_total", REMOVALS, r#"outcome="saturated""#),
+            Counter::Backfilled => (
+                "backfills_total",
+                "Database rows reconciliation inserted into the filter",
+                "",
+            ),
+            Counter::PaddingOverrun => (
+                "response_padding_overruns_total",
+                "Availability checks slower than the response time floor, which can't be padded",
+                "",
+            ),
+            Counter::WriteConflict => (
+                "filter_write_conflicts_total",
+                "Filter updates retried because the filter was written concurrently",
+                "",
+            ),
+            Counter::Resharded => (
+                "filters_resharded_total",
+                "Filters stored whole that outgrew a single value and were split into shards",
+                "",
+            ),
+            Counter::StoreFailover => (
+                "store_failovers_total",
+                "Store reads that failed and moved on to the next store",
+                "",
+            ),
+            Counter::StoreOpened => ("store_opens_total", "Stores opened", ""),
+            Counter::StoreReopened => (
+                "store_reopens_total",
+                "Stores opened again after calls to them kept failing",
+                "",
+            ),
+            Counter::ShadowMatched => ("shadow_checks_total", SHADOW, r#"result="matched""#),
+            Counter::ShadowMismatched => ("shadow_checks_total", SHADOW, r#"result="mismatched""#),
+            Counter::ShadowSkipped => ("shadow_checks_total", SHADOW, r#"result="skipped""#),
+            Counter::ShadowWriteFailed => (
+                "shadow_write_failures_total",
+                "Writes to the shadow filter store that failed",
+                "",
+            ),
+            // Labelled by `series`
+            Counter::Response { .. } => ("responses_total", "Requests answered", ""),
+        }
+    }
+
+    /// The series the counter is aggregated under
+    fn series(self) -> String {
+        let (name, _, labels) = self.describe();
+        match self {
+            Counter::Response { method, status } => {
+                series(name, &format!(r#"method="{method}",status="{status}""#))
+            }
+            _ => series(name, labels),
+        }
+    }
+
+    /// The counter of a request with `method` answered with `status`
+    pub fn response(method: &http::Method, status: http::StatusCode) -> Self {
+        let method = match *method {
+            http::Method::GET => "GET",
+            http::Method::HEAD => "HEAD",
+            http::Method::POST => "POST",
+            http::Method::PUT => "PUT",
+            http::Method::PATCH => "PATCH",
+            http::Method::DELETE => "DELETE",
+            http::Method::OPTIONS => "OPTIONS",
+            // Keeps arbitrary methods from adding series
+            _ => "other",
+        };
+        Counter::Response {
+            method,
+            status: status.as_u16(),
+        }
+    }
+}
+
+/// Something worth knowing the distribution of
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Histogram {
+    /// How long database lookups take
+    DatabaseLookupSeconds,
+    /// How long availability checks were held back to reach the floor
+    PaddingSeconds,
+    /// How big the values read from the store are
+    StoreReadBytes,
+    /// How big the values written to the store are
+    StoreWriteBytes,
+}
+
+impl Histogram {
+    /// The metric's name, help text and bucket upper bounds
+    fn describe(self) -> (&'static str, &'static str, &'static [f64]) {
+        const BYTES: &[f64] = &[
+            64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+        ];
+        match self {
+            Histogram::DatabaseLookupSeconds => (
+                "database_lookup_seconds",
+                "Time taken by database lookups",
+                &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0],
+            ),
+            Histogram::PaddingSeconds => (
+                "response_padding_seconds",
+                "Time availability checks were padded by to reach the response time floor",
+                &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25],
+            ),
+            Histogram::StoreReadBytes => (
+                "store_read_bytes",
+                "Sizes of the values read from the store, 0 for missing keys",
+                BYTES,
+            ),
+            Histogram::StoreWriteBytes => (
+                "store_write_bytes",
+                "Sizes of the values written to the store",
+                BYTES,
+            ),
+        }
+    }
+}
+
+/// Where metrics are recorded
+pub(crate) trait Metrics {
+    fn count(&self, counter: Counter);
+    fn observe(&self, histogram: Histogram, value: f64);
+    /// Persist what was recorded during the request
+    fn flush(&self, store: &Kv) -> Result<()>;
+}
+
+/// Which `Metrics` sink to use
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Sink {
+    None,
+    #[cfg(feature = "metrics")]
+    Store,
+}
+
+impl Sink {
+    pub fn metrics(self) -> Box<dyn Metrics> {
+        match self {
+            Sink::None => Box::new(NoMetrics),
+            #[cfg(feature = "metrics")]
+            Sink::Store => Box::<StoreMetrics>::default(),
+        }
+    }
+}
+
+impl std::str::FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Sink::None),
+            #[cfg(feature = "metrics")]
+            "store" => Ok(Sink::Store),
+            #[cfg(not(feature = "metrics"))]
+            "store" => anyhow::bail!("the `store` sink requires the `metrics` feature"),
+            _ => anyhow::bail!("expected `none` or `store`"),
+        }
+    }
+}
+
+/// Discards everything
+pub(crate) struct NoMetrics;
+
+impl Metrics for NoMetrics {
+    fn count(&self, _counter: Counter) {}
+
+    fn observe(&self, _histogram: Histogram, _value: f64) {}
+
+    fn flush(&self, _store: &Kv) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Aggregates metrics in the store
+#[derive(Default)]
+pub(crate) struct StoreMetrics {
+    recorded: RefCell<Aggregate>,
+}
+
+impl Metrics for StoreMetrics {
+    fn count(&self, counter: Counter) {
+        self.recorded.borrow_mut().count(counter, 1);
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        self.recorded.borrow_mut().observe(histogram, value);
+    }
+
+    fn flush(&self, store: &Kv) -> Result<()> {
+        let recorded = self.recorded.take();
+        if recorded.is_empty() {
+            return Ok(());
+        }
+        let mut aggregate = Aggregate::load(store)?;
+        aggregate.merge(recorded);
+        store.set(METRICS_KEY, &serde_json::to_vec(&aggregate)?)
+    }
+}
+
+/// How many values the store was asked to read and write, and their sizes
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub(crate) struct StoreTotals {
+    reads: u64,
+    read_bytes: f64,
+    writes: u64,
+    written_bytes: f64,
+}
+
+/// The store traffic recorded in the aggregated metrics
+pub(crate) fn store_totals(store: &Kv) -> Result<StoreTotals> {
+    let aggregate = Aggregate::load(store)?;
+    let totals = |histogram: Histogram| {
+        aggregate
+            .histograms
+            .get(histogram.describe().0)
+            .map_or((0, 0.0), |d| (d.buckets.iter().sum(), d.sum))
+    };
+    let (reads, read_bytes) = totals(Histogram::StoreReadBytes);
+    let (writes, written_bytes) = totals(Histogram::StoreWriteBytes);
+    Ok(StoreTotals {
+        reads,
+        read_bytes,
+        writes,
+        written_bytes,
+    })
+}
+
+/// Counter totals and histogram buckets by series
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Aggregate {
+    #[serde(default)]
+    counters: BTreeMap<String, u64>,
+    #[serde(default)]
+    histograms: BTreeMap<String, Distribution>,
+}
+
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+struct Distribution {
+    /// Observations per bucket, not cumulative, the last one past all bounds
+    buckets: Vec<u64>,
+    sum: f64,
+}
+
+impl Aggregate {
+    fn load(store: &Kv) -> Result<Self> {
+        Ok(match store.get(METRICS_KEY)? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => Self::default(),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.histograms.is_empty()
+    }
+
+    fn count(&mut self, counter: Counter, by: u64) {
+        *self.counters.entry(counter.series()).or_default() += by;
+    }
+
+    fn observe(&mut self, histogram: Histogram, value: f64) {
+        let (name, _, bounds) = histogram.describe();
+        let distribution = self.histograms.entry(name.to_owned()).or_default();
+        distribution.buckets.resize(bounds.len() + 1, 0);
+        let bucket = bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(bounds.len());
+        distribution.buckets[bucket] += 1;
+        distribution.sum += value;
+    }
+
+    fn merge(&mut self, other: Aggregate) {
+        for (series, count) in other.counters {
+            *self.counters.entry(series).or_default() += count;
+        }
+        for (name, other) in other.histograms {
+            let distribution = self.histograms.entry(name).or_default();
+            if distribution.buckets.len() < other.buckets.len() {
+                distribution.buckets.resize(other.buckets.len(), 0);
+            }
+            for (count, other) in distribution.buckets.iter_mut().zip(&other.buckets) {
+                *count += other;
+            }
+            distribution.sum += other.sum;
+        }
+    }
+
+    /// Render in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut family = "";
+        for (series, count) in &self.counters {
+            let name = series.split('{').next().unwrap_or_default();
+            if name != family {
+                let help = COUNTERS
+                    .iter()
+                    .map(|c| c.describe())
+                    .find(|(n, _, _)| *n == name)
+                    .map_or("", |(_, help, _)| help);
+                writeln!(text, "# HELP {NAMESPACE}_{name} {help}").unwrap();
+                writeln!(text, "# TYPE {NAMESPACE}_{name} counter").unwrap();
+                family = name;
+            }
+            writeln!(text, "{NAMESPACE}_{series} {count}").unwrap();
+        }
+        for histogram in HISTOGRAMS {
+            let (name, help, bounds) = histogram.describe();
+            let Some(distribution) = self.histograms.get(name) else {
+                continue;
+            };
+            writeln!(text, "# HELP {NAMESPACE}_{name} {help}").unwrap();
+            writeln!(text, "# TYPE {NAMESPACE}_{name} histogram").unwrap();
+            let mut cumulative = 0;
+            for (i, count) in distribution.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = bounds.get(i).map_or("+Inf".to_owned(), |b| b.to_string());
+                writeln!(
+                    text,
+                    "{NAMESPACE}_{name}_bucket{{le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            writeln!(text, "{NAMESPACE}_{name}_sum {}", distribution.sum).unwrap();
+            writeln!(text, "{NAMESPACE}_{name}_count {cumulative}").unwrap();
+        }
+        text
+    }
+}
+
+const COUNTERS: &[Counter] = &[
+    Counter::FilterNo,
+    Counter::FilterMaybe,
+    Counter::VerdictCacheHit,
+    Counter::DatabaseLookup,
+    Counter::BudgetExhausted,
+    Counter::ClientBudgetExhausted,
+    Counter::DegradedRead,
+    Counter::Registered,
+    Counter::RegistrationPending,
+    Counter::DomainQuotaExceeded,
+    Counter::StateMigrated,
+    Counter::Removed,
+    Counter::RemovedSaturated,
+    Counter::Backfilled,
+    Counter::PaddingOverrun,
+    Counter::WriteConflict,
+    Counter::Resharded,
+    Counter::StoreFailover,
+    Counter::StoreOpened,
+    Counter::StoreReopened,
+    Counter::ShadowMatched,
+    Counter::ShadowMismatched,
+    Counter::ShadowSkipped,
+    Counter::ShadowWriteFailed,
+    // Stands for every method and status
+    Counter::Response {
+        method: "GET",
+        status: 200,
+    },
+];
+
+const HISTOGRAMS: &[Histogram] = &[
+    Histogram::DatabaseLookupSeconds,
+    Histogram::PaddingSeconds,
+    Histogram::StoreReadBytes,
+    Histogram::StoreWriteBytes,
+];
+
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{name}{{{labels}}}")
+    }
+}
+
+/// Render the fraction of set bits of each slice as gauges
+fn render_saturation(text: &mut String, saturation: &[f64]) {
+    let name = "filter_saturation";
+    writeln!(
+        text,
+        "# HELP {NAMESPACE}_{name} Fraction of the filter's bits that are set, by slice"
+    )
+    .unwrap();
+    writeln!(text, "# TYPE {NAMESPACE}_{name} gauge").unwrap();
+    for (slice, saturation) in saturation.iter().enumerate() {
+        writeln!(text, "{NAMESPACE}_{name}{{slice=\"{slice}\"}} {saturation}").unwrap();
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl App {
+    /// Expose the aggregated metrics to Prometheus
+    ///
+    /// Only available with the `store` sink, and like the admin endpoints
+    /// this requires the admin token.
+    pub fn metrics_endpoint(&self, req: Request) -> Result<Response, Error> {
+        if self.config.metrics != Sink::Store {
+            return Err(Error::NotFound);
+        }
+        self.authorize(&req)?;
+        let mut text = Aggregate::load(&self.store)?.render();
+        let filter = self.load_filter()?;
+        let saturation: Vec<_> = filter
+            .slices()
+            .iter()
+            .map(|slice| slice.array.count_ones() as f64 / slice.array.len() as f64)
+            .collect();
+        render_saturation(&mut text, &saturation);
+        Ok(http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Some(text.into_bytes().into()))
+            .map_err(anyhow::Error::from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut aggregate = Aggregate::default();
+        aggregate.count(Counter::FilterNo, 2);
+        let mut request = Aggregate::default();
+        request.count(Counter::FilterNo, 1);
+        request.count(Counter::FilterMaybe, 1);
+        request.observe(Histogram::DatabaseLookupSeconds, 0.02);
+        request.observe(Histogram::DatabaseLookupSeconds, 5.0);
+        request.count(
+            Counter::response(&http::Method::GET, http::StatusCode::CONFLICT),
+            1,
+        );
+        request.count(
+            Counter::response(
+                &http::Method::from_bytes(b"BREW").unwrap(),
+                http::StatusCode::OK,
+            ),
+            1,
+        );
+        aggregate.merge(request);
+
+        let mut text = aggregate.render();
+        render_saturation(&mut text, &[0.5, 0.25]);
+        assert!(text.contains("# TYPE bloom_filter_filter_checks_total counter\n"));
+        assert_eq!(
+            text.matches("# TYPE bloom_filter_filter_checks_total")
+                .count(),
+            1
+        );
+        assert!(text.contains("bloom_filter_filter_checks_total{result=\"no\"} 3\n"));
+        assert!(text.contains("bloom_filter_filter_checks_total{result=\"maybe\"} 1\n"));
+        assert!(text.contains("bloom_filter_database_lookup_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("bloom_filter_database_lookup_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("bloom_filter_database_lookup_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("bloom_filter_database_lookup_seconds_count 2\n"));
+        assert!(text.contains("# HELP bloom_filter_responses_total Requests answered\n"));
+        assert!(text.contains("bloom_filter_responses_total{method=\"GET\",status=\"409\"} 1\n"));
+        assert!(text.contains("bloom_filter_responses_total{method=\"other\",status=\"200\"} 1\n"));
+        assert!(text.contains("# TYPE bloom_filter_filter_saturation gauge\n"));
+        assert!(text.contains("bloom_filter_filter_saturation{slice=\"1\"} 0.25\n"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Single-use nonces tying registrations to an availability check
+//!
+//! With `add_nonce_secret` set, an available answer to `GET /email` carries
+//! an `add-nonce` header, which `POST /email` must present for the same
+//! email before `add_nonce_ttl` runs out, so scripts can't register emails in
+//! bulk without going through the check. A nonce is
+//! `<key id>.<id>.<expiry>.<signature>`, signed with one of the keys so that
+//! it can't be made up, and binding the canonical email it was issued for.
+//! A nonce is verified before anything else, but only spent once the
+//! registration passed the quarantine and membership checks, so that a
+//! registration refused by those can be retried with the same nonce. Spent
+//! nonces are each remembered under `__nonce:<id>`, holding when they
+//! expire, so registrations don't contend for a single key; those that
+//! expired are forgotten when the store runs out of space. Without compare
+//! and swap, registrations presenting the same nonce at the same moment may
+//! both get through.
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    keys::{self, Keyring},
+    kv::Kv,
+    verification::decode_hex,
+};
+
+/// The header nonces are issued in and presented back in
+pub(crate) const HEADER: &str = "add-nonce";
+/// What the keys of spent nonces are prefixed with
+const SPENT_PREFIX: &str = "__nonce:";
+/// Where nonces were all remembered together, read until they expired
+const LEGACY_SPENT_KEY: &str = "__spent_nonces";
+/// The keyring's name in the key usage report
+const KEYRING: &str = "add_nonce";
+
+/// Why a nonce was refused
+#[derive(PartialEq, Debug)]
+pub(crate) enum Refusal {
+    Missing,
+    Malformed,
+    /// Signed with a key that isn't configured, or for another email
+    BadSignature,
+    Expired,
+    Spent,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Refusal::Missing => "no add-nonce was presented",
+            Refusal::Malformed => "the add-nonce is malformed",
+            Refusal::BadSignature => "the add-nonce wasn't issued for this email",
+            Refusal::Expired => "the add-nonce has expired",
+            Refusal::Spent => "the add-nonce was already used",
+        })
+    }
+}
+
+fn mac(secret: &str, id: &str, expires: u64, email: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{id}.{expires}.{email}").as_bytes());
+    mac
+}
+
+/// A nonce for registering the canonical `email` within `ttl`
+pub(crate) fn issue(keys: &Keyring, email: &str, ttl: Duration) -> Result<Option<String>> {
+    let Some((key_id, secret)) = keys.signer() else {
+        return Ok(None);
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let id = format!(
+        "{:08x}{:08x}",
+        crate::murmur3(&(now.as_nanos(), email)),
+        crate::fnv(&(email, now.as_nanos()))
+    );
+    let expires = now.as_secs() + ttl.as_secs();
+    let signature: String = mac(secret, &id, expires, email)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok(Some(format!("{key_id}.{id}.{expires}.{signature}")))
+}
+
+/// The ID and expiry of a nonce issued for `email`
+fn check<'a>(
+    keys: &Keyring,
+    email: &str,
+    nonce: &'a str,
+    now: u64,
+) -> Result<(&'a str, u64, &'a str), Refusal> {
+    if nonce.trim().is_empty() {
+        return Err(Refusal::Missing);
+    }
+    let mut parts = nonce.trim().rsplitn(4, '.');
+    let (Some(signature), Some(expires), Some(id), Some(key_id)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Refusal::Malformed);
+    };
+    let expires: u64 = expires.parse().map_err(|_| Refusal::Malformed)?;
+    let signature = decode_hex(signature).ok_or(Refusal::Malformed)?;
+    let secret = keys.get(key_id).ok_or(Refusal::BadSignature)?;
+    mac(secret, id, expires, email)
+        .verify_slice(&signature)
+        .map_err(|_| Refusal::BadSignature)?;
+    if now >= expires {
+        return Err(Refusal::Expired);
+    }
+    Ok((id, expires, key_id))
+}
+
+/// A nonce that was verified but not spent yet
+#[derive(Debug)]
+pub(crate) struct Verified {
+    id: String,
+    expires: u64,
+    key_id: String,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Verify a nonce presented for registering the canonical `email`
+pub(crate) fn verify(
+    keys: &Keyring,
+    email: &str,
+    nonce: &str,
+) -> Result<Result<Verified, Refusal>> {
+    Ok(
+        check(keys, email, nonce, now()?).map(|(id, expires, key_id)| Verified {
+            id: id.to_owned(),
+            expires,
+            key_id: key_id.to_owned(),
+        }),
+    )
+}
+
+/// Whether a spent nonce's key holding `value` still refuses it at `now`,
+/// until the expiry it holds
+fn refuses(value: &[u8], now: u64) -> bool {
+    <[u8; 8]>::try_from(value).is_ok_and(|expires| u64::from_be_bytes(expires) > now)
+}
+
+/// Use up a verified nonce, unless it already was
+pub(crate) fn spend(store: &Kv, verified: &Verified) -> Result<Result<(), Refusal>> {
+    let now = now()?;
+    let key = format!("{SPENT_PREFIX}{}", verified.id);
+    let spent = store.get(&key)?;
+    if spent.is_some_and(|value| refuses(&value, now)) || spent_before(store, &verified.id, now)? {
+        return Ok(Err(Refusal::Spent));
+    }
+    store.set(&key, &verified.expires.to_be_bytes())?;
+    keys::record_use(store, KEYRING, &verified.key_id)?;
+    Ok(Ok(()))
+}
+
+/// Whether nonce `id` is among those spent while they were remembered
+/// together, deleting them once they all expired
+fn spent_before(store: &Kv, id: &str, now: u64) -> Result<bool> {
+    let Some(json) = store.get(LEGACY_SPENT_KEY)? else {
+        return Ok(false);
+    };
+    let mut spent: BTreeMap<String, u64> = serde_json::from_slice(&json)?;
+    spent.retain(|_, expires| *expires > now);
+    if spent.is_empty() {
+        store.delete(LEGACY_SPENT_KEY)?;
+    }
+    Ok(spent.contains_key(id))
+}
+
+/// Forget the spent nonces that expired, which no registration would be
+/// let through with anyway, returning how many bytes that freed
+pub(crate) fn forget_expired(store: &Kv) -> Result<u64> {
+    let now = now()?;
+    let mut freed = 0;
+    for key in store.keys()? {
+        if !key.starts_with(SPENT_PREFIX) {
+            continue;
+        }
+        let Some(value) = store.get(&key)? else {
+            continue;
+        };
+        if !refuses(&value, now) {
+            store.delete(&key)?;
+            freed += (key.len() + value.len()) as u64;
+        }
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_are_bound_and_single_use() {
+        let keys: Keyring = "n2:new, n1:old".parse().unwrap();
+        let nonce = issue(&keys, "me@example.com", Duration::from_secs(60))
+            .unwrap()
+            .unwrap();
+        assert!(nonce.starts_with("n2."));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (id, expires, key_id) = check(&keys, "me@example.com", &nonce, now).unwrap();
+        assert_eq!((id.len(), key_id), (16, "n2"));
+        assert!(expires >= now + 59);
+        assert_eq!(
+            check(&keys, "you@example.com", &nonce, now),
+            Err(Refusal::BadSignature)
+        );
+        assert_eq!(
+            check(&keys, "me@example.com", &nonce, expires),
+            Err(Refusal::Expired)
+        );
+        assert_eq!(
+            check(&keys, "me@example.com", "n2.abc", now),
+            Err(Refusal::Malformed)
+        );
+        assert_eq!(
+            check(&keys, "me@example.com", "", now),
+            Err(Refusal::Missing)
+        );
+        let rotated: Keyring = "n1:old".parse().unwrap();
+        assert_eq!(
+            check(&rotated, "me@example.com", &nonce, now),
+            Err(Refusal::BadSignature)
+        );
+        assert_eq!(
+            issue(&Keyring::default(), "me@example.com", Duration::ZERO).unwrap(),
+            None
+        );
+
+        let spent = 100u64.to_be_bytes();
+        assert!(refuses(&spent, 50));
+        assert!(refuses(&spent, 99));
+        assert!(!refuses(&spent, 100));
+        assert!(!refuses(b"garbage", 50));
+    }
+}
@@ -0,0 +1,61 @@
+//! Email canonicalization before hashing and lookup
+//!
+//! Domains are case-insensitive, so `me@Example.com` and `me@example.com` are
+//! always the same address. Folding the case of the local part and dropping
+//! the dots Gmail ignores are opt-in, as other providers may tell those
+//! addresses apart. Plus tags are handled by `plus_alias_domains`.
+
+/// The domains on which dots in the local part are ignored
+const GMAIL_DOMAINS: &[&str] = &["gmail.com", "googlemail.com"];
+
+/// How emails are canonicalized
+#[derive(Clone, Copy, Default, Debug, serde::Serialize)]
+pub(crate) struct Normalization {
+    /// Whether the local part is lowercased too
+    pub fold_local_case: bool,
+    /// Whether dots are dropped from Gmail local parts, which are then all
+    /// on `gmail.com`
+    pub strip_gmail_dots: bool,
+}
+
+impl Normalization {
+    /// The canonical form of `email`
+    pub fn apply(self, email: &str) -> String {
+        let email = email.trim();
+        let Some((local, domain)) = email.rsplit_once('@') else {
+            return email.to_owned();
+        };
+        let mut domain = domain.to_ascii_lowercase();
+        let mut local = if self.fold_local_case {
+            local.to_lowercase()
+        } else {
+            local.to_owned()
+        };
+        if self.strip_gmail_dots && GMAIL_DOMAINS.contains(&domain.as_str()) {
+            local.retain(|c| c != '.');
+            domain = GMAIL_DOMAINS[0].to_owned();
+        }
+        format!("{local}@{domain}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonicalizes_emails() {
+        let default = Normalization::default();
+        assert_eq!(default.apply(" Foo@Example.COM "), "Foo@example.com");
+        assert_eq!(default.apply("f.o.o@gmail.com"), "f.o.o@gmail.com");
+        assert_eq!(default.apply("not an email"), "not an email");
+
+        let all = Normalization {
+            fold_local_case: true,
+            strip_gmail_dots: true,
+        };
+        assert_eq!(all.apply("Foo@Example.com"), "foo@example.com");
+        assert_eq!(all.apply("F.o.o+tag@GoogleMail.com"), "foo+tag@gmail.com");
+        assert_eq!(all.apply("f.o.o@example.com"), "f.o.o@example.com");
+    }
+}
@@ -0,0 +1,67 @@
+//! Response time padding for availability checks
+//!
+//! A check the filter can't rule out goes on to the database and takes
+//! measurably longer than one it can, so how long a check takes gives away
+//! whether the email may be registered. With a `response_time_floor`, checks
+//! that finish sooner are held back until the floor, plus up to
+//! `response_time_jitter` of random time, has passed.
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    app::App,
+    metrics::{Counter, Histogram},
+};
+
+impl App {
+    /// Run `check`, then wait out what is left of the response time floor
+    ///
+    /// A check slower than the floor can't be padded and is counted, as the
+    /// floor then needs raising to mask it.
+    pub fn padded<T>(&self, check: impl FnOnce() -> T) -> T {
+        let Some(floor) = self.config.response_floor else {
+            return check();
+        };
+        let start = Instant::now();
+        let result = check();
+        let target = floor + jitter(self.config.response_jitter);
+        match target.checked_sub(start.elapsed()) {
+            Some(padding) => {
+                self.metrics
+                    .observe(Histogram::PaddingSeconds, padding.as_secs_f64());
+                sleep(padding);
+            }
+            None => self.metrics.count(Counter::PaddingOverrun),
+        }
+        result
+    }
+}
+
+/// A random duration of at most `max`
+pub(crate) fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    // Spread the clock's fast-moving low bits over the whole range
+    let fraction = crate::murmur3(&nanos) as f64 / u32::MAX as f64;
+    max.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_is_bounded() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        let max = Duration::from_millis(20);
+        assert!((0..100).all(|_| jitter(max) <= max));
+    }
+}
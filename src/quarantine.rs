@@ -0,0 +1,97 @@
+//! Quarantining emails suspected to be compromised
+//!
+//! Emails from breach feeds are added to a filter of their own with
+//! `POST /admin/quarantine`. Registering a quarantined email is refused with a
+//! 422 problem of type `/problems/quarantined` until its owner has been
+//! verified out of band, after which an admin can register it. The filter is
+//! sized for `quarantine_capacity` emails rather than the main filter's
+//! shape, and how often registrations ran into it is kept in the store.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, bulk, error::Error, kv::Kv, BloomFilter, Exists, ProbeSet};
+
+/// The key the filter of quarantined emails is stored under
+pub(crate) const QUARANTINE_KEY: &str = "__quarantine";
+/// The key counting registrations refused as quarantined
+const HITS_KEY: &str = "__quarantine_hits";
+/// The most emails one request may quarantine
+const MAX_EMAILS: usize = 100_000;
+
+#[derive(serde::Serialize)]
+struct Report {
+    received: usize,
+    quarantined: usize,
+    /// Emails the filter may already have held
+    already_present: usize,
+}
+
+impl App {
+    /// Add emails to the quarantine
+    pub fn quarantine(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let listing = bulk::Listing::of(&req, "quarantine.request", MAX_EMAILS, &self.config)?;
+        let mut filter = self.quarantined()?;
+        let (mut quarantined, mut already_present) = (0, 0);
+        // Nothing is stored unless every email was read and checked
+        let received = listing.each_chunk(|emails| {
+            for email in emails {
+                self.config.email_length.check(email)?;
+                // The canonical form, which every form of the email is
+                // checked as
+                let addresses = self.config.addresses(email);
+                let probes = ProbeSet::new(&addresses[0]);
+                if filter.exists_probes(&probes) == Exists::Maybe {
+                    already_present += 1;
+                    continue;
+                }
+                filter.insert_probes(&probes);
+                quarantined += 1;
+            }
+            Ok(())
+        })?;
+        let report = Report {
+            received,
+            quarantined,
+            already_present,
+        };
+        self.save_filter_at(QUARANTINE_KEY, &filter)?;
+        eprintln!(
+            "quarantined {} of {} emails",
+            report.quarantined, report.received
+        );
+        Ok(crate::json_response(&report)?)
+    }
+
+    /// The filter of quarantined emails, empty if none were quarantined
+    pub fn quarantined(&self) -> Result<BloomFilter> {
+        match self.load_slice(QUARANTINE_KEY)? {
+            Some(filter) => Ok(filter),
+            None => Ok(BloomFilter::new(
+                self.config.seed.fresh(self.config.quarantine)?,
+            )),
+        }
+    }
+
+    /// Whether the canonical `email` may be quarantined, counting it if so
+    pub fn is_quarantined(&self, email: &str) -> Result<bool> {
+        let Some(filter) = self.load_slice(QUARANTINE_KEY)? else {
+            return Ok(false);
+        };
+        if filter.exists_probes(&ProbeSet::new(&email)) == Exists::No {
+            return Ok(false);
+        }
+        self.store
+            .set(HITS_KEY, &(hits(&self.store)? + 1).to_be_bytes())?;
+        Ok(true)
+    }
+}
+
+/// How many registrations were refused as quarantined
+pub(crate) fn hits(store: &Kv) -> Result<u64> {
+    Ok(store
+        .get(HITS_KEY)?
+        .and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?)))
+        .unwrap_or(0))
+}
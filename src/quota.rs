@@ -0,0 +1,207 @@
+//! A soft budget on expensive user lookups, store-wide and per client
+//!
+//! The store-wide budget is kept as a single counter per fixed time window,
+//! protecting the database's total throughput. With `client_lookup_budget`
+//! set, each client's lookups in the window are also counted, in a
+//! count-min sketch keyed by the client's address, and a client may take at
+//! most that many. Once more than half of the store-wide budget is spent,
+//! clients are held to a share of theirs that shrinks with what is left, so
+//! a noisy client that spent its budget early can't take the rest from
+//! quieter ones. Both are updated without compare and swap, so concurrent
+//! requests may overshoot the limits slightly.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{app::App, domain_quota::Sketch, error::Error, kv::Kv};
+
+const BUDGET_KEY: &str = "__lookup_budget";
+const CLIENT_KEY: &str = "__client_lookup_budget";
+
+/// The limits a lookup must fit in
+#[derive(Clone, Copy)]
+pub(crate) struct Limits {
+    /// Lookups per window across all clients, `None` for unlimited
+    pub total: Option<u64>,
+    /// Lookups per window for each client, `None` for unlimited
+    pub per_client: Option<u32>,
+    pub window: Duration,
+}
+
+/// Why a lookup didn't fit in the budget
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Exhausted {
+    /// The store-wide budget is spent
+    Total,
+    /// The client spent its share
+    Client,
+}
+
+/// Try to take one lookup by `client` out of the current window's budget
+pub(crate) fn try_acquire(store: &Kv, limits: Limits, client: &str) -> Result<Option<Exhausted>> {
+    let window = now()? / limits.window.as_secs();
+    let current = store.get(BUDGET_KEY)?;
+    let next = match limits.total {
+        Some(limit) => match next_state(current.as_deref(), window, limit) {
+            Some(next) => Some(next),
+            None => return Ok(Some(Exhausted::Total)),
+        },
+        None => None,
+    };
+    let used = used(current.as_deref(), window);
+    if let Some(per_client) = limits.per_client {
+        let mut sketch = match store.get(CLIENT_KEY)? {
+            Some(bytes) => Sketch::from_bytes(&bytes, window),
+            None => Sketch::new(window),
+        };
+        if sketch.estimate(client) >= fair_share(per_client, used, limits.total) {
+            return Ok(Some(Exhausted::Client));
+        }
+        sketch.increment(client);
+        store.set(CLIENT_KEY, &sketch.to_bytes())?;
+    }
+    if let Some(next) = next {
+        store.set(BUDGET_KEY, &next)?;
+    }
+    Ok(None)
+}
+
+/// Seconds until the current window's budget is replenished
+pub(crate) fn seconds_until_reset(window: Duration) -> u64 {
+    let window = window.as_secs();
+    now().map(|secs| window - secs % window).unwrap_or(window)
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// How many lookups the stored budget says were made in `window`
+fn used(current: Option<&[u8]>, window: u64) -> u64 {
+    match current {
+        Some(v) if v.len() == 16 && v[..8] == window.to_be_bytes() => {
+            u64::from_be_bytes(v[8..].try_into().unwrap())
+        }
+        // Missing, stale or unreadable budgets start over
+        _ => 0,
+    }
+}
+
+/// Compute the stored budget after one more lookup in `window`
+///
+/// Returns `None` when the lookup doesn't fit in the budget.
+fn next_state(current: Option<&[u8]>, window: u64, limit: u64) -> Option<[u8; 16]> {
+    let used = used(current, window);
+    if used >= limit {
+        return None;
+    }
+    let mut next = [0u8; 16];
+    next[..8].copy_from_slice(&window.to_be_bytes());
+    next[8..].copy_from_slice(&(used + 1).to_be_bytes());
+    Some(next)
+}
+
+/// How many lookups a client may have made in a window where `used` of the
+/// `total` budget are spent
+///
+/// Until half the budget is spent that is the whole `per_client` allowance,
+/// which then shrinks in step with what is left, down to a single lookup.
+fn fair_share(per_client: u32, used: u64, total: Option<u64>) -> u32 {
+    let Some(total) = total.filter(|&total| total > 0) else {
+        return per_client;
+    };
+    let left = total.saturating_sub(used);
+    if left * 2 >= total {
+        return per_client;
+    }
+    ((per_client as u64 * left * 2 / total) as u32).max(1)
+}
+
+impl App {
+    /// Report the state of the lookup budget, and of the `client` query
+    /// parameter's share of it
+    pub fn budget(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: BudgetQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let limits = self.lookup_limits();
+        let window = now()? / limits.window.as_secs();
+        let used = used(self.store.get(BUDGET_KEY)?.as_deref(), window);
+        let client = match (&query.client, limits.per_client) {
+            (Some(client), Some(_)) => {
+                let sketch = match self.store.get(CLIENT_KEY)? {
+                    Some(bytes) => Sketch::from_bytes(&bytes, window),
+                    None => Sketch::new(window),
+                };
+                Some(ClientState {
+                    client: client.clone(),
+                    used: sketch.estimate(client),
+                })
+            }
+            _ => None,
+        };
+        Ok(crate::json_response(&BudgetState {
+            window_secs: limits.window.as_secs(),
+            resets_in_secs: seconds_until_reset(limits.window),
+            used,
+            limit: limits.total,
+            per_client: limits.per_client,
+            client_share: limits
+                .per_client
+                .map(|per_client| fair_share(per_client, used, limits.total)),
+            client,
+        })?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BudgetQuery {
+    /// The address of the client to report on
+    client: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct BudgetState {
+    window_secs: u64,
+    resets_in_secs: u64,
+    /// Lookups made in the current window, across all clients
+    used: u64,
+    limit: Option<u64>,
+    per_client: Option<u32>,
+    /// How many lookups a client may make in the window at this point
+    client_share: Option<u32>,
+    client: Option<ClientState>,
+}
+
+#[derive(serde::Serialize)]
+struct ClientState {
+    client: String,
+    /// Lookups the client made in the window, possibly overcounted
+    used: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_resets_each_window() {
+        let first = next_state(None, 7, 2).unwrap();
+        let second = next_state(Some(&first), 7, 2).unwrap();
+        assert!(next_state(Some(&second), 7, 2).is_none());
+        assert!(next_state(Some(&second), 8, 2).is_some());
+        assert!(next_state(None, 7, 0).is_none());
+    }
+
+    #[test]
+    fn client_shares_shrink_once_half_is_spent() {
+        assert_eq!(fair_share(10, 0, None), 10);
+        assert_eq!(fair_share(10, 0, Some(100)), 10);
+        assert_eq!(fair_share(10, 50, Some(100)), 10);
+        assert_eq!(fair_share(10, 75, Some(100)), 5);
+        assert_eq!(fair_share(10, 99, Some(100)), 1);
+        assert_eq!(fair_share(10, 100, Some(100)), 1);
+        assert_eq!(fair_share(10, 0, Some(0)), 10);
+    }
+}
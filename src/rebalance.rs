@@ -0,0 +1,263 @@
+//! Evening out the shards of a filter
+//!
+//! Shards hold consecutive runs of a slice's bits, so when the emails
+//! inserted hash unevenly onto the bits, as they do when most share a few
+//! domains, some shards fill up well before the rest and checks probing them
+//! answer `Maybe` more often than the slice's overall fill suggests.
+//! `POST /admin/rebalance` re-derives how the bits of each sharded slice
+//! whose shards are too uneven map onto them: bit `i` is stored at position
+//! `i * multiplier % num_bits` of the shards, for a multiplier coprime to
+//! the number of bits. The multiplier is searched for simulated-annealing
+//! style, over a sample of the set bits. Each mapping is recorded in the
+//! slice's manifest with a generation, and its shards are stored under
+//! `<slice key>/m<generation>/<shard>`.
+//!
+//! The bits are then migrated one shard at a time, the manifest recording
+//! the shards written under the new mapping so far after each one. Checks
+//! meanwhile read the bits those shards hold from them, and every other bit
+//! from the shards of the old mapping, which stay whole until the migration
+//! completes, so lookups stay correct throughout. Writers inserting during a
+//! migration complete it, writing every shard under the new mapping, and a
+//! migration cut short is resumed by the next rebalance.
+
+use anyhow::{Context, Result};
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, error::Error, shards, transaction::Interleaved};
+
+/// How many set bits the search spreads, sampled evenly from the slice
+const SAMPLE: usize = 8192;
+/// How many multipliers the search tries
+const ROUNDS: usize = 64;
+/// How much worse a multiplier may be, at the start of the search, and
+/// still be moved to with a chance of 1/e
+const TEMPERATURE: f64 = 0.05;
+
+/// How the bits of a slice map onto the positions of its shards
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Mapping {
+    /// 0 for the mapping slices are first sharded with, which leaves every
+    /// bit at its own position
+    pub generation: u32,
+    pub multiplier: u32,
+    /// The multiplier's inverse modulo the number of bits
+    inverse: u32,
+    num_bits: u64,
+}
+
+impl Mapping {
+    /// The mapping slices of `num_bits` are first sharded with
+    pub fn identity(num_bits: usize) -> Self {
+        Self {
+            generation: 0,
+            multiplier: 1,
+            inverse: 1,
+            num_bits: num_bits as u64,
+        }
+    }
+
+    /// `None` unless `multiplier` is coprime to `num_bits`
+    pub fn new(generation: u32, multiplier: u32, num_bits: usize) -> Option<Self> {
+        let inverse = inverse(multiplier.into(), num_bits as u64)?;
+        Some(Self {
+            generation,
+            multiplier,
+            inverse: inverse.try_into().ok()?,
+            num_bits: num_bits as u64,
+        })
+    }
+
+    /// The position bit `index` is stored at
+    pub fn position(&self, index: usize) -> usize {
+        (index as u64 * u64::from(self.multiplier) % self.num_bits) as usize
+    }
+
+    /// The bit stored at `position`
+    pub fn index(&self, position: usize) -> usize {
+        (position as u64 * u64::from(self.inverse) % self.num_bits) as usize
+    }
+}
+
+/// The inverse of `a` modulo `m`, if they are coprime
+fn inverse(a: u64, m: u64) -> Option<u64> {
+    let (mut r0, mut r1) = (i128::from(m), i128::from(a % m));
+    let (mut t0, mut t1) = (0i128, 1i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (t0, t1) = (t1, t0 - q * t1);
+    }
+    if r0 != 1 && m != 1 {
+        return None;
+    }
+    Some(t0.rem_euclid(i128::from(m)) as u64)
+}
+
+/// How much denser than the slice its densest shard is, for set bits at
+/// `positions` of a slice of `num_bits` in shards of `shard_bits`
+///
+/// 1 when the set bits are spread evenly.
+pub(crate) fn imbalance(
+    positions: impl Iterator<Item = usize>,
+    num_bits: usize,
+    shard_bits: usize,
+) -> f64 {
+    let mut counts = vec![0u64; num_bits.div_ceil(shard_bits)];
+    let mut set = 0;
+    for position in positions {
+        counts[position / shard_bits] += 1;
+        set += 1;
+    }
+    if set == 0 {
+        return 1.0;
+    }
+    let densest = counts
+        .iter()
+        .enumerate()
+        .map(|(shard, &count)| {
+            let len = shard_bits.min(num_bits - shard * shard_bits);
+            count as f64 / len as f64
+        })
+        .fold(0.0, f64::max);
+    densest / (set as f64 / num_bits as f64)
+}
+
+/// At most `SAMPLE` of the `count` set bits `ones`, spread evenly over them
+pub(crate) fn sample(ones: impl Iterator<Item = usize>, count: usize) -> Vec<usize> {
+    let step = count.div_ceil(SAMPLE).max(1);
+    ones.step_by(step).collect()
+}
+
+/// Search for the mapping spreading the set bits `ones` most evenly over
+/// shards of `shard_bits`, starting from the current mapping `from`
+///
+/// Each round tries a multiplier a random step away from the one last moved
+/// to, the steps shrinking as the search cools, moving to it when it is
+/// better and, with a chance that shrinks as it cools, when it is worse.
+/// Returns `from` itself when nothing better was found.
+pub(crate) fn anneal(ones: &[usize], shard_bits: usize, from: Mapping, seed: u64) -> Mapping {
+    let num_bits = from.num_bits as usize;
+    let cost = |mapping: &Mapping| {
+        imbalance(
+            ones.iter().map(|&index| mapping.position(index)),
+            num_bits,
+            shard_bits,
+        )
+    };
+    let mut rng = seed;
+    let mut current = (from, cost(&from));
+    let mut best = current;
+    for round in 0..ROUNDS {
+        let temperature = 1.0 - round as f64 / ROUNDS as f64;
+        let span = ((from.num_bits as f64 * temperature) as u64).max(2);
+        let multiplier = (u64::from(current.0.multiplier) + next(&mut rng) % span) % from.num_bits;
+        let Some(candidate) = Mapping::new(from.generation + 1, multiplier as u32, num_bits) else {
+            continue;
+        };
+        let candidate_cost = cost(&candidate);
+        let worse = (candidate_cost - current.1) / (TEMPERATURE * temperature);
+        if candidate_cost < current.1 || unit(next(&mut rng)) < (-worse).exp() {
+            current = (candidate, candidate_cost);
+            if candidate_cost < best.1 {
+                best = current;
+            }
+        }
+    }
+    best.0
+}
+
+/// The next number of the SplitMix64 sequence at `state`
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// `n` as a fraction in `[0, 1)`
+fn unit(n: u64) -> f64 {
+    (n >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl App {
+    /// Rebalance the shards of every sharded slice more uneven than the
+    /// `threshold` query parameter allows
+    pub fn rebalance(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: Query = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        if query.threshold.is_nan() || query.threshold < 1.0 {
+            return Err(Error::BadRequest("`threshold` must be at least 1".into()));
+        }
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).context("no randomness for the search")?;
+        let mut slices = Vec::new();
+        for (key, state) in self.stored_slices()? {
+            if !shards::is_manifest(&state) {
+                continue;
+            }
+            let seed = u64::from_be_bytes(seed) ^ slices.len() as u64;
+            match shards::rebalance(&self.store, &key, &state, query.threshold, seed) {
+                Ok(slice) => slices.push(slice),
+                Err(e) if e.is::<Interleaved>() => {
+                    return Err(Error::Conflict(format!(
+                        "the slice at {key} was written while it was being rebalanced, try again"
+                    )))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(crate::json_response(&Report { slices })?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Query {
+    /// The imbalance past which a slice is rebalanced
+    #[serde(default = "default_threshold")]
+    threshold: f64,
+}
+
+fn default_threshold() -> f64 {
+    1.25
+}
+
+#[derive(serde::Serialize)]
+struct Report {
+    slices: Vec<shards::Rebalanced>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_are_bijections() {
+        assert!(Mapping::new(1, 4, 1000).is_none());
+        let mapping = Mapping::new(1, 337, 1000).unwrap();
+        let mut seen = vec![false; 1000];
+        for index in 0..1000 {
+            let position = mapping.position(index);
+            assert!(!seen[position]);
+            seen[position] = true;
+            assert_eq!(mapping.index(position), index);
+        }
+        let identity = Mapping::identity(1000);
+        assert_eq!((identity.position(417), identity.index(417)), (417, 417));
+    }
+
+    #[test]
+    fn annealing_spreads_clustered_bits() {
+        // Every set bit in the first of eight shards
+        let ones: Vec<usize> = (0..1024).step_by(4).collect();
+        let from = Mapping::identity(8192);
+        assert_eq!(imbalance(ones.iter().copied(), 8192, 1024), 8.0);
+        let found = anneal(&ones, 1024, from, 7);
+        assert_eq!(found.generation, 1);
+        let after = imbalance(ones.iter().map(|&i| found.position(i)), 8192, 1024);
+        assert!(after < 1.5, "{after}");
+        assert_eq!(imbalance(std::iter::empty(), 8192, 1024), 1.0);
+        assert_eq!(sample(0..100_000, 100_000).len(), 7693);
+    }
+}
@@ -0,0 +1,289 @@
+//! Regenerating the filter from the database
+//!
+//! A filter whose state got corrupted, or that was created with a shape that
+//! no longer fits, can't be repaired in place. `POST /rebuild` scans every
+//! row of the filter's table twice, once to count the rows and once to insert
+//! them into a fresh filter sized for twice as many emails, and stores that
+//! as the filter's only slice under a new generation. Writers still holding
+//! the old filter then find its generation moved on and reload. Slices beyond
+//! the first are deleted only after the new one is stored, so readers in
+//! between merely get extra `Maybe`s. Rows created after the second scan are
+//! left to the next reconciliation, which carries on from the last row
+//! scanned. The lookalike filter isn't rebuilt.
+//!
+//! Each rebuild compacts the filter, dropping the bits of removed emails
+//! that counting filters left set when their counters lost count, and of
+//! emails deleted from the database. What it achieved is recorded under
+//! `__compactions`, the last `MAX_COMPACTIONS` of them, and listed by
+//! `GET /admin/compactions`.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    app::App,
+    config::Mode,
+    db::{self, Created},
+    error::Error,
+    kv::Kv,
+    reconcile, scalable, BloomFilter, Params, ProbeSet,
+};
+
+const COMPACTIONS_KEY: &str = "__compactions";
+/// How many compactions are kept
+const MAX_COMPACTIONS: usize = 100;
+
+/// How many rows are read at a time
+const PAGE: usize = 10_000;
+/// The false positive rate a rebuilt filter is sized for
+const FP_RATE: f64 = 0.01;
+/// How many times the rows scanned a rebuilt filter is sized for, leaving
+/// room for further signups
+const HEADROOM: u64 = 2;
+
+#[derive(serde::Serialize)]
+struct Report {
+    scanned: usize,
+    num_bits: usize,
+    num_hashes: usize,
+    generation: u64,
+}
+
+/// What a rebuild achieved
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Compaction {
+    /// When it finished, in Unix seconds
+    at: u64,
+    /// The generation of the rebuilt filter
+    generation: u64,
+    scanned: usize,
+    duration_secs: f64,
+    /// Bytes written to the key-value stores, Redis bitmaps not included
+    bytes_written: u64,
+    /// `None` when the filter replaced couldn't be loaded
+    before: Option<Fill>,
+    after: Fill,
+    /// How many fewer bits are set, negative when the rebuilt filter grew
+    /// and sets more
+    bits_reclaimed: Option<i64>,
+}
+
+/// How full a filter is
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Fill {
+    slices: usize,
+    num_bits: usize,
+    set_bits: usize,
+    /// The chance that a check of an email never inserted answers `Maybe`
+    false_positive_rate: f64,
+}
+
+impl Fill {
+    fn of(slices: &[BloomFilter]) -> Self {
+        let set = |slice: &BloomFilter| slice.array.count_ones();
+        let right = slices.iter().map(|slice| {
+            let params = slice.params();
+            let saturation = set(slice) as f64 / params.num_bits as f64;
+            1.0 - saturation.powi(params.num_hashes as i32)
+        });
+        Self {
+            slices: slices.len(),
+            num_bits: slices.iter().map(|slice| slice.params().num_bits).sum(),
+            set_bits: slices.iter().map(set).sum(),
+            false_positive_rate: 1.0 - right.product::<f64>(),
+        }
+    }
+}
+
+/// Record `compaction`, dropping the oldest beyond `MAX_COMPACTIONS`
+fn record(store: &Kv, compaction: Compaction) -> Result<()> {
+    let mut compactions = load(store)?;
+    compactions.push(compaction);
+    let excess = compactions.len().saturating_sub(MAX_COMPACTIONS);
+    compactions.drain(..excess);
+    store.set(COMPACTIONS_KEY, &serde_json::to_vec(&compactions)?)
+}
+
+/// The recorded compactions, oldest first
+fn load(store: &Kv) -> Result<Vec<Compaction>> {
+    Ok(match store.get(COMPACTIONS_KEY)? {
+        Some(json) => serde_json::from_slice(&json)?,
+        None => Vec::new(),
+    })
+}
+
+impl App {
+    /// Replace the filter with one built from the database
+    pub fn rebuild(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        if self.config.rotation.is_some() {
+            // Putting every email into the current window would restart
+            // their expiry
+            return Err(Error::Conflict(
+                "rotating filters can't be rebuilt, they expire on their own".into(),
+            ));
+        }
+        if self.config.database == db::Backend::Simulated {
+            // It lists no rows, so the rebuilt filter would be empty
+            return Err(Error::Conflict(
+                "the simulated database can't be rebuilt from".into(),
+            ));
+        }
+        let started = Instant::now();
+        let written = self.store.bytes_written();
+        let before = match self.load_filter() {
+            Ok(filter) => Some(Fill::of(filter.slices())),
+            Err(e) => {
+                eprintln!("can't load the filter being rebuilt, not measuring it: {e:#}");
+                None
+            }
+        };
+        let mut rows = 0;
+        self.scan(|page| {
+            rows += page.len();
+            Ok(())
+        })?;
+        let params = self
+            .config
+            .seed
+            .fresh(sized_for(rows as u64, self.config.filter)?)?;
+
+        let mut filter = BloomFilter::new(params);
+        let mut scanned = 0;
+        let last = self.scan(|page| {
+            for row in page {
+                for email in self.config.addresses(&row.email) {
+                    filter.insert_probes(&ProbeSet::new(&email));
+                }
+            }
+            scanned += page.len();
+            Ok(())
+        })?;
+
+        let key = self.config.mode.state_key();
+        let filters = self.filters();
+        let stored = filters.state(key)?;
+        filter.generation = stored.as_deref().map_or(0, BloomFilter::stored_generation) + 1;
+        filters.save(key, &filter)?;
+        self.shadow_save(key, &filter);
+        for i in 1.. {
+            let slice_key = scalable::slice_key(key, i);
+            if !filters.delete(&slice_key)? {
+                break;
+            }
+            self.shadow_delete(&slice_key);
+        }
+        if let Some(last) = last {
+            reconcile::set_cursor(&self.store, key, &last)?;
+        }
+        eprintln!(
+            "rebuilt the filter at {key} from {scanned} rows, {} bits and {} hashes",
+            params.num_bits, params.num_hashes
+        );
+        let after = Fill::of(std::slice::from_ref(&filter));
+        record(
+            &self.store,
+            Compaction {
+                at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                generation: filter.generation,
+                scanned,
+                duration_secs: started.elapsed().as_secs_f64(),
+                bytes_written: self.store.bytes_written() - written,
+                bits_reclaimed: before
+                    .as_ref()
+                    .map(|before| before.set_bits as i64 - after.set_bits as i64),
+                before,
+                after,
+            },
+        )?;
+        Ok(crate::json_response(&Report {
+            scanned,
+            num_bits: params.num_bits,
+            num_hashes: params.num_hashes,
+            generation: filter.generation,
+        })?)
+    }
+
+    /// List the recorded compactions, newest first, at most the `limit`
+    /// query parameter of them
+    pub fn compactions(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: Query = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        let mut compactions = load(&self.store)?;
+        compactions.reverse();
+        compactions.truncate(query.limit);
+        Ok(crate::json_response(&compactions)?)
+    }
+
+    /// Pass every row of the filter's table to `visit`, a page at a time,
+    /// returning the last row
+    fn scan(&self, mut visit: impl FnMut(&[Created]) -> Result<()>) -> Result<Option<Created>> {
+        let mut last: Option<Created> = None;
+        loop {
+            let after = last.clone().unwrap_or_default();
+            let page = match self.config.mode {
+                Mode::Denylist => self.database.users_created_after(&after, PAGE)?,
+                Mode::Allowlist => self.database.invites_created_after(&after, PAGE)?,
+            };
+            visit(&page)?;
+            let full = page.len() == PAGE;
+            if let Some(row) = page.into_iter().last() {
+                last = Some(row);
+            }
+            if !full {
+                return Ok(last);
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Query {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+/// The shape of a rebuilt filter holding `rows` rows, never smaller than the
+/// `configured` one
+fn sized_for(rows: u64, configured: Params) -> Result<Params> {
+    let sized = Params::optimal(rows.max(1) * HEADROOM, FP_RATE, configured.counting)?
+        .hashed_like(configured);
+    Ok(if sized.num_bits > configured.num_bits {
+        sized
+    } else {
+        configured
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_shrinks_the_configured_shape() {
+        assert_eq!(sized_for(0, Params::LEGACY).unwrap(), Params::LEGACY);
+        let sized = sized_for(500, Params::LEGACY).unwrap();
+        assert_eq!(sized, Params::optimal(1000, FP_RATE, false).unwrap());
+        let counting = Params::new(64, 2, true).unwrap();
+        assert!(sized_for(500, counting).unwrap().counting);
+    }
+
+    #[test]
+    fn measures_fill_across_slices() {
+        let params = Params::new(64, 2, false).unwrap();
+        let (mut half, empty) = (BloomFilter::new(params), BloomFilter::new(params));
+        for i in 0..32 {
+            half.array.set(i, true);
+        }
+        let fill = Fill::of(&[half, empty]);
+        assert_eq!((fill.slices, fill.num_bits, fill.set_bits), (2, 128, 32));
+        assert_eq!(fill.false_positive_rate, 0.25);
+        assert_eq!(Fill::of(&[]).false_positive_rate, 0.0);
+    }
+}
@@ -0,0 +1,121 @@
+//! Backfilling emails that reached the database without going through `add`
+//!
+//! Other services or manual inserts can add rows the filter never saw, which
+//! it then wrongly reports as available. Reconciliation scans the rows
+//! created since the last scan, in creation order, and inserts the ones the
+//! filter rules out. Where the scan got to is kept in the store, so repeated
+//! runs only look at new rows.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+
+use crate::{
+    app::App, config::Mode, db::Created, error::Error, kv::Kv, metrics::Counter, verdict_cache,
+    Exists, ProbeSet,
+};
+
+/// The most rows one run may scan
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(serde::Deserialize)]
+struct ReconcileQuery {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    1000
+}
+
+#[derive(serde::Serialize)]
+struct Report {
+    scanned: usize,
+    /// Rows the filter was missing
+    inserted: usize,
+    /// The last row scanned, where the next run carries on from
+    cursor: Created,
+    /// Whether the scan caught up with the database
+    done: bool,
+}
+
+impl App {
+    /// Insert rows created since the last run that the filter is missing
+    ///
+    /// Backfilled emails aren't recorded for lookalike detection.
+    pub fn reconcile(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let query: ReconcileQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(Error::bad_request)?;
+        if !(1..=MAX_LIMIT).contains(&query.limit) {
+            return Err(Error::bad_request(format!(
+                "limit must be between 1 and {MAX_LIMIT}"
+            )));
+        }
+        let state_key = self.config.mode.state_key();
+        let after = cursor(&self.store, state_key)?;
+        let rows = match self.config.mode {
+            Mode::Denylist => self.database.users_created_after(&after, query.limit)?,
+            Mode::Allowlist => self.database.invites_created_after(&after, query.limit)?,
+        };
+
+        let (inserted, missing) = self.update_filter(|filter| {
+            let (mut inserted, mut missing) = (0, Vec::new());
+            for row in &rows {
+                let emails = self.config.addresses(&row.email);
+                if emails
+                    .iter()
+                    .any(|email| filter.exists_probes(&ProbeSet::new(email)) == Exists::No)
+                {
+                    for email in &emails {
+                        filter.insert_probes(&ProbeSet::new(email));
+                    }
+                    inserted += 1;
+                    missing.extend(emails);
+                }
+            }
+            Ok((inserted, missing))
+        })?;
+        if inserted > 0 {
+            eprintln!("reconciliation inserted {inserted} missing emails");
+            for _ in 0..inserted {
+                self.metrics.count(Counter::Backfilled);
+            }
+        }
+        // Earlier checks may have cached these emails as free
+        if let Some(ttl) = self.config.verdict_cache_ttl {
+            for email in &missing {
+                verdict_cache::put(&self.store, state_key, email, true, ttl)?;
+            }
+        }
+        // Only move on once the filter holds everything scanned before it
+        let cursor = match rows.last() {
+            Some(last) => {
+                set_cursor(&self.store, state_key, last)?;
+                last.clone()
+            }
+            None => after,
+        };
+        Ok(crate::json_response(&Report {
+            scanned: rows.len(),
+            inserted,
+            cursor,
+            done: rows.len() < query.limit,
+        })?)
+    }
+}
+
+fn cursor_key(state_key: &str) -> String {
+    format!("__reconcile_cursor:{state_key}")
+}
+
+/// Where the last scan of the filter's table stopped
+fn cursor(store: &Kv, state_key: &str) -> Result<Created> {
+    Ok(match store.get(&cursor_key(state_key))? {
+        Some(json) => serde_json::from_slice(&json)?,
+        None => Created::default(),
+    })
+}
+
+pub(crate) fn set_cursor(store: &Kv, state_key: &str, cursor: &Created) -> Result<()> {
+    store.set(&cursor_key(state_key), &serde_json::to_vec(cursor)?)
+}
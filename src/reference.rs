@@ -0,0 +1,190 @@
+//! Short reference codes for discussing an email without writing it down
+//!
+//! A reference is the key ID of one of the `reference_secret` keys followed by
+//! eight characters of Crockford base32 holding the first 40 bits of the
+//! HMAC-SHA256 of the canonical email, such as `k1-7FQK-2M9D`. Support staff
+//! can quote it in tickets instead of the address, and later check whether it
+//! belongs to an email someone gives them. Nothing is stored, so a reference
+//! can't be turned back into its email, and references made with a key keep
+//! verifying for as long as the key stays configured.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use spin_sdk::http::{Request, Response};
+
+use crate::{
+    app::App,
+    error::Error,
+    keys::{self, Keyring},
+};
+
+/// The keyring's name in the key usage report
+const KEYRING: &str = "reference";
+/// Crockford's base32 alphabet, without the easily confused I, L, O and U
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// How many bytes of the HMAC a reference holds
+const DIGEST_BYTES: usize = 5;
+
+#[derive(serde::Deserialize)]
+struct ReferenceRequest {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyRequest {
+    email: String,
+    reference: String,
+}
+
+#[derive(serde::Serialize)]
+struct Reference {
+    reference: String,
+}
+
+#[derive(serde::Serialize)]
+struct Verdict {
+    matches: bool,
+}
+
+impl App {
+    /// Make the reference code of an email
+    pub fn reference(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let body: ReferenceRequest = self.reference_body(&req)?;
+        let (id, secret) = self.config.reference_keys.signer().ok_or(Error::NotFound)?;
+        let email = self.config.normalization.apply(&body.email);
+        Ok(crate::json_response(&Reference {
+            reference: format!(
+                "{id}-{}",
+                encode(&mac(secret, &email).finalize().into_bytes())
+            ),
+        })?)
+    }
+
+    /// Check whether a reference code was made from an email
+    pub fn verify_reference(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        let body: VerifyRequest = self.reference_body(&req)?;
+        let email = self.config.normalization.apply(&body.email);
+        let id = verify(&self.config.reference_keys, &email, &body.reference);
+        if let Some(id) = id {
+            keys::record_use(&self.store, KEYRING, id)?;
+        }
+        Ok(crate::json_response(&Verdict {
+            matches: id.is_some(),
+        })?)
+    }
+
+    fn reference_body<T: serde::de::DeserializeOwned>(&self, req: &Request) -> Result<T, Error> {
+        if self.config.reference_keys.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let Some(body) = req.body().as_ref() else {
+            return Err(Error::bad_request("no body"));
+        };
+        serde_json::from_slice(body).map_err(Error::bad_request)
+    }
+}
+
+fn mac(secret: &str, email: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(email.as_bytes());
+    mac
+}
+
+/// An opaque name in logs for a `kind` of value, such as a request's client,
+/// made with the signing key so that it can't be matched against guessed
+/// values without the secret
+pub(crate) fn pseudonym(keys: &Keyring, kind: &str, value: &str) -> Option<String> {
+    let (_, secret) = keys.signer()?;
+    let digest = mac(secret, &format!("{kind}:{value}"))
+        .finalize()
+        .into_bytes();
+    Some(digest[..4].iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// The ID of the key `reference` was made from `email` with, if it was
+fn verify<'a>(keys: &Keyring, email: &str, reference: &'a str) -> Option<&'a str> {
+    let (id, code) = reference.trim().split_once('-')?;
+    let secret = keys.get(id)?;
+    let digest = decode(code)?;
+    mac(secret, email).verify_truncated_left(&digest).ok()?;
+    Some(id)
+}
+
+/// The first `DIGEST_BYTES` of `digest` in base32, in two groups of four
+fn encode(digest: &[u8]) -> String {
+    let bits = digest[..DIGEST_BYTES]
+        .iter()
+        .fold(0u64, |bits, &b| (bits << 8) | u64::from(b));
+    let chars: String = (0..8)
+        .rev()
+        .map(|i| ALPHABET[((bits >> (i * 5)) & 31) as usize] as char)
+        .collect();
+    format!("{}-{}", &chars[..4], &chars[4..])
+}
+
+/// The digest bytes of a code, forgiving case, dashes and the letters
+/// commonly misread for digits
+fn decode(code: &str) -> Option<[u8; DIGEST_BYTES]> {
+    let mut bits = 0u64;
+    let mut count = 0;
+    for c in code.chars().filter(|c| *c != '-') {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+        let value = ALPHABET.iter().position(|&a| a as char == c)?;
+        bits = (bits << 5) | value as u64;
+        count += 1;
+    }
+    if count != 8 {
+        return None;
+    }
+    let mut digest = [0; DIGEST_BYTES];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = (bits >> (8 * (DIGEST_BYTES - 1 - i))) as u8;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_round_trip() {
+        let keys: Keyring = "k2:new, k1:old".parse().unwrap();
+        let digest = mac("old", "me@example.com").finalize().into_bytes();
+        let code = encode(&digest);
+        assert_eq!(code.len(), 9);
+        assert_eq!(decode(&code).unwrap(), digest[..DIGEST_BYTES]);
+
+        let reference = format!("k1-{code}");
+        assert_eq!(verify(&keys, "me@example.com", &reference), Some("k1"));
+        assert_eq!(
+            verify(&keys, "me@example.com", &reference.to_lowercase()),
+            Some("k1")
+        );
+        assert_eq!(verify(&keys, "you@example.com", &reference), None);
+        assert_eq!(verify(&keys, "me@example.com", &format!("k2-{code}")), None);
+        assert_eq!(verify(&keys, "me@example.com", "k3-0000-0000"), None);
+        assert_eq!(verify(&keys, "me@example.com", "k1-0000"), None);
+        assert_eq!(decode("ABCD-EFGU"), None);
+        assert_eq!(decode("O0IL-0000"), decode("0011-0000"));
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_key() {
+        let keys: Keyring = "k1:secret".parse().unwrap();
+        let other: Keyring = "k1:other".parse().unwrap();
+        let name = pseudonym(&keys, "client", "192.0.2.1").unwrap();
+        assert_eq!(name.len(), 8);
+        assert_ne!(pseudonym(&other, "client", "192.0.2.1").unwrap(), name);
+        assert_ne!(pseudonym(&keys, "client", "192.0.2.2").unwrap(), name);
+        assert_ne!(pseudonym(&keys, "email", "192.0.2.1").unwrap(), name);
+        assert_eq!(pseudonym(&Keyring::default(), "client", "192.0.2.1"), None);
+    }
+}
@@ -0,0 +1,388 @@
+//! Filters that forget emails once their time window has passed
+//!
+//! Bloom filters can't drop an email, so without counting every email inserted
+//! stays a `Maybe` forever, even once its reservation has lapsed in the
+//! database. With `bloom_rotation_window` set, the filter is instead a chain
+//! of `bloom_rotation_windows` slices, one per window of that length: emails
+//! go into the current window's slice, checks consult every window still
+//! active, and once a window passes out of the chain its slice is deleted.
+//! An email is thus forgotten between `windows - 1` and `windows` window
+//! lengths after it was inserted. The slice of window `n` is stored under
+//! `<state key>@<n>`, windows counting from the Unix epoch.
+//!
+//! With `bloom_rotation_schedule` set instead, windows start whenever its
+//! cron expression fires, so that they can follow business schedules such as
+//! nightly at 03:00 (`0 3 * * *`). Each window's slice is then stored under
+//! `<state key>@<start>`, the Unix time the window started at. When the
+//! current window started and when the next one starts are kept under
+//! `<state key>@timing` for `GET /stats`.
+
+use anyhow::{Context, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::kv::Kv;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// How many days a schedule is searched for its next or previous firing,
+/// enough for one firing only on 29 February
+const SEARCHED_DAYS: u64 = 8 * 366;
+
+/// How windows follow each other
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Period {
+    /// Windows of a fixed length, counted from the Unix epoch
+    Every(Duration),
+    /// Windows starting whenever a cron expression fires
+    Schedule(Schedule),
+}
+
+/// The windows active when a request came in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Rotation {
+    pub period: Period,
+    /// How many windows are active at a time
+    pub windows: usize,
+    /// The oldest active window: its number for fixed windows, the Unix time
+    /// it started at for scheduled ones
+    first: u64,
+}
+
+/// When the current window started and when the next one starts, as Unix
+/// times
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Timing {
+    pub current_window_started: u64,
+    pub next_rotation: u64,
+}
+
+impl Rotation {
+    /// The windows active now
+    pub fn now(period: Period, windows: usize) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::at(period, windows, now)
+    }
+
+    fn at(period: Period, windows: usize, now: Duration) -> Self {
+        let first = match period {
+            Period::Every(window) => {
+                let current = now.as_secs() / window.as_secs().max(1);
+                (current + 1).saturating_sub(windows as u64)
+            }
+            // A schedule that hasn't fired yet has its first firing current
+            Period::Schedule(schedule) => {
+                let current = schedule
+                    .before(now.as_secs() + 1)
+                    .or_else(|| schedule.after(0))
+                    .unwrap_or_default();
+                let mut first = current;
+                for _ in 1..windows {
+                    match schedule.before(first) {
+                        Some(earlier) => first = earlier,
+                        None => break,
+                    }
+                }
+                first
+            }
+        };
+        Self {
+            period,
+            windows,
+            first,
+        }
+    }
+
+    /// The number or start of active window `index`, oldest first
+    fn window(&self, index: usize) -> u64 {
+        match self.period {
+            Period::Every(_) => self.first + index as u64,
+            Period::Schedule(schedule) => (0..index).fold(self.first, |start, _| {
+                schedule.after(start).unwrap_or(start)
+            }),
+        }
+    }
+
+    /// The key active window `index`, oldest first, is stored under
+    pub fn key(&self, state_key: &str, index: usize) -> String {
+        window_key(state_key, self.window(index))
+    }
+
+    /// The keys of the windows that passed, most recent first, which are
+    /// deleted until one is found missing
+    pub fn expired(&self, state_key: &str) -> Box<dyn Iterator<Item = String> + '_> {
+        let state_key = state_key.to_owned();
+        match self.period {
+            Period::Every(_) => Box::new(
+                (0..self.first)
+                    .rev()
+                    .map(move |window| window_key(&state_key, window)),
+            ),
+            Period::Schedule(schedule) => Box::new(
+                std::iter::successors(schedule.before(self.first), move |&start| {
+                    schedule.before(start)
+                })
+                .map(move |start| window_key(&state_key, start)),
+            ),
+        }
+    }
+
+    /// When active window `index`, oldest first, started, as a Unix time
+    pub fn started(&self, index: usize) -> u64 {
+        match self.period {
+            Period::Every(window) => self.window(index) * window.as_secs(),
+            Period::Schedule(_) => self.window(index),
+        }
+    }
+
+    /// When the current window started and when the next one starts
+    pub fn timing(&self) -> Timing {
+        let current = self.started(self.windows - 1);
+        let next_rotation = match self.period {
+            Period::Every(window) => current + window.as_secs(),
+            Period::Schedule(schedule) => schedule.after(current).unwrap_or(u64::MAX),
+        };
+        Timing {
+            current_window_started: current,
+            next_rotation,
+        }
+    }
+}
+
+fn window_key(state_key: &str, window: u64) -> String {
+    format!("{state_key}@{window}")
+}
+
+fn timing_key(state_key: &str) -> String {
+    format!("{state_key}@timing")
+}
+
+/// The timing last recorded for the filter at `state_key`
+pub(crate) fn recorded(store: &Kv, state_key: &str) -> Result<Option<Timing>> {
+    Ok(match store.get(&timing_key(state_key))? {
+        Some(bytes) => Some(serde_json::from_slice(&bytes)?),
+        None => None,
+    })
+}
+
+/// Record the timing of `rotation` for the filter at `state_key`, unless it
+/// already is
+pub(crate) fn record(store: &Kv, state_key: &str, rotation: &Rotation) -> Result<()> {
+    let timing = rotation.timing();
+    if recorded(store, state_key)? != Some(timing) {
+        store.set(&timing_key(state_key), &serde_json::to_vec(&timing)?)?;
+    }
+    Ok(())
+}
+
+/// A cron expression, evaluated in UTC
+///
+/// It has the five fields minute, hour, day of month, month and day of
+/// week, each `*`, a number, a range `a-b` or a list of them separated by
+/// commas, any of which may be followed by a step `/n`. Days of the week
+/// count from 0 for Sunday, which is also 7. As in cron, a day matches if
+/// either of the day fields does when both are restricted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Schedule {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Whether the day of month field is `*`
+    any_day: bool,
+    /// Whether the day of week field is `*`
+    any_weekday: bool,
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            anyhow::bail!("expected five fields: minute, hour, day of month, month, day of week");
+        };
+        // Sunday is both 0 and 7
+        let sundays = field(weekdays, 0, 7).context("invalid day of week")?;
+        let schedule = Self {
+            minutes: field(minutes, 0, 59).context("invalid minute")?,
+            hours: field(hours, 0, 23).context("invalid hour")? as u32,
+            days: field(days, 1, 31).context("invalid day of month")? as u32,
+            months: field(months, 1, 12).context("invalid month")? as u16,
+            weekdays: ((sundays | sundays >> 7) & 0x7f) as u8,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        };
+        if schedule.after(0).is_none() {
+            anyhow::bail!("the schedule never fires");
+        }
+        Ok(schedule)
+    }
+}
+
+/// The values from `min` to `max` a cron field allows, as a bitset
+fn field(field: &str, min: u64, max: u64) -> Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().context("invalid step")?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                None if step > 1 => (range.parse()?, max),
+                None => (range.parse()?, range.parse()?),
+            },
+        };
+        if start < min || end > max || start > end || step == 0 {
+            anyhow::bail!("`{part}` is outside {min}-{max}");
+        }
+        for value in (start..=end).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl Schedule {
+    /// Whether the schedule fires on the day `days` after the Unix epoch
+    fn fires_on(&self, days: u64) -> bool {
+        let (month, day) = civil(days);
+        let weekday = (days + 4) % 7;
+        let by_day = self.days & 1 << day != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        };
+        day_matches && self.months & 1 << month != 0
+    }
+
+    /// Whether the schedule fires at `minute` of a day it fires on
+    fn fires_at(&self, minute: u64) -> bool {
+        self.hours & 1 << (minute / 60) != 0 && self.minutes & 1 << (minute % 60) != 0
+    }
+
+    /// The last time the schedule fired before `time`, as Unix times
+    pub fn before(&self, time: u64) -> Option<u64> {
+        let last = time.checked_sub(1)? / 60;
+        let (mut day, mut until) = (last / MINUTES_PER_DAY, last % MINUTES_PER_DAY);
+        for _ in 0..SEARCHED_DAYS {
+            if self.fires_on(day) {
+                if let Some(minute) = (0..=until).rev().find(|&m| self.fires_at(m)) {
+                    return Some((day * MINUTES_PER_DAY + minute) * 60);
+                }
+            }
+            day = day.checked_sub(1)?;
+            until = MINUTES_PER_DAY - 1;
+        }
+        None
+    }
+
+    /// The next time the schedule fires after `time`, as Unix times
+    pub fn after(&self, time: u64) -> Option<u64> {
+        let next = time / 60 + 1;
+        let (mut day, mut from) = (next / MINUTES_PER_DAY, next % MINUTES_PER_DAY);
+        for _ in 0..SEARCHED_DAYS {
+            if self.fires_on(day) {
+                if let Some(minute) = (from..MINUTES_PER_DAY).find(|&m| self.fires_at(m)) {
+                    return Some((day * MINUTES_PER_DAY + minute) * 60);
+                }
+            }
+            day += 1;
+            from = 0;
+        }
+        None
+    }
+}
+
+/// The month and day of the month `days` after the Unix epoch
+fn civil(days: u64) -> (u64, u64) {
+    // Counted from 1 March 0000, so that leap days end the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let of_era = days - era * 146_097;
+    let year = (of_era - of_era / 1460 + of_era / 36_524 - of_era / 146_096) / 365;
+    let of_year = of_era - (365 * year + year / 4 - year / 100);
+    let month = (5 * of_year + 2) / 153;
+    let day = of_year - (153 * month + 2) / 5 + 1;
+    (if month < 10 { month + 3 } else { month - 9 }, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_move_on() {
+        let day = Duration::from_secs(86_400);
+        let every = Period::Every(day);
+        let rotation = Rotation::at(every, 3, day * 10 + Duration::from_secs(5));
+        let keys: Vec<_> = (0..3).map(|i| rotation.key("__state", i)).collect();
+        assert_eq!(keys, ["__state@8", "__state@9", "__state@10"]);
+        assert_eq!(
+            rotation.expired("__state").take(2).collect::<Vec<_>>(),
+            ["__state@7", "__state@6"]
+        );
+        assert_eq!(rotation.timing().next_rotation, 11 * 86_400);
+        assert_eq!(rotation.started(0), 8 * 86_400);
+
+        let later = Rotation::at(every, 3, day * 11);
+        assert_eq!(later.key("__state", 0), "__state@9");
+        let early = Rotation::at(every, 3, day);
+        assert_eq!(early.key("__state", 0), "__state@0");
+        assert_eq!(early.expired("__state").count(), 0);
+    }
+
+    #[test]
+    fn schedules_fire_like_cron() {
+        assert_eq!(civil(0), (1, 1));
+        // 29 February 2024
+        assert_eq!(civil(19_782), (2, 29));
+
+        // 03:00 every night, from 1 January 2024 10:00
+        let nightly: Schedule = "0 3 * * *".parse().unwrap();
+        let now = 1_704_103_200;
+        assert_eq!(nightly.after(now), Some(1_704_164_400));
+        assert_eq!(nightly.before(now), Some(1_704_078_000));
+        assert_eq!(nightly.before(1_704_078_000), Some(1_703_991_600));
+
+        let rotation = Rotation::at(Period::Schedule(nightly), 3, Duration::from_secs(now));
+        let keys: Vec<_> = (0..3).map(|i| rotation.key("__state", i)).collect();
+        assert_eq!(
+            keys,
+            [
+                "__state@1703905200",
+                "__state@1703991600",
+                "__state@1704078000"
+            ]
+        );
+        assert_eq!(
+            rotation.expired("__state").next().unwrap(),
+            "__state@1703818800"
+        );
+        assert_eq!(
+            rotation.timing(),
+            Timing {
+                current_window_started: 1_704_078_000,
+                next_rotation: 1_704_164_400,
+            }
+        );
+
+        // Mondays, 1 January 2024 being one, or the 15th
+        let weekly: Schedule = "30 9 15 * 1".parse().unwrap();
+        assert_eq!(weekly.after(now), Some(1_704_706_200));
+        let steps: Schedule = "*/15 9-17/4 * * 7".parse().unwrap();
+        assert_eq!(steps.hours, 1 << 9 | 1 << 13 | 1 << 17);
+        assert_eq!(steps.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(steps.weekdays, 1);
+
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 3 * * *".parse::<Schedule>().is_err());
+        assert!("0 0 30 2 *".parse::<Schedule>().is_err());
+        assert!("0 0 29 2 *".parse::<Schedule>().is_ok());
+    }
+}
@@ -0,0 +1,209 @@
+//! Filters that grow as emails accumulate
+//!
+//! A single filter's false positive rate climbs as it fills, until every
+//! check falls through to the database. A scalable filter is a chain of
+//! slices instead: emails go into the newest slice, and once it is half full
+//! a new slice twice its size with one more hash function is started, so each
+//! slice's false positive rate is tighter than the last and their sum stays
+//! bounded. An email is a `Maybe` if any slice says so.
+//!
+//! The first slice is stored under the mode's state key, and slice `i` under
+//! `<state key>:<i>`, so a filter that never grew reads as a single slice.
+
+use anyhow::Result;
+
+use crate::{BloomFilter, Exists, Params, ProbeSet, Removal, MAX_HASHES};
+
+/// The fraction of set bits at which the newest slice stops taking emails
+const FILL_THRESHOLD: f64 = 0.5;
+
+/// The key slice `index` of the filter at `state_key` is stored under
+pub(crate) fn slice_key(state_key: &str, index: usize) -> String {
+    match index {
+        0 => state_key.to_owned(),
+        _ => format!("{state_key}:{index}"),
+    }
+}
+
+/// Something emails can be checked against
+pub(crate) trait Membership {
+    fn exists_probes(&self, probes: &ProbeSet) -> Exists;
+}
+
+pub(crate) struct ScalableFilter {
+    slices: Vec<BloomFilter>,
+    /// Which slices changed since they were loaded
+    dirty: Vec<bool>,
+    /// Whether a full newest slice is followed by a new one
+    grows: bool,
+}
+
+impl ScalableFilter {
+    /// A filter of already stored slices, oldest first
+    pub fn new(slices: Vec<BloomFilter>, grows: bool) -> Self {
+        assert!(!slices.is_empty(), "a filter has at least one slice");
+        Self {
+            dirty: vec![false; slices.len()],
+            slices,
+            grows,
+        }
+    }
+
+    pub fn slices(&self) -> &[BloomFilter] {
+        &self.slices
+    }
+
+    /// The slices that changed since they were loaded, with their indices
+    pub fn dirty(&mut self) -> impl Iterator<Item = (usize, &mut BloomFilter)> {
+        self.slices
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| self.dirty[*i])
+    }
+
+    /// The slice new emails go into
+    pub fn current(&self) -> &BloomFilter {
+        self.slices.last().unwrap()
+    }
+
+    pub fn exists_probes(&self, probes: &ProbeSet) -> Exists {
+        if self
+            .slices
+            .iter()
+            .any(|slice| slice.exists_probes(probes) == Exists::Maybe)
+        {
+            Exists::Maybe
+        } else {
+            Exists::No
+        }
+    }
+
+    pub fn insert_probes(&mut self, probes: &ProbeSet) {
+        let current = self.current();
+        let fill = current.array.count_ones() as f64 / current.array.len() as f64;
+        if self.grows && fill >= FILL_THRESHOLD {
+            let params = next(current.params());
+            self.slices.push(BloomFilter::new(params));
+            self.dirty.push(true);
+        }
+        let last = self.slices.len() - 1;
+        self.slices[last].insert_probes(probes);
+        self.dirty[last] = true;
+    }
+
+    /// Remove an email from the newest slice that may hold it
+    pub fn remove_probes(&mut self, probes: &ProbeSet) -> Result<Removal> {
+        for (i, slice) in self.slices.iter_mut().enumerate().rev() {
+            let removal = slice.remove_probes(probes)?;
+            if removal != Removal::Absent {
+                self.dirty[i] = true;
+                return Ok(removal);
+            }
+        }
+        Ok(Removal::Absent)
+    }
+
+    /// Add the elements of `other` to the oldest slice of the same shape,
+    /// returning its index, or `None` if no slice has that shape
+    pub fn merge(&mut self, other: &BloomFilter) -> Result<Option<usize>> {
+        let Some(i) = self
+            .slices
+            .iter()
+            .position(|slice| slice.params() == other.params())
+        else {
+            return Ok(None);
+        };
+        self.slices[i].merge(other)?;
+        self.dirty[i] = true;
+        Ok(Some(i))
+    }
+
+    /// The index of the oldest slice of the same shape as `other`, counting
+    /// or not
+    pub fn same_shape(&self, other: &BloomFilter) -> Option<usize> {
+        let params = other.params();
+        self.slices.iter().position(|slice| {
+            let shape = slice.params();
+            (shape.num_bits, shape.num_hashes, shape.hashing, shape.seed)
+                == (
+                    params.num_bits,
+                    params.num_hashes,
+                    params.hashing,
+                    params.seed,
+                )
+        })
+    }
+
+    /// Remove the elements of `other` from the oldest slice of the same
+    /// shape, returning its index and what was removed, or `None` if no
+    /// slice has that shape
+    pub fn subtract(&mut self, other: &BloomFilter) -> Result<Option<(usize, Removal)>> {
+        let Some(i) = self
+            .slices
+            .iter()
+            .position(|slice| slice.params() == other.params())
+        else {
+            return Ok(None);
+        };
+        let removal = self.slices[i].subtract(other)?;
+        if removal != Removal::Absent {
+            self.dirty[i] = true;
+        }
+        Ok(Some((i, removal)))
+    }
+}
+
+impl Membership for ScalableFilter {
+    fn exists_probes(&self, probes: &ProbeSet) -> Exists {
+        ScalableFilter::exists_probes(self, probes)
+    }
+}
+
+/// The shape of the slice following one of shape `params`
+fn next(params: Params) -> Params {
+    Params {
+        num_bits: params.num_bits.saturating_mul(2).min(u32::MAX as usize),
+        num_hashes: (params.num_hashes + 1).min(MAX_HASHES),
+        counting: params.counting,
+        hashing: params.hashing,
+        seed: params.seed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_once_full() {
+        let mut filter = ScalableFilter::new(vec![BloomFilter::new(Params::LEGACY)], true);
+        let emails: Vec<_> = (0..200).map(|i| format!("user{i}@example.com")).collect();
+        for email in &emails {
+            filter.insert_probes(&ProbeSet::new(email));
+        }
+        assert!(filter.slices().len() > 2);
+        assert_eq!(filter.dirty().count(), filter.slices().len());
+        assert_eq!(filter.slices()[1].params().num_bits, 256);
+        assert_eq!(filter.slices()[1].params().num_hashes, 3);
+        assert!(emails
+            .iter()
+            .all(|e| filter.exists_probes(&ProbeSet::new(e)) == Exists::Maybe));
+
+        let mut fixed = ScalableFilter::new(vec![BloomFilter::new(Params::LEGACY)], false);
+        for email in &emails {
+            fixed.insert_probes(&ProbeSet::new(email));
+        }
+        assert_eq!(fixed.slices().len(), 1);
+
+        let mut other = BloomFilter::new(next(Params::LEGACY));
+        other.insert("elsewhere@example.com");
+        assert_eq!(filter.merge(&other).unwrap(), Some(1));
+        assert_eq!(
+            filter.exists_probes(&ProbeSet::new(&"elsewhere@example.com")),
+            Exists::Maybe
+        );
+        assert_eq!(fixed.merge(&other).unwrap(), None);
+        assert_eq!(slice_key("__state", 0), "__state");
+        assert_eq!(slice_key("__state", 2), "__state:2");
+    }
+}
@@ -0,0 +1,268 @@
+//! JSON Schemas of the request and response bodies
+//!
+//! The schemas under `schemas/` are built into the component and served at
+//! `/schemas/<name>.json`, so that clients can generate code and contract
+//! tests from the same definitions the component enforces. JSON request
+//! bodies are checked against their route's schema before reaching its
+//! handler. Only the keywords the schemas use are supported.
+
+use serde_json::{Map, Value};
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, error::Error};
+
+struct Schema {
+    name: &'static str,
+    source: &'static str,
+}
+
+macro_rules! schema {
+    ($name:literal) => {
+        Schema {
+            name: $name,
+            source: include_str!(concat!("../schemas/", $name, ".json")),
+        }
+    };
+}
+
+const SCHEMAS: &[Schema] = &[
+    schema!("add.request"),
+    schema!("check.request"),
+    schema!("check.response"),
+    schema!("bulk.request"),
+    schema!("bulk.response"),
+    schema!("config.request"),
+    schema!("config.response"),
+    schema!("remove.response"),
+    schema!("reconcile.response"),
+    schema!("rebuild.response"),
+    schema!("merge.response"),
+    schema!("quarantine.request"),
+    schema!("quarantine.response"),
+    schema!("reference.request"),
+    schema!("reference.response"),
+    schema!("reference-verify.request"),
+    schema!("reference-verify.response"),
+    schema!("widget.response"),
+];
+
+/// Where a body breaks its schema
+#[derive(serde::Serialize, PartialEq, Debug)]
+pub(crate) struct Violation {
+    /// A JSON Pointer to the offending value
+    pointer: String,
+    detail: String,
+}
+
+impl App {
+    /// Serve the list of schemas, or one of them
+    pub fn schemas(&self, req: Request) -> Result<Response, Error> {
+        let path = req.uri().path();
+        if path == "/schemas" {
+            let paths: Vec<_> = SCHEMAS
+                .iter()
+                .map(|s| format!("/schemas/{}.json", s.name))
+                .collect();
+            return Ok(crate::json_response(&paths)?);
+        }
+        let schema = path
+            .strip_prefix("/schemas/")
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(find)
+            .ok_or(Error::NotFound)?;
+        Ok(http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, "application/schema+json")
+            .body(Some(schema.source.into()))
+            .map_err(anyhow::Error::from)?)
+    }
+}
+
+fn find(name: &str) -> Option<&'static Schema> {
+    SCHEMAS.iter().find(|s| s.name == name)
+}
+
+/// Refuse a JSON body that doesn't match the schema called `name`
+pub(crate) fn validate(name: &str, body: &[u8]) -> Result<(), Error> {
+    let schema = find(name).expect("routes name existing schemas");
+    let schema: Value = serde_json::from_str(schema.source).expect("schemas are valid JSON");
+    let value: Value = serde_json::from_slice(body).map_err(Error::bad_request)?;
+    let mut errors = Vec::new();
+    check(&schema, &value, "", &mut errors);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(mismatch(name, errors))
+}
+
+/// The error for a body breaking the schema called `name` at `errors`
+pub(crate) fn mismatch(name: &str, errors: Vec<Violation>) -> Error {
+    Error::InvalidBody {
+        detail: format!("the body doesn't match the schema at /schemas/{name}.json"),
+        errors,
+    }
+}
+
+/// Collect where `value`, found at `pointer`, breaks `schema`
+fn check(schema: &Value, value: &Value, pointer: &str, errors: &mut Vec<Violation>) {
+    if let Some(kind) = schema.get("type") {
+        let kinds: Vec<&str> = match kind {
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            kind => kind.as_str().into_iter().collect(),
+        };
+        if !kinds.iter().any(|kind| is_a(value, kind)) {
+            errors.push(violation(
+                pointer,
+                format!("must be of type {}", kinds.join(" or ")),
+            ));
+            return;
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            let options: Vec<_> = options.iter().map(Value::to_string).collect();
+            errors.push(violation(
+                pointer,
+                format!("must be one of {}", options.join(", ")),
+            ));
+        }
+    }
+    let (measure, [min, max], unit) = match value {
+        Value::String(s) => (s.chars().count(), ["minLength", "maxLength"], " characters"),
+        Value::Array(items) => (items.len(), ["minItems", "maxItems"], " items"),
+        _ => (0, ["minimum", "maximum"], ""),
+    };
+    let (measure, verb) = match value.as_f64() {
+        Some(n) => (n, "be"),
+        None => (measure as f64, "have"),
+    };
+    let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+    if let Some(min) = bound(min).filter(|min| measure < *min) {
+        errors.push(violation(
+            pointer,
+            format!("must {verb} at least {min}{unit}"),
+        ));
+    }
+    if let Some(max) = bound(max).filter(|max| measure > *max) {
+        errors.push(violation(
+            pointer,
+            format!("must {verb} at most {max}{unit}"),
+        ));
+    }
+
+    match value {
+        Value::Array(items) => {
+            if let Some(item) = schema.get("items") {
+                for (i, value) in items.iter().enumerate() {
+                    check(item, value, &format!("{pointer}/{i}"), errors);
+                }
+            }
+        }
+        Value::Object(members) => check_members(schema, members, pointer, errors),
+        _ => {}
+    }
+}
+
+fn check_members(
+    schema: &Value,
+    members: &Map<String, Value>,
+    pointer: &str,
+    errors: &mut Vec<Violation>,
+) {
+    let member = |name: &str| format!("{pointer}/{}", name.replace('~', "~0").replace('/', "~1"));
+    for name in schema["required"].as_array().into_iter().flatten() {
+        let Some(name) = name.as_str() else { continue };
+        if !members.contains_key(name) {
+            errors.push(violation(&member(name), "is required"));
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in members {
+        match (
+            properties.and_then(|p| p.get(name)),
+            schema.get("additionalProperties"),
+        ) {
+            (Some(property), _) => check(property, value, &member(name), errors),
+            (None, Some(Value::Bool(false))) => {
+                errors.push(violation(&member(name), "is not allowed"))
+            }
+            (None, Some(additional)) => check(additional, value, &member(name), errors),
+            (None, None) => {}
+        }
+    }
+}
+
+pub(crate) fn violation(pointer: &str, detail: impl Into<String>) -> Violation {
+    Violation {
+        pointer: pointer.to_owned(),
+        detail: detail.into(),
+    }
+}
+
+fn is_a(value: &Value, kind: &str) -> bool {
+    match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(name: &str, body: &str) -> Vec<Violation> {
+        match validate(name, body.as_bytes()) {
+            Ok(()) => Vec::new(),
+            Err(Error::InvalidBody { errors, .. }) => errors,
+            Err(e) => panic!("unexpected error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn reports_pointers() {
+        for schema in SCHEMAS {
+            serde_json::from_str::<Value>(schema.source).unwrap();
+        }
+        assert!(violations("add.request", r#"{"email": "me@example.com"}"#).is_empty());
+        assert_eq!(
+            violations(
+                "add.request",
+                r#"{"email": "", "allow_existing": 1, "source": {"region": 2}}"#
+            ),
+            [
+                violation("/email", "must have at least 1 characters"),
+                violation("/allow_existing", "must be of type boolean"),
+                violation("/source/region", "must be of type string or null"),
+            ]
+        );
+        assert_eq!(
+            violations("add.request", "{}"),
+            [violation("/email", "is required")]
+        );
+        assert_eq!(
+            violations("check.request", r#"["a@example.com", 7]"#),
+            [violation("/1", "must be of type string")]
+        );
+        assert_eq!(
+            violations(
+                "bulk.response",
+                r#"{"received": -1, "inserted": 1, "already_present": 0, "a/b": 0}"#
+            ),
+            [
+                violation("/received", "must be at least 0"),
+                violation("/a~1b", "is not allowed")
+            ]
+        );
+        assert!(violations("check.response", r#"{"a@example.com": "taken"}"#).is_empty());
+        assert!(matches!(
+            validate("check.request", b"[1"),
+            Err(Error::BadRequest(_))
+        ));
+    }
+}
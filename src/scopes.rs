@@ -0,0 +1,237 @@
+//! Admin keys limited to some tenants
+//!
+//! Besides the `admin_token`, which reaches everything, `admin_keys` may list
+//! keys that only reach the tenants they are granted, so managing tenants can
+//! be handed to different teams. Each grant is `read:<tenant>` or
+//! `write:<tenant>`, `*` standing for every tenant. GET and HEAD requests need
+//! `read`, which `write` implies, and every other request `write`. Scoped keys
+//! never reach the untenanted state, which stays with the `admin_token`.
+
+use anyhow::Result;
+
+use crate::admin::constant_time_eq;
+
+/// What a request does to a tenant's state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Access {
+    Read,
+    Write,
+}
+
+impl Access {
+    /// The access a request with `method` needs
+    pub fn of(method: &http::Method) -> Self {
+        match *method {
+            http::Method::GET | http::Method::HEAD => Access::Read,
+            _ => Access::Write,
+        }
+    }
+}
+
+/// Access to one tenant, or to every tenant
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) struct Scope {
+    pub access: Access,
+    /// `None` for every tenant
+    pub tenant: Option<String>,
+}
+
+impl Scope {
+    /// Whether holding `self` allows `access` to `tenant`
+    fn covers(&self, access: Access, tenant: &str) -> bool {
+        (self.access == Access::Write || access == Access::Read)
+            && (self.tenant.is_none() || self.tenant.as_deref() == Some(tenant))
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        write!(f, "{access}:{}", self.tenant.as_deref().unwrap_or("*"))
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (access, tenant) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected `read:<tenant>` or `write:<tenant>`"))?;
+        let access = match access {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            _ => anyhow::bail!("expected `read:<tenant>` or `write:<tenant>`"),
+        };
+        let tenant = match tenant {
+            "" => anyhow::bail!("grants name a tenant or `*`"),
+            "*" => None,
+            tenant => Some(tenant.to_owned()),
+        };
+        Ok(Self { access, tenant })
+    }
+}
+
+/// A key and what it was granted
+struct ScopedKey {
+    id: String,
+    secret: String,
+    grants: Vec<Scope>,
+}
+
+/// The keys listed in `admin_keys`
+#[derive(Default)]
+pub(crate) struct ScopedKeys(Vec<ScopedKey>);
+
+/// Why a scoped key was refused
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Denial {
+    /// The untenanted state is only reached with the `admin_token`
+    Untenanted { key: String },
+    /// The key lacks the scope the request needs
+    Missing { key: String, scope: Scope },
+}
+
+impl std::fmt::Display for Denial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denial::Untenanted { key } => write!(
+                f,
+                "key `{key}` only reaches tenants; requests without a `tenant` header need the \
+                 admin token"
+            ),
+            Denial::Missing { key, scope } => {
+                write!(f, "key `{key}` lacks the `{scope}` scope")
+            }
+        }
+    }
+}
+
+impl ScopedKeys {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn presented(&self, token: &str) -> Option<&ScopedKey> {
+        self.0
+            .iter()
+            .find(|key| constant_time_eq(key.secret.as_bytes(), token.as_bytes()))
+    }
+
+    /// The ID of the key `token` is, if it is one
+    pub fn id_of(&self, token: &str) -> Option<&str> {
+        self.presented(token).map(|key| key.id.as_str())
+    }
+
+    /// Whether `token` is one of the keys, and if so whether it allows
+    /// `access` to `tenant`, `None` being the untenanted state
+    pub fn check(
+        &self,
+        token: &str,
+        access: Access,
+        tenant: Option<&str>,
+    ) -> Option<Result<(), Denial>> {
+        let key = self.presented(token)?;
+        let Some(tenant) = tenant else {
+            return Some(Err(Denial::Untenanted {
+                key: key.id.clone(),
+            }));
+        };
+        if key.grants.iter().any(|grant| grant.covers(access, tenant)) {
+            return Some(Ok(()));
+        }
+        Some(Err(Denial::Missing {
+            key: key.id.clone(),
+            scope: Scope {
+                access,
+                tenant: Some(tenant.to_owned()),
+            },
+        }))
+    }
+}
+
+impl std::str::FromStr for ScopedKeys {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated `id:secret <grant> <grant>...` entries
+    fn from_str(s: &str) -> Result<Self> {
+        let mut keys: Vec<ScopedKey> = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.split_whitespace();
+            let Some((id, secret)) = parts.next().and_then(|key| key.split_once(':')) else {
+                anyhow::bail!("expected `id:secret <grant>...` entries");
+            };
+            if id.is_empty() || secret.is_empty() {
+                anyhow::bail!("key IDs and secrets must not be empty");
+            }
+            if keys.iter().any(|key| key.id == id) {
+                anyhow::bail!("key ID `{id}` is listed twice");
+            }
+            let grants = parts
+                .map(str::parse)
+                .collect::<Result<Vec<Scope>>>()
+                .map_err(|e| anyhow::anyhow!("invalid grant of key `{id}`: {e}"))?;
+            if grants.is_empty() {
+                anyhow::bail!("key `{id}` has no grants");
+            }
+            keys.push(ScopedKey {
+                id: id.to_owned(),
+                secret: secret.to_owned(),
+                grants,
+            });
+        }
+        Ok(Self(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_limit_keys_to_their_tenants() {
+        let keys: ScopedKeys = "shop:s3cret write:shop read:blog, ops:0ps read:*"
+            .parse()
+            .unwrap();
+        assert_eq!(keys.check("nope", Access::Read, Some("shop")), None);
+        assert_eq!(keys.id_of("0ps"), Some("ops"));
+        assert_eq!(
+            keys.check("s3cret", Access::Write, Some("shop")),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            keys.check("s3cret", Access::Read, Some("blog")),
+            Some(Ok(()))
+        );
+        let denial = keys
+            .check("s3cret", Access::Write, Some("blog"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            denial.to_string(),
+            "key `shop` lacks the `write:blog` scope"
+        );
+        assert_eq!(keys.check("0ps", Access::Read, Some("forum")), Some(Ok(())));
+        assert!(matches!(
+            keys.check("0ps", Access::Read, None),
+            Some(Err(Denial::Untenanted { .. }))
+        ));
+    }
+
+    #[test]
+    fn parse_errors_leave_secrets_out() {
+        assert!("".parse::<ScopedKeys>().unwrap().is_empty());
+        for invalid in [
+            "shop:s3cret",
+            "s3cret write:shop",
+            "shop:s3cret delete:shop",
+        ] {
+            let err = invalid.parse::<ScopedKeys>().err().unwrap();
+            assert!(!format!("{err:#}").contains("s3cret"), "{err:#}");
+        }
+        assert!("a:x read:*, a:y read:*".parse::<ScopedKeys>().is_err());
+    }
+}
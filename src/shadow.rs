@@ -0,0 +1,158 @@
+//! Verifying a filter store ahead of migrating to it
+//!
+//! Moving filters to another store, such as from the key-value stores to
+//! Redis or from whole slices to shards, is a leap of faith when the new
+//! store only sees checks once it is cut over to. With
+//! `shadow_filter_store` set, and `shadow_shard_bits` for shards in the
+//! key-value stores, the store it names shadows the configured one: every
+//! slice written is written to both, and every check is answered by both,
+//! the configured store's verdict being served and the shadow's compared to
+//! it.
+//! Verdicts that differ are logged and counted in
+//! `bloom_filter_shadow_checks_total`, so the shadow can be cut over to once
+//! it has agreed for long enough.
+//!
+//! Slices only reach the shadow as they are next written, or all at once
+//! with `POST /rebuild`, and checks of a filter the shadow doesn't hold every
+//! slice of aren't compared. A shadow in the key-value stores next to the
+//! configured store keeps its slices under `__shadow/<slice key>`, other
+//! shadows under the slice keys themselves, ready to be cut over to. Writes
+//! to the shadow that fail are only logged, never failing the request.
+
+use crate::{
+    app::App,
+    filter_store::{self, FilterStore},
+    metrics::{Counter, Metrics},
+    scalable::Membership,
+    shards::{self, FilterView, Manifest, SliceView},
+    BloomFilter, Exists, ProbeSet,
+};
+
+/// What the keys of a shadow next to the configured store are prefixed with
+const PREFIX: &str = "__shadow/";
+
+/// The store shadowing the configured one
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Target {
+    pub backend: filter_store::Backend,
+    /// How many bits each shard holds in the key-value stores, `None` to
+    /// store slices whole
+    pub shard_bits: Option<usize>,
+}
+
+/// Checks answered by the configured store and compared with the shadow
+pub(crate) struct Shadowed<'a> {
+    pub primary: FilterView<'a>,
+    /// `None` when the shadow couldn't be read or lacks some slices
+    pub shadow: Option<FilterView<'a>>,
+    pub metrics: &'a dyn Metrics,
+}
+
+impl Membership for Shadowed<'_> {
+    fn exists_probes(&self, probes: &ProbeSet) -> Exists {
+        let exists = self.primary.exists_probes(probes);
+        let Some(shadow) = &self.shadow else {
+            self.metrics.count(Counter::ShadowSkipped);
+            return exists;
+        };
+        let shadowed = shadow.exists_probes(probes);
+        if shadowed == exists {
+            self.metrics.count(Counter::ShadowMatched);
+        } else {
+            eprintln!(
+                "the shadow filter store answered {shadowed:?} where the filter answered {exists:?}"
+            );
+            self.metrics.count(Counter::ShadowMismatched);
+        }
+        exists
+    }
+}
+
+impl App {
+    /// The key the shadow keeps the slice at `key` under
+    fn shadow_key(&self, target: &Target, key: &str) -> String {
+        match (&target.backend, &self.config.filter_store) {
+            (filter_store::Backend::KeyValue, filter_store::Backend::KeyValue) => {
+                format!("{PREFIX}{key}")
+            }
+            _ => key.to_owned(),
+        }
+    }
+
+    /// The shadow as a filter store
+    fn shadow_filters(&self, target: &Target) -> Box<dyn FilterStore + '_> {
+        match &target.backend {
+            filter_store::Backend::KeyValue => Box::new(self.key_value_in(target.shard_bits)),
+            #[cfg(feature = "redis-backend")]
+            filter_store::Backend::Redis(address) => {
+                Box::new(filter_store::Redis::new(address, &self.store))
+            }
+        }
+    }
+
+    /// The view checks take of the shadow's copies of the slices at `keys`,
+    /// `None` without a shadow or when it lacks one of them
+    pub fn shadow_view(&self, keys: &[String]) -> Option<FilterView<'_>> {
+        let target = self.config.shadow.as_ref()?;
+        let view = || {
+            let filters = self.shadow_filters(target);
+            let mut slices = Vec::new();
+            for key in keys {
+                let key = self.shadow_key(target, key);
+                let Some(state) = filters.state(&key)? else {
+                    return Ok(None);
+                };
+                slices.push(if shards::is_manifest(&state) {
+                    SliceView::sharded(&key, Manifest::decode(&state)?)
+                } else if filter_store::is_header(&state) {
+                    self.bitmap_view(&target.backend, key, &state)?
+                } else {
+                    SliceView::Whole(BloomFilter::deserialize(state)?)
+                });
+            }
+            anyhow::Ok(Some(slices))
+        };
+        match view() {
+            Ok(slices) => Some(FilterView::new(&self.store, &*self.metrics, slices?)),
+            Err(e) => {
+                eprintln!("can't load the shadow filter, not comparing checks: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Write `filter`, just stored at `key`, to the shadow too, unless it
+    /// already holds a newer generation of it
+    pub fn shadow_save(&self, key: &str, filter: &BloomFilter) {
+        let Some(target) = &self.config.shadow else {
+            return;
+        };
+        let filters = self.shadow_filters(target);
+        let key = self.shadow_key(target, key);
+        let saved = filters.state(&key).and_then(|stored| {
+            if stored
+                .as_deref()
+                .is_some_and(|stored| BloomFilter::stored_generation(stored) > filter.generation)
+            {
+                return Ok(());
+            }
+            filters.save(&key, filter)
+        });
+        if let Err(e) = saved {
+            eprintln!("failed to write the slice at {key} to the shadow filter store: {e:#}");
+            self.metrics.count(Counter::ShadowWriteFailed);
+        }
+    }
+
+    /// Delete the shadow's copy of the slice at `key`
+    pub fn shadow_delete(&self, key: &str) {
+        let Some(target) = &self.config.shadow else {
+            return;
+        };
+        let key = self.shadow_key(target, key);
+        if let Err(e) = self.shadow_filters(target).delete(&key) {
+            eprintln!("failed to delete the slice at {key} from the shadow filter store: {e:#}");
+            self.metrics.count(Counter::ShadowWriteFailed);
+        }
+    }
+}
@@ -0,0 +1,1003 @@
+//! Filters stored across several keys
+//!
+//! A filter stored under a single key is read in full by every check and
+//! written in full by every insert, which doesn't scale past a few kilobytes.
+//! With `bloom_shard_bits` set, a slice's bits are split into shards of that
+//! many bits, each stored under `<slice key>/<shard>` (slice keys already end
+//! in `:<slice>` for the slices of a scalable filter). The slice key holds a
+//! manifest with the filter's header and the checksum of each shard as it
+//! was last written.
+//!
+//! Availability checks read the manifest and then only the shards holding
+//! the bits they probe. Writers still read every shard, but compare each
+//! against the manifest and only write back the ones that changed. Shards
+//! are written before their manifest, so a check racing a writer may see a
+//! shard newer than the manifest, which only ever reflects more recent writes.
+//!
+//! Each write also stores how many bits each shard has set under
+//! `<slice key>/popcounts`, tagged with the checksum of the manifest written
+//! with them, so that `/stats` can report a slice's fill from the manifest
+//! and the counts rather than reading every shard.
+//!
+//! Shards hold consecutive positions, bit `i` being at position `i` until
+//! the slice is rebalanced onto another mapping (see [`crate::rebalance`]),
+//! whose shards are stored under `<slice key>/m<generation>/<shard>`.
+
+use anyhow::{Context, Result};
+use bitvec::prelude::*;
+use std::{cell::RefCell, collections::BTreeMap, ops::Range};
+
+#[cfg(feature = "redis-backend")]
+use crate::filter_store::Redis;
+use crate::{
+    filter::{
+        checksum, decode_words, CorruptState, HASHED_SHARDED_VERSION, MAGIC,
+        REMAPPED_SHARDED_VERSION, SHARDED_VERSION,
+    },
+    hashing::Hashing,
+    kv::Kv,
+    metrics::{Counter, Metrics},
+    rebalance::{self, Mapping},
+    scalable::Membership,
+    transaction::{Interleaved, KvTransaction},
+    BloomFilter, Exists, Params, ProbeSet,
+};
+
+/// The most shards a slice is split into, shards grow beyond the configured
+/// size rather than make the manifest huge
+const MAX_SHARDS: usize = 4096;
+/// The length of a manifest before its shard checksums
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8 + 8 + 4 + 1 + 1 + 4;
+/// How much longer the header is for filters hashing specially
+const HASHING_LEN: usize = 1 + 8;
+/// How much longer the header is for rebalanced filters, before a migration
+const MAPPING_LEN: usize = 4 + 4 + 1;
+/// How much longer the header is during a migration
+const MIGRATION_LEN: usize = 4 + 4 + 4;
+
+/// The key shard `index` of the slice at `key` is stored under, for shards
+/// following `mapping`
+fn shard_key(key: &str, mapping: &Mapping, index: usize) -> String {
+    match mapping.generation {
+        0 => format!("{key}/{index}"),
+        generation => format!("{key}/m{generation}/{index}"),
+    }
+}
+
+/// The key the set bits of each shard of the slice at `key` are kept under
+fn popcounts_key(key: &str) -> String {
+    format!("{key}/popcounts")
+}
+
+/// Encode the set bits of each shard, tagged with the `manifest` they were
+/// written with
+fn encode_popcounts(manifest: &[u8], popcounts: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + popcounts.len() * 4);
+    bytes.extend(tag(manifest));
+    for popcount in popcounts {
+        bytes.extend(popcount.to_be_bytes());
+    }
+    bytes
+}
+
+/// The set bits of each shard in `bytes`, `None` unless they were written
+/// with `manifest`, whose shards they count
+fn decode_popcounts(bytes: &[u8], manifest: &[u8], shards: usize) -> Option<Vec<u32>> {
+    let counts = bytes.strip_prefix(&tag(manifest))?;
+    if counts.len() != shards * 4 {
+        return None;
+    }
+    Some(
+        counts
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+/// What popcounts are tagged with: the checksum that ends `manifest`
+fn tag(manifest: &[u8]) -> [u8; 4] {
+    manifest.last_chunk::<4>().copied().unwrap_or_default()
+}
+
+/// Whether stored state is a manifest rather than a whole filter
+pub(crate) fn is_manifest(bytes: &[u8]) -> bool {
+    matches!(
+        bytes.strip_prefix(MAGIC),
+        Some([
+            SHARDED_VERSION | HASHED_SHARDED_VERSION | REMAPPED_SHARDED_VERSION,
+            ..
+        ])
+    )
+}
+
+/// The header of a filter stored in shards
+///
+/// It is stored as `MAGIC`, `SHARDED_VERSION`, the generation and the number
+/// of inserted elements as big-endian `u64`s, the number of bits as a
+/// big-endian `u32`, the number of hash functions and whether the filter
+/// counts as bytes, the number of bits per shard as a big-endian `u32`, the
+/// checksum of each shard and the checksum of everything before it, all
+/// big-endian `u32`s. Filters not hashing the default way or seeded are stored
+/// as `HASHED_SHARDED_VERSION` instead, with the id of their hashing as a byte
+/// and their seed as a big-endian `u64` after the counting byte.
+///
+/// Rebalanced filters, and those being migrated, are stored as
+/// `REMAPPED_SHARDED_VERSION`, laid out as `HASHED_SHARDED_VERSION` with
+/// the generation and multiplier of their mapping as big-endian `u32`s and
+/// whether they are being migrated as a byte after the bits per shard. During
+/// a migration the generation and multiplier of the mapping migrated to and
+/// how many of its shards were written follow, as big-endian `u32`s, and the
+/// checksums of those shards follow the others.
+#[derive(PartialEq, Debug)]
+pub(crate) struct Manifest {
+    generation: u64,
+    num: u64,
+    params: Params,
+    shard_bits: usize,
+    /// How the shards hold the bits
+    mapping: Mapping,
+    /// The checksum of each shard as it was last written
+    shards: Vec<u32>,
+    migration: Option<Migration>,
+}
+
+/// A migration of a filter's shards onto another mapping, cut short or
+/// under way
+#[derive(PartialEq, Debug)]
+struct Migration {
+    to: Mapping,
+    /// The checksums of the shards written under it so far, the first ones
+    written: Vec<u32>,
+}
+
+impl Manifest {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::parse(bytes).map_err(|e| CorruptState(format!("{e:#}")).into())
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let remapped = matches!(
+            bytes.strip_prefix(MAGIC),
+            Some([REMAPPED_SHARDED_VERSION, ..])
+        );
+        let hashed = remapped
+            || matches!(
+                bytes.strip_prefix(MAGIC),
+                Some([HASHED_SHARDED_VERSION, ..])
+            );
+        let extra = if hashed { HASHING_LEN } else { 0 };
+        let mut mapping_len = if remapped { MAPPING_LEN } else { 0 };
+        if !is_manifest(bytes) || bytes.len() < HEADER_LEN + extra + mapping_len + 4 {
+            anyhow::bail!("corrupted state: not a manifest");
+        }
+        let (checked, stored) = bytes.split_at(bytes.len() - 4);
+        if stored != checksum(checked).to_be_bytes() {
+            anyhow::bail!("corrupted state: checksum mismatch");
+        }
+        let rest = &checked[MAGIC.len() + 1..];
+        let u64_at = |at: usize| u64::from_be_bytes(rest[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_be_bytes(rest[at..at + 4].try_into().unwrap());
+        let counting = match rest[21] {
+            0 => false,
+            1 => true,
+            _ => anyhow::bail!("corrupted state"),
+        };
+        let mut params = Params::new(u32_at(16) as usize, rest[20].into(), counting)?;
+        if hashed {
+            let hashing = Hashing::from_id(rest[22]).context("corrupted state: unknown hashing")?;
+            params = params.hashed_with(hashing).seeded(u64_at(23));
+        }
+        let at = 22 + extra;
+        let shard_bits = u32_at(at) as usize;
+        let num_bits = params.num_bits;
+        let mapping_at = |at: usize| {
+            Mapping::new(u32_at(at), u32_at(at + 4), num_bits)
+                .context("corrupted state: invalid mapping")
+        };
+        let mut mapping = Mapping::identity(num_bits);
+        let mut migrating = None;
+        if remapped {
+            mapping = mapping_at(at + 4)?;
+            match rest[at + 12] {
+                0 => {}
+                1 if rest.len() >= at + 4 + MAPPING_LEN + MIGRATION_LEN => {
+                    migrating = Some((mapping_at(at + 13)?, u32_at(at + 21) as usize));
+                    mapping_len += MIGRATION_LEN;
+                }
+                _ => anyhow::bail!("corrupted state"),
+            }
+        }
+        let checksums = &rest[at + 4 + mapping_len..];
+        if shard_bits == 0 || shard_bits % 32 != 0 || checksums.len() % 4 != 0 {
+            anyhow::bail!("corrupted state");
+        }
+        let mut checksums = checksums
+            .chunks_exact(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()));
+        let count = num_bits.div_ceil(shard_bits);
+        let written = migrating.map_or(0, |(_, written)| written);
+        if checksums.len() != count + written || written > count {
+            anyhow::bail!("corrupted state: wrong number of shards");
+        }
+        Ok(Self {
+            generation: u64_at(0),
+            num: u64_at(8),
+            params,
+            shard_bits,
+            mapping,
+            shards: checksums.by_ref().take(count).collect(),
+            migration: migrating.map(|(to, _)| Migration {
+                to,
+                written: checksums.collect(),
+            }),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let written = self.migration.as_ref().map_or(&[][..], |m| &m.written[..]);
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN
+                + HASHING_LEN
+                + MAPPING_LEN
+                + MIGRATION_LEN
+                + (self.shards.len() + written.len()) * 4
+                + 4,
+        );
+        bytes.extend(MAGIC);
+        let remapped = self.mapping.generation != 0 || self.migration.is_some();
+        let hashed = remapped || self.params.hashed_specially();
+        bytes.push(if remapped {
+            REMAPPED_SHARDED_VERSION
+        } else if hashed {
+            HASHED_SHARDED_VERSION
+        } else {
+            SHARDED_VERSION
+        });
+        bytes.extend(self.generation.to_be_bytes());
+        bytes.extend(self.num.to_be_bytes());
+        bytes.extend((self.params.num_bits as u32).to_be_bytes());
+        bytes.push(self.params.num_hashes as u8);
+        bytes.push(self.params.counting.into());
+        if hashed {
+            bytes.push(self.params.hashing.id());
+            bytes.extend(self.params.seed.to_be_bytes());
+        }
+        bytes.extend((self.shard_bits as u32).to_be_bytes());
+        if remapped {
+            bytes.extend(self.mapping.generation.to_be_bytes());
+            bytes.extend(self.mapping.multiplier.to_be_bytes());
+            bytes.push(self.migration.is_some().into());
+            if let Some(migration) = &self.migration {
+                bytes.extend(migration.to.generation.to_be_bytes());
+                bytes.extend(migration.to.multiplier.to_be_bytes());
+                bytes.extend((written.len() as u32).to_be_bytes());
+            }
+        }
+        for shard in self.shards.iter().chain(written) {
+            bytes.extend(shard.to_be_bytes());
+        }
+        bytes.extend(checksum(&bytes).to_be_bytes());
+        bytes
+    }
+
+    fn count(&self) -> usize {
+        self.params.num_bits.div_ceil(self.shard_bits)
+    }
+
+    pub fn params(&self) -> Params {
+        self.params
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// How many elements were inserted
+    pub fn inserted(&self) -> u64 {
+        self.num
+    }
+
+    pub fn shard_bits(&self) -> usize {
+        self.shard_bits
+    }
+
+    /// How the shards listed by `shards` hold the bits
+    pub fn mapping(&self) -> Mapping {
+        self.mapping
+    }
+
+    /// The mapping shards are written with: the one being migrated to, if
+    /// any
+    fn target(&self) -> Mapping {
+        self.migration
+            .as_ref()
+            .map_or(self.mapping, |migration| migration.to)
+    }
+
+    /// The key, positions and checksum of each shard of the slice at `key`
+    ///
+    /// During a migration these are still the shards of the mapping being
+    /// migrated from.
+    pub fn shards<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> impl Iterator<Item = (String, Range<usize>, u32)> + 'a {
+        self.shards
+            .iter()
+            .enumerate()
+            .map(move |(index, &checksum)| {
+                (
+                    shard_key(key, &self.mapping, index),
+                    self.bits(index),
+                    checksum,
+                )
+            })
+    }
+
+    /// The mapping to read bit `index` with and the position it is at: that
+    /// of the migration once the shard holding it there was written
+    fn locate(&self, index: usize) -> (Mapping, usize) {
+        if let Some(migration) = &self.migration {
+            let position = migration.to.position(index);
+            if position / self.shard_bits < migration.written.len() {
+                return (migration.to, position);
+            }
+        }
+        (self.mapping, self.mapping.position(index))
+    }
+
+    /// The mappings the stored shards follow, with how many of each there
+    /// are
+    fn stored_shards(&self) -> impl Iterator<Item = (Mapping, usize)> + '_ {
+        std::iter::once((self.mapping, self.count())).chain(
+            self.migration
+                .iter()
+                .map(|migration| (migration.to, migration.written.len())),
+        )
+    }
+
+    /// The positions shard `index` holds
+    fn bits(&self, index: usize) -> Range<usize> {
+        let start = index * self.shard_bits;
+        start..(start + self.shard_bits).min(self.params.num_bits)
+    }
+}
+
+/// The bits per shard of a filter of shape `params` split into shards of
+/// roughly `shard_bits`
+fn shard_size(params: Params, shard_bits: usize) -> usize {
+    let least = params.num_bits.div_ceil(MAX_SHARDS).next_multiple_of(32);
+    shard_bits.max(least)
+}
+
+/// The most bits per shard, at least 32, that keep the shards of a filter
+/// of shape `params` within `bytes`
+pub(crate) fn bits_within(params: Params, bytes: usize) -> usize {
+    // A counter takes four bits next to its bit
+    let per_bit = if params.counting { 5 } else { 1 };
+    let bits = bytes.saturating_sub(4) * 8 / per_bit;
+    (bits / 32 * 32).clamp(32, u32::MAX as usize / 32 * 32)
+}
+
+/// A filter's bits and counters moved to the positions `mapping` puts them at
+fn positioned(filter: &BloomFilter, mapping: &Mapping) -> (BitVec<u32, Lsb0>, Option<Vec<u8>>) {
+    let mut array = BitVec::repeat(false, filter.array.len());
+    for index in filter.array.iter_ones() {
+        array.set(mapping.position(index), true);
+    }
+    let counters = filter.counters.as_ref().map(|counters| {
+        let mut positioned = vec![0; counters.len()];
+        for (index, &counter) in counters.iter().enumerate() {
+            positioned[mapping.position(index)] = counter;
+        }
+        positioned
+    });
+    (array, counters)
+}
+
+/// Split a filter into the manifest, the encoded shards and the set bits of
+/// each shard of it stored with `mapping`
+fn split(
+    filter: &BloomFilter,
+    shard_bits: usize,
+    mapping: Mapping,
+) -> (Manifest, Vec<Vec<u8>>, Vec<u32>) {
+    let params = filter.params();
+    let mut manifest = Manifest {
+        generation: filter.generation,
+        num: filter.num as u64,
+        params,
+        shard_bits: shard_size(params, shard_bits),
+        mapping,
+        shards: Vec::new(),
+        migration: None,
+    };
+    let moved = (mapping.generation != 0).then(|| positioned(filter, &mapping));
+    let (array, counters) = match &moved {
+        Some((array, counters)) => (array, counters),
+        None => (&filter.array, &filter.counters),
+    };
+    let words = array.as_raw_slice();
+    let shards: Vec<Vec<u8>> = (0..manifest.count())
+        .map(|index| {
+            let bits = manifest.bits(index);
+            let mut bytes = Vec::new();
+            for word in &words[bits.start / 32..bits.end.div_ceil(32)] {
+                bytes.extend(word.to_be_bytes());
+            }
+            if let Some(counters) = counters {
+                for pair in counters[bits].chunks(2) {
+                    bytes.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+                }
+            }
+            bytes.extend(checksum(&bytes).to_be_bytes());
+            bytes
+        })
+        .collect();
+    manifest.shards = shards.iter().map(|shard| checksum(shard)).collect();
+    let popcounts = (0..manifest.count())
+        .map(|index| array[manifest.bits(index)].count_ones() as u32)
+        .collect();
+    (manifest, shards, popcounts)
+}
+
+/// A shard's bits and counters
+struct Shard {
+    bits: BitVec<u32, Lsb0>,
+    counters: Vec<u8>,
+}
+
+fn decode_shard(manifest: &Manifest, index: usize, bytes: &[u8]) -> Result<Shard> {
+    let bits = manifest.bits(index);
+    let words = bits.len().div_ceil(32);
+    let counter_bytes = if manifest.params.counting {
+        bits.len().div_ceil(2)
+    } else {
+        0
+    };
+    let Some((body, stored)) = bytes.split_last_chunk::<4>() else {
+        anyhow::bail!("corrupted state: shard {index} is truncated");
+    };
+    if *stored != checksum(body).to_be_bytes() {
+        anyhow::bail!("corrupted state: checksum mismatch in shard {index}");
+    }
+    if body.len() != words * 4 + counter_bytes {
+        anyhow::bail!("corrupted state: shard {index} has the wrong length");
+    }
+    let (words, counters) = body.split_at(words * 4);
+    let mut array = BitVec::from_vec(decode_words(words));
+    array.truncate(bits.len());
+    Ok(Shard {
+        bits: array,
+        counters: counters
+            .iter()
+            .flat_map(|pair| [pair >> 4, pair & 0xf])
+            .take(bits.len())
+            .collect(),
+    })
+}
+
+/// Put a filter back together from its manifest and shards
+fn assemble(manifest: &Manifest, shards: &[Vec<u8>]) -> Result<BloomFilter> {
+    let params = manifest.params;
+    let mut array = BitVec::with_capacity(params.num_bits);
+    let mut counters = params.counting.then(|| Vec::with_capacity(params.num_bits));
+    for (index, bytes) in shards.iter().enumerate() {
+        let shard = decode_shard(manifest, index, bytes)?;
+        array.extend_from_bitslice(&shard.bits);
+        if let Some(counters) = &mut counters {
+            counters.extend(shard.counters);
+        }
+    }
+    let mapping = manifest.mapping;
+    if mapping.generation != 0 {
+        let mut indexed = BitVec::repeat(false, array.len());
+        for position in array.iter_ones() {
+            indexed.set(mapping.index(position), true);
+        }
+        array = indexed;
+        if let Some(counters) = &mut counters {
+            let mut indexed = vec![0; counters.len()];
+            for (position, &counter) in counters.iter().enumerate() {
+                indexed[mapping.index(position)] = counter;
+            }
+            *counters = indexed;
+        }
+    }
+    Ok(BloomFilter {
+        array,
+        num_hashes: params.num_hashes,
+        hashing: params.hashing,
+        seed: params.seed,
+        counters,
+        generation: manifest.generation,
+        num: usize::try_from(manifest.num).unwrap_or(usize::MAX),
+    })
+}
+
+/// Load the filter whose manifest is stored at `key`
+///
+/// During a migration it is loaded from the shards migrated from, which are
+/// whole until it completes.
+pub(crate) fn load(store: &Kv, key: &str, manifest: &Manifest) -> Result<BloomFilter> {
+    let shards = (0..manifest.count())
+        .map(|index| {
+            store
+                .get(&shard_key(key, &manifest.mapping, index))?
+                .with_context(|| CorruptState(format!("shard {index} of {key} is missing")))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assemble(manifest, &shards)
+}
+
+/// Store a filter at `key` in shards of about `shard_bits`, writing only the
+/// shards that differ from those of the manifest previously `stored` there
+///
+/// The shards, the manifest and the deletion of shards the filter no longer
+/// has are committed as one, so that a write failing halfway doesn't leave
+/// shards the manifest's checksums don't match. The filter keeps the mapping
+/// it was rebalanced onto, and a migration under way is completed. Returns
+/// the manifest written.
+pub(crate) fn save(
+    store: &Kv,
+    key: &str,
+    filter: &BloomFilter,
+    shard_bits: usize,
+    stored: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    let previous = stored
+        .filter(|bytes| is_manifest(bytes))
+        .and_then(|bytes| Manifest::decode(bytes).ok());
+    let mapping = previous
+        .as_ref()
+        .filter(|previous| previous.params.num_bits == filter.params().num_bits)
+        .map_or(
+            Mapping::identity(filter.params().num_bits),
+            Manifest::target,
+        );
+    let (manifest, shards, popcounts) = split(filter, shard_bits, mapping);
+    let unchanged = |index: usize| {
+        previous.as_ref().is_some_and(|previous| {
+            previous.migration.is_none()
+                && previous.mapping == manifest.mapping
+                && previous.shard_bits == manifest.shard_bits
+                && previous.params == manifest.params
+                && previous.shards.get(index) == manifest.shards.get(index)
+        })
+    };
+    let mut transaction = KvTransaction::new(store);
+    for (index, shard) in shards.into_iter().enumerate() {
+        if !unchanged(index) {
+            transaction.set(&shard_key(key, &manifest.mapping, index), shard);
+        }
+    }
+    let bytes = manifest.encode();
+    transaction.set(key, bytes.clone());
+    if let Some(previous) = &previous {
+        for (mapping, count) in previous.stored_shards() {
+            // Shards of the same mapping beyond the filter's are left over
+            let kept = if mapping == manifest.mapping {
+                manifest.count()
+            } else {
+                0
+            };
+            for index in kept..count {
+                transaction.delete(&shard_key(key, &mapping, index));
+            }
+        }
+    }
+    transaction.set(&popcounts_key(key), encode_popcounts(&bytes, &popcounts));
+    transaction.commit()?;
+    Ok(bytes)
+}
+
+/// What rebalancing a slice did
+#[derive(serde::Serialize)]
+pub(crate) struct Rebalanced {
+    key: String,
+    /// How much denser than the slice its densest shard was
+    before: f64,
+    /// And is now
+    after: f64,
+    /// The generation of the mapping the shards now follow
+    mapping: u32,
+    /// Whether the shards were migrated onto a new mapping
+    migrated: bool,
+}
+
+/// Rebalance the slice whose manifest `stored` at `key`, if its shards are
+/// more uneven than `threshold`, searching for the mapping from `seed`
+///
+/// A migration cut short is completed whatever the threshold. Fails with
+/// [`Interleaved`] if a writer stored the slice in between.
+pub(crate) fn rebalance(
+    store: &Kv,
+    key: &str,
+    stored: &[u8],
+    threshold: f64,
+    seed: u64,
+) -> Result<Rebalanced> {
+    let manifest = Manifest::decode(stored)?;
+    let filter = load(store, key, &manifest)?;
+    let (num_bits, shard_bits) = (manifest.params.num_bits, manifest.shard_bits);
+    let imbalance = |mapping: &Mapping| {
+        rebalance::imbalance(
+            filter
+                .array
+                .iter_ones()
+                .map(|index| mapping.position(index)),
+            num_bits,
+            shard_bits,
+        )
+    };
+    let before = imbalance(&manifest.mapping);
+    let target = match &manifest.migration {
+        Some(migration) => Some(migration.to),
+        None if before > threshold => {
+            let ones = rebalance::sample(filter.array.iter_ones(), filter.array.count_ones());
+            let found = rebalance::anneal(&ones, shard_bits, manifest.mapping, seed);
+            // The search only saw a sample
+            Some(found).filter(|found| {
+                found.generation != manifest.mapping.generation && imbalance(found) < before
+            })
+        }
+        None => None,
+    };
+    let Some(to) = target else {
+        return Ok(Rebalanced {
+            key: key.to_owned(),
+            before,
+            after: before,
+            mapping: manifest.mapping.generation,
+            migrated: false,
+        });
+    };
+    migrate(store, key, stored, manifest, &filter, to)?;
+    Ok(Rebalanced {
+        key: key.to_owned(),
+        before,
+        after: imbalance(&to),
+        mapping: to.generation,
+        migrated: true,
+    })
+}
+
+/// Move the slice stored at `key` as `stored`, decoded as `manifest` and
+/// loaded as `filter`, onto shards following `to`, one shard at a time
+///
+/// The manifest is rewritten after each shard, unless a writer replaced it,
+/// and the old shards are deleted with the last one.
+fn migrate(
+    store: &Kv,
+    key: &str,
+    stored: &[u8],
+    mut manifest: Manifest,
+    filter: &BloomFilter,
+    to: Mapping,
+) -> Result<()> {
+    let (target, shards, popcounts) = split(filter, manifest.shard_bits, to);
+    // Shards a migration cut short already wrote are kept when unchanged
+    let resumed = manifest
+        .migration
+        .take()
+        .map(|migration| migration.written)
+        .unwrap_or_default();
+    let mut last = stored.to_vec();
+    let mut written = Vec::with_capacity(shards.len());
+    for (index, shard) in shards.into_iter().enumerate() {
+        if resumed.get(index) != Some(&target.shards[index]) {
+            store.set(&shard_key(key, &to, index), &shard)?;
+        }
+        written.push(target.shards[index]);
+        manifest.migration = Some(Migration {
+            to,
+            written: written.clone(),
+        });
+        let next = manifest.encode();
+        if store.get(key)?.as_deref() != Some(&last[..]) {
+            return Err(Interleaved(key.to_owned()).into());
+        }
+        store.set(key, &next)?;
+        last = next;
+    }
+    if store.get(key)?.as_deref() != Some(&last[..]) {
+        return Err(Interleaved(key.to_owned()).into());
+    }
+    let bytes = target.encode();
+    let mut transaction = KvTransaction::new(store);
+    transaction.set(key, bytes.clone());
+    for index in 0..manifest.count() {
+        transaction.delete(&shard_key(key, &manifest.mapping, index));
+    }
+    transaction.set(&popcounts_key(key), encode_popcounts(&bytes, &popcounts));
+    transaction.commit()
+}
+
+/// How many bits are set in the slice whose manifest `stored` at `key` is
+/// `manifest`, from the popcounts written with it
+///
+/// `None` if they are missing or were written with another manifest, as
+/// when a write failed halfway or predates them.
+pub(crate) fn set_bits(
+    store: &Kv,
+    key: &str,
+    manifest: &Manifest,
+    stored: &[u8],
+) -> Result<Option<usize>> {
+    let Some(bytes) = store.get(&popcounts_key(key))? else {
+        return Ok(None);
+    };
+    Ok(decode_popcounts(&bytes, stored, manifest.count())
+        .map(|popcounts| popcounts.iter().map(|&count| count as usize).sum()))
+}
+
+/// Delete the shards of the manifest previously `stored` at `key`, once the
+/// filter there is stored whole
+pub(crate) fn forget(store: &Kv, key: &str, stored: Option<&[u8]>) -> Result<()> {
+    let Some(previous) = stored
+        .filter(|bytes| is_manifest(bytes))
+        .and_then(|bytes| Manifest::decode(bytes).ok())
+    else {
+        return Ok(());
+    };
+    for (mapping, count) in previous.stored_shards() {
+        for index in 0..count {
+            store.delete(&shard_key(key, &mapping, index))?;
+        }
+    }
+    store.delete(&popcounts_key(key))
+}
+
+/// A filter's slices, loaded only as far as checks need them
+pub(crate) struct FilterView<'a> {
+    store: &'a Kv,
+    metrics: &'a dyn Metrics,
+    slices: Vec<SliceView>,
+}
+
+/// The bits of the shards read so far by the generation of their mapping
+/// and their index, `None` for those that couldn't be
+type Loaded = RefCell<BTreeMap<(u32, usize), Option<BitVec<u32, Lsb0>>>>;
+
+pub(crate) enum SliceView {
+    Whole(BloomFilter),
+    Sharded {
+        key: String,
+        manifest: Manifest,
+        loaded: Loaded,
+    },
+    /// A slice stored as a Redis bitmap, of which checks read only the bits
+    /// they probe
+    #[cfg(feature = "redis-backend")]
+    Bitmap {
+        key: String,
+        params: Params,
+        redis: Redis,
+    },
+}
+
+impl SliceView {
+    pub fn sharded(key: &str, manifest: Manifest) -> Self {
+        SliceView::Sharded {
+            key: key.to_owned(),
+            manifest,
+            loaded: RefCell::default(),
+        }
+    }
+}
+
+impl<'a> FilterView<'a> {
+    pub fn new(store: &'a Kv, metrics: &'a dyn Metrics, slices: Vec<SliceView>) -> Self {
+        Self {
+            store,
+            metrics,
+            slices,
+        }
+    }
+
+    /// Whether bit `index` of a sharded slice is set
+    ///
+    /// A shard that can't be read is taken to have every bit set, so that
+    /// the checks it would answer fall through to the database.
+    fn bit(&self, key: &str, manifest: &Manifest, loaded: &Loaded, index: usize) -> bool {
+        let (mapping, position) = manifest.locate(index);
+        let shard = position / manifest.shard_bits;
+        let mut loaded = loaded.borrow_mut();
+        let bits = loaded
+            .entry((mapping.generation, shard))
+            .or_insert_with(|| {
+                let bytes = self
+                    .store
+                    .get(&shard_key(key, &mapping, shard))
+                    .and_then(|bytes| {
+                        let bytes = bytes.context("the shard is missing")?;
+                        decode_shard(manifest, shard, &bytes)
+                    });
+                match bytes {
+                    Ok(shard) => Some(shard.bits),
+                    Err(e) => {
+                        eprintln!(
+                            "can't load shard {shard} of {key}, checking the database: {e:#}"
+                        );
+                        self.metrics.count(Counter::DegradedRead);
+                        None
+                    }
+                }
+            });
+        match bits {
+            Some(bits) => bits[position % manifest.shard_bits],
+            None => true,
+        }
+    }
+}
+
+impl Membership for FilterView<'_> {
+    fn exists_probes(&self, probes: &ProbeSet) -> Exists {
+        let maybe = self.slices.iter().any(|slice| match slice {
+            SliceView::Whole(filter) => filter.exists_probes(probes) == Exists::Maybe,
+            SliceView::Sharded {
+                key,
+                manifest,
+                loaded,
+            } => probes
+                .indices(manifest.params)
+                .all(|index| self.bit(key, manifest, loaded, index)),
+            #[cfg(feature = "redis-backend")]
+            SliceView::Bitmap { key, params, redis } => {
+                match redis.all_set(key, probes.indices(*params)) {
+                    Ok(all) => all,
+                    // Taken to have every bit set, like a shard
+                    Err(e) => {
+                        eprintln!("can't read the bitmap of {key}, checking the database: {e:#}");
+                        self.metrics.count(Counter::DegradedRead);
+                        true
+                    }
+                }
+            }
+        });
+        if maybe {
+            Exists::Maybe
+        } else {
+            Exists::No
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split `filter` as slices are first sharded
+    fn split_whole(filter: &BloomFilter, shard_bits: usize) -> (Manifest, Vec<Vec<u8>>) {
+        let mapping = Mapping::identity(filter.params().num_bits);
+        let (manifest, shards, _) = split(filter, shard_bits, mapping);
+        (manifest, shards)
+    }
+
+    #[test]
+    fn splits_and_reassembles() {
+        let params = Params::new(1000, 3, true).unwrap();
+        let mut filter = BloomFilter::new(params);
+        for i in 0..50 {
+            filter.insert(format!("user{i}@example.com"));
+        }
+        filter.generation = 3;
+        let (manifest, shards) = split_whole(&filter, 256);
+        assert_eq!((manifest.count(), shards.len()), (4, 4));
+        assert_eq!(manifest.bits(3), 768..1000);
+        assert!(is_manifest(&manifest.encode()));
+        assert_eq!(Manifest::decode(&manifest.encode()).unwrap(), manifest);
+        assert!(!is_manifest(&filter.serialize()));
+        assert_eq!(BloomFilter::stored_generation(&manifest.encode()), 3);
+
+        let decoded = assemble(&manifest, &shards).unwrap();
+        assert_eq!(decoded.array, filter.array);
+        assert_eq!(decoded.counters, filter.counters);
+        assert_eq!((decoded.generation, decoded.num), (3, 50));
+
+        let mut damaged = shards.clone();
+        damaged[1][0] ^= 1;
+        assert!(assemble(&manifest, &damaged).is_err());
+        let mut encoded = manifest.encode();
+        encoded[12] ^= 1;
+        assert!(Manifest::decode(&encoded)
+            .err()
+            .unwrap()
+            .is::<CorruptState>());
+
+        // Only the shard holding the new element's bits changes
+        let mut one = BloomFilter::new(Params::new(1024, 1, false).unwrap());
+        let (before, _) = split_whole(&one, 256);
+        one.insert("hello");
+        let (after, _) = split_whole(&one, 256);
+        let changed = (0..4).filter(|&i| before.shards[i] != after.shards[i]);
+        assert_eq!(changed.count(), 1);
+
+        let hashed = BloomFilter::new(params.hashed_with(Hashing::XxHash64).seeded(7));
+        let (manifest, _) = split_whole(&hashed, 256);
+        assert!(is_manifest(&manifest.encode()));
+        assert_eq!(Manifest::decode(&manifest.encode()).unwrap(), manifest);
+
+        assert_eq!(
+            shard_size(Params::new(u32::MAX as usize, 1, false).unwrap(), 32),
+            1048576
+        );
+        assert_eq!(shard_size(params, 4096), 4096);
+
+        assert_eq!(bits_within(Params::LEGACY, 1028), 8192);
+        assert_eq!(bits_within(params, 1028), 1632);
+        assert_eq!(bits_within(params, 0), 32);
+        let (manifest, shards) = split_whole(&filter, bits_within(params, 100));
+        assert!(shards.iter().all(|shard| shard.len() <= 100));
+        assert_eq!(manifest.count(), shards.len());
+    }
+
+    #[test]
+    fn popcounts_belong_to_their_manifest() {
+        let mut filter = BloomFilter::new(Params::new(1024, 2, false).unwrap());
+        for i in 0..20 {
+            filter.insert(format!("user{i}@example.com"));
+        }
+        let (manifest, _) = split_whole(&filter, 256);
+        let popcounts: Vec<u32> = (0..manifest.count())
+            .map(|index| filter.array[manifest.bits(index)].count_ones() as u32)
+            .collect();
+        let stored = manifest.encode();
+        let bytes = encode_popcounts(&stored, &popcounts);
+        let decoded = decode_popcounts(&bytes, &stored, manifest.count()).unwrap();
+        assert_eq!(
+            decoded.iter().sum::<u32>() as usize,
+            filter.array.count_ones()
+        );
+
+        filter.insert("another@example.com");
+        filter.generation += 1;
+        let (newer, _) = split_whole(&filter, 256);
+        assert_eq!(
+            decode_popcounts(&bytes, &newer.encode(), newer.count()),
+            None
+        );
+        assert_eq!(decode_popcounts(&bytes, &stored, 3), None);
+    }
+
+    #[test]
+    fn remapped_shards_hold_the_same_bits() {
+        let params = Params::new(1000, 3, true).unwrap();
+        let mut filter = BloomFilter::new(params);
+        for i in 0..50 {
+            filter.insert(format!("user{i}@example.com"));
+        }
+        let mapping = Mapping::new(1, 337, 1000).unwrap();
+        let (manifest, shards, popcounts) = split(&filter, 256, mapping);
+        assert_eq!(
+            popcounts.iter().sum::<u32>() as usize,
+            filter.array.count_ones()
+        );
+        assert_eq!(manifest.encode()[3], REMAPPED_SHARDED_VERSION);
+        assert_eq!(Manifest::decode(&manifest.encode()).unwrap(), manifest);
+        let decoded = assemble(&manifest, &shards).unwrap();
+        assert_eq!(decoded.array, filter.array);
+        assert_eq!(decoded.counters, filter.counters);
+        assert_eq!(shard_key("__state", &mapping, 2), "__state/m1/2");
+
+        // Halfway through migrating onto another mapping
+        let (mut migrating, _) = split_whole(&filter, 256);
+        let to = Mapping::new(1, 7, 1000).unwrap();
+        let (target, ..) = split(&filter, 256, to);
+        migrating.migration = Some(Migration {
+            to,
+            written: target.shards[..2].to_vec(),
+        });
+        assert_eq!(Manifest::decode(&migrating.encode()).unwrap(), migrating);
+        for index in filter.array.iter_ones() {
+            let (read_with, position) = migrating.locate(index);
+            assert_eq!(read_with == to, to.position(index) / 256 < 2);
+            assert_eq!(position, read_with.position(index));
+        }
+        assert_eq!(
+            migrating.stored_shards().collect::<Vec<_>>(),
+            [(Mapping::identity(1000), 4), (to, 2)]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Lookalike detection
+//!
+//! Emails are folded into a skeleton that maps characters which are easily
+//! mistaken for one another onto a single representative, so `paypa1@x.com`
+//! and `PayPal@x.com` share a skeleton. A second filter over the skeletons of
+//! registered emails then flags signups that look like an existing one.
+
+/// The key the filter of registered skeletons is stored under
+pub(crate) const SKELETON_KEY: &str = "__skeletons";
+
+/// The response header flagging an available email that looks taken
+pub(crate) const SIMILAR_HEADER: &str = "similar-name";
+
+/// Character sequences folded before single characters, so `rn` reads as `m`
+const SEQUENCES: &[(&str, &str)] = &[("rn", "m"), ("vv", "w"), ("cl", "d")];
+
+/// The lookalike skeleton of an email
+///
+/// Only the local part is folded; the domain is merely lowercased, as
+/// lookalike domains are a matter for the mail provider.
+pub(crate) fn skeleton(email: &str) -> String {
+    let (local, domain) = email.trim().rsplit_once('@').unwrap_or((email.trim(), ""));
+    let mut folded: String = local
+        .to_lowercase()
+        .chars()
+        .filter(|c| !matches!(c, '.' | '-' | '_'))
+        .map(fold)
+        .collect();
+    for (from, to) in SEQUENCES {
+        folded = folded.replace(from, to);
+    }
+    format!("{folded}@{}", domain.to_lowercase())
+}
+
+/// The representative of the characters that look like `c`
+fn fold(c: char) -> char {
+    match c {
+        '0' | 'о' | 'ο' => 'o',
+        '1' | 'i' | '!' | '|' | 'і' | 'ι' => 'l',
+        '3' | 'е' | 'ε' => 'e',
+        '4' | '@' | 'а' | 'α' => 'a',
+        '5' | '$' | 'ѕ' => 's',
+        '7' => 't',
+        '8' => 'b',
+        'р' | 'ρ' => 'p',
+        'с' => 'c',
+        'у' => 'y',
+        'х' | 'χ' => 'x',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_confusables() {
+        assert_eq!(
+            skeleton("PayPal@Example.com"),
+            skeleton("paypa1@example.com")
+        );
+        assert_eq!(skeleton("j.smith@x.com"), skeleton("jsm1th@x.com"));
+        assert_eq!(skeleton("modern@x.com"), skeleton("rnodern@x.com"));
+        // Cyrillic `о` and `а`
+        assert_eq!(skeleton("bob@x.com"), skeleton("bоb@x.com"));
+        assert_ne!(skeleton("bob@x.com"), skeleton("bob@y.com"));
+        assert_ne!(skeleton("alice@x.com"), skeleton("alicia@x.com"));
+    }
+}
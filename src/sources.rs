@@ -0,0 +1,111 @@
+//! Signup source analytics
+//!
+//! Registrations may say where they came from. Only counts per value of each
+//! dimension are kept, never which email came from where, and each dimension
+//! keeps a bounded number of distinct values so the entry stays small.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::collections::BTreeMap;
+
+use crate::{app::App, encoding::Encoding, error::Error, kv::Kv};
+
+const SOURCES_KEY: &str = "__sources";
+/// How many distinct values a dimension tracks before lumping new ones together
+const MAX_VALUES: usize = 100;
+/// The value counted for values beyond `MAX_VALUES`
+const OTHER: &str = "(other)";
+
+/// Where a registration came from, all optional
+#[derive(Default, serde::Deserialize)]
+pub(crate) struct Source {
+    campaign: Option<String>,
+    client: Option<String>,
+    region: Option<String>,
+}
+
+/// Signup counts per value, per dimension
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Breakdown {
+    #[serde(default)]
+    campaign: BTreeMap<String, u64>,
+    #[serde(default)]
+    client: BTreeMap<String, u64>,
+    #[serde(default)]
+    region: BTreeMap<String, u64>,
+}
+
+impl Breakdown {
+    pub fn load(store: &Kv) -> Result<Self> {
+        Ok(match store.get(SOURCES_KEY)? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => Self::default(),
+        })
+    }
+
+    fn record(&mut self, source: &Source) {
+        for (counts, value) in [
+            (&mut self.campaign, &source.campaign),
+            (&mut self.client, &source.client),
+            (&mut self.region, &source.region),
+        ] {
+            let Some(value) = value.as_deref().map(str::trim).filter(|v| !v.is_empty()) else {
+                continue;
+            };
+            let value = if counts.contains_key(value) || counts.len() < MAX_VALUES {
+                value
+            } else {
+                OTHER
+            };
+            *counts.entry(value.to_owned()).or_default() += 1;
+        }
+    }
+}
+
+/// Count a registration from `source`
+pub(crate) fn record(store: &Kv, source: &Source) -> Result<()> {
+    if source.campaign.is_none() && source.client.is_none() && source.region.is_none() {
+        return Ok(());
+    }
+    // Without compare and swap, concurrent registrations may lose a count
+    let mut breakdown = Breakdown::load(store)?;
+    breakdown.record(source);
+    store.set(SOURCES_KEY, &serde_json::to_vec(&breakdown)?)
+}
+
+impl App {
+    /// Report how many registrations came from each source
+    ///
+    /// Like the admin endpoints this requires the admin token.
+    pub fn source_stats(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        Ok(Encoding::accepted(&req).response(&Breakdown::load(&self.store)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_are_bounded_per_dimension() {
+        let mut breakdown = Breakdown::default();
+        for i in 0..MAX_VALUES + 2 {
+            breakdown.record(&Source {
+                campaign: Some(format!("c{i}")),
+                region: Some("eu".into()),
+                ..Default::default()
+            });
+        }
+        breakdown.record(&Source {
+            campaign: Some("c0".into()),
+            client: Some(" ".into()),
+            ..Default::default()
+        });
+        assert_eq!(breakdown.campaign.len(), MAX_VALUES + 1);
+        assert_eq!(breakdown.campaign["c0"], 2);
+        assert_eq!(breakdown.campaign[OTHER], 2);
+        assert_eq!(breakdown.region["eu"], MAX_VALUES as u64 + 2);
+        assert!(breakdown.client.is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+//! How much of the key-value store is used, and a cap on it
+//!
+//! Usage is measured by sweeping every key within the namespace, so a
+//! tenant's keys for a tenant's requests and the whole store, every tenant's
+//! keys included, for untenanted ones, as stores' quotas are store-wide.
+//! Sweeps read every value, so their results are kept under
+//! `__storage_usage` and reused for a minute.
+//!
+//! With `storage_limit` set, registrations, bulk inserts and merges first
+//! check the usage against it. Over the limit, what can be rebuilt is
+//! pruned, cached verdicts first and then the storage traffic summaries,
+//! and if that isn't enough they are refused with a 507 until space is
+//! freed. Spent nonces that expired are forgotten before anything is
+//! pruned. Nothing else is ever pruned: the filter, the audit log, the
+//! nonces still refused and imports being uploaded would be lost for good.
+
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{app::App, error::Error, kv::Kv, nonce};
+
+const USAGE_KEY: &str = "__storage_usage";
+/// How long a sweep's results are reused for, in seconds
+const SWEEP_INTERVAL: u64 = 60;
+
+/// What a key holds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    /// Filter slices with their shards, the quarantine filter and a shadow
+    /// filter store's slices
+    Filter,
+    /// Cached database verdicts
+    Cache,
+    /// Registrations waiting for their email to be verified
+    Pending,
+    /// The chunks of imports being uploaded
+    Imports,
+    /// Spent add nonces
+    Nonces,
+    Audit,
+    /// Metrics and usage summaries
+    Metrics,
+    /// Storage traffic summaries
+    Traffic,
+    /// Lookup budgets and signup quotas
+    Quotas,
+    /// The keys of tenants, when sweeping the untenanted namespace
+    Tenants,
+    Other,
+}
+
+/// What may be pruned, in the order it is
+const PRUNABLE: &[Kind] = &[Kind::Cache, Kind::Traffic];
+
+impl Kind {
+    fn of(key: &str) -> Self {
+        const METRICS: &[&str] = &[
+            "__metrics",
+            "__storage_usage",
+            "__key_usage",
+            "__sources",
+            "__domain_counts",
+            "__quarantine_hits",
+            "__compactions",
+        ];
+        const QUOTAS: &[&str] = &[
+            "__lookup_budget",
+            "__client_lookup_budget",
+            "__domain_sketch",
+        ];
+        if key == "__storage_traffic" {
+            Kind::Traffic
+        } else if METRICS.contains(&key) {
+            Kind::Metrics
+        } else if QUOTAS.contains(&key) {
+            Kind::Quotas
+        } else if key.starts_with("t/") {
+            Kind::Tenants
+        } else if key.starts_with("__verdict:") {
+            Kind::Cache
+        } else if key.starts_with("__pending:") {
+            Kind::Pending
+        } else if key.starts_with("__import:") {
+            Kind::Imports
+        } else if key.starts_with("__nonce:") || key == "__spent_nonces" {
+            Kind::Nonces
+        } else if key.starts_with("__audit") {
+            Kind::Audit
+        } else if ["__state", "__invites", "__quarantine", "__shadow/"]
+            .iter()
+            .any(|filter| key.starts_with(filter))
+        {
+            Kind::Filter
+        } else {
+            Kind::Other
+        }
+    }
+}
+
+/// How many keys and bytes, keys included
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Size {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+impl Size {
+    fn add(&mut self, key: &str, value: &[u8]) {
+        self.keys += 1;
+        self.bytes += (key.len() + value.len()) as u64;
+    }
+}
+
+/// What a sweep found
+#[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Usage {
+    /// When the sweep ran, in Unix seconds
+    pub swept_at: u64,
+    #[serde(flatten)]
+    pub total: Size,
+    /// Keyed by what the keys hold
+    pub kinds: BTreeMap<String, Size>,
+    /// The configured `storage_limit`, in bytes
+    #[serde(skip_deserializing)]
+    pub limit: Option<u64>,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// The name `kind` is reported under
+fn name(kind: Kind) -> String {
+    serde_json::to_value(kind)
+        .ok()
+        .and_then(|name| name.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Measure every key in the namespace
+fn sweep(store: &Kv) -> Result<Usage> {
+    let mut usage = Usage {
+        swept_at: now()?,
+        ..Usage::default()
+    };
+    for key in store.keys()? {
+        let Some(value) = store.get(&key)? else {
+            continue;
+        };
+        usage.total.add(&key, &value);
+        usage
+            .kinds
+            .entry(name(Kind::of(&key)))
+            .or_default()
+            .add(&key, &value);
+    }
+    store.set(USAGE_KEY, &serde_json::to_vec(&usage)?)?;
+    Ok(usage)
+}
+
+/// Delete the keys of `kind`, returning how many bytes that freed
+fn prune(store: &Kv, kind: Kind) -> Result<u64> {
+    let mut freed = 0;
+    for key in store.keys()? {
+        if Kind::of(&key) != kind {
+            continue;
+        }
+        if let Some(value) = store.get(&key)? {
+            store.delete(&key)?;
+            freed += (key.len() + value.len()) as u64;
+        }
+    }
+    Ok(freed)
+}
+
+impl App {
+    /// The store's usage, from the last sweep if it is recent enough
+    pub fn storage_usage(&self) -> Result<Usage> {
+        let recent = match self.store.get(USAGE_KEY)? {
+            Some(json) => serde_json::from_slice::<Usage>(&json)
+                .ok()
+                .filter(|usage| usage.swept_at + SWEEP_INTERVAL > now().unwrap_or(u64::MAX)),
+            None => None,
+        };
+        let mut usage = match recent {
+            Some(usage) => usage,
+            None => sweep(&self.store)?,
+        };
+        usage.limit = self.config.storage_limit.map(|limit| limit as u64);
+        Ok(usage)
+    }
+
+    /// Refuse a write that adds to the store once it is over
+    /// `storage_limit`, unless pruning brings it back under
+    pub fn ensure_storage(&self) -> Result<(), Error> {
+        let Some(limit) = self.config.storage_limit.map(|limit| limit as u64) else {
+            return Ok(());
+        };
+        let usage = self.storage_usage()?;
+        if usage.total.bytes <= limit {
+            return Ok(());
+        }
+        // Only what the sweep found is pruned, so that once nothing is left
+        // to prune writes are refused without sweeping again
+        let mut used = usage.total.bytes;
+        let mut pruned = false;
+        if usage
+            .kinds
+            .get(&name(Kind::Nonces))
+            .is_some_and(|size| size.keys > 0)
+        {
+            let freed = nonce::forget_expired(&self.store)?;
+            eprintln!(
+                "the store is over its `storage_limit`, forgot {freed} bytes of spent nonces"
+            );
+            used = used.saturating_sub(freed);
+            pruned = freed > 0;
+        }
+        for &kind in PRUNABLE {
+            if used <= limit {
+                break;
+            }
+            if !usage
+                .kinds
+                .get(&name(kind))
+                .is_some_and(|size| size.keys > 0)
+            {
+                continue;
+            }
+            let freed = prune(&self.store, kind)?;
+            eprintln!("the store is over its `storage_limit`, pruned {freed} bytes of {kind:?}");
+            used = used.saturating_sub(freed);
+            pruned = true;
+        }
+        if pruned {
+            used = sweep(&self.store)?.total.bytes;
+        }
+        if used <= limit {
+            return Ok(());
+        }
+        Err(Error::InsufficientStorage(format!(
+            "the store holds {used} bytes, over the `storage_limit` of {limit}, and nothing \
+             more can be pruned"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_keys() {
+        assert_eq!(Kind::of("__state"), Kind::Filter);
+        assert_eq!(Kind::of("__state:2/17"), Kind::Filter);
+        assert_eq!(Kind::of("__invites@timing"), Kind::Filter);
+        assert_eq!(Kind::of("__quarantine"), Kind::Filter);
+        assert_eq!(Kind::of("__shadow/__state/m1/3"), Kind::Filter);
+        assert_eq!(Kind::of("__quarantine_hits"), Kind::Metrics);
+        assert_eq!(Kind::of("__compactions"), Kind::Metrics);
+        assert_eq!(Kind::of("__verdict:state:00ff"), Kind::Cache);
+        assert_eq!(Kind::of("__audit:12"), Kind::Audit);
+        assert_eq!(Kind::of("__import:00ff/3"), Kind::Imports);
+        assert_eq!(Kind::of("__spent_nonces"), Kind::Nonces);
+        assert_eq!(Kind::of("__nonce:00ff00ff00ff00ff"), Kind::Nonces);
+        assert_eq!(Kind::of("__domain_sketch"), Kind::Quotas);
+        assert_eq!(Kind::of("t/shop/__state"), Kind::Tenants);
+        assert_eq!(Kind::of("visitors"), Kind::Other);
+        assert_eq!(name(Kind::Cache), "cache");
+        assert_eq!(Kind::of("__storage_traffic"), Kind::Traffic);
+        assert!(PRUNABLE
+            .iter()
+            .all(|kind| !matches!(kind, Kind::Filter | Kind::Audit | Kind::Nonces)));
+    }
+}
@@ -0,0 +1,195 @@
+//! Isolated filters for the apps sharing a deployment
+//!
+//! Requests name their tenant in the `tenant` header, which must be one of
+//! those listed in `tenants`; requests without it are served from the
+//! untenanted state as before. A tenant's values are kept under its own
+//! prefix in the stores, so it has its own filter, runtime overrides, caches
+//! and metrics, and its users and invites in its own tables, named after the
+//! configured ones with a `<tenant>_` prefix. The `service` database has no
+//! tables of ours, so every request to the user service names the tenant in
+//! the same `tenant` header, and the service must keep tenants apart. Having
+//! to list tenants up front keeps clients from creating state for any name
+//! they send.
+//!
+//! Besides the settings every runtime override may change, a tenant's may
+//! set its filters' size and store and its user service failure policy (see
+//! [`crate::config::TENANT_TUNABLE`]) through `PUT /admin/config` with its
+//! `tenant` header, since tenants of very different sizes need very
+//! different filters. A size set this way takes precedence over the one
+//! `tenants` lists.
+
+use anyhow::{Context, Result};
+use spin_sdk::http::Request;
+
+use crate::{
+    config::{Config, Overrides},
+    error::Error,
+    Params,
+};
+
+/// The header naming the tenant
+pub(crate) const HEADER: &str = "tenant";
+/// The variable listing the tenants, read before any overrides as those are
+/// kept per tenant
+const VARIABLE: &str = "tenants";
+/// The longest tenant name
+const MAX_NAME: usize = 32;
+
+/// A tenant and the size of its new filters, if it has its own
+#[derive(PartialEq, Debug)]
+pub(crate) struct Tenant {
+    pub name: String,
+    /// The tenant's size, whose counting, hashing and seed come from the
+    /// deployment's shape
+    pub filter: Option<Params>,
+}
+
+impl Tenant {
+    /// Point `config` at the tenant's tables, user service tenant and filter
+    /// shape, leaving the shape alone if the tenant's `overrides` size its
+    /// filters
+    pub fn apply(&self, config: &mut Config, overrides: &Overrides) {
+        config.tenant = Some(self.name.clone());
+        if !overrides.sizes_filters() {
+            config.filter = self.shape(config.filter);
+        }
+        config.users_table = format!("{}_{}", self.name, config.users_table);
+        config.invites_table = format!("{}_{}", self.name, config.invites_table);
+    }
+
+    /// The shape of the tenant's new filters on a deployment whose new
+    /// filters are of shape `configured`
+    fn shape(&self, configured: Params) -> Params {
+        match self.filter {
+            Some(filter) => Params {
+                counting: configured.counting,
+                ..filter.hashed_like(configured)
+            },
+            None => configured,
+        }
+    }
+}
+
+/// The tenants listed in `tenants`
+#[derive(Default, PartialEq, Debug)]
+pub(crate) struct Tenants(Vec<Tenant>);
+
+impl Tenants {
+    pub fn configured() -> Result<Self> {
+        spin_sdk::config::get(VARIABLE)
+            .unwrap_or_default()
+            .parse()
+            .with_context(|| format!("invalid value for variable `{VARIABLE}`"))
+    }
+
+    /// How many tenants there are
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The tenant `req` names, `None` for untenanted requests
+    pub fn of_request(&self, req: &Request) -> Result<Option<&Tenant>, Error> {
+        let Some(name) = req.headers().get(HEADER) else {
+            return Ok(None);
+        };
+        let name = name.to_str().unwrap_or_default().trim();
+        match self.0.iter().find(|tenant| tenant.name == name) {
+            Some(tenant) => Ok(Some(tenant)),
+            None => Err(Error::bad_request(format!("unknown tenant `{name}`"))),
+        }
+    }
+}
+
+impl std::str::FromStr for Tenants {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated `name`, `name:<expected items>` or
+    /// `name:<expected items>@<false positive rate>` entries
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tenants: Vec<Tenant> = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, size) = match entry.split_once(':') {
+                Some((name, size)) => (name.trim(), Some(size.trim())),
+                None => (entry, None),
+            };
+            let valid = !name.is_empty()
+                && name.len() <= MAX_NAME
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !valid {
+                anyhow::bail!(
+                    "tenant names are 1 to {MAX_NAME} lowercase ASCII letters, digits and `_`"
+                );
+            }
+            if tenants.iter().any(|tenant| tenant.name == name) {
+                anyhow::bail!("tenant `{name}` is listed twice");
+            }
+            let filter = size
+                .map(|size| {
+                    let (items, fp_rate) = size.split_once('@').unwrap_or((size, "0.01"));
+                    let items = items.parse().context("expected items must be a number")?;
+                    let fp_rate = fp_rate
+                        .parse()
+                        .context("false positive rates must be numbers")?;
+                    Params::optimal(items, fp_rate, false)
+                })
+                .transpose()
+                .with_context(|| format!("invalid size for tenant `{name}`"))?;
+            tenants.push(Tenant {
+                name: name.to_owned(),
+                filter,
+            });
+        }
+        Ok(Self(tenants))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BloomFilter, Exists, ProbeSet, Removal};
+
+    #[test]
+    fn parses_names_and_sizes() {
+        let tenants: Tenants = "shop, blog:1000, forum:1000@0.001".parse().unwrap();
+        assert_eq!(
+            tenants.0.iter().map(|t| t.filter).collect::<Vec<_>>(),
+            [
+                None,
+                Some(Params::optimal(1000, 0.01, false).unwrap()),
+                Some(Params::optimal(1000, 0.001, false).unwrap()),
+            ]
+        );
+        assert_eq!(tenants.0[0].name, "shop");
+        assert_eq!("".parse::<Tenants>().unwrap(), Tenants::default());
+        assert!("shop, shop".parse::<Tenants>().is_err());
+        assert!("Shop".parse::<Tenants>().is_err());
+        assert!("a/b".parse::<Tenants>().is_err());
+        assert!("blog:many".parse::<Tenants>().is_err());
+        assert!("blog:0".parse::<Tenants>().is_err());
+    }
+
+    #[test]
+    fn sized_tenants_count_like_the_deployment() {
+        let tenants: Tenants = "shop, blog:1000".parse().unwrap();
+        let counting = Params::new(4096, 3, true).unwrap();
+        assert_eq!(tenants.0[0].shape(counting), counting);
+        let shape = tenants.0[1].shape(counting);
+        assert!(shape.counting);
+        assert_eq!(
+            shape.num_bits,
+            Params::optimal(1000, 0.01, true).unwrap().num_bits
+        );
+
+        let mut filter = BloomFilter::new(shape);
+        let probes = ProbeSet::new(&"me@example.com");
+        filter.insert_probes(&probes);
+        assert_eq!(
+            filter.remove_probes(&probes).unwrap(),
+            Removal::Removed { saturated: 0 }
+        );
+        assert_eq!(filter.exists_probes(&probes), Exists::No);
+        assert!(!tenants.0[1].shape(Params::LEGACY).counting);
+    }
+}
@@ -0,0 +1,95 @@
+//! Sampled decision traces
+//!
+//! Whether a request is traced is decided by hashing the canonical email it
+//! concerns, so all requests for one email are either traced or not. That
+//! hash isn't logged, as anyone could compute it for guessed emails: traces
+//! name the email by an HMAC made with the first `reference_secret` key, and
+//! not at all without one.
+
+use std::fmt::Write;
+
+/// Percentage of requests traced per route
+#[derive(Default)]
+pub(crate) struct Sampling(Vec<(String, f64)>);
+
+impl Sampling {
+    fn percent(&self, route: &str) -> f64 {
+        self.0
+            .iter()
+            .find(|(r, _)| r == route)
+            .map_or(0.0, |(_, p)| *p)
+    }
+}
+
+impl std::str::FromStr for Sampling {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated `route=percent` pairs, e.g. `available=1,add=100`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut routes = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((route, percent)) = entry.split_once('=') else {
+                anyhow::bail!("expected `route=percent`, found {entry:?}");
+            };
+            let percent: f64 = percent.trim().parse()?;
+            if !(0.0..=100.0).contains(&percent) {
+                anyhow::bail!("sample percentage {percent} is not between 0 and 100");
+            }
+            routes.push((route.trim().to_owned(), percent));
+        }
+        Ok(Self(routes))
+    }
+}
+
+/// The decisions taken while handling one request
+pub(crate) struct Trace {
+    route: &'static str,
+    /// The recorded steps, `None` when the request isn't sampled
+    steps: Option<String>,
+}
+
+impl Trace {
+    pub fn start(route: &'static str, key: &str, sampling: &Sampling) -> Self {
+        let key_hash = crate::fnv(&key) as u32;
+        let sampled = f64::from(key_hash % 10_000) < sampling.percent(route) * 100.0;
+        Self {
+            route,
+            steps: sampled.then(String::new),
+        }
+    }
+
+    /// Record a step; the description is only built for sampled requests
+    pub fn step(&mut self, describe: impl FnOnce() -> String) {
+        if let Some(steps) = &mut self.steps {
+            let _ = write!(steps, " {}", describe());
+        }
+    }
+
+    /// Log the trace of a sampled request
+    pub fn finish(self, status: u16) {
+        if let Some(steps) = self.steps {
+            eprintln!("trace route={}{steps} status={status}", self.route);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_is_per_route_and_deterministic() {
+        let sampling: Sampling = "available=100, add=0".parse().unwrap();
+        assert!(Trace::start("available", "me@example.com", &sampling)
+            .steps
+            .is_some());
+        assert!(Trace::start("add", "me@example.com", &sampling)
+            .steps
+            .is_none());
+        assert!(Trace::start("other", "me@example.com", &sampling)
+            .steps
+            .is_none());
+        assert!("available=101".parse::<Sampling>().is_err());
+        assert!("available".parse::<Sampling>().is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Storage traffic per route, for attributing metered store costs
+//!
+//! With `storage_traffic` set, how many values each route read and wrote,
+//! and how many bytes they held, is summed per hour and kept for the last
+//! two days. The summary's own reads and writes, like those of metrics and
+//! of catching up other stores, aren't counted.
+
+use anyhow::Result;
+use spin_sdk::http::{Request, Response};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{app::App, encoding::Encoding, error::Error, kv::Kv};
+
+const TRAFFIC_KEY: &str = "__storage_traffic";
+/// How many hours of traffic are kept
+const HOURS: u64 = 48;
+/// How many distinct routes an hour tracks before lumping new ones together
+const MAX_ROUTES: usize = 50;
+/// The route counted for routes beyond `MAX_ROUTES`
+const OTHER: &str = "(other)";
+
+/// What a route read and wrote
+#[derive(Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+struct Usage {
+    reads: u64,
+    read_bytes: u64,
+    writes: u64,
+    written_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.reads += other.reads;
+        self.read_bytes += other.read_bytes;
+        self.writes += other.writes;
+        self.written_bytes += other.written_bytes;
+    }
+}
+
+/// Usage per route, per hour
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Traffic {
+    /// Keyed by the start of the hour, in seconds since the epoch
+    #[serde(default)]
+    hours: BTreeMap<u64, BTreeMap<String, Usage>>,
+}
+
+impl Traffic {
+    fn load(store: &Kv) -> Result<Self> {
+        Ok(match store.get(TRAFFIC_KEY)? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => Self::default(),
+        })
+    }
+
+    /// Add a request's usage at `now`
+    fn record(&mut self, now: u64, route: &str, usage: &Usage) {
+        let hour = now - now % 3600;
+        self.hours.retain(|start, _| *start + HOURS * 3600 > hour);
+        let routes = self.hours.entry(hour).or_default();
+        let route = if routes.contains_key(route) || routes.len() < MAX_ROUTES {
+            route
+        } else {
+            OTHER
+        };
+        routes.entry(route.to_owned()).or_default().add(usage);
+    }
+}
+
+/// Add a request to `route` that read and wrote values of the given sizes
+pub(crate) fn record(store: &Kv, route: &str, reads: &[usize], writes: &[usize]) -> Result<()> {
+    let usage = Usage {
+        reads: reads.len() as u64,
+        read_bytes: reads.iter().sum::<usize>() as u64,
+        writes: writes.len() as u64,
+        written_bytes: writes.iter().sum::<usize>() as u64,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    // Without compare and swap, concurrent requests may lose some traffic
+    let mut traffic = Traffic::load(store)?;
+    traffic.record(now, route, &usage);
+    store.set(TRAFFIC_KEY, &serde_json::to_vec(&traffic)?)
+}
+
+impl App {
+    /// Report the store traffic per route and hour
+    ///
+    /// Like the admin endpoints this requires the admin token.
+    pub fn storage_traffic(&self, req: Request) -> Result<Response, Error> {
+        self.authorize(&req)?;
+        Ok(Encoding::accepted(&req).response(&Traffic::load(&self.store)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_per_hour_and_forgets_old_hours() {
+        let usage = Usage {
+            reads: 2,
+            read_bytes: 100,
+            writes: 1,
+            written_bytes: 40,
+        };
+        let mut traffic = Traffic::default();
+        traffic.record(7200, "GET /email", &usage);
+        traffic.record(7300, "GET /email", &usage);
+        assert_eq!(traffic.hours[&7200]["GET /email"].read_bytes, 200);
+
+        for i in 0..MAX_ROUTES + 1 {
+            traffic.record(7200, &format!("GET /{i}"), &usage);
+        }
+        assert_eq!(traffic.hours[&7200].len(), MAX_ROUTES + 1);
+        assert_eq!(traffic.hours[&7200][OTHER].reads, 4);
+
+        traffic.record(7200 + HOURS * 3600, "GET /email", &usage);
+        assert_eq!(
+            traffic.hours.keys().collect::<Vec<_>>(),
+            [&(7200 + HOURS * 3600)]
+        );
+    }
+}
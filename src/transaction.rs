@@ -0,0 +1,111 @@
+//! Writing several keys as one
+//!
+//! The stores can't write more than one key atomically, so a change spread
+//! over several keys, such as the shards of a filter and the manifest
+//! listing their checksums, can fail halfway and leave keys that disagree
+//! with each other. A [`KvTransaction`] stages the writes, applies them in
+//! the order they were staged, reading each key back to check the write
+//! landed, and keeps what every key held before. When a write fails or reads
+//! back as someone else's, the keys already written are restored in reverse
+//! order. The rollback is best effort: a key another writer changed since is
+//! left alone, as restoring it would lose their write, and a restore that
+//! fails is only logged.
+
+use anyhow::Result;
+
+use crate::kv::Kv;
+
+/// A key that read back differently from what was just written to it, so
+/// another writer got in between
+#[derive(Debug)]
+pub(crate) struct Interleaved(pub String);
+
+impl std::fmt::Display for Interleaved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` was written concurrently", self.0)
+    }
+}
+
+impl std::error::Error for Interleaved {}
+
+/// Writes to several keys, applied in order and rolled back on failure
+pub(crate) struct KvTransaction<'a> {
+    store: &'a Kv,
+    /// The staged writes, `None` for a delete
+    writes: Vec<(String, Option<Vec<u8>>)>,
+}
+
+/// How to undo a write
+struct Undo<'w> {
+    key: &'w str,
+    /// What the key held before, `None` if it didn't exist
+    previous: Option<Vec<u8>>,
+    written: Option<&'w [u8]>,
+}
+
+impl<'a> KvTransaction<'a> {
+    pub fn new(store: &'a Kv) -> Self {
+        Self {
+            store,
+            writes: Vec::new(),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: Vec<u8>) {
+        self.writes.push((key.to_owned(), Some(value)));
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.writes.push((key.to_owned(), None));
+    }
+
+    /// Apply the staged writes, rolling back those applied if one fails
+    ///
+    /// A write that reads back as someone else's fails with [`Interleaved`].
+    pub fn commit(self) -> Result<()> {
+        let mut applied = Vec::with_capacity(self.writes.len());
+        for (key, value) in &self.writes {
+            let written = value.as_deref();
+            let result = self.store.get(key).and_then(|previous| {
+                applied.push(Undo {
+                    key,
+                    previous,
+                    written,
+                });
+                match written {
+                    Some(value) => self.store.set(key, value)?,
+                    None => self.store.delete(key)?,
+                }
+                if self.store.get(key)?.as_deref() != written {
+                    return Err(Interleaved(key.clone()).into());
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                self.roll_back(applied);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the keys in `applied` that still hold what was written to
+    /// them, latest first
+    fn roll_back(&self, applied: Vec<Undo>) {
+        for undo in applied.into_iter().rev() {
+            let restored = self.store.get(undo.key).and_then(|current| {
+                if current.as_deref() != undo.written {
+                    eprintln!("left `{}` alone on rollback, it changed since", undo.key);
+                    return Ok(());
+                }
+                match &undo.previous {
+                    Some(previous) => self.store.set(undo.key, previous),
+                    None => self.store.delete(undo.key),
+                }
+            });
+            if let Err(e) = restored {
+                eprintln!("failed to roll back `{}`: {e:#}", undo.key);
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Checks every request goes through before reaching its handler
+//!
+//! A request with a body must say it is JSON, or another media type on the
+//! routes that take one, and name no charset other than UTF-8; anything else
+//! is refused with 415. JSON bodies of routes with a schema must match it, or
+//! are refused with 400 and where they don't. With `strict_requests` set,
+//! query parameters the route doesn't know are refused with 400, so that a
+//! misspelled parameter isn't silently ignored.
+
+use http::Method;
+use spin_sdk::http::Request;
+
+use crate::{
+    bulk,
+    encoding::{self, MSGPACK, MSGPACK_LEGACY},
+    error::Error,
+    import, merge, schema,
+};
+
+/// The query parameters and body media types a route takes
+struct Route {
+    method: Method,
+    path: &'static str,
+    query: &'static [&'static str],
+    bodies: &'static [&'static str],
+    /// The schema JSON bodies must match
+    schema: Option<&'static str>,
+}
+
+const JSON: &[&str] = &[encoding::JSON];
+
+/// The routes taking query parameters or bodies; any other route takes
+/// neither parameters nor bodies other than JSON
+const ROUTES: &[Route] = &[
+    Route {
+        method: Method::GET,
+        path: "/email",
+        query: &["email"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/email",
+        query: &[],
+        bodies: JSON,
+        schema: Some("add.request"),
+    },
+    Route {
+        method: Method::DELETE,
+        path: "/email",
+        query: &["email"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/check",
+        query: &[],
+        bodies: JSON,
+        schema: Some("check.request"),
+    },
+    Route {
+        method: Method::GET,
+        path: "/widget/available",
+        query: &["email"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/heatmap",
+        query: &["buckets", "format"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::PUT,
+        path: "/admin/config",
+        query: &[],
+        bodies: &[encoding::JSON, MSGPACK, MSGPACK_LEGACY],
+        schema: Some("config.request"),
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/capacity",
+        query: &["signups_per_day", "horizon_days", "target_fpr"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/bulk",
+        query: &[],
+        bodies: &[encoding::JSON, bulk::LINES],
+        // Checked as the body is read, see `bulk::Listing`
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/audit",
+        query: &["after", "limit"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/budget",
+        query: &["client"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/reconcile",
+        query: &["limit"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/rebalance",
+        query: &["threshold"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/compactions",
+        query: &["limit"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/export",
+        query: &["slice"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/merge",
+        query: &[],
+        bodies: &[merge::FILTER],
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/merge/estimate",
+        query: &[],
+        bodies: &[merge::FILTER],
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/subtract",
+        query: &[],
+        bodies: &[merge::FILTER],
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/import",
+        query: &["format"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/import",
+        query: &["session"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::PUT,
+        path: "/admin/import",
+        query: &["session", "sequence", "sha256"],
+        bodies: &[import::CHUNK],
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/import/commit",
+        query: &["session"],
+        bodies: JSON,
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/quarantine",
+        query: &[],
+        bodies: &[encoding::JSON, bulk::LINES],
+        schema: None,
+    },
+    Route {
+        method: Method::POST,
+        path: "/support/reference",
+        query: &[],
+        bodies: JSON,
+        schema: Some("reference.request"),
+    },
+    Route {
+        method: Method::POST,
+        path: "/support/reference/verify",
+        query: &[],
+        bodies: JSON,
+        schema: Some("reference-verify.request"),
+    },
+];
+
+/// Refuse requests whose body or, when `strict`, query the route doesn't take
+pub(crate) fn check(req: &Request, strict: bool) -> Result<(), Error> {
+    let route = ROUTES
+        .iter()
+        .find(|r| r.method == req.method() && r.path == req.uri().path());
+    let (query, bodies) = route.map_or((&[][..], JSON), |r| (r.query, r.bodies));
+
+    if let Some(body) = req.body().as_ref().filter(|body| !body.is_empty()) {
+        let content_type = req
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let essence = encoding::essence(content_type);
+        let supported = bodies.iter().any(|b| b.eq_ignore_ascii_case(essence))
+            && utf8_or_unspecified(content_type);
+        if !supported {
+            return Err(Error::UnsupportedMediaType(format!(
+                "bodies must be sent as {}",
+                bodies.join(" or ")
+            )));
+        }
+        if let Some(name) = route.and_then(|r| r.schema) {
+            if essence.eq_ignore_ascii_case(encoding::JSON) {
+                schema::validate(name, body)?;
+            }
+        }
+    }
+
+    if strict {
+        if let Some(unknown) = unknown_parameter(req.uri().query().unwrap_or_default(), query) {
+            return Err(Error::bad_request(format!(
+                "unknown query parameter `{unknown}`"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a media type names no charset or UTF-8
+fn utf8_or_unspecified(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .skip(1)
+        .all(|param| match param.trim().split_once('=') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("charset") => {
+                let value = value.trim().trim_matches('"');
+                value.eq_ignore_ascii_case("utf-8") || value.eq_ignore_ascii_case("utf8")
+            }
+            _ => true,
+        })
+}
+
+/// The first parameter in `query` that isn't `known`
+fn unknown_parameter<'a>(query: &'a str, known: &[&str]) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').map_or(pair, |(name, _)| name))
+        .find(|name| !known.contains(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charsets_and_parameters() {
+        assert!(utf8_or_unspecified("application/json"));
+        assert!(utf8_or_unspecified("application/json; charset=UTF-8"));
+        assert!(utf8_or_unspecified(r#"application/json;charset="utf-8""#));
+        assert!(!utf8_or_unspecified("application/json; charset=latin1"));
+
+        let known = &["email"][..];
+        assert_eq!(unknown_parameter("email=me@example.com", known), None);
+        assert_eq!(unknown_parameter("", known), None);
+        assert_eq!(unknown_parameter("email=a&emial=b", known), Some("emial"));
+        assert_eq!(unknown_parameter("debug", known), Some("debug"));
+    }
+}
@@ -0,0 +1,105 @@
+//! Short-lived cache of database verdicts
+//!
+//! A signup session typically checks the same email several times. Database
+//! answers are cached under a hash of the canonical email, as
+//! `Config::addresses` makes it, so repeated checks skip the expensive
+//! lookup. The store has no expiry, so entries carry their own deadline and
+//! stale ones are simply overwritten by the next lookup.
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::kv::Kv;
+
+/// The cache entry for the canonical `email` in the filter stored at
+/// `state_key`
+///
+/// The email is taken as it is: canonicalization already folded whatever
+/// the configured normalization treats as one user, and folding more here
+/// would let distinct users share a verdict.
+fn key(state_key: &str, email: &str) -> String {
+    let hash = (crate::murmur3(&email) as u64) << 32 | crate::fnv(&email) as u64;
+    let filter = state_key.trim_start_matches('_');
+    format!("__verdict:{filter}:{hash:016x}")
+}
+
+/// The cached verdict on whether `email` is in the set, if still fresh
+pub(crate) fn get(store: &Kv, state_key: &str, email: &str) -> Result<Option<bool>> {
+    let Some(entry) = store.get(&key(state_key, email))? else {
+        return Ok(None);
+    };
+    Ok(decode(&entry, now()?))
+}
+
+/// Cache whether `email` is in the set for `ttl`
+pub(crate) fn put(
+    store: &Kv,
+    state_key: &str,
+    email: &str,
+    found: bool,
+    ttl: Duration,
+) -> Result<()> {
+    let entry = encode(found, now()? + ttl.as_secs());
+    store.set(&key(state_key, email), &entry)
+}
+
+fn encode(found: bool, expires: u64) -> [u8; 9] {
+    let mut entry = [0u8; 9];
+    entry[0] = found as u8;
+    entry[1..].copy_from_slice(&expires.to_be_bytes());
+    entry
+}
+
+fn decode(entry: &[u8], now: u64) -> Option<bool> {
+    let (&found, expires) = entry.split_first()?;
+    let expires = u64::from_be_bytes(expires.try_into().ok()?);
+    (now < expires).then_some(found == 1)
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::Normalization;
+
+    #[test]
+    fn entries_expire() {
+        assert_eq!(decode(&encode(true, 100), 99), Some(true));
+        assert_eq!(decode(&encode(false, 100), 99), Some(false));
+        assert_eq!(decode(&encode(true, 100), 100), None);
+        assert_eq!(decode(&[1, 2, 3], 0), None);
+        assert_ne!(
+            key("__state", "me@example.com"),
+            key("__invites", "me@example.com")
+        );
+    }
+
+    #[test]
+    fn users_told_apart_by_case_have_their_own_verdicts() {
+        let keep_case = Normalization {
+            fold_local_case: false,
+            strip_gmail_dots: false,
+        };
+        let (upper, lower) = (
+            keep_case.apply(" Foo@Example.com"),
+            keep_case.apply("foo@example.com"),
+        );
+        assert_ne!(key("__state", &upper), key("__state", &lower));
+        assert_eq!(
+            key("__state", &upper),
+            key("__state", &keep_case.apply("Foo@example.com"))
+        );
+
+        let fold_case = Normalization {
+            fold_local_case: true,
+            ..keep_case
+        };
+        assert_eq!(
+            key("__state", &fold_case.apply("Foo@Example.com")),
+            key("__state", &fold_case.apply("foo@example.com"))
+        );
+    }
+}
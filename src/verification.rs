@@ -0,0 +1,145 @@
+//! Ownership verification before registering an email
+//!
+//! With a `verification_url` configured, `add` asks that service whether the
+//! email's owner has confirmed it and only registers confirmed emails. Answers
+//! must be signed with one of the `verification_secret` keys so that a
+//! spoofed or tampered answer can't get an email inserted. Unconfirmed emails are kept as
+//! pending rather than being inserted into the filter.
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    keys::{self, Keyring},
+    kv::Kv,
+};
+
+/// The header carrying the HMAC-SHA256 of the answer's body, as
+/// `keyid=<id>;sig=<hex>`
+const SIGNATURE_HEADER: &str = "verification-signature";
+/// The keyring's name in the key usage report
+const KEYRING: &str = "verification";
+
+#[derive(serde::Serialize)]
+struct Question<'a> {
+    email: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct Answer {
+    email: String,
+    verified: bool,
+}
+
+/// Ask the verification service whether `email` has been confirmed
+pub(crate) fn verify(store: &Kv, url: &str, keys: &Keyring, email: &str) -> Result<bool> {
+    let request = http::Request::builder()
+        .method("POST")
+        .uri(url)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(serde_json::to_vec(&Question { email })?.into()))?;
+    let response = spin_sdk::outbound_http::send_request(request)
+        .map_err(|e| anyhow::anyhow!("verification request failed: {e:?}"))?;
+    if !response.status().is_success() {
+        anyhow::bail!("verification service answered {}", response.status());
+    }
+    let signature = response
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .context("verification answer is not signed")?;
+    let (key_id, signature) = parse_signature(signature)?;
+    let secret = keys
+        .get(key_id)
+        .with_context(|| format!("verification answer is signed with unknown key `{key_id}`"))?;
+    let body = response.body().as_deref().unwrap_or_default();
+    check_signature(secret, body, signature)?;
+    keys::record_use(store, KEYRING, key_id)?;
+    let answer: Answer = serde_json::from_slice(body)?;
+    // A signed answer about another email mustn't vouch for this one
+    Ok(answer.verified && answer.email == email)
+}
+
+/// Split a signature header into the key ID and the signature itself
+fn parse_signature(header: &str) -> Result<(&str, &str)> {
+    let (mut key_id, mut signature) = (None, None);
+    for param in header.split(';') {
+        match param.trim().split_once('=') {
+            Some(("keyid", value)) => key_id = Some(value),
+            Some(("sig", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+    key_id
+        .zip(signature)
+        .context("verification signature must be `keyid=<id>;sig=<hex>`")
+}
+
+fn check_signature(secret: &str, body: &[u8], signature: &str) -> Result<()> {
+    let signature = decode_hex(signature).context("malformed verification signature")?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow::anyhow!("verification answer has a bad signature"))
+}
+
+pub(crate) fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn pending_key(email: &str) -> String {
+    format!(
+        "__pending:{:08x}{:08x}",
+        crate::murmur3(&email),
+        crate::fnv(&email)
+    )
+}
+
+/// Remember that `email` tried to register before being confirmed
+pub(crate) fn record_pending(store: &Kv, email: &str) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    store.set(&pending_key(email), &now.to_be_bytes())
+}
+
+/// Forget a pending registration once it has been confirmed
+pub(crate) fn clear_pending(store: &Kv, email: &str) -> Result<()> {
+    store.delete(&pending_key(email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_signatures() {
+        let body = br#"{"email":"me@example.com","verified":true}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert!(check_signature("secret", body, &signature).is_ok());
+        assert!(check_signature("other", body, &signature).is_err());
+        assert!(check_signature("secret", b"{}", &signature).is_err());
+        assert!(check_signature("secret", body, "zz").is_err());
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(
+            parse_signature("keyid=k2; sig=00ff").unwrap(),
+            ("k2", "00ff")
+        );
+        assert!(parse_signature("00ff").is_err());
+    }
+}
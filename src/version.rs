@@ -0,0 +1,122 @@
+//! Build and runtime information for operators
+
+use anyhow::Result;
+use spin_sdk::http::Response;
+
+use crate::config::{DegradedPolicy, Mode};
+use crate::{
+    alias::AliasDomains, app::App, db, length, normalize::Normalization, rotation::Period,
+    tenant::Tenants, Params,
+};
+
+/// The optional cargo features, with whether the component was built with
+/// each
+const CARGO_FEATURES: &[(&str, bool)] = &[
+    ("redis-backend", cfg!(feature = "redis-backend")),
+    ("sqlite-db", cfg!(feature = "sqlite-db")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("admin-api", cfg!(feature = "admin-api")),
+    ("counting-filter", cfg!(feature = "counting-filter")),
+    ("chaos", cfg!(feature = "chaos")),
+];
+
+#[derive(serde::Serialize)]
+struct Version<'a> {
+    version: &'static str,
+    git_sha: &'static str,
+    build_timestamp: u64,
+    /// The generation of the runtime configuration overrides in effect
+    config_generation: u64,
+    /// The cargo features the component was built with
+    cargo_features: Vec<&'static str>,
+    flags: Flags<'a>,
+    /// The shape of newly created filters
+    filter: Params,
+}
+
+/// The runtime settings that change the component's behavior
+#[derive(serde::Serialize)]
+struct Flags<'a> {
+    filter_mode: Mode,
+    user_database: db::Backend,
+    bloom_scalable: bool,
+    bloom_rotation: Option<RotationFlags>,
+    bloom_shard_bits: Option<usize>,
+    filter_store: &'static str,
+    shadow_filter_store: Option<&'static str>,
+    shadow_shard_bits: Option<usize>,
+    /// How many tenants are configured
+    tenants: usize,
+    /// The tenant the request named
+    tenant: Option<&'a str>,
+    strict_requests: bool,
+    lookup_budget: Option<u64>,
+    lookup_budget_window_secs: u64,
+    lookup_degraded_policy: DegradedPolicy,
+    domain_signup_limit: Option<u32>,
+    domain_signup_window_secs: u64,
+    verdict_cache_ttl_secs: Option<u64>,
+    plus_alias_domains: &'a AliasDomains,
+    email_normalization: Normalization,
+    email_length: length::Limit,
+    similar_names: bool,
+}
+
+/// How the filter rotates
+#[derive(serde::Serialize)]
+struct RotationFlags {
+    /// The length of fixed windows, `None` when windows follow
+    /// `bloom_rotation_schedule`
+    window_secs: Option<u64>,
+    scheduled: bool,
+    windows: usize,
+}
+
+impl App {
+    /// Report what exactly is deployed
+    pub fn version(&self) -> Result<Response> {
+        let config = &self.config;
+        crate::json_response(&Version {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("GIT_SHA"),
+            build_timestamp: env!("BUILD_TIMESTAMP").parse()?,
+            config_generation: config.generation,
+            cargo_features: CARGO_FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| *feature)
+                .collect(),
+            flags: Flags {
+                filter_mode: config.mode,
+                user_database: config.database,
+                bloom_scalable: config.scalable,
+                bloom_rotation: config.rotation.map(|rotation| RotationFlags {
+                    window_secs: match rotation.period {
+                        Period::Every(window) => Some(window.as_secs()),
+                        Period::Schedule(_) => None,
+                    },
+                    scheduled: matches!(rotation.period, Period::Schedule(_)),
+                    windows: rotation.windows,
+                }),
+                bloom_shard_bits: config.shard_bits,
+                filter_store: config.filter_store.name(),
+                shadow_filter_store: config.shadow.as_ref().map(|shadow| shadow.backend.name()),
+                shadow_shard_bits: config.shadow.as_ref().and_then(|shadow| shadow.shard_bits),
+                tenants: Tenants::configured()?.count(),
+                tenant: config.tenant.as_deref(),
+                strict_requests: config.strict_requests,
+                lookup_budget: config.lookup_budget,
+                lookup_budget_window_secs: config.lookup_budget_window.as_secs(),
+                lookup_degraded_policy: config.degraded_policy,
+                domain_signup_limit: config.domain_signup_limit,
+                domain_signup_window_secs: config.domain_signup_window.as_secs(),
+                verdict_cache_ttl_secs: config.verdict_cache_ttl.map(|d| d.as_secs()),
+                plus_alias_domains: &config.alias_domains,
+                email_normalization: config.normalization,
+                email_length: config.email_length,
+                similar_names: config.similar_names,
+            },
+            filter: config.filter,
+        })
+    }
+}
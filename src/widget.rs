@@ -0,0 +1,119 @@
+//! A public availability hint for signup forms, made to be served from a CDN
+//!
+//! `GET /widget/available` answers from the filter alone, so a "no" means the
+//! email is free and a "maybe" means it is probably taken. Answers may be
+//! cached briefly and served stale while the edge revalidates them, which
+//! keeps typeahead traffic away from the component.
+
+use spin_sdk::http::{Request, Response};
+
+use crate::{app::App, config::Mode, error::Error, Exists, ProbeSet};
+
+/// How long an edge may serve an answer without asking again, in seconds
+const MAX_AGE: u64 = 10;
+/// How long past `MAX_AGE` an edge may serve an answer while revalidating it
+const STALE_WHILE_REVALIDATE: u64 = 60;
+/// The header naming the filter and configuration an answer was derived from
+const GENERATION_HEADER: &str = "widget-generation";
+
+#[derive(serde::Deserialize)]
+struct Query {
+    email: String,
+}
+
+#[derive(serde::Serialize)]
+struct Hint {
+    /// Whether the filter rules the email out, so it is certainly free
+    available: bool,
+}
+
+impl App {
+    /// Hint whether an email is free, with caching headers for a CDN
+    ///
+    /// Answers carry a `widget-generation` header and a matching
+    /// `Surrogate-Key`, which change whenever the filter or the runtime
+    /// configuration does, so that an edge can purge the answers of older
+    /// generations. A request whose `If-None-Match` names the current ETag
+    /// is answered 304.
+    pub fn widget(&self, req: Request) -> Result<Response, Error> {
+        // In allowlist mode this would reveal who is invited
+        if self.config.mode != Mode::Denylist {
+            return Err(Error::NotFound);
+        }
+        let Some(query) = req.uri().query() else {
+            return Err(Error::bad_request("no query argument"));
+        };
+        let query: Query = serde_qs::from_str(query).map_err(Error::bad_request)?;
+        self.config.email_length.check(&query.email)?;
+        // Not `read_filter`, so that a degraded answer isn't cached
+        let filter = self.load_filter()?;
+
+        let hint = Hint {
+            available: self
+                .config
+                .addresses(&query.email)
+                .iter()
+                .all(|email| filter.exists_probes(&ProbeSet::new(email)) == Exists::No),
+        };
+        let generation = format!(
+            "{}-{:08x}",
+            self.config.generation,
+            crate::fnv(
+                &filter
+                    .slices()
+                    .iter()
+                    .map(|slice| slice.array.as_raw_slice())
+                    .collect::<Vec<_>>()
+            ) as u32
+        );
+        let etag = format!("\"{generation}-{}\"", u8::from(hint.available));
+
+        let not_modified = req
+            .headers()
+            .get(http::header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|tags| names(tags, &etag));
+        let response = http::Response::builder()
+            .header(
+                http::header::CACHE_CONTROL,
+                format!(
+                    "public, max-age={MAX_AGE}, stale-while-revalidate={STALE_WHILE_REVALIDATE}"
+                ),
+            )
+            .header(http::header::ETAG, &etag)
+            // Each tenant has a filter of its own
+            .header(http::header::VARY, crate::tenant::HEADER)
+            .header(GENERATION_HEADER, &generation)
+            .header("surrogate-key", format!("widget widget-{generation}"));
+        let response = if not_modified {
+            response.status(304).body(None)
+        } else {
+            let body = serde_json::to_vec(&hint).map_err(anyhow::Error::from)?;
+            response
+                .status(200)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Some(body.into()))
+        };
+        Ok(response.map_err(anyhow::Error::from)?)
+    }
+}
+
+/// Whether an `If-None-Match` list names `etag`
+fn names(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_etags() {
+        assert!(names(r#""0-1a2b-1""#, r#""0-1a2b-1""#));
+        assert!(names(r#""0-ffff-0", W/"0-1a2b-1""#, r#""0-1a2b-1""#));
+        assert!(names("*", r#""0-1a2b-1""#));
+        assert!(!names(r#""0-1a2b-0""#, r#""0-1a2b-1""#));
+    }
+}